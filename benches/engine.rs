@@ -1,7 +1,8 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{AutoCompaction, Durability, FlushPolicy, KvStore, KvStoreOptions, KvsEngine, SledKvsEngine};
 use rand::prelude::*;
 use sled;
+use std::time::Duration;
 use tempfile::TempDir;
 
 fn set_bench(c: &mut Criterion) {
@@ -37,6 +38,55 @@ fn set_bench(c: &mut Criterion) {
     group.finish();
 }
 
+fn set_batch_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set_batch_bench");
+    let pairs = || (1..(1 << 12)).map(|i| (format!("key{}", i), "value".to_string())).collect::<Vec<_>>();
+    group.bench_function("kvs", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (KvStore::open(temp_dir.path()).unwrap(), pairs(), temp_dir)
+            },
+            |(store, pairs, _temp_dir)| store.set_batch(pairs).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("sled", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (SledKvsEngine::new(sled::open(&temp_dir).unwrap()).unwrap(), pairs(), temp_dir)
+            },
+            |(db, pairs, _temp_dir)| db.set_batch(pairs).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn lazy_flush_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lazy_flush_bench");
+    for &lazy_flush in &[false, true] {
+        group.bench_function(format!("lazy_flush_{}", lazy_flush), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let options = KvStoreOptions::new().lazy_flush(lazy_flush);
+                    (KvStore::open_with_options(temp_dir.path(), options).unwrap(), temp_dir)
+                },
+                |(store, _temp_dir)| {
+                    for i in 1..(1 << 12) {
+                        store.set(format!("key{}", i), "value".to_string()).unwrap();
+                    }
+                    store.flush().unwrap();
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
 fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_bench");
     for i in &vec![8, 12, 16, 20] {
@@ -73,5 +123,254 @@ fn get_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(engine, set_bench, get_bench);
+fn sled_flush_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sled_flush_bench");
+    for &policy in &[FlushPolicy::Always, FlushPolicy::EveryN(64), FlushPolicy::Never] {
+        group.bench_function(format!("{:?}", policy), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let db = sled::open(&temp_dir).unwrap();
+                    (SledKvsEngine::with_flush_policy(db, policy).unwrap(), temp_dir)
+                },
+                |(db, _temp_dir)| {
+                    for i in 1..(1 << 10) {
+                        db.set(format!("key{}", i), "value".to_string()).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn concurrent_large_set_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_large_set_bench");
+    group.sample_size(10);
+    let value = "v".repeat(1 << 20);
+    for &threads in &[1, 4] {
+        group.bench_function(format!("threads_{}", threads), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
+                },
+                |(store, _temp_dir)| {
+                    crossbeam_utils::thread::scope(|scope| {
+                        for thread in 0..threads {
+                            let store = store.clone();
+                            let value = &value;
+                            scope.spawn(move |_| {
+                                for i in 0..(16 / threads) {
+                                    store.set(format!("key{}-{}", thread, i), value.clone()).unwrap();
+                                }
+                            });
+                        }
+                    })
+                    .unwrap();
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+// Fsynced sets of 8 writer threads, each fsyncing its own writes or sharing
+// the fsyncs of a group commit.
+fn group_commit_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("group_commit_bench");
+    group.sample_size(10);
+    for &group_commit in &[false, true] {
+        group.bench_function(format!("group_commit_{}", group_commit), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let mut options = KvStoreOptions::new().durability(Durability::Fsync);
+                    if group_commit {
+                        options = options.group_commit(Duration::from_micros(100));
+                    }
+                    (KvStore::open_with_options(temp_dir.path(), options).unwrap(), temp_dir)
+                },
+                |(store, _temp_dir)| {
+                    crossbeam_utils::thread::scope(|scope| {
+                        for thread in 0..8 {
+                            let store = store.clone();
+                            scope.spawn(move |_| {
+                                for i in 0..(1 << 6) {
+                                    store.set(format!("key{}-{}", thread, i), "value".to_string()).unwrap();
+                                }
+                            });
+                        }
+                    })
+                    .unwrap();
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn open_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open_bench");
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 1..(1 << 16) {
+        store.set(format!("key{}", i), "value".to_string()).unwrap();
+    }
+    drop(store);
+    for &expected_keys in &[0, 1 << 16] {
+        group.bench_function(format!("expected_keys_{}", expected_keys), |b| {
+            b.iter(|| {
+                let options = KvStoreOptions::new().expected_keys(expected_keys);
+                KvStore::open_with_options(temp_dir.path(), options).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn large_value_compaction_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("large_value_compaction_bench");
+    group.sample_size(10);
+    let value = "v".repeat(1 << 14);
+    for &separated in &[false, true] {
+        group.bench_function(format!("separated_{}", separated), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let mut options = KvStoreOptions::new().auto_compaction(AutoCompaction::Off);
+                    if separated {
+                        options = options.value_separation(1 << 10);
+                    }
+                    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+                    for i in 0..(1 << 10) {
+                        store.set(format!("key{}", i), value.clone()).unwrap();
+                        store.set(format!("counter{}", i % 16), i.to_string()).unwrap();
+                    }
+                    (store, temp_dir)
+                },
+                |(store, _temp_dir)| store.compact().unwrap(),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+// Writes and reads of large values with the default buffers and with buffers
+// holding a whole value.
+fn buffer_size_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("buffer_size_bench");
+    group.sample_size(10);
+    let value = "v".repeat(1 << 18);
+    for &buffer_size in &[8 << 10, 1 << 20] {
+        group.bench_function(format!("buffer_{}", buffer_size), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let options = KvStoreOptions::new().buffer_size(buffer_size);
+                    (KvStore::open_with_options(temp_dir.path(), options).unwrap(), temp_dir)
+                },
+                |(store, _temp_dir)| {
+                    for i in 0..64 {
+                        store.set(format!("key{}", i), value.clone()).unwrap();
+                    }
+                    for i in 0..64 {
+                        store.get(format!("key{}", i)).unwrap();
+                    }
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+// Reads right after opening, with and without a warmup first. Both find the
+// files in the page cache unless it is dropped between the iterations, e.g. by
+// `echo 3 > /proc/sys/vm/drop_caches` as root, for cold numbers.
+fn warmup_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("warmup_bench");
+    group.sample_size(10);
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let value = "v".repeat(1 << 10);
+    for i in 0..(1 << 14) {
+        store.set(format!("key{}", i), value.clone()).unwrap();
+    }
+    drop(store);
+    for &warmup in &[false, true] {
+        group.bench_function(format!("warmup_{}", warmup), |b| {
+            b.iter(|| {
+                let store = KvStore::open(temp_dir.path()).unwrap();
+                if warmup {
+                    store.warmup().unwrap();
+                }
+                let mut rng = thread_rng();
+                for _ in 0..(1 << 10) {
+                    store.get(format!("key{}", rng.gen_range(0..1 << 14))).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+// Random reads of four threads, named by the read path of the build. Run it
+// with and without `--features mmap` to compare reads of mapped logs with reads
+// through the buffered readers.
+fn read_path_bench(c: &mut Criterion) {
+    let path = if cfg!(feature = "mmap") { "mmap" } else { "buffered" };
+    let mut group = c.benchmark_group("read_path_bench");
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..(1 << 14) {
+        store.set(format!("key{}", i), "value".to_string()).unwrap();
+    }
+    group.bench_function(format!("get_{}", path), |b| {
+        b.iter(|| {
+            crossbeam_utils::thread::scope(|scope| {
+                for _ in 0..4 {
+                    let store = store.clone();
+                    scope.spawn(move |_| {
+                        let mut rng = thread_rng();
+                        for _ in 0..(1 << 8) {
+                            store.get(format!("key{}", rng.gen_range(0..1 << 14))).unwrap();
+                        }
+                    });
+                }
+            })
+            .unwrap();
+        })
+    });
+    group.finish();
+}
+
+// Writes of values needing escapes in JSON, named by the log format of the
+// build. Run it with and without `--features bincode` to compare the formats.
+fn log_format_bench(c: &mut Criterion) {
+    let format = if cfg!(feature = "bincode") { "bincode" } else { "json" };
+    let mut group = c.benchmark_group("log_format_bench");
+    let value = "line \"quoted\"\n".repeat(16);
+    group.bench_function(format!("set_{}", format), |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(store, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    store.set(format!("key{}", i), value.clone()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(engine, set_bench, set_batch_bench, lazy_flush_bench, get_bench, sled_flush_bench, concurrent_large_set_bench, group_commit_bench, open_bench, large_value_compaction_bench, buffer_size_bench, warmup_bench, read_path_bench, log_format_bench);
 criterion_main!(engine);
\ No newline at end of file
@@ -1,5 +1,5 @@
 use criterion::{criterion_group, criterion_main, Criterion, BenchmarkGroup};
-use kvs::{KvServer, KvStore, KvsClient, SledKvsEngine};
+use kvs::{Codec, KvServer, KvStore, KvsClient, SledKvsEngine};
 use tempfile::TempDir;
 use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool, RayonThreadPool};
 use std::thread;
@@ -55,8 +55,34 @@ fn read_rayon_sled(c: &mut Criterion) {
     group.finish();
 }
 
+fn codec_large_value(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec_large_value");
+    let addr = "127.0.0.1:7001";
+    thread::spawn(move || {
+        let temp_dir = TempDir::new().unwrap();
+        let kv_store = KvStore::open(temp_dir.path()).unwrap();
+        let pool = RayonThreadPool::new(4).unwrap();
+        KvServer::new(kv_store).start(addr, pool).unwrap();
+    });
+    while KvsClient::connect_timeout(addr, Duration::from_secs(1)).is_err() {
+        println!("Wait KvServer {} starting...", addr);
+        thread::sleep(Duration::from_secs(1));
+    }
 
-
+    let value = "value".repeat(20000);
+    for &codec in &[Codec::Json, Codec::Bincode] {
+        group.bench_function(format!("{:?}", codec), |b| {
+            let mut client = KvsClient::connect_with_codec(addr, codec).unwrap();
+            b.iter(|| {
+                for i in 0..100 {
+                    client.set(format!("key_{}", i), value.clone()).unwrap();
+                    client.get(format!("key_{}", i)).unwrap();
+                }
+            });
+        });
+    }
+    group.finish();
+}
 
 fn start_kv_store_server_with_queue(max_thread: u32, port: u32) {
     for thread_count in 1..max_thread {
@@ -102,9 +128,8 @@ fn run_write_bench(group: &mut BenchmarkGroup<WallTime>, max_thread: u32, port:
     for thread_count in 1..max_thread {
         let addr = format!("127.0.0.1:{}", port + thread_count);
         loop {
-            if let Ok(mut client) = KvsClient::connect(&addr) {
-                client.set("key".to_string(), "value".to_string()).unwrap();
-                assert_eq!(Some("value".to_string()), client.get("key".to_string()).unwrap());
+            if let Ok(mut client) = KvsClient::connect_timeout(&addr, Duration::from_secs(1)) {
+                client.ping().unwrap();
                 println!("Start KvServer Success: {}", &addr);
                 break;
             } else {
@@ -117,7 +142,7 @@ fn run_write_bench(group: &mut BenchmarkGroup<WallTime>, max_thread: u32, port:
             let mut client = KvsClient::connect(&addr).unwrap();
             b.iter(|| {
                 for i in 0..1000 {
-                    client.set(format!("key_{}", i), "value".to_string()).unwrap();
+                    client.set(format!("key_{}", i), "value").unwrap();
                 }
             });
         });
@@ -128,9 +153,9 @@ fn run_read_bench(group: &mut BenchmarkGroup<WallTime>, max_thread: u32, port: u
     for thread_count in 1..max_thread {
         let addr = format!("127.0.0.1:{}", port + thread_count);
         loop {
-            if let Ok(mut client) = KvsClient::connect(&addr) {
-                client.set("key".to_string(), "value".to_string()).unwrap();
-                assert_eq!(Some("value".to_string()), client.get("key".to_string()).expect("Get value failed from KvServer"));
+            if let Ok(mut client) = KvsClient::connect_timeout(&addr, Duration::from_secs(1)) {
+                client.set("key", "value").unwrap();
+                assert_eq!(Some("value".to_string()), client.get("key").expect("Get value failed from KvServer"));
                 println!("Start KvServer Success: {}", &addr);
                 break;
             } else {
@@ -141,7 +166,7 @@ fn run_read_bench(group: &mut BenchmarkGroup<WallTime>, max_thread: u32, port: u
         group.bench_function(format!("{}-thread", thread_count), |b| {
             let mut client = KvsClient::connect(&addr).unwrap();
             for i in 0..1000 {
-                client.set(format!("key_{}", i), "value".to_string()).unwrap();
+                client.set(format!("key_{}", i), "value").unwrap();
             }
             b.iter(|| {
                 for i in 0..1000 {
@@ -154,6 +179,46 @@ fn run_read_bench(group: &mut BenchmarkGroup<WallTime>, max_thread: u32, port: u
 
 
 
+fn pipeline_writes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pipeline_writes");
+    let addr = "127.0.0.1:7002";
+    thread::spawn(move || {
+        let temp_dir = TempDir::new().unwrap();
+        let kv_store = KvStore::open(temp_dir.path()).unwrap();
+        let pool = RayonThreadPool::new(4).unwrap();
+        KvServer::new(kv_store).start(addr, pool).unwrap();
+    });
+    while KvsClient::connect_timeout(addr, Duration::from_secs(1)).is_err() {
+        println!("Wait KvServer {} starting...", addr);
+        thread::sleep(Duration::from_secs(1));
+    }
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    group.bench_function("serial", |b| {
+        b.iter(|| {
+            for i in 0..1000 {
+                client.set(format!("key_{}", i), "value").unwrap();
+            }
+        });
+    });
+    group.bench_function("pipelined", |b| {
+        b.iter(|| {
+            let mut pipeline = client.pipeline();
+            for i in 0..1000 {
+                pipeline.set(format!("key_{}", i), "value");
+            }
+            pipeline.execute().unwrap();
+        });
+    });
+    group.bench_function("batched", |b| {
+        b.iter(|| {
+            let pairs = (0..1000).map(|i| (format!("key_{}", i), "value".to_owned())).collect();
+            assert!(client.set_batch(pairs).unwrap().all_ok());
+        });
+    });
+    group.finish();
+}
+
 criterion_group!(server,
     write_queued_kv_store,
     write_rayon_kv_store,
@@ -161,5 +226,7 @@ criterion_group!(server,
     read_rayon_kv_store,
     write_rayon_sled,
     read_rayon_sled,
+    codec_large_value,
+    pipeline_writes,
 );
 criterion_main!(server);
\ No newline at end of file
@@ -0,0 +1,87 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use crate::client::decode_response;
+use crate::protocol::{self, Codec, GetResponse, KvsRequest, RemoveResponse, SetResponse};
+use crate::Result;
+use serde::de::DeserializeOwned;
+
+/// Kvs client on a tokio connection, speaking the protocol of [`KvsClient`](crate::KvsClient).
+///
+/// Example:
+/// ```no_run
+/// # use kvs::{AsyncKvsClient, Result};
+/// # async fn try_main() -> Result<()> {
+/// let mut client = AsyncKvsClient::connect("127.0.0.1:4000").await?;
+/// client.set("key", "value").await?;
+/// assert_eq!(client.get("key").await?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncKvsClient {
+    codec: Codec,
+    stream: BufReader<TcpStream>,
+}
+
+impl AsyncKvsClient {
+    /// connect to kvs server
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        AsyncKvsClient::connect_with_codec(addr, Codec::default()).await
+    }
+
+    /// connect to kvs server, messages of the connection are encoded with codec
+    pub async fn connect_with_codec<A: ToSocketAddrs>(addr: A, codec: Codec) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        // requests are small, send them without waiting to coalesce them
+        stream.set_nodelay(true)?;
+        let mut client = AsyncKvsClient { codec, stream: BufReader::new(stream) };
+        client.handshake().await?;
+        Ok(client)
+    }
+
+    /// get value of key from server
+    pub async fn get(&mut self, key: impl Into<String>) -> Result<Option<String>> {
+        match self.request(&KvsRequest::Get { key: key.into() }).await? {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// set value for key to server
+    pub async fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> Result<()> {
+        match self.request(&KvsRequest::Set { key: key.into(), value: value.into() }).await? {
+            SetResponse::Ok(()) => Ok(()),
+            SetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// remove key from server, `KvsError::KeyNotFound` if it does not exist
+    pub async fn remove(&mut self, key: impl Into<String>) -> Result<()> {
+        match self.request(&KvsRequest::Remove { key: key.into() }).await? {
+            RemoveResponse::Ok(()) => Ok(()),
+            RemoveResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn handshake(&mut self) -> Result<()> {
+        self.stream.write_all(&protocol::client_hello(self.codec)).await?;
+        self.stream.flush().await?;
+        let status = self.stream.read_u8().await?;
+        let mut version = [0; 2];
+        if protocol::handshake_needs_version(status) {
+            self.stream.read_exact(&mut version).await?;
+        }
+        protocol::check_handshake(self.codec, status, version)
+    }
+
+    /// send a request and read its response
+    async fn request<R: DeserializeOwned>(&mut self, request: &KvsRequest) -> Result<R> {
+        let mut frame = Vec::new();
+        protocol::encode(self.codec, &mut frame, request)?;
+        self.stream.write_all(&frame).await?;
+        self.stream.flush().await?;
+        let length = self.stream.read_u32().await?;
+        let mut body = vec![0; length as usize];
+        self.stream.read_exact(&mut body).await?;
+        decode_response(self.codec, &body)
+    }
+}
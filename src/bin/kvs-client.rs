@@ -1,4 +1,6 @@
+use std::io::{self, BufRead, Write};
 use std::net::SocketAddr;
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
 use kvs::*;
 use std::process::exit;
@@ -46,6 +48,22 @@ enum Cmd {
         addr: SocketAddr,
     },
 
+    #[structopt(about = "Set the value of a string key, print its previous value.")]
+    Getset {
+        #[structopt(value_name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(value_name = "VALUE", help = "A string value of the key.")]
+        value: String,
+        #[structopt(
+        long,
+        help = "Set ip address and port number with the format IP:PORT.",
+        value_name = "IP:PORT",
+        default_value = DEFAULT_ADDR,
+        parse(try_from_str),
+        )]
+        addr: SocketAddr,
+    },
+
     #[structopt(about = "Remove a given key.")]
     Rm {
         #[structopt(value_name = "KEY", help = "A string key")]
@@ -59,8 +77,53 @@ enum Cmd {
         )]
         addr: SocketAddr,
     },
+
+    #[structopt(
+    about = "Add a delta to the integer of a key, print the new value.",
+    setting = AppSettings::AllowNegativeNumbers,
+    )]
+    Incr {
+        #[structopt(value_name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+        value_name = "DELTA",
+        help = "The integer to add, negative to subtract.",
+        default_value = "1",
+        )]
+        delta: i64,
+        #[structopt(
+        long,
+        help = "Set ip address and port number with the format IP:PORT.",
+        value_name = "IP:PORT",
+        default_value = DEFAULT_ADDR,
+        parse(try_from_str),
+        )]
+        addr: SocketAddr,
+    },
+
+    #[structopt(about = "Read commands from stdin and run them over one connection.")]
+    Shell {
+        #[structopt(
+        long,
+        help = "Set ip address and port number with the format IP:PORT.",
+        value_name = "IP:PORT",
+        default_value = DEFAULT_ADDR,
+        parse(try_from_str),
+        )]
+        addr: SocketAddr,
+    },
 }
 
+const SHELL_HELP: &str = "\
+get KEY          print the value of KEY
+set KEY VALUE    set KEY to VALUE, the rest of the line
+rm KEY           remove KEY
+keys             print every key
+stats            print the number of keys
+ping             check the connection
+help             print this help
+quit             exit the shell";
+
 fn main() {
     let opt = Opt::from_args() as Opt;
     if let Err(e) = execute(opt) {
@@ -84,10 +147,95 @@ fn execute(opt: Opt) -> Result<()> {
             let mut client = KvsClient::connect(addr)?;
             client.set(key, value)?;
         }
+        Cmd::Getset { key, value, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            if let Some(previous) = client.get_set(key, value)? {
+                println!("{}", previous)
+            } else {
+                println!("Key not found");
+            }
+        }
         Cmd::Rm { key, addr } => {
             let mut client = KvsClient::connect(addr)?;
             client.remove(key)?;
         }
+        Cmd::Incr { key, delta, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            println!("{}", client.increment(key, delta)?);
+        }
+        Cmd::Shell { addr } => {
+            let client = KvsClient::connect(addr)?;
+            shell(client)?;
+        }
     }
     Ok(())
+}
+
+/// Run the commands read from stdin until it ends or `quit`.
+/// The prompt goes to stderr, so the output of a piped session is only the results.
+fn shell(mut client: KvsClient) -> Result<()> {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        eprint!("kvs> ");
+        io::stderr().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+        match run_shell_command(&mut client, line.trim()) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            // the connection is unusable after an io error
+            Err(e @ KvsError::Io(_)) => return Err(e),
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
+
+/// Run one shell command, return false if the shell should exit.
+fn run_shell_command(client: &mut KvsClient, line: &str) -> Result<bool> {
+    let (command, args) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim_start()),
+        None => (line, ""),
+    };
+    match (command, args) {
+        ("", _) => {}
+        ("get", key) if !key.is_empty() => match client.get(key)? {
+            Some(value) => println!("{}", value),
+            None => println!("Key not found"),
+        },
+        ("set", args) => match args.find(char::is_whitespace) {
+            Some(i) => {
+                client.set(&args[..i], args[i..].trim_start())?;
+                println!("OK");
+            }
+            None => println!("usage: set KEY VALUE"),
+        },
+        ("rm", key) if !key.is_empty() => {
+            client.remove(key)?;
+            println!("OK");
+        }
+        ("keys", "") => {
+            for pair in client.dump() {
+                println!("{}", pair?.0);
+            }
+        }
+        ("stats", "") => {
+            let mut keys = 0;
+            for pair in client.dump() {
+                pair?;
+                keys += 1;
+            }
+            println!("keys: {}", keys);
+        }
+        ("ping", "") => {
+            client.ping()?;
+            println!("PONG");
+        }
+        ("help", _) => println!("{}", SHELL_HELP),
+        ("quit", _) | ("exit", _) => return Ok(false),
+        _ => println!("unknown command {:?}, type help for the commands", line),
+    }
+    Ok(true)
 }
\ No newline at end of file
@@ -7,7 +7,10 @@ use std::env::current_dir;
 use kvs::*;
 use std::fs;
 use std::process::exit;
-use kvs::thread_pool::{ThreadPool, RayonThreadPool};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use kvs::thread_pool::{ThreadPool, RayonThreadPool, SharedQueueThreadPool, NaiveThreadPool};
 
 const DEFAULT_ADDR: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
@@ -31,6 +34,32 @@ struct Opt {
     value_name = "ENGINE-NAME",
     )]
     engine: Option<Engine>,
+    #[structopt(
+    long,
+    default_value = "rayon",
+    help = "Set thread pool, either rayon, shared-queue or naive.",
+    possible_values = & Pool::variants(),
+    value_name = "POOL-NAME",
+    )]
+    pool: Pool,
+    #[structopt(
+    long,
+    help = "Set the number of worker threads. Default the number of CPUs.",
+    value_name = "THREADS",
+    )]
+    threads: Option<u32>,
+    #[structopt(
+    long,
+    help = "Close connections sending a request larger than BYTES. Default 64 MiB.",
+    value_name = "BYTES",
+    )]
+    max_request_bytes: Option<u64>,
+    #[structopt(
+    long,
+    help = "Log the engine stats every SECONDS. Default off.",
+    value_name = "SECONDS",
+    )]
+    metrics_interval: Option<u64>,
 }
 
 arg_enum! {
@@ -42,6 +71,43 @@ arg_enum! {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Pool {
+    Rayon,
+    SharedQueue,
+    Naive,
+}
+
+impl Pool {
+    fn variants() -> [&'static str; 3] {
+        ["rayon", "shared-queue", "naive"]
+    }
+}
+
+impl FromStr for Pool {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "rayon" => Ok(Pool::Rayon),
+            "shared-queue" => Ok(Pool::SharedQueue),
+            "naive" => Ok(Pool::Naive),
+            _ => Err(format!("valid values: {}", Pool::variants().join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Pool::Rayon => "rayon",
+            Pool::SharedQueue => "shared-queue",
+            Pool::Naive => "naive",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 fn main() {
     env_logger::builder().filter_level(LevelFilter::Debug).init();
     let mut opt = Opt::from_args() as Opt;
@@ -58,23 +124,24 @@ fn main() {
                 exit(1);
             }
 
-            let pool = RayonThreadPool::new(num_cpus::get() as u32)?;
             let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+            let threads = opt.threads.unwrap_or(num_cpus::get() as u32);
             info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
             info!("listening on {}", opt.addr);
             info!("use {} engines", engine);
+            info!("use {} thread pool with {} threads", opt.pool, threads);
 
             //save engine type.
             fs::write(current_dir()?.join(ENGINE_FILE_NAME), format!("{}", engine))?;
             match engine {
                 Engine::kvs => {
                     let store = KvStore::open(current_dir()?)?;
-                    start_server(&mut opt, store, pool)?;
+                    run_with_pool(&opt, store, threads)?;
                 }
                 Engine::sled => {
                     let db = sled::open(current_dir()?)?;
                     let engine = SledKvsEngine::new(db)?;
-                    start_server(&mut opt, engine, pool)?;
+                    run_with_pool(&opt, engine, threads)?;
                 }
             };
             Ok(())
@@ -85,8 +152,20 @@ fn main() {
     }
 }
 
-fn start_server<E: KvsEngine, P: ThreadPool>(opt: &mut Opt, engine: E, pool: P) -> Result<()> {
-    let server = KvServer::new(engine);
+fn run_with_pool<E: KvsEngine>(opt: &Opt, engine: E, threads: u32) -> Result<()> {
+    match opt.pool {
+        Pool::Rayon => start_server(opt, engine, RayonThreadPool::new(threads)?),
+        Pool::SharedQueue => start_server(opt, engine, SharedQueueThreadPool::new(threads)?),
+        Pool::Naive => start_server(opt, engine, NaiveThreadPool::new(threads)?),
+    }
+}
+
+fn start_server<E: KvsEngine, P: ThreadPool>(opt: &Opt, engine: E, pool: P) -> Result<()> {
+    let mut server = KvServer::new(engine)
+        .max_request_bytes(opt.max_request_bytes.unwrap_or(DEFAULT_MAX_REQUEST_BYTES));
+    if let Some(seconds) = opt.metrics_interval {
+        server = server.with_metrics_logging(Duration::from_secs(seconds));
+    }
     server.start(opt.addr, pool)?;
     Ok(())
 }
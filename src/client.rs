@@ -1,58 +1,716 @@
-use serde_json::de::Deserializer;
-use serde_json::de::{IoRead};
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpStream, ToSocketAddrs};
-use crate::{KvsError, Result};
-use crate::protocol::{GetResponse, SetResponse, RemoveResponse, KvsRequest};
-use serde::Deserialize;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+use crate::{DiskUsage, KvsError, Result, TypedValue, ValueType};
+use crate::protocol::{self, Capabilities, Codec, GetResponse, GetBatchResponse, ExistsResponse, CasResponse, AppendResponse, GetOrInsertResponse, GetSetResponse, SetResponse, RemoveResponse, PingInfo, PingResponse, WaitResponse, DumpResponse, RenameResponse, RemovePrefixResponse, SetBatchResponse, DiskUsageResponse, GetTypedResponse, IncrementResponse, UnknownCommandResponse, BusyResponse, BatchOutcome, KvsRequest};
+use serde::de::DeserializeOwned;
+
+/// The number of requests of a pipeline sent before reading their responses.
+const PIPELINE_WINDOW: usize = 128;
 
 /// Kvs Client.
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    codec: Codec,
+    reader: BufReader<TcpStream>,
     writer: BufWriter<TcpStream>,
+    // the times a request answered busy is sent at most, and the first delay between them
+    max_attempts: u32,
+    retry_delay: Duration,
+    // the addresses connected to, and how to connect again after losing the connection
+    addrs: Vec<SocketAddr>,
+    reconnect: Option<Reconnect>,
+    broken: bool,
+    // draws the jitter of the retry delays
+    jitter: Jitter,
+}
+
+/// How a client connects again after losing its connection, see [`KvsClient::reconnect`].
+#[derive(Clone, Copy, Debug)]
+struct Reconnect {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
 }
 
 impl KvsClient {
     /// connect to kvs server
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let reader_stream = TcpStream::connect(addr)?;
+        KvsClient::connect_with_codec(addr, Codec::default())
+    }
+
+    /// connect to kvs server, messages of the connection are encoded with codec
+    pub fn connect_with_codec<A: ToSocketAddrs>(addr: A, codec: Codec) -> Result<Self> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let stream = TcpStream::connect(&addrs[..])?;
+        KvsClient::handshake(stream, codec, addrs)
+    }
+
+    /// connect to kvs server, giving up on an address after `timeout`.
+    ///
+    /// Every address `addr` resolves to is tried in turn, the error of the last
+    /// one is returned, `KvsError::Timeout` if it did not answer in time. The
+    /// handshake is given `timeout` as well, the client then has no timeouts.
+    pub fn connect_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<Self> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let mut last_error = None;
+        for addr in &addrs {
+            match TcpStream::connect_timeout(addr, timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(timeout))?;
+                    stream.set_write_timeout(Some(timeout))?;
+                    let client = KvsClient::handshake(stream, Codec::default(), addrs).map_err(timed_out)?;
+                    client.set_read_timeout(None)?;
+                    client.set_write_timeout(None)?;
+                    return Ok(client);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(match last_error {
+            Some(e) if e.kind() == ErrorKind::TimedOut => KvsError::Timeout,
+            Some(e) => KvsError::Io(e),
+            None => KvsError::Io(io::Error::new(ErrorKind::InvalidInput, "address resolved to nothing")),
+        })
+    }
+
+    /// set up a client on a connected stream
+    fn handshake(reader_stream: TcpStream, codec: Codec, addrs: Vec<SocketAddr>) -> Result<Self> {
+        // requests are small, send them without waiting to coalesce them
+        reader_stream.set_nodelay(true)?;
         let writer_stream = reader_stream.try_clone()?;
-        Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(reader_stream)),
+        let mut client = KvsClient {
+            codec,
+            reader: BufReader::new(reader_stream),
             writer: BufWriter::new(writer_stream),
-        })
+            max_attempts: 1,
+            retry_delay: Duration::ZERO,
+            addrs,
+            reconnect: None,
+            broken: false,
+            jitter: Jitter::new(),
+        };
+        protocol::client_handshake(&mut client.reader, &mut client.writer, codec)?;
+        Ok(client)
+    }
+
+    /// send a request answered `KvsError::Busy` again, up to `max_attempts` times in all,
+    /// default once.
+    ///
+    /// The delays between the attempts double from `base_delay`, each one drawn at
+    /// random between its half and itself so the clients rejected together do not
+    /// retry together. The last error is returned if the server stays busy.
+    pub fn retry_busy(mut self, max_attempts: u32, base_delay: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.retry_delay = base_delay;
+        self
+    }
+
+    /// connect again when the connection fails, as after a restart of the server,
+    /// up to `max_attempts` times, default never.
+    ///
+    /// The delays between the attempts double from `base_delay` up to `max_delay`.
+    /// The request which failed is sent again once on the new connection if it
+    /// is idempotent, so a request may be applied twice. A request which is not,
+    /// as an `increment`, returns the error, the next request uses the new
+    /// connection. Streams and pipelines are not sent again.
+    pub fn reconnect(mut self, max_attempts: u32, base_delay: Duration, max_delay: Duration) -> Self {
+        self.reconnect = Some(Reconnect { max_attempts: max_attempts.max(1), base_delay, max_delay });
+        self
+    }
+
+    /// set `TCP_NODELAY` on the connection, true after connecting.
+    ///
+    /// Turn it off to let the system coalesce small writes, for throughput over latency.
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<()> {
+        Ok(self.reader.get_ref().set_nodelay(nodelay)?)
+    }
+
+    /// whether `TCP_NODELAY` is set on the connection
+    pub fn nodelay(&self) -> Result<bool> {
+        Ok(self.reader.get_ref().nodelay()?)
+    }
+
+    /// set how long to wait for a response before failing with `KvsError::Timeout`,
+    /// None to wait forever, the default.
+    ///
+    /// Drop the connection after a timeout, a late response would be read as
+    /// the answer to the next request.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.reader.get_ref().set_read_timeout(timeout)?)
+    }
+
+    /// set how long to wait for a request to be sent before failing with
+    /// `KvsError::Timeout`, None to wait forever, the default.
+    ///
+    /// Drop the connection after a timeout, the request may be sent in part.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.writer.get_ref().set_write_timeout(timeout)?)
+    }
+
+    /// whether the connection failed, as by an io error or a timeout, it is
+    /// then unusable unless the client reconnects
+    pub fn is_broken(&self) -> bool {
+        self.broken
     }
 
     /// get value of key from server
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &KvsRequest::Get { key })?;
-        self.writer.flush()?;
-        let response = GetResponse::deserialize(&mut self.reader)?;
-        match response {
+    pub fn get(&mut self, key: impl Into<String>) -> Result<Option<String>> {
+        match self.request(&KvsRequest::Get { key: key.into() })? {
             GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            GetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// get the values of many keys from server in one request.
+    ///
+    /// Return the values in the order of `keys`, none for a missing key.
+    pub fn get_batch(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self.request(&KvsRequest::GetBatch { keys })? {
+            GetBatchResponse::Ok(values) => Ok(values),
+            GetBatchResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// check whether key exists on server, without reading its value
+    pub fn contains_key(&mut self, key: impl Into<String>) -> Result<bool> {
+        match self.request(&KvsRequest::Exists { key: key.into() })? {
+            ExistsResponse::Ok(exists) => Ok(exists),
+            ExistsResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// get the value of key from server, set to `default` first if key is missing
+    pub fn get_or_insert(&mut self, key: impl Into<String>, default: impl Into<String>) -> Result<String> {
+        match self.request(&KvsRequest::GetOrInsert { key: key.into(), default: default.into() })? {
+            GetOrInsertResponse::Ok(value) => Ok(value),
+            GetOrInsertResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// set value for key to server, return the previous value of key
+    pub fn get_set(&mut self, key: impl Into<String>, value: impl Into<String>) -> Result<Option<String>> {
+        match self.request(&KvsRequest::GetSet { key: key.into(), value: value.into() })? {
+            GetSetResponse::Ok(previous) => Ok(previous),
+            GetSetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// append suffix to the value of key on server, return its new length
+    pub fn append(&mut self, key: impl Into<String>, suffix: impl Into<String>) -> Result<usize> {
+        match self.request(&KvsRequest::Append { key: key.into(), suffix: suffix.into() })? {
+            AppendResponse::Ok(len) => Ok(len as usize),
+            AppendResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// set key to `new` on server if its value is `expected`, `None` meaning
+    /// missing, return whether it was swapped
+    pub fn compare_and_swap(
+        &mut self,
+        key: impl Into<String>,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        match self.request(&KvsRequest::Cas { key: key.into(), expected, new })? {
+            CasResponse::Ok(swapped) => Ok(swapped),
+            CasResponse::Err(e) => Err(e.into()),
         }
     }
 
     /// set value for key to server
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &KvsRequest::Set { key, value })?;
-        self.writer.flush()?;
-        let response = SetResponse::deserialize(&mut self.reader)?;
-        match response {
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> Result<()> {
+        match self.request(&KvsRequest::Set { key: key.into(), value: value.into() })? {
             SetResponse::Ok(()) => Ok(()),
-            SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            SetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// set value for key to server tagged with its type
+    pub fn set_typed(&mut self, key: impl Into<String>, value: TypedValue) -> Result<()> {
+        match self.request(&KvsRequest::SetTyped { key: key.into(), value })? {
+            SetResponse::Ok(()) => Ok(()),
+            SetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// get value of key from server with the type it was set with
+    pub fn get_typed(&mut self, key: impl Into<String>) -> Result<Option<TypedValue>> {
+        match self.request(&KvsRequest::GetTyped { key: key.into() })? {
+            GetTypedResponse::Ok(value) => Ok(value),
+            GetTypedResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// set an integer for key to server
+    pub fn set_int(&mut self, key: impl Into<String>, value: i64) -> Result<()> {
+        self.set_typed(key, TypedValue::Int(value))
+    }
+
+    /// get the integer of key from server, `KvsError::TypeMismatch` if it is not one
+    pub fn get_int(&mut self, key: impl Into<String>) -> Result<Option<i64>> {
+        self.get_as(key, ValueType::Int, |value| match value {
+            TypedValue::Int(value) => Some(value),
+            _ => None,
+        })
+    }
+
+    /// set a floating point number for key to server
+    pub fn set_float(&mut self, key: impl Into<String>, value: f64) -> Result<()> {
+        self.set_typed(key, TypedValue::Float(value))
+    }
+
+    /// get the floating point number of key from server, `KvsError::TypeMismatch` if it is not one
+    pub fn get_float(&mut self, key: impl Into<String>) -> Result<Option<f64>> {
+        self.get_as(key, ValueType::Float, |value| match value {
+            TypedValue::Float(value) => Some(value),
+            _ => None,
+        })
+    }
+
+    /// set a boolean for key to server
+    pub fn set_bool(&mut self, key: impl Into<String>, value: bool) -> Result<()> {
+        self.set_typed(key, TypedValue::Bool(value))
+    }
+
+    /// get the boolean of key from server, `KvsError::TypeMismatch` if it is not one
+    pub fn get_bool(&mut self, key: impl Into<String>) -> Result<Option<bool>> {
+        self.get_as(key, ValueType::Bool, |value| match value {
+            TypedValue::Bool(value) => Some(value),
+            _ => None,
+        })
+    }
+
+    /// set bytes for key to server
+    pub fn set_bytes(&mut self, key: impl Into<String>, value: Vec<u8>) -> Result<()> {
+        self.set_typed(key, TypedValue::Bytes(value))
+    }
+
+    /// get the bytes of key from server, `KvsError::TypeMismatch` if they are not
+    pub fn get_bytes(&mut self, key: impl Into<String>) -> Result<Option<Vec<u8>>> {
+        self.get_as(key, ValueType::Bytes, |value| match value {
+            TypedValue::Bytes(value) => Some(value),
+            _ => None,
+        })
+    }
+
+    /// add `delta` to the integer of key on server, a missing key starts from 0.
+    ///
+    /// Return the new value, an error if the value is not an integer.
+    pub fn increment(&mut self, key: impl Into<String>, delta: i64) -> Result<i64> {
+        match self.request(&KvsRequest::Increment { key: key.into(), delta })? {
+            IncrementResponse::Ok(value) => Ok(value),
+            IncrementResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// set the values of many keys to server in one request.
+    ///
+    /// Return the outcome of every pair, in the order of the pairs.
+    pub fn set_batch(&mut self, pairs: Vec<(String, String)>) -> Result<BatchOutcome> {
+        match self.request(&KvsRequest::SetBatch { pairs })? {
+            SetBatchResponse::Ok(outcome) => Ok(outcome),
+            SetBatchResponse::Err(e) => Err(e.into()),
         }
     }
 
     /// remove key and value from server
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &KvsRequest::Remove { key })?;
-        self.writer.flush()?;
-        let response = RemoveResponse::deserialize(&mut self.reader)?;
-        match response {
+    pub fn remove(&mut self, key: impl Into<String>) -> Result<()> {
+        match self.request(&KvsRequest::Remove { key: key.into() })? {
             RemoveResponse::Ok(()) => Ok(()),
-            RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            RemoveResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// move the value of key `from` to key `to` on server
+    pub fn rename(&mut self, from: impl Into<String>, to: impl Into<String>) -> Result<()> {
+        match self.request(&KvsRequest::Rename { from: from.into(), to: to.into() })? {
+            RenameResponse::Ok(()) => Ok(()),
+            RenameResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// remove every key starting with `prefix` on server, return the number removed.
+    ///
+    /// Not atomic across keys, an error can leave some of them removed.
+    pub fn remove_prefix(&mut self, prefix: impl Into<String>) -> Result<usize> {
+        match self.request(&KvsRequest::RemovePrefix { prefix: prefix.into() })? {
+            RemovePrefixResponse::Ok(removed) => Ok(removed as usize),
+            RemovePrefixResponse::Err(e) => Err(e.into()),
         }
     }
-}
\ No newline at end of file
+
+    /// the approximate space the engine of the server takes on disk.
+    pub fn disk_usage(&mut self) -> Result<DiskUsage> {
+        match self.request(&KvsRequest::DiskUsage)? {
+            DiskUsageResponse::Ok(usage) => Ok(usage),
+            DiskUsageResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// what the server supports, query it once to adapt to older servers.
+    ///
+    /// A server older than the request answers `KvsError::UnsupportedCommand`,
+    /// it knows no more than the requests of its version.
+    ///
+    /// Example:
+    /// ```rust
+    /// # use kvs::{KvsClient, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// if client.capabilities()?.supports("Increment") {
+    ///     client.increment("visits".to_owned(), 1)?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn capabilities(&mut self) -> Result<Capabilities> {
+        self.request(&KvsRequest::Capabilities)
+    }
+
+    /// set value for key to server without waiting for a reply.
+    ///
+    /// Errors of the server applying it are reported by the next `ping`.
+    pub fn set_noreply(&mut self, key: impl Into<String>, value: impl Into<String>) -> Result<()> {
+        self.send(&KvsRequest::SetNoReply { key: key.into(), value: value.into() })
+    }
+
+    /// ping server, all previous requests have been applied when it returns.
+    ///
+    /// Return the version and engine of the server, or the first error of the
+    /// requests sent without reply since the last ping.
+    pub fn ping(&mut self) -> Result<PingInfo> {
+        match self.request(&KvsRequest::Ping)? {
+            PingResponse::Ok(info) => Ok(info),
+            PingResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// wait until key is written by another client, or timeout elapses.
+    ///
+    /// Return the value of key after the write, or `KvsError::Timeout`.
+    ///
+    /// The server parks the worker serving this connection for the whole wait,
+    /// up to `timeout` or the server shutdown: a pool of n threads serves no
+    /// other connection while n clients wait.
+    pub fn wait(&mut self, key: impl Into<String>, timeout: Duration) -> Result<Option<String>> {
+        let timeout_ms = timeout.as_millis() as u64;
+        match self.request(&KvsRequest::Wait { key: key.into(), timeout_ms })? {
+            WaitResponse::Ok(value) => Ok(value),
+            WaitResponse::Timeout => Err(KvsError::Timeout),
+            WaitResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// stream every key-value pair of the server.
+    ///
+    /// The connection can be used again after the iterator is dropped,
+    /// the rest of the stream is then read and discarded.
+    pub fn dump(&mut self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        self.stream(&KvsRequest::Dump)
+    }
+
+    /// stream the key-value pairs of the server whose key starts with `prefix`
+    /// and whose value contains `contains`, filtered by the server.
+    ///
+    /// An empty `prefix` or `contains` matches every pair. The connection can be
+    /// used again after the iterator is dropped, as after a `dump`.
+    pub fn scan_filter(&mut self, prefix: impl Into<String>, contains: impl Into<String>) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        self.stream(&KvsRequest::ScanFilter { prefix: prefix.into(), contains: contains.into() })
+    }
+
+    /// start a pipeline of requests, sent together by [`Pipeline::execute`]
+    /// instead of waiting for the response of each before sending the next.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline { client: self, requests: Vec::new() }
+    }
+
+    /// send a request answered by a stream of pairs
+    fn stream(&mut self, request: &KvsRequest) -> DumpIter<'_> {
+        let error = self.send(request).err();
+        DumpIter { done: error.is_some(), error, client: self }
+    }
+
+    /// get the value of key and convert it by `convert`, which returns None for other types.
+    fn get_as<T, F>(&mut self, key: impl Into<String>, expected: ValueType, convert: F) -> Result<Option<T>>
+        where F: FnOnce(TypedValue) -> Option<T>
+    {
+        match self.get_typed(key)? {
+            Some(value) => {
+                let found = value.value_type();
+                convert(value).map(Some).ok_or(KvsError::TypeMismatch { expected, found })
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// send a request and read its response, connecting again if the connection
+    /// failed and the client reconnects
+    fn request<R: DeserializeOwned>(&mut self, request: &KvsRequest) -> Result<R> {
+        match self.request_while_busy(request) {
+            Err(KvsError::Io(e)) if self.reconnect.is_some() => {
+                self.connect_again()?;
+                if request.is_idempotent() {
+                    self.request_while_busy(request)
+                } else {
+                    Err(KvsError::Io(e))
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// replace the connection by a new one to the same server, with the same options
+    fn connect_again(&mut self) -> Result<()> {
+        let policy = self.reconnect.expect("connect again without a reconnect policy");
+        let stream = self.reader.get_ref();
+        let (nodelay, read_timeout) = (stream.nodelay()?, stream.read_timeout()?);
+        let write_timeout = self.writer.get_ref().write_timeout()?;
+        let mut attempt = 1;
+        let client = loop {
+            match KvsClient::connect_with_codec(&self.addrs[..], self.codec) {
+                Ok(client) => break client,
+                Err(_) if attempt < policy.max_attempts => {
+                    thread::sleep(self.jitter.backoff(policy.base_delay, attempt).min(policy.max_delay));
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        client.set_nodelay(nodelay)?;
+        client.set_read_timeout(read_timeout)?;
+        client.set_write_timeout(write_timeout)?;
+        self.reader = client.reader;
+        self.writer = client.writer;
+        self.broken = false;
+        Ok(())
+    }
+
+    /// send a request and read its response, retrying while the server is busy
+    fn request_while_busy<R: DeserializeOwned>(&mut self, request: &KvsRequest) -> Result<R> {
+        let mut attempt = 1;
+        loop {
+            match self.request_once(request) {
+                Err(e) if e.is_retriable() && attempt < self.max_attempts => {
+                    thread::sleep(self.jitter.backoff(self.retry_delay, attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// send a request and read its response
+    fn request_once<R: DeserializeOwned>(&mut self, request: &KvsRequest) -> Result<R> {
+        self.send(request)?;
+        self.read_response()
+    }
+
+    /// read the response of the oldest request not answered yet
+    fn read_response<R: DeserializeOwned>(&mut self) -> Result<R> {
+        let body = protocol::read_frame(&mut self.reader, u32::MAX as u64).map_err(|e| self.failed(e))?;
+        decode_response(self.codec, &body)
+    }
+
+    fn send(&mut self, request: &KvsRequest) -> Result<()> {
+        protocol::encode(self.codec, &mut self.writer, request).map_err(|e| self.failed(e))?;
+        self.writer.flush().map_err(|e| self.failed(e.into()))
+    }
+
+    /// The error of reading or writing the connection, marking it broken if
+    /// the connection failed.
+    fn failed(&mut self, e: KvsError) -> KvsError {
+        let e = timed_out(e);
+        if matches!(e, KvsError::Io(_) | KvsError::Timeout) {
+            self.broken = true;
+        }
+        e
+    }
+}
+
+/// Decode the body of the response to a request, or the error the server
+/// answered instead.
+pub(crate) fn decode_response<R: DeserializeOwned>(codec: Codec, body: &[u8]) -> Result<R> {
+    protocol::decode_body(codec, body).map_err(|e| {
+        // a server older than the request answers that it does not know it
+        if let Ok(response) = protocol::decode_body::<UnknownCommandResponse>(codec, body) {
+            return KvsError::UnsupportedCommand(response.command);
+        }
+        match protocol::decode_body::<BusyResponse>(codec, body) {
+            Ok(BusyResponse) => KvsError::Busy,
+            Err(_) => e,
+        }
+    })
+}
+
+/// The error of reading or writing the connection, `KvsError::Timeout` if it
+/// timed out by the timeouts of the client.
+fn timed_out(e: KvsError) -> KvsError {
+    match e {
+        KvsError::Io(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => KvsError::Timeout,
+        e => e,
+    }
+}
+
+/// A xorshift generator of the jitter of the retry delays.
+#[derive(Debug)]
+struct Jitter(u64);
+
+impl Jitter {
+    /// Seed a generator at random, the clients started together draw apart.
+    fn new() -> Jitter {
+        // xorshift stays at zero once there
+        Jitter(RandomState::new().build_hasher().finish() | 1)
+    }
+
+    /// The delay after the failed attempt number `attempt`, doubling from `base`
+    /// with a random jitter of up to half of it.
+    fn backoff(&mut self, base: Duration, attempt: u32) -> Duration {
+        let mut random = self.0;
+        random ^= random << 13;
+        random ^= random >> 7;
+        random ^= random << 17;
+        self.0 = random;
+        let delay = base.saturating_mul(1 << (attempt - 1).min(16));
+        delay / 2 + delay.mul_f64(random as f64 / u64::MAX as f64 / 2.0)
+    }
+}
+
+/// Requests queued on a connection to be sent together, from [`KvsClient::pipeline`].
+///
+/// The requests are sent a window at a time, the responses of a window read
+/// before the next is sent, so neither peer blocks on a full socket buffer.
+/// A request answered busy is not retried.
+///
+/// Example:
+/// ```no_run
+/// # use kvs::{KvsClient, PipelineReply, Result};
+/// # fn try_main() -> Result<()> {
+/// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+/// let replies = client.pipeline().set("key1", "value1").get("key1").execute()?;
+/// assert_eq!(replies[1].as_ref().ok(), Some(&PipelineReply::Get(Some("value1".to_owned()))));
+/// # Ok(())
+/// # }
+/// ```
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<KvsRequest>,
+}
+
+/// The answer to a request of a [`Pipeline`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PipelineReply {
+    /// the set was applied
+    Set,
+    /// the value of the key read, None if it is missing
+    Get(Option<String>),
+    /// the remove was applied
+    Remove,
+}
+
+impl<'a> Pipeline<'a> {
+    /// queue setting the value of key
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.requests.push(KvsRequest::Set { key: key.into(), value: value.into() });
+        self
+    }
+
+    /// queue reading the value of key
+    pub fn get(&mut self, key: impl Into<String>) -> &mut Self {
+        self.requests.push(KvsRequest::Get { key: key.into() });
+        self
+    }
+
+    /// queue removing key
+    pub fn remove(&mut self, key: impl Into<String>) -> &mut Self {
+        self.requests.push(KvsRequest::Remove { key: key.into() });
+        self
+    }
+
+    /// send the queued requests and return their replies in the order queued,
+    /// the pipeline is then empty.
+    ///
+    /// The error of a single request is returned in its reply, an error of the
+    /// connection fails the whole pipeline and leaves the connection unusable.
+    pub fn execute(&mut self) -> Result<Vec<Result<PipelineReply>>> {
+        let requests = std::mem::take(&mut self.requests);
+        let mut replies = Vec::with_capacity(requests.len());
+        for window in requests.chunks(PIPELINE_WINDOW) {
+            for request in window {
+                protocol::encode(self.client.codec, &mut self.client.writer, request).map_err(|e| self.client.failed(e))?;
+            }
+            self.client.writer.flush().map_err(|e| self.client.failed(e.into()))?;
+            for request in window {
+                match self.read_reply(request) {
+                    Err(e @ (KvsError::Io(_) | KvsError::Timeout)) => return Err(e),
+                    reply => replies.push(reply),
+                }
+            }
+        }
+        Ok(replies)
+    }
+
+    fn read_reply(&mut self, request: &KvsRequest) -> Result<PipelineReply> {
+        match request {
+            KvsRequest::Set { .. } => match self.client.read_response()? {
+                SetResponse::Ok(()) => Ok(PipelineReply::Set),
+                SetResponse::Err(e) => Err(e.into()),
+            },
+            KvsRequest::Get { .. } => match self.client.read_response()? {
+                GetResponse::Ok(value) => Ok(PipelineReply::Get(value)),
+                GetResponse::Err(e) => Err(e.into()),
+            },
+            KvsRequest::Remove { .. } => match self.client.read_response()? {
+                RemoveResponse::Ok(()) => Ok(PipelineReply::Remove),
+                RemoveResponse::Err(e) => Err(e.into()),
+            },
+            _ => unreachable!("only sets, gets and removes are pipelined"),
+        }
+    }
+}
+
+/// Iterator over the stream answering a `Dump` or `ScanFilter` request.
+struct DumpIter<'a> {
+    client: &'a mut KvsClient,
+    // an error to return before ending
+    error: Option<KvsError>,
+    done: bool,
+}
+
+impl<'a> Iterator for DumpIter<'a> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        if self.done {
+            return None;
+        }
+        let response = protocol::decode(self.client.codec, &mut self.client.reader).map_err(|e| self.client.failed(e));
+        match response {
+            Ok(DumpResponse::Entry(key, value)) => Some(Ok((key, value))),
+            Ok(DumpResponse::End) => {
+                self.done = true;
+                None
+            }
+            Ok(DumpResponse::Err(e)) => {
+                self.done = true;
+                Some(Err(e.into()))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<'a> Drop for DumpIter<'a> {
+    fn drop(&mut self) {
+        // drain the stream so the next response read is not a dumped pair
+        for _ in self {}
+    }
+}
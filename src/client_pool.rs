@@ -0,0 +1,144 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex};
+use crate::{KvsClient, KvsError, Result};
+
+/// A fixed number of connections to a server shared by threads, each handed
+/// out to one thread at a time by [`KvsClientPool::get`].
+///
+/// Connections are opened when first needed. A connection which failed is
+/// dropped when handed back, another is opened in its place on demand. The
+/// timeouts and `TCP_NODELAY` a thread set are reset when it hands one back.
+///
+/// Example:
+/// ```no_run
+/// # use kvs::{KvsClientPool, Result};
+/// # fn try_main() -> Result<()> {
+/// let pool = KvsClientPool::new("127.0.0.1:4000", 4)?;
+/// let workers: Vec<_> = (0..16)
+///     .map(|i| {
+///         let pool = pool.clone();
+///         std::thread::spawn(move || pool.get()?.set(format!("key{}", i), "value"))
+///     })
+///     .collect();
+/// for worker in workers {
+///     worker.join().unwrap()?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct KvsClientPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    addrs: Vec<SocketAddr>,
+    size: usize,
+    state: Mutex<PoolState>,
+    // notified when a connection is handed back or closed
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct PoolState {
+    idle: Vec<KvsClient>,
+    // connections open, idle or handed out
+    open: usize,
+}
+
+impl KvsClientPool {
+    /// Create a pool of at most `size` connections to the server at `addr`.
+    pub fn new<A: ToSocketAddrs>(addr: A, size: usize) -> Result<KvsClientPool> {
+        if size == 0 {
+            return Err(KvsError::InvalidConfig("size of a client pool must be positive".to_owned()));
+        }
+        Ok(KvsClientPool {
+            inner: Arc::new(PoolInner {
+                addrs: addr.to_socket_addrs()?.collect(),
+                size,
+                state: Mutex::new(PoolState::default()),
+                released: Condvar::new(),
+            }),
+        })
+    }
+
+    /// A connection of the pool, handed back when the guard is dropped.
+    ///
+    /// Wait for one to be handed back if all are in use, return the error of
+    /// connecting if a new one is needed and the server can not be reached.
+    pub fn get(&self) -> Result<PooledClient> {
+        let mut state = self.inner.state.lock().unwrap();
+        loop {
+            if let Some(client) = state.idle.pop() {
+                return Ok(PooledClient { pool: self.inner.clone(), client: Some(client) });
+            }
+            if state.open < self.inner.size {
+                state.open += 1;
+                drop(state);
+                return match KvsClient::connect(&self.inner.addrs[..]) {
+                    Ok(client) => Ok(PooledClient { pool: self.inner.clone(), client: Some(client) }),
+                    Err(e) => {
+                        self.inner.close();
+                        Err(e)
+                    }
+                };
+            }
+            state = self.inner.released.wait(state).unwrap();
+        }
+    }
+
+    /// The number of connections open, idle or in use.
+    pub fn open_connections(&self) -> usize {
+        self.inner.state.lock().unwrap().open
+    }
+}
+
+impl PoolInner {
+    /// Forget a connection closed, another may be opened.
+    fn close(&self) {
+        self.state.lock().unwrap().open -= 1;
+        self.released.notify_one();
+    }
+}
+
+/// A connection of a [`KvsClientPool`], handed back to the pool on drop.
+pub struct PooledClient {
+    pool: Arc<PoolInner>,
+    client: Option<KvsClient>,
+}
+
+impl Deref for PooledClient {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().expect("pooled client already handed back")
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().expect("pooled client already handed back")
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            if client.is_broken() || reset(&client).is_err() {
+                drop(client);
+                self.pool.close();
+            } else {
+                self.pool.state.lock().unwrap().idle.push(client);
+                self.pool.released.notify_one();
+            }
+        }
+    }
+}
+
+/// Set the connection back as it was opened, for the next thread.
+fn reset(client: &KvsClient) -> Result<()> {
+    client.set_read_timeout(None)?;
+    client.set_write_timeout(None)?;
+    client.set_nodelay(true)
+}
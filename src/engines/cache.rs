@@ -0,0 +1,61 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// A map of key to value holding at most `capacity` keys, the key least
+/// recently used is dropped first.
+pub(crate) struct LruCache<V> {
+    capacity: usize,
+    next_tick: u64,
+    // tick of the last use to key, the first is dropped first
+    queue: BTreeMap<u64, String>,
+    entries: HashMap<String, (u64, V)>,
+}
+
+impl<V> LruCache<V> {
+    pub(crate) fn new(capacity: usize) -> LruCache<V> {
+        LruCache {
+            capacity,
+            next_tick: 0,
+            queue: BTreeMap::new(),
+            entries: HashMap::with_capacity(capacity),
+        }
+    }
+
+    /// The value of key, which becomes the most recently used.
+    pub(crate) fn get(&mut self, key: &str) -> Option<&V> {
+        let tick = self.next_tick;
+        let (last_tick, value) = self.entries.get_mut(key)?;
+        let key = self.queue.remove(last_tick).expect("cached key not queued");
+        self.queue.insert(tick, key);
+        *last_tick = tick;
+        self.next_tick += 1;
+        Some(value)
+    }
+
+    /// Cache the value of key, dropping the least recently used key if full.
+    pub(crate) fn insert(&mut self, key: String, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.queue.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.queue.insert(self.next_tick, key.clone());
+        self.entries.insert(key, (self.next_tick, value));
+        self.next_tick += 1;
+    }
+
+    /// Forget key.
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some((tick, _)) = self.entries.remove(key) {
+            self.queue.remove(&tick);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.queue.clear();
+        self.entries.clear();
+    }
+}
@@ -0,0 +1,91 @@
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+
+/// Limits how many compactions run at once across the stores sharing it.
+///
+/// Stores share the limiter of their options, by default one limiting the whole
+/// process to a single compaction at a time.
+///
+/// Example:
+/// ```rust
+/// # use kvs::{CompactionLimiter, KvStoreOptions};
+/// let limiter = CompactionLimiter::new(2);
+/// let options = KvStoreOptions::new().compaction_limiter(limiter.clone());
+/// ```
+#[derive(Clone)]
+pub struct CompactionLimiter {
+    inner: Arc<LimiterState>,
+}
+
+struct LimiterState {
+    max_running: usize,
+    // the compactions running and the most that ever ran at once
+    running: Mutex<(usize, usize)>,
+    released: Condvar,
+}
+
+impl CompactionLimiter {
+    /// Create a limiter letting `max_running` compactions run at once, at least one.
+    pub fn new(max_running: usize) -> CompactionLimiter {
+        CompactionLimiter {
+            inner: Arc::new(LimiterState {
+                max_running: max_running.max(1),
+                running: Mutex::new((0, 0)),
+                released: Condvar::new(),
+            }),
+        }
+    }
+
+    /// The limiter shared by the stores not given one, one compaction at a time.
+    pub fn global() -> CompactionLimiter {
+        static GLOBAL: OnceLock<CompactionLimiter> = OnceLock::new();
+        GLOBAL.get_or_init(|| CompactionLimiter::new(1)).clone()
+    }
+
+    /// The most compactions that ran at once.
+    pub fn peak(&self) -> usize {
+        self.inner.running.lock().unwrap().1
+    }
+
+    /// Wait for a compaction to be allowed, it runs until the permit drops.
+    pub(crate) fn acquire(&self) -> CompactionPermit<'_> {
+        let mut running = self.inner.running.lock().unwrap();
+        while running.0 >= self.inner.max_running {
+            running = self.inner.released.wait(running).unwrap();
+        }
+        running.0 += 1;
+        running.1 = running.1.max(running.0);
+        CompactionPermit { limiter: self }
+    }
+
+    /// A permit if a compaction is allowed now, without waiting.
+    pub(crate) fn try_acquire(&self) -> Option<CompactionPermit<'_>> {
+        let mut running = self.inner.running.lock().unwrap();
+        if running.0 >= self.inner.max_running {
+            return None;
+        }
+        running.0 += 1;
+        running.1 = running.1.max(running.0);
+        Some(CompactionPermit { limiter: self })
+    }
+}
+
+impl fmt::Debug for CompactionLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompactionLimiter")
+            .field("max_running", &self.inner.max_running)
+            .finish()
+    }
+}
+
+/// A running compaction, the next one may start once it drops.
+pub(crate) struct CompactionPermit<'a> {
+    limiter: &'a CompactionLimiter,
+}
+
+impl Drop for CompactionPermit<'_> {
+    fn drop(&mut self) {
+        self.limiter.inner.running.lock().unwrap().0 -= 1;
+        self.limiter.inner.released.notify_one();
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Which key a bounded [`KvStore`](crate::KvStore) evicts when a `set` takes it over its bounds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// the key least recently read or written
+    #[default]
+    Lru,
+    /// the key first inserted, setting a key again keeps its place
+    Fifo,
+}
+
+/// The keys of a bounded store in the order they are evicted.
+pub(crate) struct EvictionQueue {
+    policy: EvictionPolicy,
+    next_tick: u64,
+    // tick of the last use to key, the first is evicted first
+    queue: BTreeMap<u64, String>,
+    ticks: HashMap<String, u64>,
+}
+
+impl EvictionQueue {
+    /// An empty queue with room for `capacity` keys before it grows.
+    pub(crate) fn new(policy: EvictionPolicy, capacity: usize) -> EvictionQueue {
+        EvictionQueue {
+            policy,
+            next_tick: 0,
+            queue: BTreeMap::new(),
+            ticks: HashMap::with_capacity(capacity),
+        }
+    }
+
+    /// Record a read of an existing key.
+    pub(crate) fn read(&mut self, key: &str) {
+        if self.policy == EvictionPolicy::Lru && self.ticks.contains_key(key) {
+            self.touch(key);
+        }
+    }
+
+    /// Record a write of key.
+    pub(crate) fn write(&mut self, key: &str) {
+        if self.policy == EvictionPolicy::Lru || !self.ticks.contains_key(key) {
+            self.touch(key);
+        }
+    }
+
+    /// Forget a removed key.
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.queue.remove(&tick);
+        }
+    }
+
+    /// The key to evict next, other than `keep`.
+    pub(crate) fn next_except(&self, keep: &str) -> Option<String> {
+        self.queue.values().find(|key| *key != keep).cloned()
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.remove(key);
+        self.queue.insert(self.next_tick, key.to_owned());
+        self.ticks.insert(key.to_owned(), self.next_tick);
+        self.next_tick += 1;
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use fs2::FileExt;
+
+/// A file opened for reading.
+pub trait ReadFile: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadFile for T {}
+
+/// A file opened for appending.
+pub trait WriteFile: Write + Seek + Send {
+    /// Make the written data durable.
+    fn sync_all(&self) -> io::Result<()>;
+}
+
+impl WriteFile for File {
+    fn sync_all(&self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+}
+
+/// A lock of a file, released when dropped.
+pub struct FileLock {
+    _guard: Box<dyn Send>,
+}
+
+impl FileLock {
+    /// Create a lock released when `guard` drops.
+    pub fn new<G: Send + 'static>(guard: G) -> FileLock {
+        FileLock { _guard: Box::new(guard) }
+    }
+}
+
+/// The file operations a [`KvStore`](crate::KvStore) makes on its directory.
+pub trait FileSystem: fmt::Debug + Send + Sync {
+    /// Create a directory and all its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Open a file for reading from its start.
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadFile>>;
+
+    /// Open a file for appending, creating it if it does not exist.
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WriteFile>>;
+
+    /// Rename a file, replacing `to` if it exists.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Remove a file.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Make the files created, renamed and removed in a directory durable.
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// List the paths of the files in a directory.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// The length of a file in bytes.
+    fn file_len(&self, path: &Path) -> io::Result<u64>;
+
+    /// Cut a file to `len` bytes.
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()>;
+
+    /// Lock a file, creating it if it does not exist.
+    /// Return None if it is locked by someone else.
+    fn try_lock(&self, path: &Path) -> io::Result<Option<FileLock>>;
+
+    /// Map a file into memory for reading, none if the file system can not.
+    /// The default can not.
+    #[cfg(feature = "mmap")]
+    fn map(&self, _path: &Path) -> io::Result<Option<memmap2::Mmap>> {
+        Ok(None)
+    }
+}
+
+/// The file system of the operating system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        Ok(Box::new(OpenOptions::new().create(true).append(true).open(path)?))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        // windows can not open a directory as a file
+        if cfg!(unix) {
+            File::open(path)?.sync_all()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        OpenOptions::new().write(true).open(path)?.set_len(len)
+    }
+
+    fn try_lock(&self, path: &Path) -> io::Result<Option<FileLock>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(Some(FileLock::new(file))),
+            Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The map must not outlive a truncation of the file, a store maps only
+    /// the logs of a directory it locked, which are appended to or deleted.
+    /// A failed batch cuts the active log only past the records indexed, no
+    /// read reaches there.
+    #[cfg(feature = "mmap")]
+    fn map(&self, path: &Path) -> io::Result<Option<memmap2::Mmap>> {
+        let file = File::open(path)?;
+        Ok(Some(unsafe { memmap2::Mmap::map(&file)? }))
+    }
+}
+
+/// A file system kept in memory, shared by its clones.
+///
+/// It can simulate a crash: once [`crash_after`](MemoryFileSystem::crash_after)
+/// is reached, the triggering write is cut short and every later change fails,
+/// leaving the files as a killed process would, until [`restart`](MemoryFileSystem::restart).
+#[derive(Clone, Debug, Default)]
+pub struct MemoryFileSystem {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+type Inode = Arc<Mutex<Vec<u8>>>;
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    dirs: HashSet<PathBuf>,
+    files: HashMap<PathBuf, Inode>,
+    // a map of locked path to the token of its lock
+    locks: HashMap<PathBuf, u64>,
+    next_token: u64,
+    crash_point: Option<CrashPoint>,
+    crashed: bool,
+}
+
+#[derive(Debug)]
+struct CrashPoint {
+    suffix: String,
+    remaining: u64,
+}
+
+impl MemoryState {
+    fn check_alive(&self) -> io::Result<()> {
+        if self.crashed {
+            return Err(io::Error::other("simulated crash"));
+        }
+        Ok(())
+    }
+
+    fn inode(&self, path: &Path) -> io::Result<Inode> {
+        self.files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{:?} not found", path)))
+    }
+}
+
+impl MemoryFileSystem {
+    /// Create an empty file system.
+    pub fn new() -> MemoryFileSystem {
+        MemoryFileSystem::default()
+    }
+
+    /// Crash once more than `bytes` bytes are written to files whose path ends with `suffix`.
+    pub fn crash_after(&self, suffix: &str, bytes: u64) {
+        self.state.lock().unwrap().crash_point = Some(CrashPoint {
+            suffix: suffix.to_owned(),
+            remaining: bytes,
+        });
+    }
+
+    /// Whether the simulated crash happened.
+    pub fn is_crashed(&self) -> bool {
+        self.state.lock().unwrap().crashed
+    }
+
+    /// Recover from a simulated crash, the locks of the crashed process are released.
+    pub fn restart(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.crashed = false;
+        state.crash_point = None;
+        state.locks.clear();
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check_alive()?;
+        for dir in path.ancestors() {
+            state.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        let inode = self.state.lock().unwrap().inode(path)?;
+        Ok(Box::new(MemoryReader { inode, pos: 0 }))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        let mut state = self.state.lock().unwrap();
+        state.check_alive()?;
+        let inode = state.files.entry(path.to_path_buf()).or_default().clone();
+        Ok(Box::new(MemoryWriter {
+            path: path.to_path_buf(),
+            inode,
+            state: self.state.clone(),
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check_alive()?;
+        let inode = state.inode(from)?;
+        state.files.remove(from);
+        state.files.insert(to.to_path_buf(), inode);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check_alive()?;
+        state.inode(path)?;
+        state.files.remove(path);
+        Ok(())
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        let state = self.state.lock().unwrap();
+        state.check_alive()?;
+        if !state.dirs.contains(path) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{:?} not found", path)));
+        }
+        Ok(())
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state.lock().unwrap();
+        if !state.dirs.contains(path) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{:?} not found", path)));
+        }
+        Ok(state
+            .files
+            .keys()
+            .filter(|file| file.parent() == Some(path))
+            .cloned()
+            .collect())
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        let inode = self.state.lock().unwrap().inode(path)?;
+        let len = inode.lock().unwrap().len() as u64;
+        Ok(len)
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let state = self.state.lock().unwrap();
+        state.check_alive()?;
+        state.inode(path)?.lock().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn try_lock(&self, path: &Path) -> io::Result<Option<FileLock>> {
+        let mut state = self.state.lock().unwrap();
+        state.check_alive()?;
+        if state.locks.contains_key(path) {
+            return Ok(None);
+        }
+        state.files.entry(path.to_path_buf()).or_default();
+        state.next_token += 1;
+        let token = state.next_token;
+        state.locks.insert(path.to_path_buf(), token);
+        Ok(Some(FileLock::new(MemoryLock {
+            path: path.to_path_buf(),
+            token,
+            state: self.state.clone(),
+        })))
+    }
+}
+
+struct MemoryReader {
+    inode: Inode,
+    pos: u64,
+}
+
+impl Read for MemoryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.inode.lock().unwrap();
+        let start = (self.pos as usize).min(data.len());
+        let length = buf.len().min(data.len() - start);
+        buf[..length].copy_from_slice(&data[start..start + length]);
+        self.pos += length as u64;
+        Ok(length)
+    }
+}
+
+impl Seek for MemoryReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.inode.lock().unwrap().len() as u64;
+        self.pos = seek_position(self.pos, len, pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// A file opened for appending, every write goes to its end.
+struct MemoryWriter {
+    path: PathBuf,
+    inode: Inode,
+    state: Arc<Mutex<MemoryState>>,
+}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        state.check_alive()?;
+        let mut length = buf.len();
+        let path = self.path.to_string_lossy();
+        if let Some(crash_point) = state.crash_point.as_mut().filter(|point| path.ends_with(&point.suffix)) {
+            if length as u64 > crash_point.remaining {
+                length = crash_point.remaining as usize;
+                state.crashed = true;
+            } else {
+                crash_point.remaining -= length as u64;
+            }
+        }
+        self.inode.lock().unwrap().extend_from_slice(&buf[..length]);
+        state.check_alive()?;
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        // writes always append, only the end position is meaningful
+        let len = self.inode.lock().unwrap().len() as u64;
+        seek_position(len, len, pos)
+    }
+}
+
+impl WriteFile for MemoryWriter {
+    fn sync_all(&self) -> io::Result<()> {
+        self.state.lock().unwrap().check_alive()
+    }
+}
+
+struct MemoryLock {
+    path: PathBuf,
+    token: u64,
+    state: Arc<Mutex<MemoryState>>,
+}
+
+impl Drop for MemoryLock {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        // the lock may have been released by a restart and taken again since
+        if state.locks.get(&self.path) == Some(&self.token) {
+            state.locks.remove(&self.path);
+        }
+    }
+}
+
+fn seek_position(current: u64, len: u64, pos: SeekFrom) -> io::Result<u64> {
+    let pos = match pos {
+        SeekFrom::Start(offset) => Some(offset),
+        SeekFrom::End(offset) => offset_position(len, offset),
+        SeekFrom::Current(offset) => offset_position(current, offset),
+    };
+    pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"))
+}
+
+fn offset_position(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.unsigned_abs())
+    }
+}
@@ -1,25 +1,44 @@
-use std::collections::BTreeMap;
+use std::collections::{btree_map, BTreeMap, BTreeSet};
 use std::ffi::OsStr;
-use std::{fs, io};
-use std::fs::{File, OpenOptions};
+use std::io;
 use std::io::{BufReader, BufWriter, Write, Seek, SeekFrom, Read};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
 
-use log::{debug, error};
+use log::{debug, error, warn};
 
-use crate::{KvsError, Result};
+use crate::{KvsError, Result, TypedValue, ValueType};
 
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
-use crate::engines::KvsEngine;
-use std::sync::{Arc, Mutex};
-use std::cell::RefCell;
-use std::sync::atomic::{AtomicU64, Ordering};
+use serde_json::json;
+use crate::engines::{Counters, DiskUsage, KvsEngine, Stats};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread;
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::borrow::Cow;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use crossbeam_skiplist::SkipMap;
+use crate::engines::fs::{FileLock, FileSystem, OsFileSystem, ReadFile, WriteFile};
+use crate::engines::eviction::{EvictionPolicy, EvictionQueue};
+use crate::engines::cache::LruCache;
+use crate::engines::compaction::{CompactionLimiter, CompactionPermit};
+use crate::engines::record::{self, Entry, Format};
+#[cfg(feature = "compression")]
+use crate::engines::zlog::{ZlogReader, ZlogWriter};
 
 
-const MERGED_THRESHOLD: u64 = 100;
+// the garbage bytes triggering a compaction by default
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 4 * 1024 * 1024;
+// the bytes of the buffers of the log readers and writers by default, as `BufReader::new`
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
 const INIT_GENERATION: u64 = 0;
+const LOCK_FILE_NAME: &str = "db.lock";
+// holds the generation of the first log after the last clear, the logs before it hold no key
+const CLEAR_FILE_NAME: &str = "clear";
+const CLEAR_TMP_FILE_NAME: &str = "clear.tmp";
 
 /// The `KvStore` stores string key-value pairs.
 ///
@@ -42,70 +61,795 @@ const INIT_GENERATION: u64 = 0;
 pub struct KvStore {
     // directory of file
     path: Arc<PathBuf>,
+    options: Arc<KvStoreOptions>,
+    recovery: Arc<RecoveryInfo>,
+    counters: Arc<Counters>,
     // a map of key to command info
-    index: Arc<SkipMap<String, CommandInfo>>,
+    index: Arc<Index>,
+    // the background compactions, declared before the writer to stop before it drops
+    _compactor: Option<Arc<Compactor>>,
     writer: Arc<Mutex<KvStoreWriter>>,
     reader: KvStoreReader,
+    // the eviction order of a bounded store
+    eviction: Option<Arc<Mutex<EvictionQueue>>>,
+    // set while the write buffer holds records
+    unflushed: Arc<AtomicBool>,
+    // the values last read, none if the options give no cache
+    cache: Option<Arc<Mutex<ValueCache>>>,
+    // the commits of the writes grouped across writers, none unless the options ask for it
+    group_commit: Option<Arc<GroupCommit>>,
+}
+
+/// Options used when opening a [`KvStore`].
+///
+/// Example:
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let options = KvStoreOptions::new().recovery(true);
+/// let store = KvStore::open_with_options(current_dir()?, options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct KvStoreOptions {
+    recovery: bool,
+    key_normalizer: Option<KeyNormalizer>,
+    expected_keys: usize,
+    file_system: Arc<dyn FileSystem>,
+    auto_compaction: AutoCompaction,
+    compaction_threshold: u64,
+    background_compaction: bool,
+    max_keys: Option<usize>,
+    max_bytes: Option<u64>,
+    eviction_policy: EvictionPolicy,
+    compaction_limiter: CompactionLimiter,
+    clock: Option<Clock>,
+    value_threshold: Option<usize>,
+    max_entries_per_pass: Option<usize>,
+    durability: Durability,
+    buffer_size: usize,
+    value_cache: Option<usize>,
+    group_commit: Option<Duration>,
+}
+
+type KeyNormalizer = Arc<dyn Fn(&str) -> String + Send + Sync>;
+type Clock = Arc<dyn Fn() -> SystemTime + Send + Sync>;
+
+impl fmt::Debug for KvStoreOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KvStoreOptions")
+            .field("recovery", &self.recovery)
+            .field("key_normalizer", &self.key_normalizer.is_some())
+            .field("expected_keys", &self.expected_keys)
+            .field("file_system", &self.file_system)
+            .field("auto_compaction", &self.auto_compaction)
+            .field("compaction_threshold", &self.compaction_threshold)
+            .field("background_compaction", &self.background_compaction)
+            .field("max_keys", &self.max_keys)
+            .field("max_bytes", &self.max_bytes)
+            .field("eviction_policy", &self.eviction_policy)
+            .field("compaction_limiter", &self.compaction_limiter)
+            .field("clock", &self.clock.is_some())
+            .field("value_threshold", &self.value_threshold)
+            .field("max_entries_per_pass", &self.max_entries_per_pass)
+            .field("durability", &self.durability)
+            .field("buffer_size", &self.buffer_size)
+            .field("value_cache", &self.value_cache)
+            .field("group_commit", &self.group_commit)
+            .finish()
+    }
+}
+
+impl KvStoreOptions {
+    /// The options as a JSON object, the callbacks only tell whether they are set.
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "recovery": self.recovery,
+            "key_normalizer": self.key_normalizer.is_some(),
+            "expected_keys": self.expected_keys,
+            "file_system": format!("{:?}", self.file_system),
+            "auto_compaction": format!("{:?}", self.auto_compaction),
+            "compaction_threshold": self.compaction_threshold,
+            "background_compaction": self.background_compaction,
+            "max_keys": self.max_keys,
+            "max_bytes": self.max_bytes,
+            "eviction_policy": format!("{:?}", self.eviction_policy),
+            "compaction_limiter": format!("{:?}", self.compaction_limiter),
+            "clock": self.clock.is_some(),
+            "value_threshold": self.value_threshold,
+            "max_entries_per_pass": self.max_entries_per_pass,
+            "durability": format!("{:?}", self.durability),
+            "buffer_size": self.buffer_size,
+            "value_cache": self.value_cache,
+            "group_commit": self.group_commit.map(|window| window.as_micros() as u64),
+        })
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            recovery: false,
+            key_normalizer: None,
+            expected_keys: 0,
+            file_system: Arc::new(OsFileSystem),
+            auto_compaction: AutoCompaction::default(),
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            background_compaction: false,
+            max_keys: None,
+            max_bytes: None,
+            eviction_policy: EvictionPolicy::default(),
+            compaction_limiter: CompactionLimiter::global(),
+            clock: None,
+            value_threshold: None,
+            max_entries_per_pass: None,
+            durability: Durability::default(),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            value_cache: None,
+            group_commit: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Create options with the default settings.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Enable recovery mode, default false.
+    ///
+    /// When a `get` fails to read the record the index points at, the logs are
+    /// rescanned for the latest readable record of that key and the index entry
+    /// is repaired from it.
+    pub fn recovery(mut self, recovery: bool) -> KvStoreOptions {
+        self.recovery = recovery;
+        self
+    }
+
+    /// Normalize every key before it is used, default none.
+    ///
+    /// The normalized key is what is written to the log, so the same
+    /// normalizer must be given every time the store is opened.
+    ///
+    /// ```rust
+    /// # use kvs::KvStoreOptions;
+    /// let options = KvStoreOptions::new().key_normalizer(|key: &str| key.to_lowercase());
+    /// ```
+    pub fn key_normalizer<F>(mut self, normalizer: F) -> KvStoreOptions
+        where F: Fn(&str) -> String + Send + Sync + 'static
+    {
+        self.key_normalizer = Some(Arc::new(normalizer));
+        self
+    }
+
+    /// A hint of the number of live keys, default 0.
+    ///
+    /// Used to presize the eviction order of a bounded store, built while
+    /// loading the logs, and the keys copied by a merge. The index is a skip
+    /// list, it takes no capacity.
+    pub fn expected_keys(mut self, expected_keys: usize) -> KvStoreOptions {
+        self.expected_keys = expected_keys;
+        self
+    }
+
+    /// The file system the store is kept in, default the one of the operating system.
+    ///
+    /// ```rust
+    /// # use std::sync::Arc;
+    /// # use kvs::{KvStore, KvStoreOptions, MemoryFileSystem, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let options = KvStoreOptions::new().file_system(Arc::new(MemoryFileSystem::new()));
+    /// let store = KvStore::open_with_options("/db", options)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn file_system(mut self, file_system: Arc<dyn FileSystem>) -> KvStoreOptions {
+        self.file_system = file_system;
+        self
+    }
+
+    /// When a write may trigger a compaction, default [`AutoCompaction::Threshold`].
+    ///
+    /// [`KvStore::compact`] works whatever the policy.
+    pub fn auto_compaction(mut self, auto_compaction: AutoCompaction) -> KvStoreOptions {
+        self.auto_compaction = auto_compaction;
+        self
+    }
+
+    /// The bytes of garbage, the records overwritten or removed, over which a
+    /// write compacts the logs, default 4 MiB.
+    ///
+    /// A lower threshold keeps the logs smaller at the cost of more frequent
+    /// compactions, each one copying every live record.
+    pub fn compaction_threshold(mut self, bytes: u64) -> KvStoreOptions {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Run the automatic compactions on a thread of the store instead of in the
+    /// write crossing the threshold, default false.
+    ///
+    /// The write only wakes the thread up and returns. The thread holds the
+    /// writer lock while it runs a pass, the writes wait for the pass to end.
+    /// Without [`KvStoreOptions::max_entries_per_pass`] a pass is the whole
+    /// compaction, copying every live key, give it to bound how long they wait.
+    /// A failed compaction is logged and tried again on the next trigger, the
+    /// store is left as it was.
+    pub fn background_compaction(mut self, background_compaction: bool) -> KvStoreOptions {
+        self.background_compaction = background_compaction;
+        self
+    }
+
+    /// The most live keys the store keeps, default unbounded.
+    ///
+    /// A `set` taking the store over its bounds evicts keys by the eviction
+    /// policy, writing a remove record for each.
+    ///
+    /// ```rust
+    /// # use kvs::{EvictionPolicy, KvStoreOptions};
+    /// let options = KvStoreOptions::new().max_keys(1000).eviction_policy(EvictionPolicy::Fifo);
+    /// ```
+    pub fn max_keys(mut self, max_keys: usize) -> KvStoreOptions {
+        self.max_keys = Some(max_keys);
+        self
+    }
+
+    /// The most bytes the live records of the store take, default unbounded.
+    ///
+    /// The key just set is never evicted, even if its record alone is larger.
+    pub fn max_bytes(mut self, max_bytes: u64) -> KvStoreOptions {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Which keys a bounded store evicts first, default [`EvictionPolicy::Lru`].
+    pub fn eviction_policy(mut self, eviction_policy: EvictionPolicy) -> KvStoreOptions {
+        self.eviction_policy = eviction_policy;
+        self
+    }
+
+    /// The limiter of the compactions running at once, shared with other
+    /// stores, default [`CompactionLimiter::global`].
+    ///
+    /// The compactions wait for it before taking the writer lock, except the
+    /// automatic compaction of a write: it is skipped while the limiter allows
+    /// no more and tried again by a later write.
+    pub fn compaction_limiter(mut self, compaction_limiter: CompactionLimiter) -> KvStoreOptions {
+        self.compaction_limiter = compaction_limiter;
+        self
+    }
+
+    /// The clock the store reads the current time from, default the system clock.
+    ///
+    /// Time dependent behavior, as the window of [`AutoCompaction::Window`],
+    /// can then be tested without waiting:
+    ///
+    /// ```rust
+    /// # use std::sync::{Arc, Mutex};
+    /// # use std::time::{Duration, UNIX_EPOCH};
+    /// # use kvs::KvStoreOptions;
+    /// let now = Arc::new(Mutex::new(UNIX_EPOCH));
+    /// let clock = now.clone();
+    /// let options = KvStoreOptions::new().clock(move || *clock.lock().unwrap());
+    /// *now.lock().unwrap() += Duration::from_secs(60);
+    /// ```
+    pub fn clock<F>(mut self, clock: F) -> KvStoreOptions
+        where F: Fn() -> SystemTime + Send + Sync + 'static
+    {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Store the values of at least `threshold` bytes in value logs apart from
+    /// the key logs, default none.
+    ///
+    /// The key log keeps a pointer to the value, so a compaction copies the
+    /// pointer instead of the value. A value log is deleted by the compaction
+    /// after its last live value, one less than half live has its values moved.
+    /// Reading a separated value takes one more read.
+    ///
+    /// ```rust
+    /// # use kvs::KvStoreOptions;
+    /// let options = KvStoreOptions::new().value_separation(4096);
+    /// ```
+    pub fn value_separation(mut self, threshold: usize) -> KvStoreOptions {
+        self.value_threshold = Some(threshold);
+        self
+    }
+
+    /// The most keys a compaction copies in one pass, default unbounded.
+    ///
+    /// A compaction of more keys runs in passes, each holding up writes only
+    /// while it copies its keys. An automatic compaction runs a pass on each
+    /// trigger, [`KvStore::compact`] runs the passes left letting writes in
+    /// between them. The stale logs are deleted after the last pass.
+    pub fn max_entries_per_pass(mut self, max_entries_per_pass: usize) -> KvStoreOptions {
+        self.max_entries_per_pass = Some(max_entries_per_pass.max(1));
+        self
+    }
+
+    /// Leave the records written in the write buffer until it is full, default false.
+    ///
+    /// The same as the durability `Durability::None` if true, `Durability::Flush`
+    /// if false.
+    pub fn lazy_flush(mut self, lazy_flush: bool) -> KvStoreOptions {
+        self.durability = if lazy_flush { Durability::None } else { Durability::Flush };
+        self
+    }
+
+    /// How far a write goes towards stable storage before it returns, default
+    /// `Durability::Flush`.
+    pub fn durability(mut self, durability: Durability) -> KvStoreOptions {
+        self.durability = durability;
+        self
+    }
+
+    /// The bytes of the buffers of the log readers and writers, default 8 KiB.
+    ///
+    /// A larger buffer takes fewer system calls to read or write a large
+    /// value, every open log file of every clone of the store has one.
+    pub fn buffer_size(mut self, bytes: usize) -> KvStoreOptions {
+        self.buffer_size = bytes.max(1);
+        self
+    }
+
+    /// Keep the values of the `capacity` keys last read in memory, default none.
+    ///
+    /// A `get` of a cached key reads no log. A cached value is only used while
+    /// the index still points at the record it was read from, so a write or a
+    /// compaction moving the record makes it read again. Every clone of the
+    /// store shares the cache.
+    ///
+    /// The cache is a single LRU list behind a mutex: every `get`, hit or miss,
+    /// locks it to move the key up, so the threads reading at once wait on each
+    /// other there. It pays off when reading the logs costs more than that, as
+    /// for values not in the page cache, compressed or separated.
+    ///
+    /// ```rust
+    /// # use kvs::KvStoreOptions;
+    /// let options = KvStoreOptions::new().value_cache(10_000);
+    /// ```
+    pub fn value_cache(mut self, capacity: usize) -> KvStoreOptions {
+        self.value_cache = Some(capacity);
+        self
+    }
+
+    /// Commit the writes of concurrent writers together, default off.
+    ///
+    /// A write appends its record, then waits until a flush, or fsync as the
+    /// durability asks, covers it: the first writer waiting runs it for all
+    /// the records appended so far, after waiting `window` for more writers.
+    /// A zero window still groups the writes appended during the previous
+    /// flush. A write returns once its record is committed, as without the
+    /// option, but many writers then share a flush. `Durability::None` does
+    /// not commit the writes, the option has no effect on it.
+    ///
+    /// ```rust
+    /// # use std::time::Duration;
+    /// # use kvs::{Durability, KvStoreOptions};
+    /// let options = KvStoreOptions::new()
+    ///     .durability(Durability::Fsync)
+    ///     .group_commit(Duration::from_micros(100));
+    /// ```
+    pub fn group_commit(mut self, window: Duration) -> KvStoreOptions {
+        self.group_commit = Some(window);
+        self
+    }
+
+    fn now(&self) -> SystemTime {
+        match &self.clock {
+            Some(clock) => clock(),
+            None => SystemTime::now(),
+        }
+    }
+
+    /// Whether `value` goes to a value log.
+    fn separates(&self, value: &str) -> bool {
+        self.value_threshold.is_some_and(|threshold| value.len() >= threshold)
+    }
+
+    fn is_bounded(&self) -> bool {
+        self.max_keys.is_some() || self.max_bytes.is_some()
+    }
+
+    fn normalize(&self, key: String) -> String {
+        match &self.key_normalizer {
+            Some(normalizer) => normalizer(&key),
+            None => key,
+        }
+    }
+
+    fn normalize_str<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match &self.key_normalizer {
+            Some(normalizer) => Cow::Owned(normalizer(key)),
+            None => Cow::Borrowed(key),
+        }
+    }
+}
+
+/// How far a write of a [`KvStore`] goes towards stable storage before it returns.
+///
+/// The stronger the policy the fewer writes are lost by a crash, and the slower
+/// the writes: an fsync takes from tens of microseconds to milliseconds, a
+/// flush is a write call to the operating system.
+///
+/// Every policy but `None` fsyncs the logs written by a merge and the directory
+/// before deleting the fsynced logs they replace.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// leave the records in the write buffer until it is full, many small
+    /// writes reach the log file at once. The records still in the buffer are
+    /// lost if the process stops, or until [`KvStore::flush`], and other
+    /// processes do not see them. A read of the store flushes the buffer first
+    /// if it holds records. Separated values are written at once.
+    None,
+    /// write every record to the log file, it survives the process but not a
+    /// crash of the operating system or a power failure
+    #[default]
+    Flush,
+    /// fsync the log file after every write, it survives a power failure
+    Fsync,
+    /// flush every write and fsync the log file after every `n` writes, a
+    /// power failure loses the writes since the last fsync at most
+    EveryN(u32),
+}
+
+/// When a write of a [`KvStore`] compacts the logs once enough garbage piled up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AutoCompaction {
+    /// compact whenever the garbage exceeds [`KvStoreOptions::compaction_threshold`]
+    #[default]
+    Threshold,
+    /// never compact automatically, only by [`KvStore::compact`]
+    Off,
+    /// compact over the threshold only within a daily window of UTC time,
+    /// given as offsets from midnight. The window wraps midnight if `start > end`.
+    Window {
+        /// start of the window
+        start: Duration,
+        /// end of the window, excluded
+        end: Duration,
+    },
+}
+
+impl AutoCompaction {
+    /// Whether a compaction may fire at `now`.
+    fn allows(&self, now: SystemTime) -> bool {
+        match *self {
+            AutoCompaction::Threshold => true,
+            AutoCompaction::Off => false,
+            AutoCompaction::Window { start, end } => {
+                let day = Duration::from_secs(24 * 60 * 60);
+                let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+                let time_of_day = Duration::from_nanos((since_epoch.as_nanos() % day.as_nanos()) as u64);
+                if start <= end {
+                    start <= time_of_day && time_of_day < end
+                } else {
+                    start <= time_of_day || time_of_day < end
+                }
+            }
+        }
+    }
+}
+
+/// The change `KvStore::update` makes to a key.
+pub(crate) enum Update {
+    /// leave the key as it is
+    Keep,
+    /// set the key to the value
+    Set(String),
+    /// remove the key, which must exist
+    Remove,
+}
+
+/// Signs of an unclean shutdown found and repaired when a [`KvStore`] was opened.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryInfo {
+    /// number of partially written records cut from the end of log files
+    pub truncated_records: u64,
+    /// number of temporary files of unfinished merges that were deleted
+    pub removed_tmp_files: u64,
+    /// number of empty log files that were deleted
+    pub cleaned_empty_gens: u64,
+}
+
+impl RecoveryInfo {
+    /// Whether the store was shut down cleanly, nothing had to be repaired.
+    pub fn is_clean(&self) -> bool {
+        *self == RecoveryInfo::default()
+    }
+}
+
+/// The state of the logs of a [`KvStore`], as returned by [`KvStore::store_stats`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KvStoreStats {
+    /// number of non-empty log files
+    pub generations: usize,
+    /// generation of the active log file
+    pub write_generation: u64,
+    /// bytes of stale records, a compaction runs once they exceed the threshold
+    pub unmerged_bytes: u64,
+    /// the compaction threshold in effect
+    pub compaction_threshold: u64,
+    /// number of keys
+    pub live_keys: u64,
+}
+
+/// A record of a key found in the logs, as returned by [`KvStore::history`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// generation of the log file holding the record
+    pub generation: u64,
+    /// offset of the record in its log file
+    pub offset: u64,
+    /// the value set, none for a remove
+    pub value: Option<String>,
+}
+
+/// A point in the logs of a store, [`KvStore::changes_since`] reads the changes after it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeCursor {
+    /// generation of the log file written at that point
+    pub generation: u64,
+    /// offset in the log file
+    pub offset: u64,
+}
+
+/// A change of a key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// the key was set to the value
+    Set {
+        /// the key
+        key: String,
+        /// the value it holds now
+        value: String,
+    },
+    /// the key was removed
+    Remove {
+        /// the key
+        key: String,
+    },
+}
+
+/// The changes read by [`KvStore::changes_since`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Changes {
+    /// the latest change of each key, in key order
+    pub changes: Vec<Change>,
+    /// whether `changes` sets every live key instead, the keys missing from it
+    /// are removed
+    pub full: bool,
+    /// the point the changes go to, to read the next changes from
+    pub cursor: ChangeCursor,
 }
 
 struct KvStoreWriter {
     // directory of file
     path: Arc<PathBuf>,
+    // holds the lock of the directory until the last clone of the store drops, none for a replica
+    _lock: Option<FileLock>,
+    options: Arc<KvStoreOptions>,
+    counters: Arc<Counters>,
     // number of active log file
     write_generation: u64,
-    // writer of active log file
-    writer: KvsBufWriter<File>,
+    // writer of active log file, none for a replica
+    writer: Option<LogWriter>,
+    // writer of the value log of the active generation, opened by its first value
+    value_writer: Option<LogWriter>,
+    // set while the write buffer holds records, shared with the store
+    unflushed: Arc<AtomicBool>,
+    // writes since the last fsync of the log, counted for `Durability::EveryN`
+    unsynced: u32,
+    // number of writes appended, a group commit waits until they are committed
+    appended: u64,
+    // the commits of the writes grouped across writers
+    group_commit: Option<Arc<GroupCommit>>,
+    // the bytes loaded of each log file, a reload of a replica goes on from there
+    loaded: BTreeMap<u64, u64>,
     // the bytes of invalid command in the log file which would be delete during the next log merge.
     unmerged: u64,
+    // the part of `unmerged` in the logs the running merge keeps, left once it is done
+    unmerged_kept: u64,
+    // the bytes of the commands the index points at
+    live_bytes: u64,
+    // the merge whose passes are not all run yet
+    merging: Option<MergeProgress>,
+    // the generations written by merges, their records are copies
+    merged_generations: BTreeSet<u64>,
+    // wakes up the thread running the compactions in the background
+    compactor: Option<SyncSender<()>>,
     reader: KvStoreReader,
     // a map of key to command info
-    index: Arc<SkipMap<String, CommandInfo>>,
+    index: Arc<Index>,
+    eviction: Option<Arc<Mutex<EvictionQueue>>>,
+    cache: Option<Arc<Mutex<ValueCache>>>,
 }
 
 struct KvStoreReader {
     path: Arc<PathBuf>,
+    fs: Arc<dyn FileSystem>,
+    buffer_size: usize,
     // a map of log number to log file reader
-    readers: RefCell<BTreeMap<u64, KvsBufReader<File>>>,
+    readers: RefCell<BTreeMap<u64, LogReader>>,
     // The newest generation of [`KvWriter`] merged.
     merged_gen: Arc<AtomicU64>,
+    // buffer reused by reads of raw commands
+    buffer: RefCell<Vec<u8>>,
+    // a map of generation to value log reader, closed when a merge may have deleted them
+    value_readers: RefCell<BTreeMap<u64, LogReader>>,
+    // the merged generation when the value readers were last closed
+    value_readers_gen: Cell<u64>,
+    // a map of log number to the memory map of the log file, none if it is not mapped
+    #[cfg(feature = "mmap")]
+    maps: RefCell<BTreeMap<u64, Option<memmap2::Mmap>>>,
+    // whether the logs are mapped, only if the directory is locked: a replica
+    // could see another process truncate a mapped log
+    #[cfg(feature = "mmap")]
+    map_logs: bool,
 }
 
 impl Clone for KvStoreReader {
     fn clone(&self) -> Self {
         KvStoreReader {
             path: self.path.clone(),
+            fs: self.fs.clone(),
+            buffer_size: self.buffer_size,
             readers: RefCell::new(BTreeMap::new()),
+            #[cfg(feature = "mmap")]
+            maps: RefCell::new(BTreeMap::new()),
+            #[cfg(feature = "mmap")]
+            map_logs: self.map_logs,
             merged_gen: self.merged_gen.clone(),
+            buffer: RefCell::new(Vec::new()),
+            value_readers: RefCell::new(BTreeMap::new()),
+            value_readers_gen: Cell::new(INIT_GENERATION),
         }
     }
 }
 
 impl KvStoreReader {
+    /// Read the command at `cmd_info`, a separated value is read from its value log.
     fn read_command(&self, cmd_info: CommandInfo) -> Result<Command> {
-        self.read_and(cmd_info, |cmd_reader| Ok(serde_json::from_reader(cmd_reader)?))
+        let mut buffer = self.buffer.borrow_mut();
+        buffer.clear();
+        self.read_and(cmd_info, |cmd_reader| Ok(cmd_reader.read_to_end(&mut buffer)?))?;
+        let cmd = record::payload(&buffer, cmd_info.generation, cmd_info.pos_start)?.decode()?;
+        drop(buffer);
+        self.resolve(cmd)
+    }
+
+    /// Read the separated value of a set command into it.
+    fn resolve(&self, cmd: Command) -> Result<Command> {
+        match cmd {
+            Command::Set { key, value_type, value_pointer: Some(pointer), .. } => {
+                let mut value = String::new();
+                self.read_value(pointer, &mut value)?;
+                Ok(Command::Set { key, value, value_type, value_pointer: None })
+            }
+            cmd => Ok(cmd),
+        }
+    }
+
+    /// Read the value at `pointer` into `buf`.
+    fn read_value(&self, pointer: ValuePointer, buf: &mut String) -> Result<()> {
+        let mut readers = self.value_readers.borrow_mut();
+        // a merge may have deleted value logs of any generation
+        let merged_gen = self.merged_gen.load(Ordering::SeqCst);
+        if self.value_readers_gen.replace(merged_gen) != merged_gen {
+            readers.clear();
+        }
+        let reader = match readers.entry(pointer.generation) {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            btree_map::Entry::Vacant(entry) => {
+                let file = self.fs.open_read(&value_log_name(&self.path, pointer.generation))?;
+                entry.insert(KvsBufReader::new(file, self.buffer_size)?)
+            }
+        };
+        reader.seek(SeekFrom::Start(pointer.offset))?;
+        buf.clear();
+        let length = reader.take(pointer.length).read_to_string(buf)?;
+        if length as u64 != pointer.length {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(())
+    }
+
+    /// Copy the value of a set command into `buf`, reusing the allocations of
+    /// the reader and `buf`.
+    fn read_value_into(&self, cmd_info: CommandInfo, buf: &mut String) -> Result<()> {
+        let mut buffer = self.buffer.borrow_mut();
+        buffer.clear();
+        self.read_and(cmd_info, |cmd_reader| Ok(cmd_reader.read_to_end(&mut buffer)?))?;
+        let payload = record::payload(&buffer, cmd_info.generation, cmd_info.pos_start)?;
+        if payload.format == Format::Bincode {
+            // bincode can not skip the key, the value is decoded into a new string first
+            return match self.resolve(payload.decode()?)? {
+                Command::Set { value, .. } => {
+                    buf.clear();
+                    buf.push_str(&value);
+                    Ok(())
+                }
+                Command::Remove { .. } => Err(KvsError::UnknownCommand),
+            };
+        }
+        match serde_json::from_slice(payload.bytes)? {
+            CommandRef::Set { value_pointer: Some(pointer), .. } => self.read_value(pointer, buf),
+            CommandRef::Set { value, .. } => {
+                buf.clear();
+                buf.push_str(&value);
+                Ok(())
+            }
+            CommandRef::Remove { .. } => Err(KvsError::UnknownCommand),
+        }
     }
 
     fn read_and<F, R>(&self, cmd_info: CommandInfo, fuc: F) -> Result<R>
-        where F: FnOnce(io::Take<&mut KvsBufReader<File>>) -> Result<R>
+        where F: FnOnce(&mut dyn Read) -> Result<R>
     {
         // delete merged file
         self.close_stale_reader();
+        #[cfg(feature = "mmap")]
+        {
+            let mut maps = self.maps.borrow_mut();
+            let end = cmd_info.pos_start + cmd_info.length;
+            if let Some(map) = self.mapped(&mut maps, cmd_info.generation, end)? {
+                return fuc(&mut &map[cmd_info.pos_start as usize..end as usize]);
+            }
+        }
         // create reader which not exist in readers
         let mut readers = self.readers.borrow_mut();
         let cur_gen = cmd_info.generation;
         if !readers.contains_key(&cur_gen) {
-            let file = File::open(log_file_name(&self.path, cur_gen))?;
-            let reader = KvsBufReader::new(file)?;
+            let file = open_log(&*self.fs, &self.path, cur_gen)?;
+            let reader = KvsBufReader::new(file, self.buffer_size)?;
             readers.insert(cur_gen, reader);
         }
         // read command from file
         let reader = readers.get_mut(&cur_gen).unwrap();
         reader.seek(SeekFrom::Start(cmd_info.pos_start))?;
-        let cmd_reader = reader.take(cmd_info.length);
-        fuc(cmd_reader)
+        fuc(&mut reader.take(cmd_info.length))
+    }
+
+    /// The map of the log of `generation` if it holds its first `end` bytes.
+    /// None if the log is not mapped, a compressed log is read through its reader.
+    ///
+    /// A log is mapped once: the records appended since, as to the active log,
+    /// are read through its reader rather than mapping it again on every read.
+    #[cfg(feature = "mmap")]
+    fn mapped<'a>(
+        &self,
+        maps: &'a mut BTreeMap<u64, Option<memmap2::Mmap>>,
+        generation: u64,
+        end: u64,
+    ) -> Result<Option<&'a memmap2::Mmap>> {
+        if !self.map_logs {
+            return Ok(None);
+        }
+        let map = match maps.entry(generation) {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            btree_map::Entry::Vacant(entry) => {
+                let map = match self.fs.map(&log_file_name(&self.path, generation)) {
+                    Ok(map) => map,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(e.into()),
+                };
+                entry.insert(map)
+            }
+        };
+        Ok(map.as_ref().filter(|map| map.len() as u64 >= end))
     }
 
     fn close_stale_reader(&self) {
+        #[cfg(feature = "mmap")]
+        {
+            let merged_gen = self.merged_gen.load(Ordering::SeqCst);
+            let mut maps = self.maps.borrow_mut();
+            *maps = maps.split_off(&merged_gen);
+        }
         let mut readers = self.readers.borrow_mut();
         while !readers.is_empty() {
             let generation = *readers.keys().next().unwrap();
@@ -116,179 +860,1839 @@ impl KvStoreReader {
             }
         }
     }
-}
+}
+
+impl KvStoreWriter {
+    /// Set the value of a string key to a string of the type.
+    /// Return an error if the value is not written successfully, or
+    /// `KvsError::CompactionFailed` if it was written but the merge it triggered failed.
+    fn set(&mut self, key: String, value: String, value_type: ValueType) -> Result<()> {
+        let info = self.append_set(&key, &value, value_type)?;
+        self.commit_write()?;
+        self.point_at(key, info)?;
+        self.compact_over_threshold()
+    }
+
+    /// Append the set record of key to the active log, not flushed yet. A
+    /// value the options separate goes to the value log first.
+    fn append_set(&mut self, key: &str, value: &str, value_type: ValueType) -> Result<CommandInfo> {
+        if self.options.separates(value) {
+            let pointer = self.write_value(value)?;
+            let record = encode_separated(key, value_type, pointer)?;
+            return self.append(&record, Some(pointer));
+        }
+        let record = encode_set(key, value, value_type)?;
+        self.append(&record, None)
+    }
+
+    /// Append a separated value to the value log of the active generation.
+    fn write_value(&mut self, value: &str) -> Result<ValuePointer> {
+        if self.writer.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        let value_writer = match &mut self.value_writer {
+            Some(value_writer) => value_writer,
+            None => {
+                let fs = &*self.options.file_system;
+                let file_name = value_log_name(&self.path, self.write_generation);
+                self.value_writer.insert(open_log_writer(fs, &file_name, self.options.buffer_size)?)
+            }
+        };
+        let offset = value_writer.pos;
+        value_writer.write_all(value.as_bytes())?;
+        value_writer.flush()?;
+        Ok(ValuePointer {
+            generation: self.write_generation,
+            offset,
+            length: value.len() as u64,
+        })
+    }
+
+    /// Append the set record of key serialized by `encode_set`, or by
+    /// `encode_separated` with the pointer to its value. Return errors as `set`.
+    fn write_set(&mut self, key: String, record: &[u8], value: Option<ValuePointer>) -> Result<()> {
+        let info = self.append(record, value)?;
+        self.commit_write()?;
+        self.point_at(key, info)?;
+        self.compact_over_threshold()
+    }
+
+    /// Append the sets of a batch and flush them once, then point the index at
+    /// them. Return errors as `set`, the records of a batch failing before they
+    /// are flushed are cut from the log, none of its keys is set.
+    fn set_batch(&mut self, sets: Vec<(String, BatchSet)>) -> Result<()> {
+        let start_pos = self.writer.as_ref().ok_or(KvsError::ReadOnly)?.pos;
+        let mut written = Vec::with_capacity(sets.len());
+        let result = sets.into_iter().try_for_each(|(key, set)| {
+            let (record, value) = match set {
+                BatchSet::Record(record) => (record, None),
+                BatchSet::Separated(value) => {
+                    let pointer = self.write_value(&value)?;
+                    (encode_separated(&key, ValueType::String, pointer)?, Some(pointer))
+                }
+            };
+            written.push((key, self.append(&record, value)?));
+            Ok(())
+        });
+        if let Err(e) = result.and_then(|()| self.commit_write()) {
+            if let Err(truncate_error) = self.truncate_active(start_pos) {
+                error!("Failed to cut a failed batch from the log: {}", truncate_error);
+            }
+            return Err(e);
+        }
+        for (key, info) in written {
+            self.point_at(key, info)?;
+        }
+        self.compact_over_threshold()
+    }
+
+    /// Drop the records appended to the active log from `pos` on, those still
+    /// in the write buffer and those written to the file.
+    fn truncate_active(&mut self, pos: u64) -> Result<()> {
+        let writer = self.writer.take().ok_or(KvsError::ReadOnly)?;
+        // the buffer is dropped without writing it
+        drop(writer.writer.into_parts());
+        let fs = &*self.options.file_system;
+        let path = log_file_name(&self.path, self.write_generation);
+        let truncated = fs.truncate(&path, pos);
+        let mut file = fs.open_append(&path)?;
+        file.seek(SeekFrom::End(0))?;
+        self.writer = Some(KvsBufWriter::new(file, self.options.buffer_size)?);
+        Ok(truncated?)
+    }
+
+    /// Append a record to the active log, not flushed yet.
+    fn append(&mut self, record: &[u8], value: Option<ValuePointer>) -> Result<CommandInfo> {
+        let writer = self.writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        let start_pos = writer.pos;
+        writer.write_all(record)?;
+        Ok(CommandInfo::new(self.write_generation, start_pos, writer.pos)?.with_value(value))
+    }
+
+    /// Point the index at the set record of key, then evict the keys over the
+    /// bounds of the store.
+    fn point_at(&mut self, key: String, info: CommandInfo) -> Result<()> {
+        let kept = self.eviction.is_some().then(|| key.clone());
+        self.index_at(key, info);
+        if let Some(key) = kept {
+            self.evict(&key)?;
+        }
+        Ok(())
+    }
+
+    /// Point the index at the set record of key, without evicting any key.
+    fn index_at(&mut self, key: String, info: CommandInfo) {
+        if let Some(eviction) = &self.eviction {
+            eviction.lock().unwrap().write(&key);
+        }
+        self.live_bytes += info.live_len();
+        self.uncache(&key);
+        if let Some(old_cmd_info) = self.index.insert(key, info) {
+            self.add_unmerged(old_cmd_info.generation, old_cmd_info.length);
+            self.live_bytes -= old_cmd_info.live_len();
+        }
+    }
+
+    /// Flush or fsync the record just written as the durability asks, or leave
+    /// it to the group commit.
+    fn commit_write(&mut self) -> Result<()> {
+        if self.writer.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        self.appended += 1;
+        if let Durability::EveryN(_) = self.options.durability {
+            self.unsynced += 1;
+        }
+        if self.options.durability == Durability::None || self.group_commit.is_some() {
+            // set before the index points at the record, a read seeing it flushes
+            self.unflushed.store(true, Ordering::SeqCst);
+            return Ok(());
+        }
+        self.commit_appended()
+    }
+
+    /// Flush or fsync the records appended as the durability asks.
+    fn commit_appended(&mut self) -> Result<()> {
+        let writer = self.writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        let sync = match self.options.durability {
+            Durability::None | Durability::Flush => false,
+            Durability::Fsync => true,
+            Durability::EveryN(n) => self.unsynced >= n.max(1),
+        };
+        if sync {
+            // the value a record points at is durable first
+            if let Some(value_writer) = &mut self.value_writer {
+                value_writer.sync()?;
+            }
+            writer.sync()?;
+            self.unsynced = 0;
+        } else {
+            writer.flush()?;
+        }
+        self.unflushed.store(false, Ordering::SeqCst);
+        self.committed();
+        Ok(())
+    }
+
+    /// Tell the writers waiting on the group commit their writes are committed.
+    fn committed(&self) {
+        if let Some(group_commit) = &self.group_commit {
+            group_commit.committed(self.appended);
+        }
+    }
+
+    /// Merge the logs if the garbage exceeds the compaction threshold and the
+    /// policy allows it now, or leave it to the background thread.
+    ///
+    /// The write holds the writer lock, it does not wait for the compaction
+    /// limiter: the merge is skipped if no compaction is allowed now, a later
+    /// write tries again.
+    fn compact_over_threshold(&mut self) -> Result<()> {
+        if self.needs_compaction() {
+            match &self.compactor {
+                // a wake up already pending covers this one
+                Some(compactor) => {
+                    let _ = compactor.try_send(());
+                }
+                None => {
+                    let limiter = self.options.compaction_limiter.clone();
+                    match limiter.try_acquire() {
+                        Some(permit) => {
+                            self.merge(&permit)?;
+                        }
+                        None => debug!("Compaction skipped, the limiter allows no more now"),
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the cached value of key.
+    fn uncache(&self, key: &str) {
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().remove(key);
+        }
+    }
+
+    /// Drop every cached value, the records they were read from moved.
+    fn clear_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().clear();
+        }
+    }
+
+    /// Count `length` stale bytes of the log of `generation`.
+    fn add_unmerged(&mut self, generation: u64, length: u64) {
+        self.unmerged += length;
+        if self.merging.as_ref().is_some_and(|progress| generation >= progress.sources_end) {
+            self.unmerged_kept += length;
+        }
+    }
+
+    /// Whether the garbage exceeds the compaction threshold and the policy
+    /// allows a compaction now.
+    fn needs_compaction(&self) -> bool {
+        self.unmerged > self.options.compaction_threshold && self.options.auto_compaction.allows(self.options.now())
+    }
+
+    /// Remove a given key.
+    /// Return an error if the key does not exist or is not removed successfully.
+    fn remove(&mut self, key: String) -> Result<()> {
+        let writer = self.writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        if self.index.contains_key(&key) {
+            let cmd = Command::remove(key);
+            let record = record::encode(&cmd)?;
+            writer.write_all(&record)?;
+            self.commit_write()?;
+            // the remove record is stale too, a merge drops it
+            self.add_unmerged(self.write_generation, record.len() as u64);
+            if let Command::Remove { key } = cmd {
+                let old_cmd_info = self.index.remove(&key)
+                    .expect("Key not found");
+                self.uncache(&key);
+                self.add_unmerged(old_cmd_info.generation, old_cmd_info.length);
+                self.live_bytes -= old_cmd_info.live_len();
+                if let Some(eviction) = &self.eviction {
+                    eviction.lock().unwrap().remove(&key);
+                }
+            }
+            self.compact_over_threshold()
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    /// Remove every key: mark the logs before a new active log as cleared,
+    /// switch to it, then delete the older logs and value logs. The marker is
+    /// made as durable as the writes first, the logs left by a crash or a
+    /// failed delete are not loaded again.
+    fn clear(&mut self) -> Result<()> {
+        if self.writer.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        let fs = self.options.file_system.clone();
+        let previous_generation = self.write_generation;
+        let generation = previous_generation + 1;
+        let writer = create_log_file(&*fs, generation, &self.path, self.options.buffer_size)?;
+        write_clear_marker(&*fs, &self.path, generation, self.options.durability != Durability::None)?;
+        self.writer = Some(writer);
+        self.value_writer = None;
+        self.write_generation = generation;
+        self.index.clear();
+        self.clear_cache();
+        if let Some(eviction) = &self.eviction {
+            *eviction.lock().unwrap() = EvictionQueue::new(self.options.eviction_policy, self.options.expected_keys);
+        }
+        self.unflushed.store(false, Ordering::SeqCst);
+        self.unsynced = 0;
+        // the writes waiting on the group commit were to the logs deleted
+        self.committed();
+        self.unmerged = 0;
+        self.unmerged_kept = 0;
+        self.live_bytes = 0;
+        self.merging = None;
+        self.loaded.clear();
+        // a cursor before the clear finds its generation merged, and reads the keys anew
+        self.merged_generations.clear();
+        self.merged_generations.insert(previous_generation);
+        // readers drop the files, a read of a deleted file finds its key gone
+        self.reader.merged_gen.store(generation, Ordering::SeqCst);
+        self.reader.close_stale_reader();
+
+        // a log before its value log, the records must not point at deleted values
+        let mut stale: Vec<_> = log_files(&*fs, &self.path)?
+            .into_iter()
+            .chain(value_log_files(&*fs, &self.path)?)
+            .filter(|&(file_generation, _)| file_generation < generation)
+            .collect();
+        stale.sort_by_key(|&(file_generation, _)| file_generation);
+        for (_, path) in stale {
+            if let Err(e) = fs.remove_file(&path) {
+                error!("Cleared files delete failed: {:?}, {}", path, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove keys in eviction order until the store is within its bounds,
+    /// `keep` is not evicted.
+    fn evict(&mut self, keep: &str) -> Result<()> {
+        let eviction = match &self.eviction {
+            Some(eviction) => eviction.clone(),
+            None => return Ok(()),
+        };
+        while self.options.max_keys.is_some_and(|max_keys| self.index.len() > max_keys)
+            || self.options.max_bytes.is_some_and(|max_bytes| self.live_bytes > max_bytes)
+        {
+            let next = eviction.lock().unwrap().next_except(keep);
+            match next {
+                Some(key) => {
+                    debug!("evict {}", key);
+                    self.remove(key)?;
+                }
+                // the key kept is over the bounds on its own
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush the records left in the write buffer, those left to the group
+    /// commit are committed.
+    fn flush(&mut self) -> Result<()> {
+        if self.group_commit.as_ref().is_some_and(|group_commit| group_commit.is_behind(self.appended)) {
+            return self.commit_appended();
+        }
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+        }
+        self.unflushed.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Move the value of `from` to `to`, no other write can happen in between.
+    fn rename(&mut self, from: String, to: String) -> Result<()> {
+        let cmd_info = self.index.get(&from).ok_or(KvsError::KeyNotFound)?;
+        if from == to {
+            return Ok(());
+        }
+        self.flush()?;
+        // merges only run under the writer, so the record can not move meanwhile
+        let (value, value_type) = match self.reader.read_command(cmd_info)? {
+            Command::Set { value, value_type, .. } => (value, value_type),
+            Command::Remove { .. } => return Err(KvsError::UnknownCommand),
+        };
+        let info = self.append_set(&to, &value, value_type)?;
+        self.commit_write()?;
+        self.index_at(to.clone(), info);
+        // `from` is removed before the eviction, which could pick it otherwise
+        self.remove(from)?;
+        self.evict(&to)
+    }
+
+    /// Read the value of key, no write can happen meanwhile.
+    fn read(&mut self, key: &str) -> Result<Option<String>> {
+        self.flush()?;
+        match self.index.get(key) {
+            Some(cmd_info) => match self.reader.read_command(cmd_info)? {
+                Command::Set { value, .. } => Ok(Some(value)),
+                Command::Remove { .. } => Err(KvsError::UnknownCommand),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Get or set the value of key, no other write can happen in between.
+    fn get_or_insert(&mut self, key: String, default: String) -> Result<String> {
+        if let Some(value) = self.read(&key)? {
+            return Ok(value);
+        }
+        self.set(key, default.clone(), ValueType::String)?;
+        Ok(default)
+    }
+
+    /// Append to the value of key, no other write can happen in between.
+    fn append_to(&mut self, key: String, suffix: String) -> Result<usize> {
+        let mut value = self.read(&key)?.unwrap_or_default();
+        value.push_str(&suffix);
+        let len = value.len();
+        self.set(key, value, ValueType::String)?;
+        Ok(len)
+    }
+
+    /// Set the value of key and return the previous one, no other write can happen in between.
+    fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let previous = self.read(&key)?;
+        self.set(key, value, ValueType::String)?;
+        Ok(previous)
+    }
+
+    /// Swap the value of key if it is `expected`, no other write can happen in between.
+    fn compare_and_swap(&mut self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        let current = self.read(&key)?;
+        if current != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.set(key, value, ValueType::String)?,
+            None if current.is_some() => self.remove(key)?,
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// Add `delta` to the `Int` value of key, no other write can happen in between.
+    fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.flush()?;
+        let current = match self.index.get(&key) {
+            Some(cmd_info) => match self.reader.read_command(cmd_info)? {
+                Command::Set { value, value_type, .. } => TypedValue::from_text(value_type, value)?.parse_int()?,
+                Command::Remove { .. } => return Err(KvsError::UnknownCommand),
+            },
+            None => 0,
+        };
+        let value = current
+            .checked_add(delta)
+            .ok_or_else(|| KvsError::StringError(format!("Integer overflow adding {} to {}", delta, current)))?;
+        self.set(key, value.to_string(), ValueType::Int)?;
+        Ok(value)
+    }
+
+    /// merge log files to a merged file and delete invalid command
+    ///
+    /// Runs a pass copying at most `max_entries_per_pass` keys, starting a merge
+    /// if none is running. Return whether the merge is done.
+    ///
+    /// If it fails, the partial merged file is deleted and the store is left
+    /// as it was, a `KvsError::CompactionFailed` is returned.
+    ///
+    /// The permit of the compaction limiter is taken by the caller, before the
+    /// writer lock, so no write waits on the compactions of other stores.
+    pub fn merge(&mut self, _permit: &CompactionPermit<'_>) -> Result<bool> {
+        if self.writer.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        // the records copied are read from the files
+        self.flush()?;
+        let merged_generation = self.write_generation + 1;
+        let active_generation = self.write_generation + 2;
+        // the logs before the first pass are merged, later ones hold newer records
+        let progress = self.merging.clone().unwrap_or(MergeProgress {
+            sources_end: merged_generation,
+            last_key: None,
+        });
+        debug!("merging, pass from {:?}", progress.last_key);
+        // write to a temporary file, a crash during the merge leaves no partial log file
+        let tmp_path = tmp_file_name(&self.path, merged_generation);
+        let tmp_value_path = tmp_value_log_name(&self.path, merged_generation);
+        let fs = &*self.options.file_system;
+        let mut entries: Vec<_> = self.index.iter_after(progress.last_key.as_deref())
+            .filter(|(_, cmd_info)| cmd_info.generation < progress.sources_end)
+            .take(self.options.max_entries_per_pass.map_or(usize::MAX, |max| max + 1))
+            .collect();
+        let done = self.options.max_entries_per_pass.is_none_or(|max| entries.len() <= max);
+        if !done {
+            entries.pop();
+        }
+        let last_key = entries.last().map(|(key, _)| key.clone()).or(progress.last_key);
+        let result = create_log_file(fs, active_generation, &self.path, self.options.buffer_size).and_then(|writer| {
+            Ok((self.write_merged(merged_generation, &tmp_path, &tmp_value_path, entries)?, writer))
+        });
+        let (merged, writer) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Merge into generation {} failed: {}", merged_generation, e);
+                let _ = fs.remove_file(&tmp_path);
+                let _ = fs.remove_file(&tmp_value_path);
+                let _ = fs.remove_file(&value_log_name(&self.path, merged_generation));
+                let _ = fs.remove_file(&log_file_name(&self.path, active_generation));
+                return Err(KvsError::CompactionFailed(Box::new(e)));
+            }
+        };
+
+        // nothing can fail from here, switch to the merged file and the new active file
+        let previous = self.writer.replace(writer).expect("merge of a replica");
+        let previous_generation = self.write_generation;
+        self.value_writer = None;
+        self.write_generation = active_generation;
+        for (key, cmd_info) in merged {
+            self.index.insert(key, cmd_info);
+        }
+        self.clear_cache();
+        self.merged_generations.insert(merged_generation);
+        // the active file of the previous pass, if nothing was written to it
+        if previous.pos == 0 && previous_generation >= progress.sources_end {
+            drop(previous);
+            let _ = fs.remove_file(&log_file_name(&self.path, previous_generation));
+        }
+        if !done {
+            self.merging = Some(MergeProgress { last_key, ..progress });
+            return Ok(false);
+        }
+        self.merging = None;
+        let merged_generation = progress.sources_end;
+        // the logs before it are deleted, a cursor in them is found too old by its generation
+        self.merged_generations = self.merged_generations.split_off(&merged_generation);
+        self.reader.merged_gen.store(merged_generation, Ordering::SeqCst);
+        self.reader.close_stale_reader();
+        Counters::incr(&self.counters.compactions);
+
+        // delete log file which have merged
+        let stale_logs = log_files(fs, &self.path)?
+            .into_iter()
+            .filter(|&(generation, _)| generation < merged_generation);
+        for (_, full_path_name) in stale_logs {
+            if let Err(e) = fs.remove_file(&full_path_name) {
+                error!("Stale files delete failed: {:?}, {}", full_path_name, e);
+            }
+        }
+        // delete the value logs no live record points at
+        let live_values = self.live_value_bytes();
+        for (generation, path) in value_log_files(fs, &self.path)? {
+            if generation < active_generation && !live_values.contains_key(&generation) {
+                if let Err(e) = fs.remove_file(&path) {
+                    error!("Stale files delete failed: {:?}, {}", path, e);
+                }
+            }
+        }
+        // the stale records written between the passes are in the logs kept
+        self.unmerged = std::mem::take(&mut self.unmerged_kept);
+        Ok(true)
+    }
+
+    /// write the live commands to the merged file through a temporary file.
+    ///
+    /// The values of the value logs less than half live are moved to the value
+    /// log of the merged generation, through a temporary file as well.
+    fn write_merged(
+        &self,
+        merged_generation: u64,
+        tmp_path: &Path,
+        tmp_value_path: &Path,
+        entries: Vec<(String, CommandInfo)>,
+    ) -> Result<Vec<(String, CommandInfo)>> {
+        let fs = &*self.options.file_system;
+        let mut rewrite = BTreeSet::new();
+        for (generation, live_bytes) in self.live_value_bytes() {
+            if live_bytes * 2 < fs.file_len(&value_log_name(&self.path, generation))? {
+                rewrite.insert(generation);
+            }
+        }
+        let value_writer = if rewrite.is_empty() {
+            None
+        } else {
+            Some(open_log_writer(fs, tmp_value_path, self.options.buffer_size)?)
+        };
+        let mut values = ValueCopy::Keep { rewrite, generation: merged_generation, writer: value_writer };
+        let mut new_writer = open_log_writer(fs, tmp_path, self.options.buffer_size)?;
+        // copy old generation file data to merged_generation file.
+        #[cfg(feature = "compression")]
+        let merged = {
+            let mut compressor = ZlogWriter::new(&mut new_writer);
+            let merged = self.copy_live(merged_generation, &mut compressor, entries, &mut values)?;
+            compressor.finish()?;
+            merged
+        };
+        #[cfg(not(feature = "compression"))]
+        let merged = self.copy_live(merged_generation, &mut new_writer, entries, &mut values)?;
+        // the merged files replace fsynced logs, they are made as durable before
+        let sync = self.options.durability != Durability::None;
+        // the merged file must not point at a value log not yet in place
+        if let ValueCopy::Keep { writer: Some(mut value_writer), .. } = values {
+            if sync {
+                value_writer.sync()?;
+            } else {
+                value_writer.flush()?;
+            }
+            fs.rename(tmp_value_path, &value_log_name(&self.path, merged_generation))?;
+        }
+        // readers may follow the index to the merged file only after it is flushed
+        if sync {
+            new_writer.sync()?;
+        } else {
+            new_writer.flush()?;
+        }
+        fs.rename(tmp_path, &merged_log_name(&self.path, merged_generation))?;
+        // the renames are durable before the merged logs are deleted
+        if sync {
+            fs.sync_dir(&self.path)?;
+        }
+        Ok(merged)
+    }
+
+    /// copy the live commands of `entries` to `writer`, the log file of
+    /// `generation`, the separated values as `values` tells.
+    /// Return the new command info of every copied key.
+    fn copy_live(
+        &self,
+        generation: u64,
+        writer: &mut impl Write,
+        entries: impl IntoIterator<Item = (String, CommandInfo)>,
+        values: &mut ValueCopy,
+    ) -> Result<Vec<(String, CommandInfo)>> {
+        let mut start_pos = 0;
+        let mut copied = Vec::with_capacity(self.options.expected_keys);
+        for (key, cmd_info) in entries {
+            let (length, value) = match cmd_info.value {
+                Some(pointer) if values.moves(pointer) => {
+                    let (value, value_type) = match self.reader.read_command(cmd_info)? {
+                        Command::Set { value, value_type, .. } => (value, value_type),
+                        Command::Remove { .. } => return Err(KvsError::UnknownCommand),
+                    };
+                    let (record, pointer) = values.write(&key, &value, value_type)?;
+                    writer.write_all(&record)?;
+                    (record.len() as u64, pointer)
+                }
+                value => {
+                    let length = self.reader.read_and(cmd_info, |mut cmd_reader| {
+                        Ok(io::copy(&mut cmd_reader, writer)?)
+                    })?;
+                    (length, value)
+                }
+            };
+            copied.push((key, CommandInfo::new(generation, start_pos, start_pos + length)?.with_value(value)));
+            start_pos += length;
+        }
+        Ok(copied)
+    }
+
+    /// The bytes of the live values of each value log.
+    fn live_value_bytes(&self) -> BTreeMap<u64, u64> {
+        let mut live_bytes = BTreeMap::new();
+        for (_, cmd_info) in self.index.iter() {
+            if let Some(pointer) = cmd_info.value {
+                *live_bytes.entry(pointer.generation).or_insert(0) += pointer.length;
+            }
+        }
+        live_bytes
+    }
+
+    /// The point after the last record written, or loaded by a replica.
+    fn cursor(&self) -> ChangeCursor {
+        match &self.writer {
+            Some(writer) => ChangeCursor { generation: self.write_generation, offset: writer.pos },
+            None => {
+                let (&generation, &offset) = self.loaded.iter().next_back().unwrap_or((&INIT_GENERATION, &0));
+                ChangeCursor { generation, offset }
+            }
+        }
+    }
+
+    /// Load the records appended to the logs since they were last loaded.
+    ///
+    /// If a merge deleted logs loaded before, the index is rebuilt from the logs
+    /// left: the records missed in the deleted logs are in the merged one.
+    fn reload(&mut self) -> Result<()> {
+        if self.writer.is_some() {
+            return Ok(());
+        }
+        let fs = &*self.options.file_system;
+        let generation_list = read_generation(fs, &self.path)?;
+        let rebuild = self.loaded.keys().any(|generation| !generation_list.contains(generation));
+        let rebuilt = Index::default();
+        let index = if rebuild {
+            self.loaded.clear();
+            &rebuilt
+        } else {
+            &*self.index
+        };
+        for generation in generation_list {
+            let start = self.loaded.get(&generation).copied().unwrap_or(0);
+            let file = match open_log(fs, &self.path, generation) {
+                Ok(file) => file,
+                // merged meanwhile, its records are in the merged log listed after it
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            // a partial record at the end is loaded by a later reload, once it is written
+            let (_, valid_len, _) = load_log(generation, start, &mut KvsBufReader::new(file, self.options.buffer_size)?, index)?;
+            self.loaded.insert(generation, valid_len);
+        }
+
+        if rebuild {
+            let removed: Vec<String> = self.index.iter()
+                .map(|(key, _)| key)
+                .filter(|key| !rebuilt.contains_key(key))
+                .collect();
+            for key in removed {
+                self.index.remove(&key);
+            }
+            for (key, cmd_info) in rebuilt.iter() {
+                self.index.insert(key, cmd_info);
+            }
+            // the readers of the deleted logs are closed
+            if let Some(&oldest) = self.loaded.keys().next() {
+                self.reader.merged_gen.store(oldest, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl KvStore {
+    /// Open the KvStore at a given path.
+    /// Return the KvStore.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Open the KvStore at a given path with the given options.
+    /// Return the KvStore.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        KvStore::open_logs(path.into(), options, true)
+    }
+
+    /// Open the KvStore at a given path read only, to inspect it: unlike
+    /// [`KvStore::open`] no active log file is created and no file is changed.
+    /// It is opened as a replica, writes return `KvsError::ReadOnly`.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_replica(path)
+    }
+
+    /// Open a read only replica of the KvStore at a given path, which another
+    /// process may be writing to. Call [`KvStore::reload`] to see its writes.
+    ///
+    /// The directory is neither locked nor repaired, writes return `KvsError::ReadOnly`.
+    pub fn open_replica(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_replica_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Open a read only replica of the KvStore at a given path with the given options.
+    pub fn open_replica_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        KvStore::open_logs(path.into(), options, false)
+    }
+
+    fn open_logs(path: PathBuf, options: KvStoreOptions, writable: bool) -> Result<KvStore> {
+        let fs = options.file_system.clone();
+        let mut recovery = RecoveryInfo::default();
+        let lock = if writable {
+            fs.create_dir_all(&path)?;
+            let lock = lock_dir(&*fs, &path)?;
+            recovery.removed_tmp_files = remove_tmp_files(&*fs, &path)?;
+            // must run before the new active log file is created
+            recovery.cleaned_empty_gens = remove_empty_generations(&*fs, &path)?;
+            Some(lock)
+        } else {
+            None
+        };
+        let index = Index::default();
+        let generation_list = read_generation(&*fs, &path)?;
+
+        // init reader
+        let last_generation = generation_list.iter().max().copied().unwrap_or(INIT_GENERATION);
+        let mut unmerged = 0;
+        let mut loaded = BTreeMap::new();
+        let mut readers = BTreeMap::new();
+        for &generation in &generation_list {
+            let mut reader = KvsBufReader::new(open_log(&*fs, &path, generation)?, options.buffer_size)?;
+            let (log_unmerged, valid_len, truncated) = load_log(generation, 0, &mut reader, &index)?;
+            unmerged += log_unmerged;
+            // only the newest log was being appended to when a crash cut its
+            // last record short, a partial record before whole ones is corrupt
+            if truncated && (generation != last_generation || records_after(&mut reader, valid_len)?) {
+                return Err(KvsError::Corruption { generation, offset: valid_len });
+            }
+            // the partial record of a replica may still be being written, a
+            // compressed log is written whole by a merge
+            if truncated && writable {
+                let path = log_file_name(&path, generation);
+                warn!("Truncate partial record at the end of {:?} to {} bytes", path, valid_len);
+                fs.truncate(&path, valid_len)?;
+                recovery.truncated_records += 1;
+            }
+            loaded.insert(generation, valid_len);
+            readers.insert(generation, KvsBufReader::new(open_log(&*fs, &path, generation)?, options.buffer_size)?);
+        }
+
+        // open a new log file as the active file for writing logs, after the
+        // cleared logs a failed delete left
+        let write_generation = last_generation.max(cleared_generation(&*fs, &path)?) + 1;
+        // init writer
+        let writer = if writable {
+            // values written by a process which stopped before writing their records
+            for (generation, value_path) in value_log_files(&*fs, &path)? {
+                if generation >= write_generation {
+                    warn!("remove value log {:?} no record points at", value_path);
+                    fs.remove_file(&value_path)?;
+                }
+            }
+            Some(create_log_file(&*fs, write_generation, &path, options.buffer_size)?)
+        } else {
+            None
+        };
+
+        let path = Arc::new(path);
+        let reader = KvStoreReader {
+            path: path.clone(),
+            fs,
+            buffer_size: options.buffer_size,
+            readers: RefCell::new(readers),
+            // merge method will set the really newest merged generation for it
+            merged_gen: Arc::new(AtomicU64::new(INIT_GENERATION)),
+            buffer: RefCell::new(Vec::new()),
+            value_readers: RefCell::new(BTreeMap::new()),
+            value_readers_gen: Cell::new(INIT_GENERATION),
+            #[cfg(feature = "mmap")]
+            maps: RefCell::new(BTreeMap::new()),
+            #[cfg(feature = "mmap")]
+            map_logs: writable,
+        };
+        let live_bytes = index.iter().map(|(_, cmd_info)| cmd_info.live_len()).sum();
+        let eviction = if options.is_bounded() {
+            // the keys last written before the store was opened are evicted last
+            let mut keys: Vec<_> = index.iter().collect();
+            keys.sort_by_key(|(_, cmd_info)| (cmd_info.generation, cmd_info.pos_start));
+            let capacity = keys.len().max(options.expected_keys);
+            let mut eviction = EvictionQueue::new(options.eviction_policy, capacity);
+            for (key, _) in keys {
+                eviction.write(&key);
+            }
+            Some(Arc::new(Mutex::new(eviction)))
+        } else {
+            None
+        };
+        let cache = options.value_cache.map(|capacity| Arc::new(Mutex::new(LruCache::new(capacity))));
+        let group_commit = match options.group_commit {
+            Some(window) if options.durability != Durability::None => Some(Arc::new(GroupCommit::new(window))),
+            _ => None,
+        };
+        let index = Arc::new(index);
+        let options = Arc::new(options);
+        let counters = Arc::new(Counters::default());
+        let unflushed = Arc::new(AtomicBool::new(false));
+        let writer = Arc::new(Mutex::new(KvStoreWriter {
+            path: path.clone(),
+            _lock: lock,
+            options: options.clone(),
+            counters: counters.clone(),
+            write_generation,
+            writer,
+            value_writer: None,
+            unflushed: unflushed.clone(),
+            unsynced: 0,
+            appended: 0,
+            group_commit: group_commit.clone(),
+            loaded,
+            unmerged,
+            unmerged_kept: 0,
+            live_bytes,
+            merging: None,
+            merged_generations: BTreeSet::new(),
+            compactor: None,
+            reader: reader.clone(),
+            index: index.clone(),
+            eviction: eviction.clone(),
+            cache: cache.clone(),
+        }));
+        let compactor = if writable && options.background_compaction {
+            let compactor = Compactor::spawn(Arc::downgrade(&writer), options.compaction_limiter.clone())?;
+            writer.lock().unwrap().compactor = Some(compactor.wake.clone());
+            Some(Arc::new(compactor))
+        } else {
+            None
+        };
+
+        Ok(KvStore {
+            path,
+            options,
+            recovery: Arc::new(recovery),
+            counters,
+            index,
+            _compactor: compactor,
+            writer,
+            reader,
+            eviction,
+            unflushed,
+            cache,
+            group_commit,
+        })
+    }
+
+    /// Merge the log files now, dropping the stale records, and return the
+    /// bytes the files shrank by. Nothing is merged if no record is stale.
+    /// Return `KvsError::CompactionFailed` if it fails, the store is then left as it was.
+    ///
+    /// Other writes may run between the passes of the merge, the bytes they
+    /// add are taken from the bytes reclaimed.
+    pub fn compact(&self) -> Result<u64> {
+        {
+            let writer = self.writer.lock().unwrap();
+            if writer.writer.is_some() && writer.unmerged == 0 && writer.merging.is_none() {
+                return Ok(0);
+            }
+        }
+        let before = self.disk_usage()?.total_bytes;
+        let permit = self.options.compaction_limiter.acquire();
+        while !self.writer.lock().unwrap().merge(&permit)? {}
+        Ok(before.saturating_sub(self.disk_usage()?.total_bytes))
+    }
+
+    /// Load the writes another process made to the logs of a replica since it
+    /// was opened or last reloaded. A store opened for writing is the only writer
+    /// of its logs, it has nothing to reload.
+    ///
+    /// Example:
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine, Result};
+    /// # fn try_main() -> Result<()> {
+    /// use std::env::current_dir;
+    /// let replica = KvStore::open_replica(current_dir()?)?;
+    /// replica.reload()?;
+    /// let val = replica.get("key".to_owned())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn reload(&self) -> Result<()> {
+        self.writer.lock().unwrap().reload()
+    }
+
+    /// Every record of key left in the logs, oldest first.
+    ///
+    /// A compaction keeps only the latest set of each live key, so the history
+    /// goes back to the last compaction at most. Writes are blocked while the
+    /// logs are scanned.
+    pub fn history(&self, key: &str) -> Result<Vec<HistoryEntry>> {
+        let key = &*self.options.normalize_str(key);
+        // hold the writer so a merge can not delete the logs during the scan
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush()?;
+        let mut records = Vec::new();
+        let fs = &*self.options.file_system;
+        for generation in read_generation(fs, &self.path)? {
+            scan_log(fs, &self.path, generation, 0, |cmd, cmd_info| {
+                if cmd.key() == key {
+                    records.push((cmd, cmd_info));
+                }
+            })?;
+        }
+        records.into_iter()
+            .map(|(cmd, cmd_info)| {
+                Ok(HistoryEntry {
+                    generation: cmd_info.generation,
+                    offset: cmd_info.pos_start,
+                    value: match self.reader.resolve(cmd)? {
+                        Command::Set { value, .. } => Some(value),
+                        Command::Remove { .. } => None,
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// A cursor at the last write, to read the changes made after it.
+    pub fn cursor(&self) -> ChangeCursor {
+        self.writer.lock().unwrap().cursor()
+    }
+
+    /// The latest change of each key set or removed after `cursor`, read by
+    /// replaying the logs from it.
+    ///
+    /// Once a compaction ran past the cursor the logs no longer tell what
+    /// changed, every live key is returned instead and `full` is set. Compactions
+    /// started before the store was last opened are not known, keys they copied
+    /// may be returned unchanged. Writes are blocked while the logs are read.
+    ///
+    /// Example:
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine, Result};
+    /// # fn try_main() -> Result<()> {
+    /// use std::env::current_dir;
+    /// let store = KvStore::open(current_dir()?)?;
+    /// let cursor = store.cursor();
+    /// store.set("key".to_owned(), "value".to_owned())?;
+    /// let changes = store.changes_since(cursor)?;
+    /// // ship changes.changes, then go on from changes.cursor
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn changes_since(&self, cursor: ChangeCursor) -> Result<Changes> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush()?;
+        let fs = &*self.options.file_system;
+        let generations = read_generation(fs, &self.path)?;
+        // a compaction deleted the log of the cursor, or copied records after it
+        let merged = generations.first().is_some_and(|&oldest| cursor.generation < oldest)
+            || writer.merged_generations.range(cursor.generation..).next().is_some();
+        if merged {
+            let mut changes = Vec::with_capacity(self.index.len());
+            for (key, _) in self.index.iter() {
+                if let Some(value) = writer.read(&key)? {
+                    changes.push(Change::Set { key, value });
+                }
+            }
+            return Ok(Changes { changes, full: true, cursor: writer.cursor() });
+        }
+
+        let mut latest = BTreeMap::new();
+        for generation in generations.into_iter().filter(|&generation| generation >= cursor.generation) {
+            let start = if generation == cursor.generation { cursor.offset } else { 0 };
+            scan_log(fs, &self.path, generation, start, |cmd, _| {
+                latest.insert(cmd.key().to_owned(), cmd);
+            })?;
+        }
+        let changes = latest.into_values()
+            .map(|cmd| match self.reader.resolve(cmd)? {
+                Command::Set { key, value, .. } => Ok(Change::Set { key, value }),
+                Command::Remove { key } => Ok(Change::Remove { key }),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Changes { changes, full: false, cursor: writer.cursor() })
+    }
+
+    /// Flush the records left in the write buffer by `Durability::None`.
+    pub fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+
+    /// Read every log file and value log through once, so the reads after a
+    /// restart find them in the page cache of the operating system.
+    ///
+    /// Writes go on meanwhile, a file deleted by a compaction is skipped.
+    ///
+    /// Example:
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn try_main() -> Result<()> {
+    /// use std::env::current_dir;
+    /// let store = KvStore::open(current_dir()?)?;
+    /// store.warmup()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn warmup(&self) -> Result<()> {
+        let fs = &*self.options.file_system;
+        let mut files = log_files(fs, &self.path)?;
+        files.extend(value_log_files(fs, &self.path)?);
+        // the newest first, their records are the likeliest to be live
+        files.sort_unstable_by_key(|&(generation, _)| std::cmp::Reverse(generation));
+        for (_, path) in files {
+            let mut file = match fs.open_read(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let bytes = io::copy(&mut file, &mut io::sink())?;
+            debug!("warmed up {:?}, {} bytes", path, bytes);
+        }
+        Ok(())
+    }
+
+    /// The internal state of the store as a JSON object, to diagnose it.
+    ///
+    /// It holds the active and the newest merged generation, the bytes left
+    /// to compaction, the stats, the size of the files of every generation and
+    /// the options in effect. The fields may change between versions.
+    pub fn debug_dump(&self) -> Result<serde_json::Value> {
+        // hold the writer so no merge changes the files meanwhile
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush()?;
+        let fs = &*self.options.file_system;
+        let mut generations = BTreeMap::new();
+        for (generation, path) in log_files(fs, &self.path)? {
+            generations.insert(generation, (Some(fs.file_len(&path)?), None));
+        }
+        for (generation, path) in value_log_files(fs, &self.path)? {
+            generations.entry(generation).or_insert((None, None)).1 = Some(fs.file_len(&path)?);
+        }
+        let generations: Vec<_> = generations
+            .into_iter()
+            .map(|(generation, (log_bytes, value_log_bytes))| json!({
+                "generation": generation,
+                "log_bytes": log_bytes,
+                "value_log_bytes": value_log_bytes,
+                "merged": writer.merged_generations.contains(&generation),
+            }))
+            .collect();
+        Ok(json!({
+            "path": self.path.display().to_string(),
+            "write_generation": writer.write_generation,
+            "merged_generation": self.reader.merged_gen.load(Ordering::SeqCst),
+            "unmerged_bytes": writer.unmerged,
+            "live_bytes": writer.live_bytes,
+            "merging": writer.merging.as_ref().map(|progress| json!({
+                "sources_end": progress.sources_end,
+                "last_key": progress.last_key,
+            })),
+            "stats": self.counters.snapshot(self.index.len() as u64),
+            "generations": generations,
+            "options": self.options.to_json(),
+        }))
+    }
+
+    /// The generations of the logs and the bytes left to compaction, to see
+    /// how close the store is to its next compaction.
+    pub fn store_stats(&self) -> Result<KvStoreStats> {
+        // hold the writer so no merge changes the files meanwhile
+        let writer = self.writer.lock().unwrap();
+        let generations = read_generation(&*self.options.file_system, &self.path)?;
+        Ok(KvStoreStats {
+            generations: generations.len(),
+            write_generation: writer.write_generation,
+            unmerged_bytes: writer.unmerged,
+            compaction_threshold: self.options.compaction_threshold,
+            live_keys: self.index.len() as u64,
+        })
+    }
+
+    /// The number of live keys, in constant time: the index keeps a count.
+    ///
+    /// A write running in another thread meanwhile may be counted or not.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Whether the store holds no key.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The pairs of the keys starting with `prefix`, in key order. The prefix
+    /// is normalized as a key.
+    ///
+    /// The index is walked from `prefix` until a key does not start with it.
+    /// The values are read one at a time, writes made meanwhile may or may not
+    /// be seen.
+    pub fn scan_prefix(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let prefix = self.options.normalize(prefix);
+        let mut pairs = Vec::new();
+        for key in self.index.keys_with_prefix(&prefix) {
+            Counters::incr(&self.counters.gets);
+            // skip keys removed since the scan started
+            if let Some((value, _)) = self.read_key(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// The pairs of the keys from `start`, included, to `end`, excluded, in
+    /// key order. Both bounds are normalized as keys, the range is empty unless
+    /// `start < end`. The next page of a range starts at the `end` of the last.
+    ///
+    /// The values are read one at a time, as by [`KvStore::scan_prefix`].
+    pub fn range(&self, start: String, end: String) -> Result<Vec<(String, String)>> {
+        let start = self.options.normalize(start);
+        let end = self.options.normalize(end);
+        let mut pairs = Vec::new();
+        for key in self.index.keys_in_range(&start, &end) {
+            Counters::incr(&self.counters.gets);
+            if let Some((value, _)) = self.read_key(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// What was repaired when the store was opened.
+    pub fn last_recovery(&self) -> &RecoveryInfo {
+        &self.recovery
+    }
+
+    /// Read the value of key into `buf`, reusing its allocation across calls.
+    /// Return whether the key exists, `buf` is left unchanged if it does not.
+    ///
+    /// Example:
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn try_main() -> Result<()> {
+    /// use std::env::current_dir;
+    /// let store = KvStore::open(current_dir()?)?;
+    /// let mut buf = String::new();
+    /// for key in &["key1", "key2"] {
+    ///     if store.get_into(key, &mut buf)? {
+    ///         println!("{}", buf);
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_into(&self, key: &str, buf: &mut String) -> Result<bool> {
+        let key = &*self.options.normalize_str(key);
+        self.record_read(key);
+        Counters::incr(&self.counters.gets);
+        loop {
+            let cmd_info = match self.index.get(key) {
+                Some(cmd_info) => cmd_info,
+                None => return Ok(false),
+            };
+            self.flush_unflushed()?;
+            return match self.reader.read_value_into(cmd_info, buf) {
+                Ok(()) => Ok(true),
+                Err(_) if self.is_moved(key, &cmd_info) => continue,
+                Err(e) if self.options.recovery => match self.recover(key.to_owned(), cmd_info, e)? {
+                    Some((value, _)) => {
+                        buf.clear();
+                        buf.push_str(&value);
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                },
+                Err(e) => Err(e),
+            };
+        }
+    }
+
+    /// Get the value of key without waiting on a lock.
+    /// Return `None` if a lock of the read path is held, or the result of `get`.
+    ///
+    /// Recovery mode does not apply, its rescan would wait on writes.
+    pub fn try_get(&self, key: String) -> Result<Option<Option<String>>> {
+        let key = self.options.normalize(key);
+        if let Some(eviction) = &self.eviction {
+            match eviction.try_lock() {
+                Ok(mut eviction) => eviction.read(&key),
+                Err(_) => return Ok(None),
+            }
+        }
+        let value = loop {
+            let cmd_info = match self.index.try_get(&key) {
+                Some(Some(cmd_info)) => cmd_info,
+                Some(None) => break None,
+                None => return Ok(None),
+            };
+            if self.unflushed.load(Ordering::SeqCst) {
+                match self.writer.try_lock() {
+                    Ok(mut writer) => writer.flush()?,
+                    Err(_) => return Ok(None),
+                }
+            }
+            match self.reader.read_command(cmd_info) {
+                Ok(Command::Set { value, .. }) => break Some(value),
+                Ok(Command::Remove { .. }) => return Err(KvsError::UnknownCommand),
+                Err(_) if self.is_moved(&key, &cmd_info) => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        // a call which would have blocked read nothing
+        Counters::incr(&self.counters.gets);
+        Ok(Some(value))
+    }
+
+    /// Read the values of many keys as of a single point in time.
+    ///
+    /// Writes are blocked while reading, so the values are those after some
+    /// write and before the next one: a write made by another thread is seen
+    /// for all keys or for none. Plain `get`s have no such guarantee, a write can
+    /// land between two of them. The values are in the order of `keys`.
+    ///
+    /// Recovery mode does not apply, a record that can not be read is an error.
+    pub fn multi_get_consistent(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut writer = self.writer.lock().unwrap();
+        keys.into_iter()
+            .map(|key| {
+                Counters::incr(&self.counters.gets);
+                writer.read(&self.options.normalize(key))
+            })
+            .collect()
+    }
+
+    /// Pass the value of key to `f` and apply the update it returns,
+    /// no other write can happen in between. Return the result of `f`.
+    pub(crate) fn update<F, R>(&self, key: String, f: F) -> Result<R>
+        where F: FnOnce(Option<String>) -> (Update, R)
+    {
+        let key = self.options.normalize(key);
+        self.write(|writer| {
+            Counters::incr(&self.counters.gets);
+            let (update, result) = f(writer.read(&key)?);
+            match update {
+                Update::Keep => {}
+                Update::Set(value) => {
+                    Counters::incr(&self.counters.sets);
+                    writer.set(key, value, ValueType::String)?;
+                }
+                Update::Remove => {
+                    Counters::incr(&self.counters.removes);
+                    writer.remove(key)?;
+                }
+            }
+            Ok(result)
+        })
+    }
+
+    /// Whether key exists, known from the index alone.
+    pub(crate) fn has_key(&self, key: &str) -> bool {
+        self.index.contains_key(&self.options.normalize_str(key))
+    }
+
+    /// Write all live keys as a single merged log file into the directory `dest`,
+    /// which can then be opened as a `KvStore`. The store itself is left untouched.
+    ///
+    /// Writes are blocked while copying. Return an error if `dest` already holds log files.
+    pub fn compact_to(&self, dest: &Path) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush()?;
+        let fs = &*self.options.file_system;
+        fs.create_dir_all(dest)?;
+        let dest = dest.to_path_buf();
+        if !log_files(fs, &dest)?.is_empty() {
+            return Err(KvsError::Io(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already contains log files", dest),
+            )));
+        }
+        let generation = INIT_GENERATION + 1;
+        let mut dest_writer = create_log_file(fs, generation, &dest, self.options.buffer_size)?;
+        // the values are written into the records, `dest` has no value logs
+        writer.copy_live(generation, &mut dest_writer, self.index.iter(), &mut ValueCopy::Inline)?;
+        dest_writer.sync()?;
+        Ok(())
+    }
+
+    /// Write every live key with its value to `out`, in key order, as a stream
+    /// of `Command::Set` records framed like those of a log file.
+    ///
+    /// Writes are blocked while exporting, so the dump is a snapshot of a single
+    /// point in time: a write made by another thread is in it for all its keys
+    /// or for none. Reads go on meanwhile.
+    pub fn export(&self, mut out: impl Write) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush()?;
+        for (_, cmd_info) in self.index.iter() {
+            // a separated value is read into the record
+            let cmd = writer.reader.read_command(cmd_info)?;
+            if let Command::Remove { .. } = cmd {
+                return Err(KvsError::UnknownCommand);
+            }
+            out.write_all(&record::encode(&cmd)?)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Set the keys of a stream written by [`KvStore::export`], through the
+    /// usual writes. Return the number of keys set.
+    ///
+    /// The keys set before are kept unless the stream sets them. Writes of
+    /// other threads go on meanwhile, the last write of a key wins. A record
+    /// not matching its checksum is a `KvsError::Corruption` of generation 0,
+    /// a stream ending within a record an `io::ErrorKind::UnexpectedEof`.
+    /// The keys before such a record are set.
+    pub fn import(&self, input: impl Read) -> Result<u64> {
+        let mut reader = BufReader::new(input);
+        let mut offset = 0;
+        let mut imported = 0;
+        loop {
+            let cmd = match record::read::<Command, _>(&mut reader, INIT_GENERATION, offset)? {
+                Entry::Record(cmd, length) => {
+                    offset += length;
+                    cmd
+                }
+                Entry::Truncated => {
+                    return Err(KvsError::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("import stream ends within the record at offset {}", offset),
+                    )))
+                }
+                Entry::End => return Ok(imported),
+            };
+            match cmd {
+                Command::Set { key, value, value_type, value_pointer: None } => {
+                    Counters::incr(&self.counters.sets);
+                    let key = self.options.normalize(key);
+                    self.write(|writer| writer.set(key, value, value_type))?;
+                    imported += 1;
+                }
+                _ => return Err(KvsError::UnknownCommand),
+            }
+        }
+    }
+
+    /// Run a write under the writer lock, then wait until the group commit
+    /// covers it if the options ask for one.
+    fn write<F, R>(&self, f: F) -> Result<R>
+        where F: FnOnce(&mut KvStoreWriter) -> Result<R>
+    {
+        let mut writer = self.writer.lock().unwrap();
+        let result = f(&mut writer)?;
+        let appended = writer.appended;
+        drop(writer);
+        if let Some(group_commit) = &self.group_commit {
+            group_commit.wait(appended, &self.writer)?;
+        }
+        Ok(result)
+    }
+
+    /// Move key back in the eviction order of a bounded store.
+    fn record_read(&self, key: &str) {
+        if let Some(eviction) = &self.eviction {
+            eviction.lock().unwrap().read(key);
+        }
+    }
+
+    /// Flush the write buffer if it holds records, a record the index points at
+    /// may be one of them.
+    fn flush_unflushed(&self) -> Result<()> {
+        if self.unflushed.load(Ordering::SeqCst) {
+            self.writer.lock().unwrap().flush()?;
+        }
+        Ok(())
+    }
+
+    /// Read the value and its type of a normalized key, from the cache if it
+    /// holds the record the index points at.
+    fn read_key(&self, key: String) -> Result<Option<(String, ValueType)>> {
+        self.record_read(&key);
+        loop {
+            let cmd_info = match self.index.get(&key) {
+                Some(cmd_info) => cmd_info,
+                None => return Ok(None),
+            };
+            if let Some(cached) = self.cached(&key, &cmd_info) {
+                return Ok(Some(cached));
+            }
+            self.flush_unflushed()?;
+            return match self.reader.read_command(cmd_info) {
+                Ok(Command::Set { value, value_type, .. }) => {
+                    self.cache_value(key, cmd_info, &value, value_type);
+                    Ok(Some((value, value_type)))
+                }
+                Ok(Command::Remove { .. }) => Err(KvsError::UnknownCommand),
+                // a merge moved the record and deleted its file during the read
+                Err(_) if self.is_moved(&key, &cmd_info) => continue,
+                Err(e) if self.options.recovery => self.recover(key, cmd_info, e),
+                Err(e) => Err(e),
+            };
+        }
+    }
+
+    /// The cached value of key if it was read from the record at `cmd_info`.
+    fn cached(&self, key: &str, cmd_info: &CommandInfo) -> Option<(String, ValueType)> {
+        let mut cache = self.cache.as_ref()?.lock().unwrap();
+        let cached = cache.get(key)?;
+        // a write may have moved the record after the value was cached
+        if !cached.record.same_record(cmd_info) {
+            return None;
+        }
+        Some((cached.value.clone(), cached.value_type))
+    }
+
+    /// Cache the value of key read from the record at `cmd_info`.
+    fn cache_value(&self, key: String, cmd_info: CommandInfo, value: &str, value_type: ValueType) {
+        if let Some(cache) = &self.cache {
+            let cached = CachedValue { record: cmd_info, value: value.to_owned(), value_type };
+            cache.lock().unwrap().insert(key, cached);
+        }
+    }
+
+    /// Whether the index entry of key no longer points at the record.
+    fn is_moved(&self, key: &str, cmd_info: &CommandInfo) -> bool {
+        match self.index.get(key) {
+            Some(current) => !current.same_record(cmd_info),
+            None => true,
+        }
+    }
+
+    /// Rescan the logs for the latest readable record of `key`, skipping the
+    /// record at `failed`, and repair the index entry from it.
+    fn recover(&self, key: String, failed: CommandInfo, err: KvsError) -> Result<Option<(String, ValueType)>> {
+        warn!("Read of key {} failed at {:?}: {}, rescanning logs", key, failed, err);
+        // hold the writer so the index entry can not change during the rescan
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush()?;
+        if self.is_moved(&key, &failed) {
+            // the entry was changed by a writer meanwhile
+            drop(writer);
+            return self.read_key(key);
+        }
+
+        let mut latest = None;
+        let fs = &*self.options.file_system;
+        for generation in read_generation(fs, &self.path)? {
+            scan_log(fs, &self.path, generation, 0, |cmd, cmd_info| {
+                if cmd.key() == key && !cmd_info.same_record(&failed) {
+                    latest = Some((cmd, cmd_info));
+                }
+            })?;
+        }
+        let latest = match latest {
+            Some((cmd, cmd_info)) => Some((self.reader.resolve(cmd)?, cmd_info)),
+            None => None,
+        };
+        match latest {
+            Some((Command::Set { value, value_type, .. }, cmd_info)) => {
+                warn!("Key {} recovered from {:?}", key, cmd_info);
+                self.index.insert(key, cmd_info);
+                Ok(Some((value, value_type)))
+            }
+            Some((Command::Remove { .. }, _)) => {
+                warn!("Key {} recovered as removed", key);
+                self.index.remove(&key);
+                Ok(None)
+            }
+            None => Err(err),
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// Get the string value of a string key.
+    /// If the key does not exist, return None.
+    /// Return an error if the value is not read successfully.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Counters::incr(&self.counters.gets);
+        Ok(self.read_key(self.options.normalize(key))?.map(|(value, _)| value))
+    }
+
+    /// The records are read in their order in the logs, so the reads of a log
+    /// go forward, and the values put back in the order of `keys`.
+    fn get_batch(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        Counters::add(&self.counters.gets, keys.len() as u64);
+        let mut keys: Vec<String> = keys.into_iter().map(|key| self.options.normalize(key)).collect();
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by_cached_key(|&i| self.index.get(&keys[i]).map(|info| (info.generation, info.pos_start)));
+        let mut values = vec![None; keys.len()];
+        for i in order {
+            let key = std::mem::take(&mut keys[i]);
+            values[i] = self.read_key(key)?.map(|(value, _)| value);
+        }
+        Ok(values)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let key = self.options.normalize(key);
+        Counters::incr(&self.counters.sets);
+        if self.options.separates(&value) {
+            return self.write(|writer| writer.set(key, value, ValueType::String));
+        }
+        // serialized before taking the lock, a large value does not hold up other writers
+        let record = encode_set(&key, &value, ValueType::String)?;
+        self.write(|writer| writer.write_set(key, &record, None))
+    }
+
+    /// The records are appended and flushed at once under the writer lock,
+    /// the durability counts the batch as a single write.
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        Counters::add(&self.counters.sets, pairs.len() as u64);
+        // serialized before taking the lock as well
+        let sets = pairs
+            .into_iter()
+            .map(|(key, value)| {
+                let key = self.options.normalize(key);
+                let set = if self.options.separates(&value) {
+                    BatchSet::Separated(value)
+                } else {
+                    BatchSet::Record(encode_set(&key, &value, ValueType::String)?)
+                };
+                Ok((key, set))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.write(|writer| writer.set_batch(sets))
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Counters::incr(&self.counters.gets);
+        Ok(self.has_key(&key))
+    }
+
+    fn set_typed(&self, key: String, value: TypedValue) -> Result<()> {
+        let key = self.options.normalize(key);
+        let value_type = value.value_type();
+        Counters::incr(&self.counters.sets);
+        let value = value.into_text();
+        if self.options.separates(&value) {
+            return self.write(|writer| writer.set(key, value, value_type));
+        }
+        let record = encode_set(&key, &value, value_type)?;
+        self.write(|writer| writer.write_set(key, &record, None))
+    }
+
+    fn get_typed(&self, key: String) -> Result<Option<TypedValue>> {
+        Counters::incr(&self.counters.gets);
+        self.read_key(self.options.normalize(key))?
+            .map(|(value, value_type)| TypedValue::from_text(value_type, value))
+            .transpose()
+    }
+
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        let key = self.options.normalize(key);
+        Counters::incr(&self.counters.sets);
+        self.write(|writer| writer.increment(key, delta))
+    }
+
+    fn get_or_insert(&self, key: String, default: String) -> Result<String> {
+        let key = self.options.normalize(key);
+        Counters::incr(&self.counters.gets);
+        self.write(|writer| writer.get_or_insert(key, default))
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        let key = self.options.normalize(key);
+        Counters::incr(&self.counters.sets);
+        self.write(|writer| writer.append_to(key, suffix))
+    }
+
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        let key = self.options.normalize(key);
+        Counters::incr(&self.counters.sets);
+        self.write(|writer| writer.get_set(key, value))
+    }
+
+    /// The value is compared as text, whatever its type.
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        let key = self.options.normalize(key);
+        Counters::incr(&self.counters.sets);
+        self.write(|writer| writer.compare_and_swap(key, expected, new))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let key = self.options.normalize(key);
+        Counters::incr(&self.counters.removes);
+        self.write(|writer| writer.remove(key))
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        let from = self.options.normalize(from);
+        let to = self.options.normalize(to);
+        self.write(|writer| writer.rename(from, to))
+    }
+
+    /// The prefix is normalized as a key. Other writes wait until all the keys are removed.
+    fn remove_prefix(&self, prefix: String) -> Result<usize> {
+        let prefix = self.options.normalize(prefix);
+        self.write(|writer| {
+            let keys = self.index.keys_with_prefix(&prefix);
+            let mut removed = 0;
+            for key in keys {
+                writer.remove(key)?;
+                Counters::incr(&self.counters.removes);
+                removed += 1;
+            }
+            Ok(removed)
+        })
+    }
+
+    /// Other writes wait until the old logs are deleted.
+    fn clear(&self) -> Result<()> {
+        self.write(|writer| writer.clear())
+    }
 
-impl KvStoreWriter {
-    /// Set the value of a string key to a string.
-    /// Return an error if the value is not written successfully.
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let start_pos = self.writer.pos;
-        let cmd = Command::set(key, value);
-        serde_json::to_writer(self.writer.by_ref(), &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd_info) = self.index.get(&key) {
-                self.unmerged += old_cmd_info.value().length;
+    fn for_each<F>(&self, mut f: F) -> Result<()>
+        where F: FnMut(String, String) -> Result<()>
+    {
+        for (key, _) in self.index.iter() {
+            Counters::incr(&self.counters.gets);
+            // skip keys removed since the iteration started
+            if let Some((value, _)) = self.read_key(key.clone())? {
+                f(key, value)?;
             }
-            let info = CommandInfo::new(self.write_generation, start_pos, self.writer.pos);
-            self.index.insert(key, info);
-        }
-        if self.unmerged > MERGED_THRESHOLD {
-            self.merge()?;
         }
         Ok(())
     }
 
-
-    /// Remove a given key.
-    /// Return an error if the key does not exist or is not removed successfully.
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            serde_json::to_writer(self.writer.by_ref(), &cmd)?;
-            self.writer.flush()?;
-            if let Command::Remove { key } = cmd {
-                let old_cmd_info = self.index.remove(&key)
-                    .expect("Key not found");
-                self.unmerged += old_cmd_info.value().length;
+    fn for_each_prefix<F>(&self, prefix: &str, mut f: F) -> Result<()>
+        where F: FnMut(String, String) -> Result<()>
+    {
+        for key in self.index.iter_prefix(prefix) {
+            Counters::incr(&self.counters.gets);
+            // skip keys removed since the iteration started
+            if let Some((value, _)) = self.read_key(key.clone())? {
+                f(key, value)?;
             }
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
         }
+        Ok(())
     }
 
-    /// merge log files to a merged file and delete invalid command
-    pub fn merge(&mut self) -> Result<()> {
-        debug!("merging");
-        // copy valid command to a new log file
-        self.write_generation += 1;
-        let merged_generation = self.write_generation;
-        self.write_generation += 1;
-        self.writer = self.create_log_file(self.write_generation)?;
-
-        let mut new_writer = self.create_log_file(merged_generation)?;
-
-        // copy old generation file data to merged_generation file.
-        let mut start_pos = 0;
-        for entry in self.index.iter() {
-            let length = self.reader.read_and(entry.value().clone(), |mut cmd_reader| {
-                Ok(io::copy(&mut cmd_reader, &mut new_writer)?)
-            })?;
-            let cmd_info = CommandInfo::new(merged_generation, start_pos, start_pos + length);
-            self.index.insert(entry.key().clone(), cmd_info);
-            start_pos += length;
+    fn disk_usage(&self) -> Result<DiskUsage> {
+        // hold the writer so no merge changes the files meanwhile
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush()?;
+        let fs = &*self.options.file_system;
+        let mut usage = DiskUsage::default();
+        for (_, path) in log_files(fs, &self.path)? {
+            usage.total_bytes += fs.file_len(&path)?;
+            usage.generations += 1;
         }
-        new_writer.flush()?;
-        self.reader.merged_gen.store(merged_generation, Ordering::SeqCst);
-        self.reader.close_stale_reader();
-
-        // delete log file which have merged
-        let stale_generations = read_generation(&self.path)?
-            .into_iter()
-            .filter(|&generation| generation < merged_generation);
-        for generation in stale_generations {
-            let full_path_name = log_file_name(&self.path, generation);
-            if let Err(e) = fs::remove_file(&full_path_name) {
-                error!("Stale files delete failed: {:?}, {}", full_path_name, e);
-            }
+        for (_, path) in value_log_files(fs, &self.path)? {
+            usage.total_bytes += fs.file_len(&path)?;
         }
-        self.unmerged = 0;
-        Ok(())
+        usage.live_bytes = self.index.iter().map(|(_, cmd_info)| cmd_info.live_len()).sum();
+        Ok(usage)
     }
 
-    fn create_log_file(&mut self, generation: u64) -> Result<KvsBufWriter<File>> {
-        create_log_file(generation, &self.path)
+    fn stats(&self) -> Result<Stats> {
+        Ok(self.counters.snapshot(self.index.len() as u64))
+    }
+
+    fn name(&self) -> &'static str {
+        "kvs"
     }
 }
 
-impl KvStore {
-    /// Open the KvStore at a given path.
-    /// Return the KvStore.
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = path.into();
-        std::fs::create_dir_all(&path)?;
-        let mut index: SkipMap<String, CommandInfo> = SkipMap::new();
-        let generation_list = read_generation(&path)?;
+/// The commits of the writes of a store grouped across its writers, see
+/// [`KvStoreOptions::group_commit`].
+///
+/// A writer appends its record under the writer lock, then waits here without
+/// it until the writes are committed up to its own.
+struct GroupCommit {
+    window: Duration,
+    state: Mutex<CommitState>,
+    committed: Condvar,
+}
 
-        // init reader
-        let mut unmerged = 0;
-        let mut readers = BTreeMap::new();
-        for &generation in &generation_list {
-            let path = log_file_name(&path, generation);
-            let mut reader = KvsBufReader::new(File::open(&path)?)?;
-            unmerged += load_log(generation, &mut reader, &mut index)?;
-            readers.insert(generation, KvsBufReader::new(File::open(&path)?)?);
-        }
+struct CommitState {
+    // number of writes committed
+    committed: u64,
+    // whether a writer is committing the writes of the others
+    leading: bool,
+}
 
-        // open a new log file as the active file for writing logs
-        let write_generation = generation_list.iter().max().unwrap_or(&INIT_GENERATION) + 1;
-        // init writer
-        let writer = create_log_file(write_generation, &path)?;
+impl GroupCommit {
+    fn new(window: Duration) -> GroupCommit {
+        GroupCommit {
+            window,
+            state: Mutex::new(CommitState { committed: 0, leading: false }),
+            committed: Condvar::new(),
+        }
+    }
 
-        let path = Arc::new(path);
-        let reader = KvStoreReader {
-            path: path.clone(),
-            readers: RefCell::new(readers),
-            // merge method will set the really newest merged generation for it
-            merged_gen: Arc::new(AtomicU64::new(INIT_GENERATION)),
-        };
-        let index = Arc::new(index);
-        let writer = Arc::new(Mutex::new(KvStoreWriter {
-            path: path.clone(),
-            write_generation,
-            writer,
-            unmerged,
-            reader: reader.clone(),
-            index: index.clone(),
-        }));
+    /// Whether some of the first `appended` writes are not committed yet.
+    fn is_behind(&self, appended: u64) -> bool {
+        self.state.lock().unwrap().committed < appended
+    }
 
-        Ok(KvStore {
-            path,
-            index,
-            writer,
-            reader,
-        })
+    /// Wake up the writers waiting on the first `appended` writes, which are committed.
+    fn committed(&self, appended: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.committed = state.committed.max(appended);
+        self.committed.notify_all();
     }
-}
 
-impl KvsEngine for KvStore {
-    /// Get the string value of a string key.
-    /// If the key does not exist, return None.
-    /// Return an error if the value is not read successfully.
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(entry) = self.index.get(&key) {
-            if let Command::Set { value, .. } = self.reader.read_command(entry.value().clone())? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnknownCommand)
+    /// Wait until the first `appended` writes are committed, committing the
+    /// writes of the others as well if no writer is.
+    ///
+    /// A failed commit is returned to the writer which ran it, a waiting writer
+    /// then tries again.
+    fn wait(&self, appended: u64, writer: &Mutex<KvStoreWriter>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while state.committed < appended {
+            if state.leading {
+                state = self.committed.wait(state).unwrap();
+                continue;
             }
-        } else {
-            Ok(None)
+            state.leading = true;
+            drop(state);
+            // more writers append their records meanwhile
+            if !self.window.is_zero() {
+                thread::sleep(self.window);
+            }
+            let result = writer.lock().unwrap().flush();
+            state = self.state.lock().unwrap();
+            state.leading = false;
+            self.committed.notify_all();
+            result?;
         }
+        Ok(())
     }
+}
 
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+/// The thread running the compactions of a store in the background.
+///
+/// It is stopped and joined when the last clone of the store drops, before the
+/// writer, so the directory is unlocked once the store is dropped.
+struct Compactor {
+    wake: SyncSender<()>,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Compactor {
+    /// Start the thread, it runs the compactions of `writer` once woken up.
+    /// Each pass waits for a permit of `limiter` before it locks the writer.
+    fn spawn(writer: Weak<Mutex<KvStoreWriter>>, limiter: CompactionLimiter) -> Result<Compactor> {
+        let (wake, receiver) = mpsc::sync_channel(1);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::Builder::new().name("kvs-compactor".to_owned()).spawn(move || {
+            while receiver.recv().is_ok() {
+                // the passes left, letting the writes in between them
+                while !stopped.load(Ordering::SeqCst) {
+                    let writer = match writer.upgrade() {
+                        Some(writer) => writer,
+                        None => return,
+                    };
+                    let permit = limiter.acquire();
+                    let mut writer = writer.lock().unwrap();
+                    if writer.merging.is_none() && !writer.needs_compaction() {
+                        break;
+                    }
+                    if let Err(e) = writer.merge(&permit) {
+                        error!("Background compaction failed: {}", e);
+                        break;
+                    }
+                }
+                if stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+            }
+        })?;
+        Ok(Compactor { wake, stop, thread: Some(thread) })
     }
+}
 
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // a full channel already holds a wake up
+        let _ = self.wake.try_send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
     }
 }
 
 fn create_log_file(
+    fs: &dyn FileSystem,
     active_generation: u64,
     path: &Path,
-) -> Result<KvsBufWriter<File>> {
-    let file_name = log_file_name(path, active_generation);
-    let writer = KvsBufWriter::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&file_name)?
-    )?;
+    buffer_size: usize,
+) -> Result<LogWriter> {
+    open_log_writer(fs, &log_file_name(path, active_generation), buffer_size)
+}
+
+fn open_log_writer(fs: &dyn FileSystem, file_name: &Path, buffer_size: usize) -> Result<LogWriter> {
+    let writer = KvsBufWriter::new(fs.open_append(file_name)?, buffer_size)?;
     Ok(writer)
 }
 
@@ -297,51 +2701,335 @@ fn log_file_name(dir: &Path, generation: u64) -> PathBuf {
     dir.join(format!("{}.log", generation))
 }
 
-fn read_generation(path: &PathBuf) -> Result<Vec<u64>> {
-    let generation_list = fs::read_dir(path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+fn compressed_log_name(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("{}.zlog", generation))
+}
+
+/// The name of the log file a merge writes, compressed with the `compression` feature.
+fn merged_log_name(dir: &Path, generation: u64) -> PathBuf {
+    if cfg!(feature = "compression") {
+        compressed_log_name(dir, generation)
+    } else {
+        log_file_name(dir, generation)
+    }
+}
+
+/// Open the log file of a generation, a compressed one reads as the plain log.
+fn open_log(fs: &dyn FileSystem, dir: &Path, generation: u64) -> io::Result<Box<dyn ReadFile>> {
+    match fs.open_read(&compressed_log_name(dir, generation)) {
+        Ok(file) => return open_compressed(file),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        Err(_) => {}
+    }
+    fs.open_read(&log_file_name(dir, generation))
+}
+
+#[cfg(feature = "compression")]
+fn open_compressed(file: Box<dyn ReadFile>) -> io::Result<Box<dyn ReadFile>> {
+    Ok(Box::new(ZlogReader::new(file)?))
+}
+
+#[cfg(not(feature = "compression"))]
+fn open_compressed(_file: Box<dyn ReadFile>) -> io::Result<Box<dyn ReadFile>> {
+    Err(io::Error::other("compressed log, kvs is built without the compression feature"))
+}
+
+fn tmp_file_name(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("{}.log.tmp", generation))
+}
+
+fn value_log_name(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("{}.vlog", generation))
+}
+
+fn tmp_value_log_name(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("{}.vlog.tmp", generation))
+}
+
+/// Read the generations of the non-empty log files in the directory, the
+/// logs before the last clear left out.
+fn read_generation(fs: &dyn FileSystem, path: &Path) -> Result<Vec<u64>> {
+    let cleared = cleared_generation(fs, path)?;
+    let mut generation_list: Vec<u64> = log_files(fs, path)?
+        .into_iter()
+        .filter(|&(generation, _)| generation >= cleared)
+        .filter(|(_, path)| !is_empty_file(fs, path))
+        .map(|(generation, _)| generation)
+        .collect();
+    // the logs are replayed in order, the directory lists them in any order
+    generation_list.sort_unstable();
+    Ok(generation_list)
+}
+
+/// The generation of the first log after the last clear of the directory,
+/// zero if it was never cleared.
+fn cleared_generation(fs: &dyn FileSystem, path: &Path) -> Result<u64> {
+    let mut file = match fs.open_read(&path.join(CLEAR_FILE_NAME)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(INIT_GENERATION),
+        Err(e) => return Err(e.into()),
+    };
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    content.trim().parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("invalid clear marker {:?}", content)).into()
+    })
+}
+
+/// Mark the logs before `generation` as cleared, through a temporary file
+/// renamed over the marker. The marker and the rename are fsynced if `sync`.
+fn write_clear_marker(fs: &dyn FileSystem, path: &Path, generation: u64, sync: bool) -> Result<()> {
+    let tmp_path = path.join(CLEAR_TMP_FILE_NAME);
+    // the file is opened for appending, a marker left by a failed clear goes first
+    let _ = fs.remove_file(&tmp_path);
+    let mut file = fs.open_append(&tmp_path)?;
+    file.write_all(generation.to_string().as_bytes())?;
+    file.flush()?;
+    if sync {
+        file.sync_all()?;
+    }
+    drop(file);
+    fs.rename(&tmp_path, &path.join(CLEAR_FILE_NAME))?;
+    if sync {
+        fs.sync_dir(path)?;
+    }
+    Ok(())
+}
+
+/// Delete the empty log files in the directory, they carry no data.
+/// Return the number of deleted files.
+fn remove_empty_generations(fs: &dyn FileSystem, path: &Path) -> Result<u64> {
+    let mut removed = 0;
+    for (generation, path) in log_files(fs, path)? {
+        if is_empty_file(fs, &path) {
+            debug!("remove empty generation {}", generation);
+            fs.remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Take the lock of the directory, so only one process can open it.
+fn lock_dir(fs: &dyn FileSystem, path: &Path) -> Result<FileLock> {
+    fs.try_lock(&path.join(LOCK_FILE_NAME))?.ok_or(KvsError::Locked)
+}
+
+/// Delete the temporary files left by merges that did not finish.
+fn remove_tmp_files(fs: &dyn FileSystem, path: &Path) -> Result<u64> {
+    let mut removed = 0;
+    for path in fs.read_dir(path)? {
+        let name = path.to_string_lossy();
+        if name.ends_with(".log.tmp") || name.ends_with(".vlog.tmp") {
+            warn!("remove temporary file {:?} of an unfinished merge", path);
+            fs.remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// The log files in the directory, plain or compressed.
+fn log_files(fs: &dyn FileSystem, path: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut files = generation_files(fs, path, "log")?;
+    files.extend(generation_files(fs, path, "zlog")?);
+    Ok(files)
+}
+
+fn value_log_files(fs: &dyn FileSystem, path: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    generation_files(fs, path, "vlog")
+}
+
+/// The files named by their generation with the extension in the directory.
+fn generation_files(fs: &dyn FileSystem, path: &Path, extension: &str) -> Result<Vec<(u64, PathBuf)>> {
+    let files = fs.read_dir(path)?
+        .into_iter()
+        .filter(|path| path.extension() == Some(extension.as_ref()))
         .flat_map(|path| {
-            path.file_name()
+            path.file_stem()
                 .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
-                .map(str::parse::<u64>)
+                .and_then(|s| s.parse::<u64>().ok())
+                .map(|generation| (generation, path))
         })
-        .flatten()
         .collect();
-    Ok(generation_list)
+    Ok(files)
+}
+
+fn is_empty_file(fs: &dyn FileSystem, path: &Path) -> bool {
+    fs.file_len(path).map(|len| len == 0).unwrap_or(false)
 }
 
+/// Load the records of a log file from `start` into the index.
+/// Return the bytes of stale records, the length of the complete records and
+/// whether the file ends within a record.
+///
+/// Only the last record may be partial, the one a crash cut short. An
+/// unreadable record before the end fails the load.
 fn load_log(
     generation: u64,
-    reader: &mut KvsBufReader<File>,
-    index: &mut SkipMap<String, CommandInfo>,
-) -> Result<u64> {
-    let mut start_pos = reader.seek(SeekFrom::Start(0))?;
-    let reader = reader.reader.get_mut();
-    let mut stream = Deserializer::from_reader(reader)
-        .into_iter::<Command>();
+    start: u64,
+    reader: &mut LogReader,
+    index: &Index,
+) -> Result<(u64, u64, bool)> {
+    let mut start_pos = reader.seek(SeekFrom::Start(start))?;
+    let reader = &mut reader.reader;
 
     let mut unmerged = 0;
-    while let Some(cmd) = stream.next() {
-        let current_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                let info = CommandInfo::new(generation, start_pos, current_pos);
-                if let Some(entry) = index.get(&key) {
-                    unmerged += entry.value().length;
+    loop {
+        let (cmd, current_pos) = match record::read::<Command, _>(reader, generation, start_pos)? {
+            Entry::Record(cmd, length) => (cmd, start_pos + length),
+            Entry::Truncated => return Ok((unmerged, start_pos, true)),
+            Entry::End => return Ok((unmerged, start_pos, false)),
+        };
+        match cmd {
+            Command::Set { key, value_pointer, .. } => {
+                let info = CommandInfo::new(generation, start_pos, current_pos)?.with_value(value_pointer);
+                if let Some(old_cmd_info) = index.insert(key, info) {
+                    unmerged += old_cmd_info.length;
                 }
-                index.insert(key, info);
             }
             Command::Remove { key } => {
-                if let Some(entry) = index.remove(&key) {
-                    unmerged += entry.value().length;
+                unmerged += current_pos - start_pos;
+                if let Some(old_cmd_info) = index.remove(&key) {
+                    unmerged += old_cmd_info.length;
                 }
             }
         }
         start_pos = current_pos;
     }
-    Ok(unmerged)
+}
+
+/// Whether whole records follow the partial one at `valid_len` of a log, the
+/// length field of which is then corrupt rather than cut by a crash.
+fn records_after(reader: &mut LogReader, valid_len: u64) -> Result<bool> {
+    reader.seek(SeekFrom::Start(valid_len))?;
+    let mut tail = Vec::new();
+    reader.read_to_end(&mut tail)?;
+    Ok(record::contains_record::<Command>(&tail))
+}
+
+/// Scan the readable records of a log file in order from `start`, stopping at
+/// the first record that can not be deserialized.
+fn scan_log<F>(fs: &dyn FileSystem, path: &Path, generation: u64, start: u64, mut f: F) -> Result<()>
+    where F: FnMut(Command, CommandInfo)
+{
+    let mut file = open_log(fs, path, generation)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut reader = BufReader::new(file);
+    let mut start_pos = start;
+    while let Ok(Entry::Record(cmd, length)) = record::read::<Command, _>(&mut reader, generation, start_pos) {
+        f(cmd, CommandInfo::new(generation, start_pos, start_pos + length)?);
+        start_pos += length;
+    }
+    Ok(())
+}
+
+/// A map of key to command info.
+///
+/// `SkipMap::insert` unlinks an existing entry before linking the new one, so a
+/// concurrent lookup could miss a key while it is overwritten. Entries are
+/// updated in place instead, all changes are made under the writer lock.
+#[derive(Default)]
+struct Index {
+    map: SkipMap<String, Mutex<CommandInfo>>,
+}
+
+impl Index {
+    fn get(&self, key: &str) -> Option<CommandInfo> {
+        self.map.get(key).map(|entry| *entry.value().lock().unwrap())
+    }
+
+    /// The keys starting with `prefix`, in order.
+    fn keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.iter_prefix(prefix).collect()
+    }
+
+    /// The keys starting with `prefix` in order, read as the iterator goes.
+    fn iter_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = String> + 'a {
+        self.map
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .map(|entry| entry.key().clone())
+            .take_while(move |key| key.starts_with(prefix))
+    }
+
+    /// The keys from `start` to `end`, excluded, in order.
+    fn keys_in_range(&self, start: &str, end: &str) -> Vec<String> {
+        if start >= end {
+            return Vec::new();
+        }
+        self.map
+            .range::<str, _>((Bound::Included(start), Bound::Excluded(end)))
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    /// The command info of key, or none if its entry is locked.
+    fn try_get(&self, key: &str) -> Option<Option<CommandInfo>> {
+        match self.map.get(key) {
+            Some(entry) => entry.value().try_lock().ok().map(|cmd_info| Some(*cmd_info)),
+            None => Some(None),
+        }
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        self.map.contains_key(key)
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Point key at the command, return the command it pointed at before.
+    fn insert(&self, key: String, cmd_info: CommandInfo) -> Option<CommandInfo> {
+        match self.map.get(&key) {
+            Some(entry) => {
+                let mut current = entry.value().lock().unwrap();
+                Some(std::mem::replace(&mut *current, cmd_info))
+            }
+            None => {
+                self.map.insert(key, Mutex::new(cmd_info));
+                None
+            }
+        }
+    }
+
+    fn clear(&self) {
+        self.map.clear();
+    }
+
+    fn remove(&self, key: &str) -> Option<CommandInfo> {
+        self.map.remove(key).map(|entry| *entry.value().lock().unwrap())
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (String, CommandInfo)> + '_ {
+        self.map.iter().map(|entry| (entry.key().clone(), *entry.value().lock().unwrap()))
+    }
+
+    /// The entries of the keys after `after` in order, all of them if none.
+    fn iter_after<'a>(&'a self, after: Option<&'a str>) -> impl Iterator<Item = (String, CommandInfo)> + 'a {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        self.map
+            .range::<str, _>((start, Bound::Unbounded))
+            .map(|entry| (entry.key().clone(), *entry.value().lock().unwrap()))
+    }
+}
+
+type ValueCache = LruCache<CachedValue>;
+
+/// A value in the cache of a store, valid while the index points at `record`.
+struct CachedValue {
+    record: CommandInfo,
+    value: String,
+    value_type: ValueType,
+}
+
+/// How far the passes of a merge went.
+#[derive(Clone, Debug)]
+struct MergeProgress {
+    // the logs before this generation are merged
+    sources_end: u64,
+    // the last key copied, the next pass goes on after it
+    last_key: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -349,37 +3037,170 @@ struct CommandInfo {
     generation: u64,
     pos_start: u64,
     length: u64,
+    // the separated value of a set record
+    value: Option<ValuePointer>,
 }
 
 impl CommandInfo {
-    fn new(generation: u64, pos_start: u64, pos_stop: u64) -> CommandInfo {
-        let length = pos_stop - pos_start;
-        CommandInfo {
+    /// Return `KvsError::InvalidRecord` if the record would end before it starts.
+    fn new(generation: u64, pos_start: u64, pos_stop: u64) -> Result<CommandInfo> {
+        let length = pos_stop.checked_sub(pos_start).ok_or(KvsError::InvalidRecord {
+            generation,
+            start: pos_start,
+            stop: pos_stop,
+        })?;
+        Ok(CommandInfo {
             generation,
             pos_start,
             length,
-        }
+            value: None,
+        })
+    }
+
+    /// The command info of a set record whose value is separated at `value`.
+    fn with_value(mut self, value: Option<ValuePointer>) -> CommandInfo {
+        self.value = value;
+        self
+    }
+
+    /// The bytes of the record and its separated value.
+    fn live_len(&self) -> u64 {
+        self.length + self.value.map_or(0, |value| value.length)
+    }
+
+    fn same_record(&self, other: &CommandInfo) -> bool {
+        self.generation == other.generation && self.pos_start == other.pos_start
     }
 }
 
 
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
+    Set {
+        key: String,
+        value: String,
+        // left out of JSON for strings, so their records are as before types were
+        // added. bincode can not leave out fields, the records of both are read.
+        #[serde(default)]
+        #[cfg_attr(not(feature = "bincode"), serde(skip_serializing_if = "ValueType::is_string"))]
+        value_type: ValueType,
+        // the value is in a value log, `value` is then empty
+        #[serde(default)]
+        #[cfg_attr(not(feature = "bincode"), serde(skip_serializing_if = "Option::is_none"))]
+        value_pointer: Option<ValuePointer>,
+    },
     Remove { key: String },
 }
 
-impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+/// Where a separated value is, in the value log of `generation`.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+struct ValuePointer {
+    generation: u64,
+    offset: u64,
+    length: u64,
+}
+
+/// A set command serialized from borrowed strings, its record is the one of `Command::Set`.
+#[derive(Serialize)]
+enum SetRecord<'a> {
+    Set {
+        key: &'a str,
+        value: &'a str,
+        #[cfg_attr(not(feature = "bincode"), serde(skip_serializing_if = "ValueType::is_string"))]
+        value_type: ValueType,
+        #[cfg_attr(not(feature = "bincode"), serde(skip_serializing_if = "Option::is_none"))]
+        value_pointer: Option<ValuePointer>,
+    },
+}
+
+/// Serialize the record of a set command, which needs no lock.
+fn encode_set(key: &str, value: &str, value_type: ValueType) -> Result<Vec<u8>> {
+    record::encode(&SetRecord::Set { key, value, value_type, value_pointer: None })
+}
+
+/// Serialize the record of a set command whose value is separated at `pointer`.
+fn encode_separated(key: &str, value_type: ValueType, pointer: ValuePointer) -> Result<Vec<u8>> {
+    let value_pointer = Some(pointer);
+    record::encode(&SetRecord::Set { key, value: "", value_type, value_pointer })
+}
+
+/// What `copy_live` does with the separated values.
+enum ValueCopy {
+    /// write the values into the records, the copy needs no value log
+    Inline,
+    /// keep the pointers, except to the value logs in `rewrite` whose values are
+    /// moved to `writer`, the value log of `generation`
+    Keep {
+        rewrite: BTreeSet<u64>,
+        generation: u64,
+        writer: Option<LogWriter>,
+    },
+}
+
+impl ValueCopy {
+    /// Whether the value at `pointer` is written again.
+    fn moves(&self, pointer: ValuePointer) -> bool {
+        match self {
+            ValueCopy::Inline => true,
+            ValueCopy::Keep { rewrite, .. } => rewrite.contains(&pointer.generation),
+        }
+    }
+
+    /// Write a moved value, return the set record of key and where the value went.
+    fn write(&mut self, key: &str, value: &str, value_type: ValueType) -> Result<(Vec<u8>, Option<ValuePointer>)> {
+        match self {
+            ValueCopy::Inline => Ok((encode_set(key, value, value_type)?, None)),
+            ValueCopy::Keep { generation, writer, .. } => {
+                let writer = writer.as_mut().expect("no value log to move values to");
+                let pointer = ValuePointer {
+                    generation: *generation,
+                    offset: writer.pos,
+                    length: value.len() as u64,
+                };
+                writer.write_all(value.as_bytes())?;
+                Ok((encode_separated(key, value_type, pointer)?, Some(pointer)))
+            }
+        }
     }
+}
+
+/// A set of a batch, serialized before taking the writer lock unless its
+/// value goes to a value log.
+enum BatchSet {
+    Record(Vec<u8>),
+    Separated(String),
+}
+
+/// A command borrowing its value from the serialized record when possible,
+/// the key is skipped.
+#[derive(Deserialize)]
+enum CommandRef<'a> {
+    Set {
+        #[serde(borrow)]
+        value: Cow<'a, str>,
+        #[serde(default)]
+        value_pointer: Option<ValuePointer>,
+    },
+    Remove {},
+}
 
+impl Command {
     fn remove(key: String) -> Command {
         Command::Remove { key }
     }
+
+    fn key(&self) -> &str {
+        match self {
+            Command::Set { key, .. } => key,
+            Command::Remove { key } => key,
+        }
+    }
 }
 
 
+type LogReader = KvsBufReader<Box<dyn ReadFile>>;
+type LogWriter = KvsBufWriter<Box<dyn WriteFile>>;
+
 struct KvsBufReader<R: Read + Seek> {
     reader: BufReader<R>,
     pos: u64,
@@ -391,10 +3212,10 @@ struct KvsBufWriter<W: Write + Seek> {
 }
 
 impl<R: Read + Seek> KvsBufReader<R> {
-    fn new(mut inner: R) -> Result<Self> {
+    fn new(mut inner: R, capacity: usize) -> Result<Self> {
         let pos = inner.seek(SeekFrom::Current(0))?;
         Ok(KvsBufReader {
-            reader: BufReader::new(inner),
+            reader: BufReader::with_capacity(capacity, inner),
             pos,
         })
     }
@@ -416,15 +3237,23 @@ impl<R: Read + Seek> Seek for KvsBufReader<R> {
 }
 
 impl<W: Write + Seek> KvsBufWriter<W> {
-    fn new(mut inner: W) -> Result<Self> {
+    fn new(mut inner: W, capacity: usize) -> Result<Self> {
         let pos = inner.seek(SeekFrom::Current(0))?;
         Ok(KvsBufWriter {
-            writer: BufWriter::new(inner),
+            writer: BufWriter::with_capacity(capacity, inner),
             pos,
         })
     }
 }
 
+impl LogWriter {
+    /// Flush the buffer and fsync the file.
+    fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()
+    }
+}
+
 impl<W: Write + Seek> Write for KvsBufWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let length = self.writer.write(buf)?;
@@ -444,4 +3273,128 @@ impl<W: Write + Seek> Seek for KvsBufWriter<W> {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_info_rejects_backward_range() {
+        assert!(matches!(
+            CommandInfo::new(1, 10, 4),
+            Err(KvsError::InvalidRecord { generation: 1, start: 10, stop: 4 })
+        ));
+        assert_eq!(CommandInfo::new(1, 4, 4).unwrap().length, 0);
+        assert_eq!(CommandInfo::new(1, 4, 10).unwrap().length, 6);
+    }
+
+    #[test]
+    fn auto_compaction_skipped_without_permit() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let limiter = CompactionLimiter::new(1);
+        let options = KvStoreOptions::new().compaction_threshold(1000).compaction_limiter(limiter.clone());
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone()).unwrap();
+        let background_dir = tempfile::TempDir::new().unwrap();
+        let background = KvStore::open_with_options(background_dir.path(), options.background_compaction(true)).unwrap();
+
+        // the writes go on while another store holds the only permit
+        let permit = limiter.acquire();
+        for i in 0..1000 {
+            store.set("key1".to_owned(), format!("value{}", i)).unwrap();
+            background.set("key1".to_owned(), format!("value{}", i)).unwrap();
+        }
+        assert_eq!(store.stats().unwrap().compactions, 0);
+        assert_eq!(background.stats().unwrap().compactions, 0);
+
+        drop(permit);
+        // by the background thread or this call, whichever takes the permit first
+        background.compact().unwrap();
+        assert!(background.stats().unwrap().compactions > 0);
+        // the background thread is joined, the next write takes the free permit
+        drop(background);
+        store.set("key1".to_owned(), "value".to_owned()).unwrap();
+        assert_eq!(store.stats().unwrap().compactions, 1);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn active_log_mapped_once() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key0".to_owned(), "value0".to_owned()).unwrap();
+        assert_eq!(store.get("key0".to_owned()).unwrap(), Some("value0".to_owned()));
+        let mapped_len = |store: &KvStore| {
+            let maps = store.reader.maps.borrow();
+            maps.values().next().unwrap().as_ref().unwrap().len()
+        };
+        let first_len = mapped_len(&store);
+
+        for i in 1..100 {
+            store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+            assert_eq!(store.get(format!("key{}", i)).unwrap(), Some(format!("value{}", i)));
+        }
+        // the records past the map were read through the reader
+        assert_eq!(mapped_len(&store), first_len);
+        assert_eq!(store.get("key0".to_owned()).unwrap(), Some("value0".to_owned()));
+    }
+
+    #[test]
+    fn try_get_would_block() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        let entry = store.index.map.get("key1").unwrap();
+        let guard = entry.value().lock().unwrap();
+
+        let other = store.clone();
+        let result = std::thread::spawn(move || other.try_get("key1".to_owned()).unwrap()).join().unwrap();
+        assert_eq!(result, None);
+        // other keys are not locked
+        assert_eq!(store.try_get("key2".to_owned()).unwrap(), Some(None));
+        drop(guard);
+        assert_eq!(store.try_get("key1".to_owned()).unwrap(), Some(Some("value1".to_owned())));
+    }
+
+    #[test]
+    fn export_every_key_once() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let options = KvStoreOptions::new().value_separation(16);
+        let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        for i in 0..50 {
+            store.set(format!("key{}", i * 2), "v".repeat(i)).unwrap();
+        }
+        for i in 90..100 {
+            store.remove(format!("key{}", i)).unwrap();
+        }
+        let mut dump = Vec::new();
+        store.export(&mut dump).unwrap();
+
+        let mut reader = io::Cursor::new(&dump);
+        let mut offset = 0;
+        let mut exported = Vec::new();
+        while let Entry::Record(cmd, length) = record::read::<Command, _>(&mut reader, 0, offset).unwrap() {
+            match cmd {
+                Command::Set { key, value, value_pointer: None, .. } => exported.push((key, value)),
+                cmd => panic!("unexpected {:?}", cmd),
+            }
+            offset += length;
+        }
+        assert_eq!(offset, dump.len() as u64);
+        let mut expected: Vec<_> = (0..90)
+            .map(|i| (format!("key{}", i), store.get(format!("key{}", i)).unwrap().unwrap()))
+            .collect();
+        expected.sort();
+        assert_eq!(exported, expected);
+    }
 
+    #[test]
+    fn set_record_matches_command() {
+        for &value_type in &[ValueType::String, ValueType::Int] {
+            let command = Command::Set { key: "key".to_owned(), value: "1".to_owned(), value_type, value_pointer: None };
+            let record = encode_set("key", "1", value_type).unwrap();
+            assert_eq!(record, record::encode(&command).unwrap());
+        }
+    }
+}
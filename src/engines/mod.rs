@@ -1,19 +1,424 @@
-use crate::Result;
+use crate::{KvsError, Result, TypedValue};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 /// Trait for a key value storage engine
 pub trait KvsEngine: Clone + Send + 'static {
     /// Get the value of key
     fn get(&self, key: String) -> Result<Option<String>>;
 
+    /// Get the values of many keys, in the order of `keys`, none for a missing
+    /// key. The default gets them one by one.
+    fn get_batch(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
     /// Set the value of key
     fn set(&self, key: String, value: String) -> Result<()>;
 
+    /// Set the values of many keys. The default sets them one by one, engines
+    /// which can write them at once override it.
+    ///
+    /// It is not atomic across keys, a failure or crash can leave some of them set.
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
     /// Remove the value-key pair.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Whether key exists. The default reads its value, engines which can
+    /// tell without it override it.
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Set the value of key tagged with its type. The default sets strings
+    /// alone, returning `KvsError::UnsupportedCommand` for the other types.
+    fn set_typed(&self, key: String, value: TypedValue) -> Result<()> {
+        match value {
+            TypedValue::String(value) => self.set(key, value),
+            _ => Err(KvsError::UnsupportedCommand("SetTyped".to_owned())),
+        }
+    }
+
+    /// Get the value of key with the type it was set with. The default reads
+    /// every value as a string.
+    fn get_typed(&self, key: String) -> Result<Option<TypedValue>> {
+        Ok(self.get(key)?.map(TypedValue::String))
+    }
+
+    /// Set bytes, not necessarily UTF-8, as the value of key tagged `Bytes`.
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.set_typed(key, TypedValue::Bytes(value))
+    }
+
+    /// Get the bytes of key, or `KvsError::TypeMismatch` if the value is of another type.
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        self.get_typed(key)?.map(TypedValue::into_bytes).transpose()
+    }
+
+    /// Add `delta` to the integer of key atomically, a missing key starts from 0.
+    /// A string value is parsed as an integer, the sum is stored as an `Int`.
+    /// Return the new value, `KvsError::NotAnInteger` if the string is not an
+    /// integer, or `KvsError::TypeMismatch` if the value is of another type.
+    ///
+    /// The default reads the value and swaps in the sum with `compare_and_swap`
+    /// until no other write came in between, it stores the sum as a string.
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        loop {
+            let current = self.get(key.clone())?;
+            let value = match &current {
+                Some(text) => TypedValue::String(text.clone()).parse_int()?,
+                None => 0,
+            };
+            let value = value.checked_add(delta).ok_or_else(|| {
+                KvsError::StringError(format!("Integer overflow adding {} to {}", delta, value))
+            })?;
+            if self.compare_and_swap(key.clone(), current, Some(value.to_string()))? {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Get the value of key, or set it to `default` and return it if key is
+    /// missing, atomically: racing callers all get the value stored.
+    ///
+    /// The default creates key with `compare_and_swap`, reading it again if
+    /// another caller was first.
+    fn get_or_insert(&self, key: String, default: String) -> Result<String> {
+        loop {
+            if let Some(value) = self.get(key.clone())? {
+                return Ok(value);
+            }
+            if self.compare_and_swap(key.clone(), None, Some(default.clone()))? {
+                return Ok(default);
+            }
+        }
+    }
+
+    /// Append `suffix` to the text of the value of key atomically, a missing key
+    /// starts from an empty string. The value is stored as a string, return
+    /// its new length in bytes.
+    ///
+    /// The default swaps in the longer text with `compare_and_swap` until no
+    /// other write came in between.
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        loop {
+            let current = self.get(key.clone())?;
+            let value = current.clone().unwrap_or_default() + &suffix;
+            let len = value.len();
+            if self.compare_and_swap(key.clone(), current, Some(value))? {
+                return Ok(len);
+            }
+        }
+    }
+
+    /// Set the value of key to a string and return the previous value as text,
+    /// or `None` if key was missing, atomically.
+    ///
+    /// The default swaps in the value with `compare_and_swap` until no other
+    /// write came in between.
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        loop {
+            let current = self.get(key.clone())?;
+            if self.compare_and_swap(key.clone(), current.clone(), Some(value.clone()))? {
+                return Ok(current);
+            }
+        }
+    }
+
+    /// Set the value of key to `new` if it is `expected`, atomically, `None`
+    /// meaning missing for both: `new` of `None` removes key, `expected` of
+    /// `None` creates it. Return whether the value was swapped.
+    ///
+    /// The default returns `KvsError::UnsupportedCommand`, the other atomic
+    /// methods default to loops over it.
+    fn compare_and_swap(
+        &self,
+        _key: String,
+        _expected: Option<String>,
+        _new: Option<String>,
+    ) -> Result<bool> {
+        Err(KvsError::UnsupportedCommand("Cas".to_owned()))
+    }
+
+    /// Move the value of key `from` to key `to`, overwriting the value of `to`.
+    /// Return `KvsError::KeyNotFound` if `from` does not exist.
+    fn rename(&self, from: String, to: String) -> Result<()>;
+
+    /// Remove every key starting with `prefix`, return the number removed.
+    ///
+    /// The keys are removed one by one: it is not atomic across keys, a failure
+    /// or crash can leave some of them removed. The default lists them with
+    /// `for_each` before removing them.
+    fn remove_prefix(&self, prefix: String) -> Result<usize> {
+        let mut keys = Vec::new();
+        self.for_each(|key, _| {
+            if key.starts_with(&prefix) {
+                keys.push(key);
+            }
+            Ok(())
+        })?;
+        let mut removed = 0;
+        for key in keys {
+            match self.remove(key) {
+                Ok(()) => removed += 1,
+                // a concurrent remove may have been first
+                Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Remove every key. The default lists them with `for_each` before removing
+    /// them one by one, it is not atomic: a failure or crash can leave some of
+    /// them removed.
+    fn clear(&self) -> Result<()> {
+        let mut keys = Vec::new();
+        self.for_each(|key, _| {
+            keys.push(key);
+            Ok(())
+        })?;
+        for key in keys {
+            match self.remove(key) {
+                // a concurrent remove may have been first
+                Ok(()) | Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Call `f` with every key-value pair, stop at the first error it returns.
+    ///
+    /// Pairs are read one at a time, writes made meanwhile may or may not be seen.
+    /// The default returns `KvsError::UnsupportedCommand`, engines which can
+    /// list their keys override it.
+    fn for_each<F>(&self, _f: F) -> Result<()>
+        where F: FnMut(String, String) -> Result<()>
+    {
+        Err(KvsError::UnsupportedCommand("Dump".to_owned()))
+    }
+
+    /// Call `f` with every key-value pair whose key starts with `prefix`, as
+    /// `for_each`. The default filters `for_each`, engines which can seek to
+    /// the prefix override it.
+    fn for_each_prefix<F>(&self, prefix: &str, mut f: F) -> Result<()>
+        where F: FnMut(String, String) -> Result<()>
+    {
+        self.for_each(|key, value| if key.starts_with(prefix) { f(key, value) } else { Ok(()) })
+    }
+
+    /// The approximate space the engine takes on disk. The default returns
+    /// `KvsError::UnsupportedCommand`.
+    fn disk_usage(&self) -> Result<DiskUsage> {
+        Err(KvsError::UnsupportedCommand("DiskUsage".to_owned()))
+    }
+
+    /// Counts of the operations since the engine was opened, shared by its clones.
+    /// The default returns `KvsError::UnsupportedCommand`.
+    fn stats(&self) -> Result<Stats> {
+        Err(KvsError::UnsupportedCommand("Stats".to_owned()))
+    }
+
+    /// The name of the engine, as `"kvs"` or `"sled"`. The default is the name
+    /// of the type.
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+}
+
+/// Counts of the operations of an engine since it was opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    /// number of reads
+    pub gets: u64,
+    /// number of writes of a value
+    pub sets: u64,
+    /// number of removes
+    pub removes: u64,
+    /// number of finished compactions
+    pub compactions: u64,
+    /// number of keys
+    pub live_keys: u64,
+}
+
+/// The counters behind [`Stats`].
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) gets: AtomicU64,
+    pub(crate) sets: AtomicU64,
+    pub(crate) removes: AtomicU64,
+    pub(crate) compactions: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self, live_keys: u64) -> Stats {
+        Stats {
+            gets: self.gets.load(Ordering::Relaxed),
+            sets: self.sets.load(Ordering::Relaxed),
+            removes: self.removes.load(Ordering::Relaxed),
+            compactions: self.compactions.load(Ordering::Relaxed),
+            live_keys,
+        }
+    }
+}
+
+/// The approximate space an engine takes on disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskUsage {
+    /// bytes of all data files
+    pub total_bytes: u64,
+    /// bytes of the live records, the rest is garbage left to compaction.
+    /// Records in compressed logs are counted uncompressed
+    pub live_bytes: u64,
+    /// number of log files, 0 for engines without generations
+    pub generations: u64,
 }
 
 mod sled;
 mod kvs;
+mod fs;
+mod eviction;
+mod cache;
+mod tiered;
+mod compaction;
+mod record;
+#[cfg(feature = "compression")]
+mod zlog;
+
+pub use self::sled::{FlushPolicy, SledKvsEngine};
+pub(crate) use self::kvs::Update;
+pub use self::kvs::{AutoCompaction, Change, Durability, ChangeCursor, Changes, HistoryEntry, KvStore, KvStoreOptions, KvStoreStats, RecoveryInfo};
+pub use self::eviction::EvictionPolicy;
+pub use self::compaction::CompactionLimiter;
+pub use self::tiered::{TieredKvsEngine, WritePolicy};
+pub use self::fs::{FileLock, FileSystem, MemoryFileSystem, OsFileSystem, ReadFile, WriteFile};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    /// An engine implementing the required methods, `compare_and_swap` and
+    /// `for_each` alone, to run the defaults of the others.
+    #[derive(Clone, Default)]
+    struct MapEngine {
+        map: Arc<Mutex<BTreeMap<String, String>>>,
+    }
 
-pub use self::sled::SledKvsEngine;
-pub use self::kvs::KvStore;
+    impl KvsEngine for MapEngine {
+        fn get(&self, key: String) -> Result<Option<String>> {
+            Ok(self.map.lock().unwrap().get(&key).cloned())
+        }
+
+        fn set(&self, key: String, value: String) -> Result<()> {
+            self.map.lock().unwrap().insert(key, value);
+            Ok(())
+        }
+
+        fn remove(&self, key: String) -> Result<()> {
+            self.map.lock().unwrap().remove(&key).map(|_| ()).ok_or(KvsError::KeyNotFound)
+        }
+
+        fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+            let mut map = self.map.lock().unwrap();
+            if map.get(&key) != expected.as_ref() {
+                return Ok(false);
+            }
+            match new {
+                Some(new) => map.insert(key, new),
+                None => map.remove(&key),
+            };
+            Ok(true)
+        }
+
+        fn rename(&self, from: String, to: String) -> Result<()> {
+            let mut map = self.map.lock().unwrap();
+            let value = map.remove(&from).ok_or(KvsError::KeyNotFound)?;
+            map.insert(to, value);
+            Ok(())
+        }
+
+        fn for_each<F>(&self, mut f: F) -> Result<()>
+            where F: FnMut(String, String) -> Result<()>
+        {
+            let pairs = self.map.lock().unwrap().clone();
+            pairs.into_iter().try_for_each(|(key, value)| f(key, value))
+        }
+    }
+
+    #[test]
+    fn defaults() -> Result<()> {
+        let engine = MapEngine::default();
+        assert!(engine.name().ends_with("MapEngine"));
+        assert!(matches!(engine.stats(), Err(KvsError::UnsupportedCommand(_))));
+
+        engine.set_typed("n".to_owned(), TypedValue::String("7".to_owned()))?;
+        assert!(matches!(engine.set_typed("n".to_owned(), TypedValue::Int(7)), Err(KvsError::UnsupportedCommand(_))));
+        assert_eq!(engine.increment("n".to_owned(), 3)?, 10);
+        assert_eq!(engine.get_typed("n".to_owned())?, Some(TypedValue::String("10".to_owned())));
+        assert_eq!(engine.increment("counter".to_owned(), -1)?, -1);
+        engine.set("text".to_owned(), "a".to_owned())?;
+        assert!(matches!(engine.increment("text".to_owned(), 1), Err(KvsError::NotAnInteger)));
+        engine.set("max".to_owned(), i64::MAX.to_string())?;
+        assert!(engine.increment("max".to_owned(), 1).is_err());
+
+        assert_eq!(engine.append("text".to_owned(), "bc".to_owned())?, 3);
+        assert_eq!(engine.append("suffix".to_owned(), "d".to_owned())?, 1);
+        assert_eq!(engine.get_or_insert("text".to_owned(), "x".to_owned())?, "abc");
+        assert_eq!(engine.get_or_insert("new".to_owned(), "x".to_owned())?, "x");
+        assert_eq!(engine.get_set("new".to_owned(), "y".to_owned())?, Some("x".to_owned()));
+        assert_eq!(engine.get_set("other".to_owned(), "z".to_owned())?, None);
+
+        let mut visited = Vec::new();
+        engine.for_each_prefix("n", |key, _| {
+            visited.push(key);
+            Ok(())
+        })?;
+        assert_eq!(visited, vec!["n".to_owned(), "new".to_owned()]);
+        assert_eq!(engine.remove_prefix("n".to_owned())?, 2);
+        assert_eq!(engine.get("n".to_owned())?, None);
+        assert_eq!(engine.get("text".to_owned())?, Some("abc".to_owned()));
+        engine.clear()?;
+        assert!(engine.map.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn defaults_race() -> Result<()> {
+        let engine = MapEngine::default();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let engine = engine.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    for _ in 0..100 {
+                        engine.increment("counter".to_owned(), 1)?;
+                        engine.append("text".to_owned(), "a".to_owned())?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+        assert_eq!(engine.get("counter".to_owned())?, Some("400".to_owned()));
+        assert_eq!(engine.get("text".to_owned())?.map(|text| text.len()), Some(400));
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+use std::convert::{TryFrom, TryInto};
+use std::io::{self, BufRead, Read};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{KvsError, Result};
+
+/// Bytes of the header of a record, its payload length and checksum.
+const HEADER_LEN: usize = 8;
+
+/// The first byte of the records written before they were framed, bare JSON objects.
+const LEGACY_START: u8 = b'{';
+
+/// The bit of the length field set for a payload encoded by bincode.
+const BINCODE_FLAG: u32 = 1 << 30;
+
+/// The longest payload, the bits above it flag the encoding. A length field
+/// never starts with the byte of a legacy record.
+const MAX_PAYLOAD_LEN: u32 = BINCODE_FLAG - 1;
+
+/// The encoding of the payload of a record.
+///
+/// Every record tells its encoding, a store reads the records of both whatever
+/// the `bincode` feature. The feature only selects the encoding of new records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Format {
+    Json,
+    Bincode,
+}
+
+impl Format {
+    /// The encoding of the records written by this build.
+    pub(crate) const WRITTEN: Format = if cfg!(feature = "bincode") { Format::Bincode } else { Format::Json };
+}
+
+/// The encoded payload of a record.
+pub(crate) struct Payload<'a> {
+    pub(crate) format: Format,
+    pub(crate) bytes: &'a [u8],
+}
+
+impl<'a> Payload<'a> {
+    pub(crate) fn decode<T: DeserializeOwned>(&self) -> Result<T> {
+        match self.format {
+            Format::Json => Ok(serde_json::from_slice(self.bytes)?),
+            Format::Bincode => Ok(bincode::deserialize(self.bytes)?),
+        }
+    }
+}
+
+/// What the next bytes of a log hold.
+pub(crate) enum Entry<T> {
+    /// a record and its length in the log
+    Record(T, u64),
+    /// the log ends within a record
+    Truncated,
+    /// the log ends
+    End,
+}
+
+/// Serialize a command into a record of the format of this build.
+pub(crate) fn encode<T: Serialize>(command: &T) -> Result<Vec<u8>> {
+    encode_as(Format::WRITTEN, command)
+}
+
+/// Serialize a command into a record: its payload length, with the bincode
+/// flag, and CRC32, both big-endian `u32`, then the payload.
+pub(crate) fn encode_as<T: Serialize>(format: Format, command: &T) -> Result<Vec<u8>> {
+    let mut record = vec![0; HEADER_LEN];
+    match format {
+        Format::Json => serde_json::to_writer(&mut record, command)?,
+        Format::Bincode => bincode::serialize_into(&mut record, command)?,
+    }
+    let payload_len = record.len() - HEADER_LEN;
+    let length = u32::try_from(payload_len)
+        .ok()
+        .filter(|&length| length <= MAX_PAYLOAD_LEN)
+        .ok_or(KvsError::FrameTooLarge { size: payload_len as u64, limit: MAX_PAYLOAD_LEN as u64 })?;
+    let length = match format {
+        Format::Json => length,
+        Format::Bincode => length | BINCODE_FLAG,
+    };
+    let checksum = crc32fast::hash(&record[HEADER_LEN..]);
+    record[..4].copy_from_slice(&length.to_be_bytes());
+    record[4..HEADER_LEN].copy_from_slice(&checksum.to_be_bytes());
+    Ok(record)
+}
+
+/// The payload of the whole record `record`, read at `offset` of the log of
+/// `generation`. Return `KvsError::Corruption` if its checksum does not match.
+pub(crate) fn payload(record: &[u8], generation: u64, offset: u64) -> Result<Payload<'_>> {
+    if record.first() == Some(&LEGACY_START) {
+        return Ok(Payload { format: Format::Json, bytes: record });
+    }
+    let corruption = || KvsError::Corruption { generation, offset };
+    if record.len() < HEADER_LEN {
+        return Err(corruption());
+    }
+    let (format, length, checksum) = read_header(&record[..HEADER_LEN]).ok_or_else(corruption)?;
+    let bytes = &record[HEADER_LEN..];
+    if bytes.len() as u64 != length as u64 || crc32fast::hash(bytes) != checksum {
+        return Err(corruption());
+    }
+    Ok(Payload { format, bytes })
+}
+
+/// Read the record at `offset` of the log of `generation` from `reader`, and
+/// deserialize its payload. Return `KvsError::Corruption` if its checksum
+/// does not match.
+pub(crate) fn read<T: DeserializeOwned, R: BufRead>(reader: &mut R, generation: u64, offset: u64) -> Result<Entry<T>> {
+    let first = match reader.fill_buf()?.first() {
+        Some(&first) => first,
+        None => return Ok(Entry::End),
+    };
+    if first == LEGACY_START {
+        let mut stream = serde_json::Deserializer::from_reader(reader.by_ref()).into_iter::<T>();
+        return match stream.next() {
+            Some(Ok(command)) => Ok(Entry::Record(command, stream.byte_offset() as u64)),
+            Some(Err(e)) if e.is_eof() => Ok(Entry::Truncated),
+            Some(Err(e)) => Err(e.into()),
+            None => Ok(Entry::End),
+        };
+    }
+    let mut header = [0; HEADER_LEN];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(Entry::Truncated),
+        Err(e) => return Err(e.into()),
+    }
+    let (format, length, checksum) = read_header(&header).ok_or(KvsError::Corruption { generation, offset })?;
+    let mut bytes = Vec::new();
+    reader.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != length as u64 {
+        return Ok(Entry::Truncated);
+    }
+    if crc32fast::hash(&bytes) != checksum {
+        return Err(KvsError::Corruption { generation, offset });
+    }
+    let command = Payload { format, bytes: &bytes }.decode()?;
+    Ok(Entry::Record(command, (HEADER_LEN + bytes.len()) as u64))
+}
+
+/// Whether a whole record, its checksum matching and its payload a `T`,
+/// starts at any offset of `bytes`. The bytes after a record a crash cut
+/// short hold none, the ones after a corrupt length field still do.
+pub(crate) fn contains_record<T: DeserializeOwned>(bytes: &[u8]) -> bool {
+    (0..bytes.len().saturating_sub(HEADER_LEN - 1)).any(|start| {
+        let record = &bytes[start..];
+        let (format, length, checksum) = match read_header(&record[..HEADER_LEN]) {
+            Some(header) => header,
+            None => return false,
+        };
+        // an empty payload would match the checksum of a run of zeros
+        match record.get(HEADER_LEN..HEADER_LEN + length as usize) {
+            Some(bytes) if !bytes.is_empty() && crc32fast::hash(bytes) == checksum => {
+                Payload { format, bytes }.decode::<T>().is_ok()
+            }
+            _ => false,
+        }
+    })
+}
+
+/// The format, payload length and checksum of a header, none if the bits of
+/// its length field flag no format.
+fn read_header(header: &[u8]) -> Option<(Format, u32, u32)> {
+    let length = u32::from_be_bytes(header[..4].try_into().unwrap());
+    let checksum = u32::from_be_bytes(header[4..HEADER_LEN].try_into().unwrap());
+    let format = match length & !MAX_PAYLOAD_LEN {
+        0 => Format::Json,
+        BINCODE_FLAG => Format::Bincode,
+        _ => return None,
+    };
+    Some((format, length & MAX_PAYLOAD_LEN, checksum))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn records_of_every_format_read_back() {
+        let commands = vec![("key1".to_owned(), 1u64), ("key2".to_owned(), u64::MAX)];
+        let mut log = Vec::new();
+        for (command, format) in commands.iter().zip([Format::Json, Format::Bincode]) {
+            log.extend(encode_as(format, command).unwrap());
+        }
+        let mut reader = Cursor::new(&log);
+        let mut offset = 0;
+        for command in &commands {
+            match read::<(String, u64), _>(&mut reader, 1, offset).unwrap() {
+                Entry::Record(read, length) => {
+                    assert_eq!(&read, command);
+                    offset += length;
+                }
+                _ => panic!("expect a record"),
+            }
+        }
+        assert!(matches!(read::<(String, u64), _>(&mut reader, 1, offset).unwrap(), Entry::End));
+
+        let bincode = encode_as(Format::Bincode, &commands[1]).unwrap();
+        let payload = payload(&bincode, 1, 0).unwrap();
+        assert_eq!(payload.format, Format::Bincode);
+        assert_eq!(payload.decode::<(String, u64)>().unwrap(), commands[1]);
+    }
+
+    #[test]
+    fn records_found_after_a_corrupt_header() {
+        let mut log = encode_as(Format::Json, &("key1".to_owned(), 1u64)).unwrap();
+        let record_len = log.len();
+        log.extend(encode_as(Format::Bincode, &("key2".to_owned(), 2u64)).unwrap());
+        assert!(!contains_record::<(String, u64)>(&log[..record_len - 1]));
+        assert!(!contains_record::<(String, u64)>(&[0; 64]));
+        assert!(contains_record::<(String, u64)>(&log[1..]));
+        assert!(contains_record::<(String, u64)>(&log[record_len..]));
+    }
+}
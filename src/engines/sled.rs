@@ -1,22 +1,139 @@
-use sled::Db;
-use crate::engines::KvsEngine;
-use crate::{Result, KvsError};
+use sled::{Db, IVec, Tree};
+use sled::transaction::{ConflictableTransactionError, ConflictableTransactionResult, TransactionError, TransactionalTree, Transactional};
+use crate::engines::{Counters, DiskUsage, KvsEngine, Stats};
+use crate::{Result, KvsError, TypedValue, ValueType};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// When `SledKvsEngine` flushes writes to disk.
+///
+/// A flush waits for sled to write out its dirty pages, flushing every write
+/// defeats the batching of sled. Reads see the writes whatever the policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// flush after every write, a write is durable when it returns
+    Always,
+    /// never flush explicitly, rely on the background flusher of sled, every
+    /// 500 ms unless the `sled::Config` says otherwise, and
+    /// [`SledKvsEngine::flush`]
+    #[default]
+    Never,
+    /// flush after every n writes
+    EveryN(u64),
+}
+
+// the tree of the type tags of the values not of type string
+const VALUE_TYPES_TREE: &str = "kvs_value_types";
 
 /// sled ksv engine
 #[derive(Clone)]
 pub struct SledKvsEngine {
     engine: Db,
+    value_types: Tree,
+    flush_policy: FlushPolicy,
+    // writes since the last flush, shared by all clones
+    unflushed: Arc<AtomicU64>,
+    counters: Arc<Counters>,
 }
 
 impl SledKvsEngine {
     /// create a SledKvsEngine instance
     pub fn new(engine: Db) -> Result<Self> {
-        Ok(SledKvsEngine { engine })
+        SledKvsEngine::with_flush_policy(engine, FlushPolicy::default())
+    }
+
+    /// create a SledKvsEngine instance flushing writes by the flush policy
+    pub fn with_flush_policy(engine: Db, flush_policy: FlushPolicy) -> Result<Self> {
+        Ok(SledKvsEngine {
+            value_types: engine.open_tree(VALUE_TYPES_TREE)?,
+            engine,
+            flush_policy,
+            unflushed: Arc::new(AtomicU64::new(0)),
+            counters: Arc::new(Counters::default()),
+        })
+    }
+
+    /// Run `f` on the trees of the values and of their types in a transaction.
+    fn transaction<T, F>(&self, f: F) -> Result<T>
+        where F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<T, KvsError>
+    {
+        (&*self.engine, &self.value_types)
+            .transaction(|(values, types)| f(values, types))
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => KvsError::Sled(e),
+            })
+    }
+
+    /// The pairs of the keys from `start`, included, to `end`, excluded, in
+    /// key order, empty unless `start < end`.
+    pub fn range(&self, start: String, end: String) -> Result<Vec<(String, String)>> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        let mut pairs = Vec::new();
+        for pair in self.engine.range(start..end) {
+            let (key, value) = pair?;
+            pairs.push((String::from_utf8(key.to_vec())?, String::from_utf8(value.to_vec())?));
+        }
+        Ok(pairs)
+    }
+
+    /// Set the keys paired with a value and remove those paired with none, in
+    /// one atomic batch of sled flushed once. Removing a missing key is no error.
+    pub fn apply_batch(&self, ops: Vec<(String, Option<String>)>) -> Result<()> {
+        let mut values = sled::Batch::default();
+        let mut types = sled::Batch::default();
+        for (key, value) in ops {
+            // the values of a batch are strings
+            types.remove(key.as_bytes());
+            match value {
+                Some(value) => {
+                    Counters::incr(&self.counters.sets);
+                    values.insert(key.into_bytes(), value.into_bytes());
+                }
+                None => {
+                    Counters::incr(&self.counters.removes);
+                    values.remove(key.into_bytes());
+                }
+            }
+        }
+        self.transaction(|value_tree, type_tree| {
+            value_tree.apply_batch(&values)?;
+            type_tree.apply_batch(&types)?;
+            Ok(())
+        })?;
+        // a single flush for the whole batch
+        self.after_write()
+    }
+
+    /// Flush the writes to disk now, whatever the flush policy.
+    pub fn flush(&self) -> Result<()> {
+        self.unflushed.store(0, Ordering::SeqCst);
+        self.engine.flush()?;
+        Ok(())
+    }
+
+    fn after_write(&self) -> Result<()> {
+        match self.flush_policy {
+            FlushPolicy::Always => {
+                self.engine.flush()?;
+            }
+            FlushPolicy::Never => {}
+            FlushPolicy::EveryN(n) => {
+                if self.unflushed.fetch_add(1, Ordering::SeqCst) + 1 >= n {
+                    self.unflushed.store(0, Ordering::SeqCst);
+                    self.engine.flush()?;
+                }
+            }
+        }
+        Ok(())
     }
 }
 
 impl KvsEngine for SledKvsEngine {
     fn get(&self, key: String) -> Result<Option<String>> {
+        Counters::incr(&self.counters.gets);
         let value = self.engine.get(key)?;
         Ok(value
             .map(|i_vec| AsRef::as_ref(&i_vec).to_vec())
@@ -26,14 +143,242 @@ impl KvsEngine for SledKvsEngine {
     }
 
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.engine.insert(key, value.into_bytes()).map(|_| ())?;
-        self.engine.flush()?;
-        Ok(())
+        self.set_typed(key, TypedValue::String(value))
+    }
+
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.apply_batch(pairs.into_iter().map(|(key, value)| (key, Some(value))).collect())
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Counters::incr(&self.counters.gets);
+        Ok(self.engine.contains_key(key)?)
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        self.engine.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+        Counters::incr(&self.counters.removes);
+        self.transaction(|values, types| {
+            values.remove(key.as_bytes())?.ok_or(ConflictableTransactionError::Abort(KvsError::KeyNotFound))?;
+            types.remove(key.as_bytes())?;
+            Ok(())
+        })?;
+        self.after_write()
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        if from == to {
+            return self.engine.get(&from)?.map(|_| ()).ok_or(KvsError::KeyNotFound);
+        }
+        self.transaction(|values, types| {
+            let value = values.remove(from.as_bytes())?.ok_or(ConflictableTransactionError::Abort(KvsError::KeyNotFound))?;
+            values.insert(to.as_bytes(), value)?;
+            match types.remove(from.as_bytes())? {
+                Some(tag) => types.insert(to.as_bytes(), tag)?,
+                None => types.remove(to.as_bytes())?,
+            };
+            Ok(())
+        })?;
+        self.after_write()
+    }
+
+    fn remove_prefix(&self, prefix: String) -> Result<usize> {
+        let mut removed = 0;
+        for entry in self.engine.scan_prefix(prefix.as_bytes()) {
+            let (key, _) = entry?;
+            let existed = self.transaction(|values, types| {
+                types.remove(&key)?;
+                Ok(values.remove(&key)?.is_some())
+            })?;
+            // a concurrent remove may have been first
+            if existed {
+                Counters::incr(&self.counters.removes);
+                removed += 1;
+            }
+        }
+        self.after_write()?;
+        Ok(removed)
+    }
+
+    fn set_typed(&self, key: String, value: TypedValue) -> Result<()> {
+        Counters::incr(&self.counters.sets);
+        let value_type = value.value_type();
+        let text = value.into_text();
+        self.transaction(|values, types| {
+            values.insert(key.as_bytes(), text.as_bytes())?;
+            set_value_type(types, &key, value_type)?;
+            Ok(())
+        })?;
+        self.after_write()
+    }
+
+    fn get_typed(&self, key: String) -> Result<Option<TypedValue>> {
+        Counters::incr(&self.counters.gets);
+        let stored = self.transaction(|values, types| match values.get(key.as_bytes())? {
+            Some(value) => Ok(Some((value, types.get(key.as_bytes())?))),
+            None => Ok(None),
+        })?;
+        stored.map(|(value, tag)| to_typed(value, tag)).transpose()
+    }
+
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        Counters::incr(&self.counters.sets);
+        let value = self.transaction(|values, types| {
+            let current = match values.get(key.as_bytes())? {
+                Some(value) => to_typed(value, types.get(key.as_bytes())?)
+                    .and_then(TypedValue::parse_int)
+                    .map_err(ConflictableTransactionError::Abort)?,
+                None => 0,
+            };
+            let value = current.checked_add(delta).ok_or_else(|| {
+                ConflictableTransactionError::Abort(KvsError::StringError(format!(
+                    "Integer overflow adding {} to {}",
+                    delta, current
+                )))
+            })?;
+            values.insert(key.as_bytes(), value.to_string().as_bytes())?;
+            set_value_type(types, &key, ValueType::Int)?;
+            Ok(value)
+        })?;
+        self.after_write()?;
+        Ok(value)
+    }
+
+    fn get_or_insert(&self, key: String, default: String) -> Result<String> {
+        Counters::incr(&self.counters.gets);
+        let value = self.transaction(|values, types| match values.get(key.as_bytes())? {
+            Some(value) => Ok(Some(value)),
+            None => {
+                values.insert(key.as_bytes(), default.as_bytes())?;
+                types.remove(key.as_bytes())?;
+                Ok(None)
+            }
+        })?;
+        match value {
+            Some(value) => Ok(String::from_utf8(value.to_vec())?),
+            None => {
+                self.after_write()?;
+                Ok(default)
+            }
+        }
+    }
+
+    /// Not a plain `fetch_and_update` of sled, the type of the value is dropped along.
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        Counters::incr(&self.counters.sets);
+        let len = self.transaction(|values, types| {
+            let mut value = values.get(key.as_bytes())?.map(|value| value.to_vec()).unwrap_or_default();
+            value.extend_from_slice(suffix.as_bytes());
+            values.insert(key.as_bytes(), value.as_slice())?;
+            types.remove(key.as_bytes())?;
+            Ok(value.len())
+        })?;
+        self.after_write()?;
+        Ok(len)
+    }
+
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        Counters::incr(&self.counters.sets);
+        let previous = self.transaction(|values, types| {
+            let previous = values.insert(key.as_bytes(), value.as_bytes())?;
+            types.remove(key.as_bytes())?;
+            Ok(previous)
+        })?;
+        self.after_write()?;
+        Ok(previous.map(|value| String::from_utf8(value.to_vec())).transpose()?)
+    }
+
+    /// The value is compared as text, whatever its type. Not a plain
+    /// `compare_and_swap` of sled, the type of the value is swapped along.
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        Counters::incr(&self.counters.sets);
+        let swapped = self.transaction(|values, types| {
+            let current = values.get(key.as_bytes())?;
+            if current.as_deref() != expected.as_ref().map(String::as_bytes) {
+                return Ok(false);
+            }
+            match &new {
+                Some(value) => values.insert(key.as_bytes(), value.as_bytes())?,
+                None => values.remove(key.as_bytes())?,
+            };
+            types.remove(key.as_bytes())?;
+            Ok(true)
+        })?;
+        if swapped {
+            self.after_write()?;
+        }
+        Ok(swapped)
+    }
+
+    /// Flushed whatever the flush policy, the keys must not come back.
+    fn clear(&self) -> Result<()> {
+        self.engine.clear()?;
+        self.value_types.clear()?;
         self.engine.flush()?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn for_each<F>(&self, mut f: F) -> Result<()>
+        where F: FnMut(String, String) -> Result<()>
+    {
+        for pair in self.engine.iter() {
+            let (key, value) = pair?;
+            f(String::from_utf8(key.to_vec())?, String::from_utf8(value.to_vec())?)?;
+        }
+        Ok(())
+    }
+
+    fn for_each_prefix<F>(&self, prefix: &str, mut f: F) -> Result<()>
+        where F: FnMut(String, String) -> Result<()>
+    {
+        for pair in self.engine.scan_prefix(prefix.as_bytes()) {
+            let (key, value) = pair?;
+            f(String::from_utf8(key.to_vec())?, String::from_utf8(value.to_vec())?)?;
+        }
+        Ok(())
+    }
+
+    fn disk_usage(&self) -> Result<DiskUsage> {
+        let mut live_bytes = 0;
+        for pair in self.engine.iter() {
+            let (key, value) = pair?;
+            live_bytes += (key.len() + value.len()) as u64;
+        }
+        Ok(DiskUsage {
+            total_bytes: self.engine.size_on_disk()?,
+            live_bytes,
+            generations: 0,
+        })
+    }
+
+    /// sled compacts on its own, no compaction is counted.
+    /// The keys are counted by a full scan.
+    fn stats(&self) -> Result<Stats> {
+        Ok(self.counters.snapshot(self.engine.len() as u64))
+    }
+
+    fn name(&self) -> &'static str {
+        "sled"
+    }
+}
+
+/// Tag key with the type, strings are left untagged.
+fn set_value_type(
+    types: &TransactionalTree,
+    key: &str,
+    value_type: ValueType,
+) -> std::result::Result<(), sled::transaction::UnabortableTransactionError> {
+    if value_type.is_string() {
+        types.remove(key.as_bytes())?;
+    } else {
+        types.insert(key.as_bytes(), &[value_type.to_byte()])?;
+    }
+    Ok(())
+}
+
+fn to_typed(value: IVec, tag: Option<IVec>) -> Result<TypedValue> {
+    let value_type = match tag {
+        Some(tag) => ValueType::from_byte(tag.first().copied().unwrap_or_default())?,
+        None => ValueType::String,
+    };
+    TypedValue::from_text(value_type, String::from_utf8(value.to_vec())?)
+}
@@ -0,0 +1,232 @@
+use crate::engines::{Counters, DiskUsage, KvsEngine, Stats};
+use crate::{KvsError, Result, TypedValue};
+use std::sync::{Arc, Mutex};
+
+/// How `TieredKvsEngine` writes to its hot engine.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// write the value to both engines
+    #[default]
+    WriteThrough,
+    /// write the value to the cold engine only and remove it from the hot one,
+    /// the next read promotes it again
+    Invalidate,
+}
+
+/// An engine reading through a hot engine, a cache, to a cold one holding all the data.
+///
+/// A read missing the hot engine reads the cold one and promotes the value to
+/// the hot engine. Writes go to the cold engine first, then to the hot one by
+/// the write policy.
+///
+/// Example:
+/// ```rust
+/// # use std::sync::Arc;
+/// # use kvs::{KvStore, KvStoreOptions, KvsEngine, MemoryFileSystem, Result, TieredKvsEngine};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// // a bounded store in memory evicting the least recently used keys
+/// let options = KvStoreOptions::new()
+///     .file_system(Arc::new(MemoryFileSystem::new()))
+///     .max_keys(1000);
+/// let hot = KvStore::open_with_options("/cache", options)?;
+/// let engine = TieredKvsEngine::new(hot, KvStore::open(current_dir()?)?);
+/// engine.set("key".to_owned(), "value".to_owned())?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct TieredKvsEngine<Hot: KvsEngine, Cold: KvsEngine> {
+    hot: Hot,
+    cold: Cold,
+    write_policy: WritePolicy,
+    // held by writes and promotions, a promotion can not bring back a value overwritten meanwhile
+    write_lock: Arc<Mutex<()>>,
+    counters: Arc<Counters>,
+}
+
+impl<Hot: KvsEngine, Cold: KvsEngine> TieredKvsEngine<Hot, Cold> {
+    /// Create an engine caching the values of `cold` in `hot`.
+    ///
+    /// `hot` should be empty or hold the values of `cold`, it is trusted on a hit.
+    pub fn new(hot: Hot, cold: Cold) -> Self {
+        TieredKvsEngine {
+            hot,
+            cold,
+            write_policy: WritePolicy::default(),
+            write_lock: Arc::new(Mutex::new(())),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    /// Set the write policy, default [`WritePolicy::WriteThrough`].
+    pub fn write_policy(mut self, write_policy: WritePolicy) -> Self {
+        self.write_policy = write_policy;
+        self
+    }
+
+    /// Remove key from the hot engine, a missing key is fine.
+    fn invalidate(&self, key: String) -> Result<()> {
+        match self.hot.remove(key) {
+            Ok(()) | Err(KvsError::KeyNotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write a value just written to the cold engine to the hot one by the write policy.
+    fn write_hot(&self, key: String, value: TypedValue) -> Result<()> {
+        match self.write_policy {
+            WritePolicy::WriteThrough => match self.hot.set_typed(key.clone(), value) {
+                Ok(()) => Ok(()),
+                // the hot engine must not keep the old value
+                Err(_) => self.invalidate(key),
+            },
+            WritePolicy::Invalidate => self.invalidate(key),
+        }
+    }
+}
+
+impl<Hot: KvsEngine, Cold: KvsEngine> KvsEngine for TieredKvsEngine<Hot, Cold> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.get_typed(key)?.map(TypedValue::into_text))
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_typed(key, TypedValue::String(value))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        Counters::incr(&self.counters.removes);
+        let _guard = self.write_lock.lock().unwrap();
+        let result = self.cold.remove(key.clone());
+        // cleared even if the cold engine failed, the key may be gone from it
+        self.invalidate(key)?;
+        result
+    }
+
+    fn set_typed(&self, key: String, value: TypedValue) -> Result<()> {
+        Counters::incr(&self.counters.sets);
+        let _guard = self.write_lock.lock().unwrap();
+        self.cold.set_typed(key.clone(), value.clone())?;
+        self.write_hot(key, value)
+    }
+
+    fn get_typed(&self, key: String) -> Result<Option<TypedValue>> {
+        Counters::incr(&self.counters.gets);
+        if let Some(value) = self.hot.get_typed(key.clone())? {
+            return Ok(Some(value));
+        }
+        // read again under the lock, the value promoted must be the latest
+        let _guard = self.write_lock.lock().unwrap();
+        let value = self.cold.get_typed(key.clone())?;
+        if let Some(value) = &value {
+            self.hot.set_typed(key, value.clone())?;
+        }
+        Ok(value)
+    }
+
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        Counters::incr(&self.counters.sets);
+        let _guard = self.write_lock.lock().unwrap();
+        let value = self.cold.increment(key.clone(), delta)?;
+        self.write_hot(key, TypedValue::Int(value))?;
+        Ok(value)
+    }
+
+    fn get_or_insert(&self, key: String, default: String) -> Result<String> {
+        Counters::incr(&self.counters.gets);
+        if let Some(value) = self.hot.get(key.clone())? {
+            return Ok(value);
+        }
+        let _guard = self.write_lock.lock().unwrap();
+        let value = self.cold.get_or_insert(key.clone(), default)?;
+        self.invalidate(key)?;
+        Ok(value)
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        Counters::incr(&self.counters.sets);
+        let _guard = self.write_lock.lock().unwrap();
+        let len = self.cold.append(key.clone(), suffix)?;
+        self.invalidate(key)?;
+        Ok(len)
+    }
+
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        Counters::incr(&self.counters.sets);
+        let _guard = self.write_lock.lock().unwrap();
+        let previous = self.cold.get_set(key.clone(), value.clone())?;
+        self.write_hot(key, TypedValue::String(value))?;
+        Ok(previous)
+    }
+
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        Counters::incr(&self.counters.sets);
+        let _guard = self.write_lock.lock().unwrap();
+        let swapped = self.cold.compare_and_swap(key.clone(), expected, new)?;
+        if swapped {
+            self.invalidate(key)?;
+        }
+        Ok(swapped)
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        Counters::incr(&self.counters.sets);
+        let _guard = self.write_lock.lock().unwrap();
+        self.cold.rename(from.clone(), to.clone())?;
+        self.invalidate(from)?;
+        self.invalidate(to)
+    }
+
+    fn remove_prefix(&self, prefix: String) -> Result<usize> {
+        let _guard = self.write_lock.lock().unwrap();
+        let result = self.cold.remove_prefix(prefix.clone());
+        self.hot.remove_prefix(prefix)?;
+        let removed = result?;
+        Counters::add(&self.counters.removes, removed as u64);
+        Ok(removed)
+    }
+
+    fn clear(&self) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        let result = self.cold.clear();
+        // cleared even if the cold engine failed, some keys may be gone from it
+        self.hot.clear()?;
+        result
+    }
+
+    fn for_each<F>(&self, f: F) -> Result<()>
+        where F: FnMut(String, String) -> Result<()>
+    {
+        self.cold.for_each(f)
+    }
+
+    fn for_each_prefix<F>(&self, prefix: &str, f: F) -> Result<()>
+        where F: FnMut(String, String) -> Result<()>
+    {
+        self.cold.for_each_prefix(prefix, f)
+    }
+
+    fn disk_usage(&self) -> Result<DiskUsage> {
+        let hot = self.hot.disk_usage()?;
+        let cold = self.cold.disk_usage()?;
+        Ok(DiskUsage {
+            total_bytes: hot.total_bytes + cold.total_bytes,
+            live_bytes: hot.live_bytes + cold.live_bytes,
+            generations: hot.generations + cold.generations,
+        })
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        let hot = self.hot.stats()?;
+        let cold = self.cold.stats()?;
+        Ok(Stats {
+            compactions: hot.compactions + cold.compactions,
+            ..self.counters.snapshot(cold.live_keys)
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "tiered"
+    }
+}
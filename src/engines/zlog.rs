@@ -0,0 +1,177 @@
+use std::convert::TryInto;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Bytes of log compressed together, a read decompresses the whole block
+/// holding its offset.
+const BLOCK_LEN: usize = 64 * 1024;
+
+/// Bytes of the header of a block, its compressed and its plain length.
+const HEADER_LEN: usize = 8;
+
+/// Writes a log compressed by zstd, as blocks of `BLOCK_LEN` bytes compressed
+/// one by one so a reader can seek to any offset of the plain log.
+///
+/// Every block is written after a header of its compressed and plain length,
+/// both big-endian `u32`. [`ZlogWriter::finish`] writes the last block.
+pub(crate) struct ZlogWriter<W: Write> {
+    inner: W,
+    block: Vec<u8>,
+}
+
+impl<W: Write> ZlogWriter<W> {
+    pub(crate) fn new(inner: W) -> ZlogWriter<W> {
+        ZlogWriter { inner, block: Vec::with_capacity(BLOCK_LEN) }
+    }
+
+    /// Write the buffered block, the bytes written after are lost.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        self.write_block()?;
+        Ok(self.inner)
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let compressed = zstd::bulk::compress(&self.block, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+        self.inner.write_all(&(compressed.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&(self.block.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&compressed)?;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ZlogWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = buf.len().min(BLOCK_LEN - self.block.len());
+        self.block.extend_from_slice(&buf[..length]);
+        if self.block.len() == BLOCK_LEN {
+            self.write_block()?;
+        }
+        Ok(length)
+    }
+
+    /// Flush the blocks written so far, the partial block is kept.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A block of a compressed log.
+struct Block {
+    // offset of its first byte in the plain log
+    plain_start: u64,
+    plain_len: usize,
+    // offset of its compressed bytes in the file
+    file_offset: u64,
+    compressed_len: usize,
+}
+
+/// Reads a log written by [`ZlogWriter`] as the plain log.
+///
+/// The headers of the blocks are read when it is opened, the last block read
+/// is kept decompressed.
+pub(crate) struct ZlogReader<R: Read + Seek> {
+    inner: R,
+    blocks: Vec<Block>,
+    len: u64,
+    pos: u64,
+    // index in `blocks` and bytes of the decompressed block
+    cached: Option<(usize, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> ZlogReader<R> {
+    pub(crate) fn new(mut inner: R) -> io::Result<ZlogReader<R>> {
+        let file_len = inner.seek(SeekFrom::End(0))?;
+        let mut blocks = Vec::new();
+        let mut plain_start = 0;
+        let mut offset = inner.seek(SeekFrom::Start(0))?;
+        while offset < file_len {
+            let mut header = [0; HEADER_LEN];
+            inner.read_exact(&mut header)?;
+            let compressed_len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+            let plain_len = u32::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+            let file_offset = offset + HEADER_LEN as u64;
+            if file_offset + compressed_len as u64 > file_len {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "compressed log ends within a block"));
+            }
+            blocks.push(Block { plain_start, plain_len, file_offset, compressed_len });
+            plain_start += plain_len as u64;
+            offset = inner.seek(SeekFrom::Start(file_offset + compressed_len as u64))?;
+        }
+        Ok(ZlogReader { inner, blocks, len: plain_start, pos: 0, cached: None })
+    }
+
+    /// The decompressed block at index `i`.
+    fn block(&mut self, i: usize) -> io::Result<&[u8]> {
+        if self.cached.as_ref().is_none_or(|(cached, _)| *cached != i) {
+            let block = &self.blocks[i];
+            let mut compressed = vec![0; block.compressed_len];
+            self.inner.seek(SeekFrom::Start(block.file_offset))?;
+            self.inner.read_exact(&mut compressed)?;
+            let plain = zstd::bulk::decompress(&compressed, block.plain_len)?;
+            if plain.len() != block.plain_len {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "compressed block of a wrong length"));
+            }
+            self.cached = Some((i, plain));
+        }
+        Ok(&self.cached.as_ref().unwrap().1)
+    }
+}
+
+impl<R: Read + Seek> Read for ZlogReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let pos = self.pos;
+        let i = self.blocks.partition_point(|block| block.plain_start <= pos) - 1;
+        let start = (pos - self.blocks[i].plain_start) as usize;
+        let block = self.block(i)?;
+        let length = buf.len().min(block.len() - start);
+        buf[..length].copy_from_slice(&block[start..start + length]);
+        self.pos += length as u64;
+        Ok(length)
+    }
+}
+
+impl<R: Read + Seek> Seek for ZlogReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn reads_back_at_any_offset() {
+        let plain: Vec<u8> = (0..3 * BLOCK_LEN + 100).map(|i| (i % 251) as u8).collect();
+        let mut writer = ZlogWriter::new(Vec::new());
+        for chunk in plain.chunks(1000) {
+            writer.write_all(chunk).unwrap();
+        }
+        let compressed = writer.finish().unwrap();
+        assert!(compressed.len() < plain.len());
+
+        let mut reader = ZlogReader::new(Cursor::new(compressed)).unwrap();
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, plain);
+        for &offset in &[BLOCK_LEN as u64 - 3, 10, 2 * BLOCK_LEN as u64 + 7] {
+            reader.seek(SeekFrom::Start(offset)).unwrap();
+            let mut bytes = [0; 16];
+            reader.read_exact(&mut bytes).unwrap();
+            assert_eq!(&bytes[..], &plain[offset as usize..offset as usize + 16]);
+        }
+    }
+}
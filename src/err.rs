@@ -1,41 +1,119 @@
-use failure::Fail;
+use thiserror::Error;
 use std::io;
 use core::fmt::{Debug};
-use std::io::Error;
 use std::string::FromUtf8Error;
+use crate::ValueType;
 
 /// kvs error
-#[derive(Fail, Debug)]
+#[derive(Error, Debug)]
 pub enum KvsError {
     /// IO error
-    #[fail(display = "{}", _0)]
-    Io(#[cause] io::Error),
+    #[error("{0}")]
+    Io(#[source] io::Error),
     /// Serde serialization or deserialization error
-    #[fail(display = "{}", _0)]
-    Serde(#[cause] serde_json::Error),
+    #[error("{0}")]
+    Serde(#[source] serde_json::Error),
+    /// Bincode serialization or deserialization error
+    #[error("{0}")]
+    Bincode(#[source] bincode::Error),
     /// Sled error
-    #[fail(display = "sled error: {}", _0)]
-    Sled(#[cause] sled::Error),
+    #[error("sled error: {0}")]
+    Sled(#[source] sled::Error),
     /// Converting a `String` from a UTF-8 byte vector error
-    #[fail(display = "UTF-8 error: {}", _0)]
-    Utf8(#[cause] FromUtf8Error),
+    #[error("UTF-8 error: {0}")]
+    Utf8(#[source] FromUtf8Error),
     /// Remove a not exit key error
-    #[fail(display = "Key not found")]
+    #[error("Key not found")]
     KeyNotFound,
     /// Server config is invalid error.
-    #[fail(display = "Server start failed.")]
+    #[error("Server start failed.")]
     ServerStart,
     /// Client send a invalid request to server.
-    #[fail(display = "{}", _0)]
+    #[error("{0}")]
     StringError(String),
     /// Unknown command
-    #[fail(display = "Unknown command")]
+    #[error("Unknown command")]
     UnknownCommand,
+    /// Operation timed out
+    #[error("Operation timed out")]
+    Timeout,
+    /// Merging the log files failed, the store is left as it was before
+    #[error("Compaction failed: {0}")]
+    CompactionFailed(#[source] Box<KvsError>),
+    /// The store directory is opened by another process
+    #[error("Store directory is locked by another process")]
+    Locked,
+    /// A message frame is longer than allowed
+    #[error("Frame of {size} bytes exceeds the limit of {limit} bytes")]
+    FrameTooLarge {
+        /// length of the frame
+        size: u64,
+        /// the largest length allowed
+        limit: u64,
+    },
+    /// A configuration value is invalid
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    /// The server does not know a request, it is newer than the server
+    #[error("Server does not support command {0}")]
+    UnsupportedCommand(String),
+    /// A log record was found at an invalid range of its file
+    #[error("Invalid record range {start}..{stop} in generation {generation}")]
+    InvalidRecord {
+        /// generation of the log file
+        generation: u64,
+        /// offset of the start of the record
+        start: u64,
+        /// offset of the end of the record
+        stop: u64,
+    },
+    /// A log record does not match its checksum
+    #[error("Corrupt record at offset {offset} of generation {generation}")]
+    Corruption {
+        /// generation of the log file
+        generation: u64,
+        /// offset of the start of the record
+        offset: u64,
+    },
+    /// A value is not of the type the operation requires
+    #[error("Value is of type {found:?}, not {expected:?}")]
+    TypeMismatch {
+        /// the type required
+        expected: ValueType,
+        /// the type of the value
+        found: ValueType,
+    },
+    /// A string value to increment is not the text of an integer
+    #[error("Value is not an integer")]
+    NotAnInteger,
+    /// A write was made to a store opened without write access
+    #[error("Store is opened read only")]
+    ReadOnly,
+    /// The server is too loaded to handle the request now, it was not applied
+    #[error("Server is busy")]
+    Busy,
+    /// The client and the server speak different versions of the protocol
+    #[error("Protocol version {client} of the client does not match version {server} of the server")]
+    ProtocolMismatch {
+        /// version of the client
+        client: u16,
+        /// version of the server
+        server: u16,
+    },
+}
+
+impl KvsError {
+    /// Whether the error is transient, the same request may succeed if sent again later.
+    ///
+    /// Only `Busy` is, a request failing otherwise may have been applied.
+    pub fn is_retriable(&self) -> bool {
+        matches!(self, KvsError::Busy)
+    }
 }
 
 
 impl From<io::Error> for KvsError {
-    fn from(err: Error) -> KvsError {
+    fn from(err: io::Error) -> KvsError {
         KvsError::Io(err)
     }
 }
@@ -46,6 +124,12 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
 impl From<sled::Error> for KvsError {
     fn from(err: sled::Error) -> KvsError {
         KvsError::Sled(err)
@@ -1,15 +1,31 @@
 #![deny(missing_docs)]
 //! A simple key-value storage.
-pub use client::KvsClient;
-pub use engines::{KvsEngine, KvStore, SledKvsEngine};
+pub use client::{KvsClient, Pipeline, PipelineReply};
+pub use client_pool::{KvsClientPool, PooledClient};
+#[cfg(feature = "async")]
+pub use async_client::AsyncKvsClient;
+pub use engines::{AutoCompaction, DiskUsage, EvictionPolicy, Stats, KvsEngine, KvStore, KvStoreOptions, KvStoreStats, RecoveryInfo, SledKvsEngine, FlushPolicy};
+pub use engines::{Change, ChangeCursor, Durability, Changes, CompactionLimiter, HistoryEntry, TieredKvsEngine, WritePolicy};
+pub use engines::{FileLock, FileSystem, MemoryFileSystem, OsFileSystem, ReadFile, WriteFile};
 pub use err::{KvsError, Result};
-pub use server::KvServer;
+pub use server::{KvServer, ShutdownHandle, DEFAULT_MAX_REQUEST_BYTES};
+pub use protocol::{BatchOutcome, Capabilities, Codec, ErrorCode, PingInfo, ProtocolError, PROTOCOL_VERSION};
+pub use value::{TypedValue, ValueType};
+pub use map::{KvMap, KvMapEntry};
 
 mod err;
 mod protocol;
 mod client;
+mod client_pool;
+#[cfg(feature = "async")]
+mod async_client;
 mod server;
 mod engines;
+mod value;
+mod map;
 /// thread pool
 pub mod thread_pool;
+/// helpers for testing against a running server
+#[cfg(feature = "test-support")]
+pub mod test_support;
 
@@ -0,0 +1,121 @@
+use std::path::PathBuf;
+use crate::engines::Update;
+use crate::{KvStore, KvsEngine, Result};
+
+/// A `KvStore` with the method names of `HashMap`.
+///
+/// Every method returns a `Result` instead of panicking, and `entry` is
+/// applied atomically with respect to the other writers of the store.
+///
+/// Example:
+/// ```rust
+/// # use kvs::{KvMap, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let map = KvMap::open(current_dir()?)?;
+/// map.insert("visits".to_owned(), "0".to_owned())?;
+/// map.entry("visits".to_owned())
+///     .and_modify(|visits| *visits = (visits.parse::<u64>().unwrap() + 1).to_string())
+///     .or_insert("1".to_owned())?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct KvMap {
+    store: KvStore,
+}
+
+impl KvMap {
+    /// Wrap an opened store.
+    pub fn new(store: KvStore) -> KvMap {
+        KvMap { store }
+    }
+
+    /// Open the store at a given path.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvMap> {
+        Ok(KvMap::new(KvStore::open(path)?))
+    }
+
+    /// The value of key.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.store.get(key.to_owned())
+    }
+
+    /// Whether key exists.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.store.has_key(key)
+    }
+
+    /// Set the value of key, return the value it replaced.
+    pub fn insert(&self, key: String, value: String) -> Result<Option<String>> {
+        self.store.update(key, |old| (Update::Set(value), old))
+    }
+
+    /// Remove key, return its value. A missing key is not an error.
+    pub fn remove(&self, key: &str) -> Result<Option<String>> {
+        self.store.update(key.to_owned(), |old| match old {
+            Some(old) => (Update::Remove, Some(old)),
+            None => (Update::Keep, None),
+        })
+    }
+
+    /// The entry of key for in-place access.
+    pub fn entry(&self, key: String) -> KvMapEntry<'_> {
+        KvMapEntry {
+            map: self,
+            key,
+            modify: None,
+        }
+    }
+
+    /// The store behind the map.
+    pub fn store(&self) -> &KvStore {
+        &self.store
+    }
+}
+
+/// The entry of a key of a [`KvMap`], nothing is read or written until it
+/// is resolved by one of the `or_insert` methods.
+pub struct KvMapEntry<'a> {
+    map: &'a KvMap,
+    key: String,
+    modify: Option<Modifier<'a>>,
+}
+
+type Modifier<'a> = Box<dyn FnOnce(&mut String) + 'a>;
+
+impl<'a> KvMapEntry<'a> {
+    /// The key of the entry.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Modify the value if the key exists when the entry is resolved.
+    pub fn and_modify<F: FnOnce(&mut String) + 'a>(mut self, f: F) -> KvMapEntry<'a> {
+        self.modify = Some(Box::new(f));
+        self
+    }
+
+    /// Set the key to `default` if it does not exist, return its value.
+    pub fn or_insert(self, default: String) -> Result<String> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Set the key to what `default` returns if it does not exist, return its value.
+    pub fn or_insert_with<F: FnOnce() -> String>(self, default: F) -> Result<String> {
+        let modify = self.modify;
+        self.map.store.update(self.key, |current| match current {
+            Some(mut value) => match modify {
+                Some(modify) => {
+                    modify(&mut value);
+                    (Update::Set(value.clone()), value)
+                }
+                None => (Update::Keep, value),
+            },
+            None => {
+                let value = default();
+                (Update::Set(value.clone()), value)
+            }
+        })
+    }
+}
@@ -1,29 +1,706 @@
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{self, DeserializeOwned, DeserializeSeed, EnumAccess, VariantAccess, Visitor};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Read, Write};
+use crate::{DiskUsage, KvsError, Result, TypedValue};
+
+/// Encoding of the messages on the wire, chosen by the client when connecting.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// JSON, the default
+    #[default]
+    Json,
+    /// bincode, compact for large values
+    Bincode,
+}
+
+impl Codec {
+    fn to_byte(self) -> u8 {
+        match self {
+            Codec::Json => 0,
+            Codec::Bincode => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Codec> {
+        match byte {
+            0 => Some(Codec::Json),
+            1 => Some(Codec::Bincode),
+            _ => None,
+        }
+    }
+}
+
+/// The version of the protocol, a server accepts only clients of the same version.
+///
+/// Bump it with any change to the handshake, the framing or the messages that
+/// a peer of the previous version would misread.
+pub const PROTOCOL_VERSION: u16 = 3;
+
+// the bytes every connection starts with
+const HANDSHAKE_MAGIC: [u8; 4] = *b"KVS\0";
+const HANDSHAKE_OK: u8 = 0;
+const HANDSHAKE_UNSUPPORTED_CODEC: u8 = 1;
+const HANDSHAKE_PROTOCOL_MISMATCH: u8 = 2;
+
+/// Send the protocol version and the codec of the connection to the server
+/// and wait for their acceptance.
+///
+/// The client sends the magic bytes, its version as 2 bytes big endian, then
+/// the codec. The server answers a status byte, followed by its own version
+/// if it does not match.
+pub fn client_handshake<R: Read, W: Write>(reader: &mut R, writer: &mut W, codec: Codec) -> Result<()> {
+    writer.write_all(&client_hello(codec))?;
+    writer.flush()?;
+    let mut status = [0; 1];
+    reader.read_exact(&mut status)?;
+    let mut version = [0; 2];
+    if handshake_needs_version(status[0]) {
+        reader.read_exact(&mut version)?;
+    }
+    check_handshake(codec, status[0], version)
+}
+
+/// The bytes a client starts its connection with.
+pub(crate) fn client_hello(codec: Codec) -> [u8; 7] {
+    let mut hello = [0; 7];
+    hello[..4].copy_from_slice(&HANDSHAKE_MAGIC);
+    hello[4..6].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    hello[6] = codec.to_byte();
+    hello
+}
+
+/// Whether the version of the server follows the status answering the hello.
+pub(crate) fn handshake_needs_version(status: u8) -> bool {
+    status == HANDSHAKE_PROTOCOL_MISMATCH
+}
+
+/// The outcome of the handshake by the status of the server, and its version
+/// if the status is followed by it.
+pub(crate) fn check_handshake(codec: Codec, status: u8, version: [u8; 2]) -> Result<()> {
+    match status {
+        HANDSHAKE_OK => Ok(()),
+        HANDSHAKE_PROTOCOL_MISMATCH => {
+            Err(KvsError::ProtocolMismatch { client: PROTOCOL_VERSION, server: u16::from_be_bytes(version) })
+        }
+        _ => Err(KvsError::StringError(format!("Server does not support codec {:?}", codec))),
+    }
+}
+
+/// Check the protocol version of the client and read the codec of the
+/// connection it chose. The connection is to be closed on an error.
+pub fn server_handshake<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<Codec> {
+    let mut hello = [0; 7];
+    reader.read_exact(&mut hello)?;
+    if hello[..4] != HANDSHAKE_MAGIC {
+        return Err(KvsError::StringError(format!("Not a kvs client, handshake {:?}", hello)));
+    }
+    let version = u16::from_be_bytes([hello[4], hello[5]]);
+    if version != PROTOCOL_VERSION {
+        writer.write_all(&[HANDSHAKE_PROTOCOL_MISMATCH])?;
+        writer.write_all(&PROTOCOL_VERSION.to_be_bytes())?;
+        writer.flush()?;
+        return Err(KvsError::ProtocolMismatch { client: version, server: PROTOCOL_VERSION });
+    }
+    match Codec::from_byte(hello[6]) {
+        Some(codec) => {
+            writer.write_all(&[HANDSHAKE_OK])?;
+            writer.flush()?;
+            Ok(codec)
+        }
+        None => {
+            writer.write_all(&[HANDSHAKE_UNSUPPORTED_CODEC])?;
+            writer.flush()?;
+            Err(KvsError::StringError(format!("Unsupported codec {}", hello[6])))
+        }
+    }
+}
+
+/// Write a message as a frame, the writer is not flushed.
+///
+/// A frame is the length of the encoded message as 4 bytes big endian,
+/// followed by the message encoded with the codec.
+pub fn encode<W: Write, T: Serialize>(codec: Codec, writer: &mut W, message: &T) -> Result<()> {
+    let body = match codec {
+        Codec::Json => serde_json::to_vec(message)?,
+        Codec::Bincode => bincode::serialize(message)?,
+    };
+    let length = u32::try_from(body.len()).map_err(|_| KvsError::FrameTooLarge {
+        size: body.len() as u64,
+        limit: u32::MAX as u64,
+    })?;
+    writer.write_all(&length.to_be_bytes())?;
+    writer.write_all(&body)?;
+    Ok(())
+}
+
+/// Read a message frame with the codec.
+pub fn decode<R: Read, T: DeserializeOwned>(codec: Codec, reader: &mut R) -> Result<T> {
+    decode_limited(codec, reader, u32::MAX as u64)
+}
+
+/// Read a message frame with the codec, rejecting a frame longer than
+/// `max_bytes` before its body is read.
+pub fn decode_limited<R: Read, T: DeserializeOwned>(codec: Codec, reader: &mut R, max_bytes: u64) -> Result<T> {
+    decode_body(codec, &read_frame(reader, max_bytes)?)
+}
+
+/// Read the body of a message frame, rejecting a frame longer than `max_bytes`
+/// before its body is read.
+pub fn read_frame<R: Read>(reader: &mut R, max_bytes: u64) -> Result<Vec<u8>> {
+    let mut header = [0; 4];
+    reader.read_exact(&mut header)?;
+    let length = u32::from_be_bytes(header) as u64;
+    if length > max_bytes {
+        return Err(KvsError::FrameTooLarge { size: length, limit: max_bytes });
+    }
+    let mut body = vec![0; length as usize];
+    reader.read_exact(&mut body)?;
+    Ok(body)
+}
+
+/// Decode the body of a message frame with the codec.
+pub fn decode_body<T: DeserializeOwned>(codec: Codec, body: &[u8]) -> Result<T> {
+    match codec {
+        Codec::Json => Ok(serde_json::from_slice(body)?),
+        Codec::Bincode => Ok(bincode::deserialize(body)?),
+    }
+}
+
+/// The names of the requests in the order of `KvsRequest`, which tell an
+/// unknown request from a malformed one. Keep in sync with `KvsRequest`.
+const REQUEST_NAMES: &[&str] = &[
+    "Get", "Set", "Remove", "SetNoReply", "Ping", "Wait", "Dump", "Rename", "SetBatch",
+    "DiskUsage", "SetTyped", "GetTyped", "Increment", "ScanFilter", "RemovePrefix", "Capabilities",
+    "Exists", "GetBatch", "Cas", "Append", "GetOrInsert", "GetSet",
+];
+
+/// The name of the request of a frame body which failed to decode, if it is a
+/// request this version does not know. Unknown requests of the bincode codec
+/// are named by their index, as `#index`.
+pub fn unknown_request(codec: Codec, body: &[u8]) -> Option<String> {
+    match codec {
+        Codec::Json => {
+            let tag = match serde_json::from_slice(body).ok()? {
+                serde_json::Value::String(tag) => tag,
+                serde_json::Value::Object(map) if map.len() == 1 => map.into_iter().next()?.0,
+                _ => return None,
+            };
+            Some(tag).filter(|tag| !REQUEST_NAMES.contains(&tag.as_str()))
+        }
+        Codec::Bincode => {
+            let index: u32 = bincode::deserialize(body.get(..4)?).ok()?;
+            Some(format!("#{}", index)).filter(|_| index as usize >= REQUEST_NAMES.len())
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum KvsRequest {
     Get { key: String },
     Set { key: String, value: String },
     Remove { key: String },
+    SetNoReply { key: String, value: String },
+    Ping,
+    Wait { key: String, timeout_ms: u64 },
+    Dump,
+    Rename { from: String, to: String },
+    SetBatch { pairs: Vec<(String, String)> },
+    DiskUsage,
+    SetTyped { key: String, value: TypedValue },
+    GetTyped { key: String },
+    Increment { key: String, delta: i64 },
+    ScanFilter { prefix: String, contains: String },
+    RemovePrefix { prefix: String },
+    Capabilities,
+    Exists { key: String },
+    GetBatch { keys: Vec<String> },
+    Cas { key: String, expected: Option<String>, new: Option<String> },
+    Append { key: String, suffix: String },
+    GetOrInsert { key: String, default: String },
+    GetSet { key: String, value: String },
+}
+
+impl KvsRequest {
+    /// Whether sending the request twice has the same outcome as sending it
+    /// once, so it can be sent again if its response was lost.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            KvsRequest::Get { .. }
+                | KvsRequest::Set { .. }
+                | KvsRequest::Ping
+                | KvsRequest::Wait { .. }
+                | KvsRequest::SetBatch { .. }
+                | KvsRequest::DiskUsage
+                | KvsRequest::SetTyped { .. }
+                | KvsRequest::GetTyped { .. }
+                | KvsRequest::Capabilities
+                | KvsRequest::Exists { .. }
+                | KvsRequest::GetBatch { .. }
+                | KvsRequest::GetOrInsert { .. }
+        )
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
     Ok(Option<String>),
-    Err(String),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetBatchResponse {
+    Ok(Vec<Option<String>>),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ExistsResponse {
+    Ok(bool),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetOrInsertResponse {
+    Ok(String),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetSetResponse {
+    Ok(Option<String>),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AppendResponse {
+    Ok(u64),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CasResponse {
+    Ok(bool),
+    Err(ProtocolError),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse {
     Ok(()),
-    Err(String),
+    Err(ProtocolError),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
-    Err(String),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RenameResponse {
+    Ok(()),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RemovePrefixResponse {
+    Ok(u64),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PingResponse {
+    Ok(PingInfo),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WaitResponse {
+    Ok(Option<String>),
+    Timeout,
+    Err(ProtocolError),
+}
+
+/// One message of the stream answering a `Dump` or `ScanFilter` request.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DumpResponse {
+    Entry(String, String),
+    End,
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetTypedResponse {
+    Ok(Option<TypedValue>),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum IncrementResponse {
+    Ok(i64),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DiskUsageResponse {
+    Ok(DiskUsage),
+    Err(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SetBatchResponse {
+    Ok(BatchOutcome),
+    Err(ProtocolError),
+}
+
+/// The response to a request the server does not know, instead of the response
+/// of the request.
+///
+/// Its variant index is out of the range of every other response, so the
+/// compact codecs can not mistake it for one.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UnknownCommandResponse {
+    pub command: String,
+}
+
+const UNKNOWN_COMMAND: VariantTag = VariantTag { name: "UnknownCommand", index: u32::MAX };
+
+impl Serialize for UnknownCommandResponse {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_variant(
+            "UnknownCommandResponse",
+            UNKNOWN_COMMAND.index,
+            UNKNOWN_COMMAND.name,
+            &self.command,
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for UnknownCommandResponse {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct ResponseVisitor;
+
+        impl<'de> Visitor<'de> for ResponseVisitor {
+            type Value = UnknownCommandResponse;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "an unknown command response")
+            }
+
+            fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> std::result::Result<Self::Value, A::Error> {
+                let ((), variant) = data.variant_seed(UNKNOWN_COMMAND)?;
+                Ok(UnknownCommandResponse { command: variant.newtype_variant()? })
+            }
+        }
+
+        deserializer.deserialize_enum("UnknownCommandResponse", &[UNKNOWN_COMMAND.name], ResponseVisitor)
+    }
+}
+
+/// The response to a request the server is too loaded to handle now, instead
+/// of the response of the request. The request was not applied.
+///
+/// Like `UnknownCommandResponse`, its variant index is out of the range of every
+/// other response.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BusyResponse;
+
+const BUSY: VariantTag = VariantTag { name: "Busy", index: u32::MAX - 1 };
+
+impl Serialize for BusyResponse {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_unit_variant("BusyResponse", BUSY.index, BUSY.name)
+    }
+}
+
+impl<'de> Deserialize<'de> for BusyResponse {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct ResponseVisitor;
+
+        impl<'de> Visitor<'de> for ResponseVisitor {
+            type Value = BusyResponse;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "a busy response")
+            }
+
+            fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> std::result::Result<Self::Value, A::Error> {
+                let ((), variant) = data.variant_seed(BUSY)?;
+                variant.unit_variant()?;
+                Ok(BusyResponse)
+            }
+        }
+
+        deserializer.deserialize_enum("BusyResponse", &[BUSY.name], ResponseVisitor)
+    }
+}
+
+/// The single variant tag of a response outside of the response enums, matched
+/// by name or by index.
+#[derive(Copy, Clone)]
+struct VariantTag {
+    name: &'static str,
+    index: u32,
+}
+
+impl<'de> DeserializeSeed<'de> for VariantTag {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> std::result::Result<(), D::Error> {
+        deserializer.deserialize_identifier(self)
+    }
+}
+
+impl<'de> Visitor<'de> for VariantTag {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the variant {}", self.name)
+    }
+
+    fn visit_u64<E: de::Error>(self, index: u64) -> std::result::Result<(), E> {
+        if index == self.index as u64 {
+            Ok(())
+        } else {
+            Err(E::invalid_value(de::Unexpected::Unsigned(index), &self))
+        }
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> std::result::Result<(), E> {
+        if name == self.name {
+            Ok(())
+        } else {
+            Err(E::unknown_variant(name, &[]))
+        }
+    }
+}
+
+/// The kind of an error reported by the server, for the client to tell the
+/// errors it can handle from the failures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// the key does not exist
+    KeyNotFound,
+    /// the value to increment is not an integer
+    NotAnInteger,
+    /// the store of the server is opened read only
+    ReadOnly,
+    /// any other error, described by the message only
+    Other,
+}
+
+/// An error of a request reported by the server.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolError {
+    code: ErrorCode,
+    message: String,
+}
+
+impl ProtocolError {
+    pub(crate) fn new(message: impl Into<String>) -> ProtocolError {
+        ProtocolError { code: ErrorCode::Other, message: message.into() }
+    }
+
+    /// The kind of the error.
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    /// The error message.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<KvsError> for ProtocolError {
+    fn from(err: KvsError) -> ProtocolError {
+        let code = match err {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::NotAnInteger => ErrorCode::NotAnInteger,
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            _ => ErrorCode::Other,
+        };
+        ProtocolError { code, message: format!("{}", err) }
+    }
+}
+
+impl From<ProtocolError> for KvsError {
+    fn from(err: ProtocolError) -> KvsError {
+        match err.code {
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::NotAnInteger => KvsError::NotAnInteger,
+            ErrorCode::ReadOnly => KvsError::ReadOnly,
+            ErrorCode::Other => KvsError::StringError(err.message),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// What a server supports, the answer to a `Capabilities` request.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// version of the server
+    pub version: String,
+    /// the names of the requests the server knows, as `"SetBatch"` or `"Increment"`
+    pub features: Vec<String>,
+}
+
+impl Capabilities {
+    /// The capabilities of this version.
+    pub(crate) fn current() -> Capabilities {
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            features: REQUEST_NAMES.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    /// Whether the server knows the request named `feature`.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|known| known == feature)
+    }
+}
+
+/// Who answered a `Ping` request.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PingInfo {
+    /// version of the server
+    pub version: String,
+    /// name of the engine of the server, as `"kvs"` or `"sled"`
+    pub engine: String,
+}
+
+/// The outcome of every item of a batch request, in the order of the items.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchOutcome {
+    results: Vec<std::result::Result<(), ProtocolError>>,
+}
+
+impl BatchOutcome {
+    pub(crate) fn new(results: Vec<std::result::Result<(), ProtocolError>>) -> BatchOutcome {
+        BatchOutcome { results }
+    }
+
+    /// Whether every item succeeded.
+    pub fn all_ok(&self) -> bool {
+        self.results.iter().all(|result| result.is_ok())
+    }
+
+    /// The outcome of every item.
+    pub fn results(&self) -> &[std::result::Result<(), ProtocolError>] {
+        &self.results
+    }
+
+    /// The indices and errors of the failed items.
+    pub fn failures(&self) -> impl Iterator<Item = (usize, &ProtocolError)> {
+        self.results
+            .iter()
+            .enumerate()
+            .filter_map(|(i, result)| result.as_ref().err().map(|e| (i, e)))
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_names_match_requests() {
+        let key = || "key".to_owned();
+        let requests = vec![
+            KvsRequest::Get { key: key() },
+            KvsRequest::Set { key: key(), value: key() },
+            KvsRequest::Remove { key: key() },
+            KvsRequest::SetNoReply { key: key(), value: key() },
+            KvsRequest::Ping,
+            KvsRequest::Wait { key: key(), timeout_ms: 0 },
+            KvsRequest::Dump,
+            KvsRequest::Rename { from: key(), to: key() },
+            KvsRequest::SetBatch { pairs: Vec::new() },
+            KvsRequest::DiskUsage,
+            KvsRequest::SetTyped { key: key(), value: TypedValue::Int(0) },
+            KvsRequest::GetTyped { key: key() },
+            KvsRequest::Increment { key: key(), delta: 0 },
+            KvsRequest::ScanFilter { prefix: key(), contains: key() },
+            KvsRequest::RemovePrefix { prefix: key() },
+            KvsRequest::Capabilities,
+            KvsRequest::Exists { key: key() },
+            KvsRequest::GetBatch { keys: vec![key()] },
+            KvsRequest::Cas { key: key(), expected: None, new: Some(key()) },
+            KvsRequest::Append { key: key(), suffix: key() },
+            KvsRequest::GetOrInsert { key: key(), default: key() },
+            KvsRequest::GetSet { key: key(), value: key() },
+        ];
+        assert_eq!(requests.len(), REQUEST_NAMES.len());
+        for (index, request) in requests.iter().enumerate() {
+            let name = REQUEST_NAMES[index];
+            let json = serde_json::to_string(request).unwrap();
+            assert!(json.starts_with(&format!("{{\"{}\"", name)) || json == format!("\"{}\"", name));
+            let bincode = bincode::serialize(request).unwrap();
+            assert_eq!(bincode::deserialize::<u32>(&bincode[..4]).unwrap(), index as u32);
+        }
+        let next = REQUEST_NAMES.len() as u32;
+        assert_eq!(unknown_request(Codec::Bincode, &next.to_le_bytes()), Some(format!("#{}", next)));
+    }
 
+    #[test]
+    fn request_frames_round_trip() {
+        for codec in [Codec::Json, Codec::Bincode] {
+            let mut stream = Vec::new();
+            encode(codec, &mut stream, &KvsRequest::Set { key: "key".to_owned(), value: "value".to_owned() }).unwrap();
+            encode(codec, &mut stream, &KvsRequest::Get { key: "key".to_owned() }).unwrap();
+            let length = u32::from_be_bytes([stream[0], stream[1], stream[2], stream[3]]) as usize;
+            assert!(decode_body::<KvsRequest>(codec, &stream[4..4 + length]).is_ok());
 
+            // each read takes exactly one frame of the stream
+            let mut reader = stream.as_slice();
+            assert!(matches!(
+                decode(codec, &mut reader).unwrap(),
+                KvsRequest::Set { key, value } if key == "key" && value == "value"
+            ));
+            assert!(matches!(decode(codec, &mut reader).unwrap(), KvsRequest::Get { key } if key == "key"));
+            assert!(reader.is_empty());
+
+            // an oversized frame is rejected from its header, its body left unread
+            let mut reader = stream.as_slice();
+            let limit = length as u64 - 1;
+            assert!(matches!(
+                decode_limited::<_, KvsRequest>(codec, &mut reader, limit),
+                Err(KvsError::FrameTooLarge { size, limit: max }) if size == length as u64 && max == limit
+            ));
+            assert_eq!(reader.len(), stream.len() - 4);
+        }
+    }
+
+    #[test]
+    fn unknown_command_response_round_trips() {
+        let response = UnknownCommandResponse { command: "Frobnicate".to_owned() };
+        for codec in [Codec::Json, Codec::Bincode] {
+            let mut frame = Vec::new();
+            encode(codec, &mut frame, &response).unwrap();
+            let body = &frame[4..];
+            assert_eq!(decode_body::<UnknownCommandResponse>(codec, body).unwrap(), response);
+            // no other response mistakes it for its own
+            assert!(decode_body::<GetResponse>(codec, body).is_err());
+            assert!(decode_body::<BusyResponse>(codec, body).is_err());
+        }
+    }
+
+    #[test]
+    fn busy_response_round_trips() {
+        for codec in [Codec::Json, Codec::Bincode] {
+            let mut frame = Vec::new();
+            encode(codec, &mut frame, &BusyResponse).unwrap();
+            let body = &frame[4..];
+            assert_eq!(decode_body::<BusyResponse>(codec, body).unwrap(), BusyResponse);
+            assert!(decode_body::<GetResponse>(codec, body).is_err());
+            assert!(decode_body::<UnknownCommandResponse>(codec, body).is_err());
+        }
+    }
+}
@@ -1,80 +1,850 @@
-use std::net::{ToSocketAddrs, TcpListener, TcpStream};
-use crate::err::Result;
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs, TcpListener, TcpStream};
+use crate::err::{KvsError, Result};
+use crate::TypedValue;
 use crate::protocol::*;
-use log::{debug, error};
-use std::io::{BufReader, BufWriter, Write};
+use log::{debug, error, info, warn};
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use crate::engines::KvsEngine;
 use crate::thread_pool::{ThreadPool};
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Serialize;
 
 /// struct server
 pub struct KvServer<E: KvsEngine> {
     engine: E,
+    watchers: Arc<Watchers>,
+    config: ServerConfig,
+    shutdown: ShutdownHandle,
 }
 
+/// A handle stopping a running [`KvServer`] from another thread.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<ShutdownState>,
+}
+
+#[derive(Debug, Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    // address of the listener, connected to wake up a blocked accept
+    addr: Mutex<Option<SocketAddr>>,
+}
+
+impl ShutdownHandle {
+    /// Stop accepting connections, the server returns once it is woken.
+    ///
+    /// The server then stops reading from its connections, answers the requests
+    /// already read and waits for them before it returns and drops its pool.
+    /// Clients blocked in a `Wait` are answered a timeout.
+    pub fn shutdown(&self) {
+        self.inner.requested.store(true, Ordering::SeqCst);
+        if let Some(addr) = *self.inner.addr.lock().unwrap() {
+            // a failed connect means the listener is already closed
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    fn is_requested(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+}
+
+/// Limits of the requests a server accepts.
+#[derive(Clone, Copy, Debug)]
+struct ServerConfig {
+    max_value_bytes: usize,
+    max_request_bytes: u64,
+    metrics_interval: Option<Duration>,
+    nodelay: bool,
+    // the backlogs starting and stopping the shedding of connections
+    shed_watermarks: Option<(usize, usize)>,
+    // the requests handled at once over every connection
+    max_in_flight: Option<usize>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_value_bytes: usize::MAX,
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+            metrics_interval: None,
+            nodelay: true,
+            shed_watermarks: None,
+            max_in_flight: None,
+        }
+    }
+}
+
+/// Default limit of the encoded size of a request, 64 MiB.
+pub const DEFAULT_MAX_REQUEST_BYTES: u64 = 64 * 1024 * 1024;
+
 impl<E: KvsEngine> KvServer<E> {
     /// crate a kvs server instance
     pub fn new(engine: E) -> Self {
-        KvServer { engine }
+        KvServer {
+            engine,
+            watchers: Arc::new(Watchers::default()),
+            config: ServerConfig::default(),
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    /// Close connections sending a request larger than `bytes` once encoded,
+    /// default [`DEFAULT_MAX_REQUEST_BYTES`].
+    ///
+    /// The request is rejected by its length, before its body is read.
+    pub fn max_request_bytes(mut self, bytes: u64) -> Self {
+        self.config.max_request_bytes = bytes;
+        self
+    }
+
+    /// A handle stopping the server once it is started.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Reject values larger than `bytes`, default no limit.
+    pub fn max_value_bytes(mut self, bytes: usize) -> Self {
+        self.config.max_value_bytes = bytes;
+        self
+    }
+
+    /// Set `TCP_NODELAY` on the accepted connections, default true.
+    ///
+    /// Small responses are then sent at once instead of waiting to be coalesced,
+    /// turn it off to favor throughput over latency.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.config.nodelay = nodelay;
+        self
+    }
+
+    /// Close new connections at once while the pool is saturated, default off.
+    ///
+    /// Once more than `high_watermark` connections wait for a worker, the
+    /// connections accepted are closed without being served, until the backlog
+    /// drains to `low_watermark`. Their clients fail to connect and may retry.
+    pub fn shed_connections(mut self, high_watermark: usize, low_watermark: usize) -> Self {
+        self.config.shed_watermarks = Some((high_watermark, low_watermark.min(high_watermark)));
+        self
+    }
+
+    /// Answer busy to the requests arriving while `limit` requests are handled,
+    /// default no limit.
+    ///
+    /// A rejected request is not applied, its client gets `KvsError::Busy` and
+    /// may send it again later. Requests without reply, streamed or waiting for
+    /// a key are never rejected.
+    pub fn max_in_flight_requests(mut self, limit: usize) -> Self {
+        self.config.max_in_flight = Some(limit);
+        self
+    }
+
+    /// Log the engine stats and the connections waiting for a worker every `interval`,
+    /// default off.
+    pub fn with_metrics_logging(mut self, interval: Duration) -> Self {
+        self.config.metrics_interval = Some(interval);
+        self
     }
 
     /// Start kvs server
     pub fn start<A: ToSocketAddrs, P: ThreadPool>(self, addr: A, pool: P) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
+        self.serve(TcpListener::bind(addr)?, pool)
+    }
+
+    /// Serve the connections of a bound listener until shut down.
+    pub fn serve<P: ThreadPool>(self, listener: TcpListener, pool: P) -> Result<()> {
+        *self.shutdown.inner.addr.lock().unwrap() = Some(listener.local_addr()?);
+        // a shutdown requested before the address was known could not wake the accept
+        if self.shutdown.is_requested() {
+            return Ok(());
+        }
+        // connections accepted but not yet picked up by a worker
+        let queued = Arc::new(AtomicUsize::new(0));
+        // requests being handled, for the admission of new ones
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        // the metrics thread stops once the sender drops with this function
+        let (stop_metrics, stop_receiver) = mpsc::channel::<()>();
+        let metrics = match self.config.metrics_interval {
+            Some(interval) => Some(spawn_metrics_logger(self.engine.clone(), queued.clone(), interval, stop_receiver)?),
+            None => None,
+        };
+        let connections = Arc::new(Connections::default());
+        let mut shedding = false;
         for stream in listener.incoming() {
+            if self.shutdown.is_requested() {
+                debug!("Server shut down");
+                break;
+            }
+            if let Some((high_watermark, low_watermark)) = self.config.shed_watermarks {
+                let backlog = queued.load(Ordering::SeqCst);
+                if !shedding && backlog > high_watermark {
+                    warn!("{} connections wait for a worker, shedding new connections", backlog);
+                    shedding = true;
+                } else if shedding && backlog <= low_watermark {
+                    info!("{} connections wait for a worker, accepting connections again", backlog);
+                    shedding = false;
+                }
+                if shedding {
+                    debug!("Connection shed");
+                    drop(stream);
+                    continue;
+                }
+            }
             let engine = self.engine.clone();
-            pool.spawn(move || match stream {
-                Err(e) => error!("Connection failed: {}", e),
-                Ok(stream) => {
-                    if let Err(e) = handle_client(engine, stream) {
-                        error!("Handle client stream failed: {}", e);
+            let watchers = self.watchers.clone();
+            let config = self.config;
+            let queued = queued.clone();
+            let in_flight = in_flight.clone();
+            queued.fetch_add(1, Ordering::SeqCst);
+            let connection = Connection::new(connections.clone());
+            pool.spawn(move || {
+                // locals drop in reverse: the engine clone is dropped before the
+                // connection is finished, the store is released once serve returns
+                let mut connection = connection;
+                let engine = engine;
+                queued.fetch_sub(1, Ordering::SeqCst);
+                match stream {
+                    Err(e) => error!("Connection failed: {}", e),
+                    Ok(stream) => {
+                        if !connection.start(&stream) {
+                            debug!("Connection dropped by the shutdown before it was served");
+                            return;
+                        }
+                        if let Err(e) = handle_client(engine, &watchers, config, &in_flight, stream) {
+                            error!("Handle client stream failed: {}", e);
+                        }
                     }
                 }
             })
         }
+        // no job is spawned any more, finish the running ones before the pool drops
+        self.watchers.close();
+        connections.close_and_wait();
+        drop(stop_metrics);
+        if let Some(metrics) = metrics {
+            let _ = metrics.join();
+        }
         Ok(())
     }
 }
 
-fn handle_client<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
+/// The connections handed to the pool, closed and waited for on shutdown.
+#[derive(Default)]
+struct Connections {
+    state: Mutex<ConnectionsState>,
+    finished: Condvar,
+}
+
+#[derive(Default)]
+struct ConnectionsState {
+    next_id: u64,
+    // connections handed to the pool and not finished
+    pending: usize,
+    // the streams of the connections being served
+    streams: HashMap<u64, TcpStream>,
+    closing: bool,
+}
+
+impl Connections {
+    /// Stop reading from the connections being served and wait until every
+    /// connection handed to the pool is finished.
+    ///
+    /// A request already read is answered, the connections not yet picked up
+    /// by a worker are closed without being served.
+    fn close_and_wait(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closing = true;
+        for stream in state.streams.values() {
+            // wakes up a worker blocked reading the next request
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        while state.pending > 0 {
+            state = self.finished.wait(state).unwrap();
+        }
+    }
+}
+
+/// A connection handed to the pool, finished when dropped.
+struct Connection {
+    connections: Arc<Connections>,
+    id: Option<u64>,
+}
+
+impl Connection {
+    fn new(connections: Arc<Connections>) -> Connection {
+        connections.state.lock().unwrap().pending += 1;
+        Connection { connections, id: None }
+    }
+
+    /// Register the stream when a worker starts serving it.
+    /// Return false if the server is shutting down, the connection must then be dropped.
+    fn start(&mut self, stream: &TcpStream) -> bool {
+        let mut state = self.connections.state.lock().unwrap();
+        if state.closing {
+            return false;
+        }
+        match stream.try_clone() {
+            Ok(stream) => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.streams.insert(id, stream);
+                self.id = Some(id);
+                true
+            }
+            Err(e) => {
+                error!("Connection can not be registered: {}", e);
+                false
+            }
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut state = self.connections.state.lock().unwrap();
+        if let Some(id) = self.id {
+            state.streams.remove(&id);
+        }
+        state.pending -= 1;
+        self.connections.finished.notify_all();
+    }
+}
+
+/// Log a snapshot of the metrics every `interval` until `stop` is disconnected.
+fn spawn_metrics_logger<E: KvsEngine>(
+    engine: E,
+    queued: Arc<AtomicUsize>,
+    interval: Duration,
+    stop: mpsc::Receiver<()>,
+) -> Result<thread::JoinHandle<()>> {
+    let handle = thread::Builder::new()
+        .name("kvs-metrics".to_owned())
+        .spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(interval) {
+                match engine.stats() {
+                    Ok(stats) => info!(
+                        "Metrics: gets {} sets {} removes {} compactions {} live keys {} queued connections {}",
+                        stats.gets,
+                        stats.sets,
+                        stats.removes,
+                        stats.compactions,
+                        stats.live_keys,
+                        queued.load(Ordering::SeqCst)
+                    ),
+                    Err(e) => error!("Reading the engine stats failed: {}", e),
+                }
+            }
+        })?;
+    Ok(handle)
+}
+
+/// Clients blocked in a `Wait` request, woken by the writes of other clients.
+///
+/// Only keys with waiters are tracked, a key is forgotten when its last waiter
+/// returns, so waiters of disconnected clients live no longer than their timeout.
+/// A waiter blocks the worker of its connection until then.
+#[derive(Default)]
+struct Watchers {
+    // a map of key to its write count and the number of its waiters
+    keys: Mutex<HashMap<String, (u64, usize)>>,
+    written: Condvar,
+    // set on shutdown, no waiter blocks any more
+    closed: AtomicBool,
+}
+
+impl Watchers {
+    /// Wake the waiters of key.
+    fn notify(&self, key: &str) {
+        let mut keys = self.keys.lock().unwrap();
+        if let Some((writes, _)) = keys.get_mut(key) {
+            *writes += 1;
+            self.written.notify_all();
+        }
+    }
+
+    /// Wake the waiters of the keys starting with `prefix`.
+    fn notify_prefix(&self, prefix: &str) {
+        let mut keys = self.keys.lock().unwrap();
+        for (_, (writes, _)) in keys.iter_mut().filter(|(key, _)| key.starts_with(prefix)) {
+            *writes += 1;
+        }
+        self.written.notify_all();
+    }
+
+    /// Wake every waiter without a write and stop blocking new ones.
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        // taking the lock orders the flag before a waiter about to block
+        let _keys = self.keys.lock().unwrap();
+        self.written.notify_all();
+    }
+
+    /// Block until key is written, the timeout elapses or the watchers are closed.
+    /// Return whether key was written.
+    fn wait(&self, key: &str, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut keys = self.keys.lock().unwrap();
+        let entry = keys.entry(key.to_owned()).or_insert((0, 0));
+        entry.1 += 1;
+        let start_writes = entry.0;
+        let written = loop {
+            if keys[key].0 != start_writes {
+                break true;
+            }
+            let now = Instant::now();
+            if now >= deadline || self.closed.load(Ordering::SeqCst) {
+                break false;
+            }
+            keys = self.written.wait_timeout(keys, deadline - now).unwrap().0;
+        };
+        let entry = keys.get_mut(key).unwrap();
+        entry.1 -= 1;
+        if entry.1 == 0 {
+            keys.remove(key);
+        }
+        written
+    }
+}
+
+fn handle_client<E: KvsEngine>(
+    engine: E,
+    watchers: &Watchers,
+    config: ServerConfig,
+    in_flight: &AtomicUsize,
+    stream: TcpStream,
+) -> Result<()> {
     let peer = stream.peer_addr()?;
     debug!("Connection established from {}", &peer);
-    let reader = BufReader::new(&stream);
+    configure_stream(&stream, &config)?;
+    let mut reader = BufReader::new(&stream);
     let mut writer = BufWriter::new(&stream);
-    let deserializer_iter = serde_json::Deserializer::from_reader(reader)
-        .into_iter::<KvsRequest>();
-    for request in deserializer_iter {
-        let request = request?;
+    let codec = server_handshake(&mut reader, &mut writer)?;
+    debug!("Connection from {} uses codec {:?}", &peer, codec);
+    let mut session = Session {
+        engine,
+        watchers,
+        config,
+        peer,
+        codec,
+        deferred_error: None,
+    };
+    // stop at the end of stream
+    while !reader.fill_buf()?.is_empty() {
+        let body = read_frame(&mut reader, config.max_request_bytes)?;
+        let request: KvsRequest = match decode_body(codec, &body) {
+            Ok(request) => request,
+            Err(e) => match unknown_request(codec, &body) {
+                Some(command) => {
+                    warn!("Unknown command {} from {}", command, &peer);
+                    encode(codec, &mut writer, &UnknownCommandResponse { command })?;
+                    writer.flush()?;
+                    continue;
+                }
+                None => return Err(e),
+            },
+        };
         debug!("recv from {}: {:?}", &peer, &request);
-        match request {
-            KvsRequest::Get { key } => {
-                let response = match engine.get(key) {
-                    Ok(value) => GetResponse::Ok(value),
-                    Err(e) => GetResponse::Err(format!("{}", e)),
-                };
-                serde_json::to_writer(&mut writer, &response)?;
-                writer.flush()?;
-                debug!("resp to   {}: {:?}", &peer, &response);
+        let _admission = match config.max_in_flight {
+            Some(limit) if needs_admission(&request) => match Admission::enter(in_flight, limit) {
+                Some(admission) => Some(admission),
+                None => {
+                    warn!("Server busy, rejected a request from {}", &peer);
+                    encode(codec, &mut writer, &BusyResponse)?;
+                    writer.flush()?;
+                    continue;
+                }
+            },
+            _ => None,
+        };
+        session.dispatch(request, &mut writer)?;
+    }
+    Ok(())
+}
+
+/// Set the socket options of the config on an accepted stream.
+fn configure_stream(stream: &TcpStream, config: &ServerConfig) -> Result<()> {
+    stream.set_nodelay(config.nodelay)?;
+    Ok(())
+}
+
+/// Whether a request goes through the admission control, the requests without
+/// reply, streamed or waiting for a key are never rejected.
+fn needs_admission(request: &KvsRequest) -> bool {
+    !matches!(
+        request,
+        KvsRequest::SetNoReply { .. } | KvsRequest::Wait { .. } | KvsRequest::Dump | KvsRequest::ScanFilter { .. }
+    )
+}
+
+/// A request counted as in flight until dropped.
+struct Admission<'a> {
+    in_flight: &'a AtomicUsize,
+}
+
+impl<'a> Admission<'a> {
+    /// Count a request in flight, None if `limit` requests already are.
+    fn enter(in_flight: &'a AtomicUsize, limit: usize) -> Option<Admission<'a>> {
+        in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| (count < limit).then_some(count + 1))
+            .ok()
+            .map(|_| Admission { in_flight })
+    }
+}
+
+impl<'a> Drop for Admission<'a> {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The response of a request, serialized as the response of its own type.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum Response {
+    Get(GetResponse),
+    Exists(ExistsResponse),
+    GetBatch(GetBatchResponse),
+    Cas(CasResponse),
+    Append(AppendResponse),
+    GetOrInsert(GetOrInsertResponse),
+    GetSet(GetSetResponse),
+    Set(SetResponse),
+    Remove(RemoveResponse),
+    Ping(PingResponse),
+    Wait(WaitResponse),
+    Rename(RenameResponse),
+    SetBatch(SetBatchResponse),
+    DiskUsage(DiskUsageResponse),
+    GetTyped(GetTypedResponse),
+    Increment(IncrementResponse),
+    RemovePrefix(RemovePrefixResponse),
+    Capabilities(Capabilities),
+}
+
+/// The state of a client connection, with a handler for every request type.
+struct Session<'a, E: KvsEngine> {
+    engine: E,
+    watchers: &'a Watchers,
+    config: ServerConfig,
+    peer: SocketAddr,
+    codec: Codec,
+    // the first error of a request without reply, reported on the next ping
+    deferred_error: Option<ProtocolError>,
+}
+
+impl<'a, E: KvsEngine> Session<'a, E> {
+    /// Handle a request and write its response.
+    fn dispatch<W: Write>(&mut self, request: KvsRequest, writer: &mut W) -> Result<()> {
+        let response = match request {
+            KvsRequest::Get { key } => Response::Get(self.get(key)),
+            KvsRequest::Set { key, value } => Response::Set(self.set(key, value)),
+            KvsRequest::Remove { key } => Response::Remove(self.remove(key)),
+            KvsRequest::SetNoReply { key, value } => {
+                self.set_noreply(key, value);
+                return Ok(());
             }
-            KvsRequest::Set { key, value } => {
-                let response = match engine.set(key, value) {
-                    Ok(value) => SetResponse::Ok(value),
-                    Err(e) => SetResponse::Err(format!("{}", e)),
-                };
-                serde_json::to_writer(&mut writer, &response)?;
-                writer.flush()?;
-                debug!("resp to   {}: {:?}", &peer, &response);
+            KvsRequest::Ping => Response::Ping(self.ping()),
+            KvsRequest::Wait { key, timeout_ms } => Response::Wait(self.wait(key, timeout_ms)),
+            KvsRequest::Dump => return self.dump(writer, "", |_| true),
+            KvsRequest::ScanFilter { prefix, contains } => {
+                return self.dump(writer, &prefix, |value| value.contains(&contains));
             }
-            KvsRequest::Remove { key } => {
-                let response = match engine.remove(key) {
-                    Ok(value) => RemoveResponse::Ok(value),
-                    Err(e) => RemoveResponse::Err(format!("{}", e)),
-                };
-                serde_json::to_writer(&mut writer, &response)?;
-                writer.flush()?;
-                debug!("resp to   {}: {:?}", &peer, &response);
+            KvsRequest::Rename { from, to } => Response::Rename(self.rename(from, to)),
+            KvsRequest::SetBatch { pairs } => Response::SetBatch(self.set_batch(pairs)),
+            KvsRequest::DiskUsage => Response::DiskUsage(self.disk_usage()),
+            KvsRequest::SetTyped { key, value } => Response::Set(self.set_typed(key, value)),
+            KvsRequest::GetTyped { key } => Response::GetTyped(self.get_typed(key)),
+            KvsRequest::Increment { key, delta } => Response::Increment(self.increment(key, delta)),
+            KvsRequest::RemovePrefix { prefix } => Response::RemovePrefix(self.remove_prefix(prefix)),
+            KvsRequest::Capabilities => Response::Capabilities(Capabilities::current()),
+            KvsRequest::Exists { key } => Response::Exists(self.exists(key)),
+            KvsRequest::GetBatch { keys } => Response::GetBatch(self.get_batch(keys)),
+            KvsRequest::Cas { key, expected, new } => Response::Cas(self.compare_and_swap(key, expected, new)),
+            KvsRequest::Append { key, suffix } => Response::Append(self.append(key, suffix)),
+            KvsRequest::GetOrInsert { key, default } => Response::GetOrInsert(self.get_or_insert(key, default)),
+            KvsRequest::GetSet { key, value } => Response::GetSet(self.get_set(key, value)),
+        };
+        encode(self.codec, writer, &response)?;
+        writer.flush()?;
+        debug!("resp to   {}: {:?}", &self.peer, &response);
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> GetResponse {
+        match self.engine.get(key) {
+            Ok(value) => GetResponse::Ok(value),
+            Err(e) => GetResponse::Err(e.into()),
+        }
+    }
+
+    fn get_batch(&mut self, keys: Vec<String>) -> GetBatchResponse {
+        match self.engine.get_batch(keys) {
+            Ok(values) => GetBatchResponse::Ok(values),
+            Err(e) => GetBatchResponse::Err(e.into()),
+        }
+    }
+
+    fn exists(&mut self, key: String) -> ExistsResponse {
+        match self.engine.contains_key(key) {
+            Ok(exists) => ExistsResponse::Ok(exists),
+            Err(e) => ExistsResponse::Err(e.into()),
+        }
+    }
+
+    fn set(&mut self, key: String, value: String) -> SetResponse {
+        self.set_typed(key, TypedValue::String(value))
+    }
+
+    fn set_typed(&mut self, key: String, value: TypedValue) -> SetResponse {
+        match self.apply_set(key, value) {
+            Ok(value) => SetResponse::Ok(value),
+            Err(e) => SetResponse::Err(e.into()),
+        }
+    }
+
+    fn get_typed(&mut self, key: String) -> GetTypedResponse {
+        match self.engine.get_typed(key) {
+            Ok(value) => GetTypedResponse::Ok(value),
+            Err(e) => GetTypedResponse::Err(e.into()),
+        }
+    }
+
+    fn increment(&mut self, key: String, delta: i64) -> IncrementResponse {
+        let response = match self.engine.increment(key.clone(), delta) {
+            Ok(value) => IncrementResponse::Ok(value),
+            Err(e) => IncrementResponse::Err(e.into()),
+        };
+        self.watchers.notify(&key);
+        response
+    }
+
+    fn compare_and_swap(&mut self, key: String, expected: Option<String>, new: Option<String>) -> CasResponse {
+        let swapped = match new.as_ref().map_or(0, String::len) {
+            len if len > self.config.max_value_bytes => Err(KvsError::StringError(format!(
+                "Value of {} bytes exceeds the limit of {} bytes",
+                len, self.config.max_value_bytes
+            ))),
+            _ => self.engine.compare_and_swap(key.clone(), expected, new),
+        };
+        match swapped {
+            Ok(swapped) => {
+                if swapped {
+                    self.watchers.notify(&key);
+                }
+                CasResponse::Ok(swapped)
             }
+            Err(e) => CasResponse::Err(e.into()),
+        }
+    }
+
+    fn get_or_insert(&mut self, key: String, default: String) -> GetOrInsertResponse {
+        if default.len() > self.config.max_value_bytes {
+            return GetOrInsertResponse::Err(ProtocolError::new(format!(
+                "Value of {} bytes exceeds the limit of {} bytes",
+                default.len(),
+                self.config.max_value_bytes
+            )));
+        }
+        let response = match self.engine.get_or_insert(key.clone(), default) {
+            Ok(value) => GetOrInsertResponse::Ok(value),
+            Err(e) => GetOrInsertResponse::Err(e.into()),
         };
+        // the default may have been inserted
+        self.watchers.notify(&key);
+        response
+    }
+
+    fn get_set(&mut self, key: String, value: String) -> GetSetResponse {
+        if value.len() > self.config.max_value_bytes {
+            return GetSetResponse::Err(ProtocolError::new(format!(
+                "Value of {} bytes exceeds the limit of {} bytes",
+                value.len(),
+                self.config.max_value_bytes
+            )));
+        }
+        let response = match self.engine.get_set(key.clone(), value) {
+            Ok(previous) => GetSetResponse::Ok(previous),
+            Err(e) => GetSetResponse::Err(e.into()),
+        };
+        self.watchers.notify(&key);
+        response
+    }
+
+    fn append(&mut self, key: String, suffix: String) -> AppendResponse {
+        let response = match self.engine.append(key.clone(), suffix) {
+            Ok(len) => AppendResponse::Ok(len as u64),
+            Err(e) => AppendResponse::Err(e.into()),
+        };
+        self.watchers.notify(&key);
+        response
+    }
+
+    fn set_batch(&mut self, pairs: Vec<(String, String)>) -> SetBatchResponse {
+        let results = pairs
+            .into_iter()
+            .map(|(key, value)| {
+                self.apply_set(key, TypedValue::String(value))
+                    .map_err(ProtocolError::from)
+            })
+            .collect();
+        SetBatchResponse::Ok(BatchOutcome::new(results))
+    }
+
+    /// Check the limits of a set and apply it.
+    fn apply_set(&mut self, key: String, value: TypedValue) -> Result<()> {
+        if value.text_len() > self.config.max_value_bytes {
+            return Err(KvsError::StringError(format!(
+                "Value of {} bytes exceeds the limit of {} bytes",
+                value.text_len(),
+                self.config.max_value_bytes
+            )));
+        }
+        self.engine.set_typed(key.clone(), value)?;
+        self.watchers.notify(&key);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> RemoveResponse {
+        let response = match self.engine.remove(key.clone()) {
+            Ok(value) => RemoveResponse::Ok(value),
+            Err(e) => RemoveResponse::Err(e.into()),
+        };
+        self.watchers.notify(&key);
+        response
+    }
+
+    fn remove_prefix(&mut self, prefix: String) -> RemovePrefixResponse {
+        let response = match self.engine.remove_prefix(prefix.clone()) {
+            Ok(removed) => RemovePrefixResponse::Ok(removed as u64),
+            Err(e) => RemovePrefixResponse::Err(e.into()),
+        };
+        self.watchers.notify_prefix(&prefix);
+        response
+    }
+
+    fn rename(&mut self, from: String, to: String) -> RenameResponse {
+        let response = match self.engine.rename(from.clone(), to.clone()) {
+            Ok(value) => RenameResponse::Ok(value),
+            Err(e) => RenameResponse::Err(e.into()),
+        };
+        self.watchers.notify(&from);
+        self.watchers.notify(&to);
+        response
+    }
+
+    fn disk_usage(&mut self) -> DiskUsageResponse {
+        match self.engine.disk_usage() {
+            Ok(usage) => DiskUsageResponse::Ok(usage),
+            Err(e) => DiskUsageResponse::Err(e.into()),
+        }
+    }
+
+    fn set_noreply(&mut self, key: String, value: String) {
+        if let Err(e) = self.apply_set(key, TypedValue::String(value)) {
+            error!("Set without reply from {} failed: {}", &self.peer, e);
+            self.deferred_error.get_or_insert(e.into());
+        }
+    }
+
+    fn ping(&mut self) -> PingResponse {
+        match self.deferred_error.take() {
+            None => PingResponse::Ok(PingInfo {
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                engine: self.engine.name().to_owned(),
+            }),
+            Some(e) => PingResponse::Err(e),
+        }
+    }
+
+    fn wait(&mut self, key: String, timeout_ms: u64) -> WaitResponse {
+        if self.watchers.wait(&key, Duration::from_millis(timeout_ms)) {
+            match self.engine.get(key) {
+                Ok(value) => WaitResponse::Ok(value),
+                Err(e) => WaitResponse::Err(e.into()),
+            }
+        } else {
+            WaitResponse::Timeout
+        }
+    }
+
+    /// Stream every pair of a key starting with `prefix` and a value passing
+    /// `filter` to the client, followed by an end marker.
+    fn dump<W, F>(&mut self, writer: &mut W, prefix: &str, mut filter: F) -> Result<()>
+        where W: Write, F: FnMut(&str) -> bool
+    {
+        let codec = self.codec;
+        let mut count = 0;
+        let mut client_gone = false;
+        let result = self.engine.for_each_prefix(prefix, |key, value| {
+            if !filter(&value) {
+                return Ok(());
+            }
+            count += 1;
+            let result = encode(codec, writer, &DumpResponse::Entry(key, value));
+            client_gone = result.is_err();
+            result
+        });
+        let end = match result {
+            Ok(()) => DumpResponse::End,
+            // the client disconnected, end the connection
+            Err(e) if client_gone => return Err(e),
+            Err(e) => DumpResponse::Err(e.into()),
+        };
+        encode(codec, writer, &end)?;
+        writer.flush()?;
+        debug!("dump to   {}: {} pairs, {:?}", &self.peer, count, &end);
+        Ok(())
     }
-    Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Block until `count` clients wait for key.
+    fn wait_for_waiters(watchers: &Watchers, key: &str, count: usize) {
+        let mut keys = watchers.keys.lock().unwrap();
+        while keys.get(key).map_or(0, |&(_, waiters)| waiters) < count {
+            drop(keys);
+            thread::yield_now();
+            keys = watchers.keys.lock().unwrap();
+        }
+    }
+
+    #[test]
+    fn accepted_stream_nodelay() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let _client = TcpStream::connect(listener.local_addr()?)?;
+        let (stream, _) = listener.accept()?;
+        configure_stream(&stream, &ServerConfig::default())?;
+        assert!(stream.nodelay()?);
+        configure_stream(&stream, &ServerConfig { nodelay: false, ..ServerConfig::default() })?;
+        assert!(!stream.nodelay()?);
+        Ok(())
+    }
+
+    #[test]
+    fn wakes_registered_waiters() {
+        let watchers = Arc::new(Watchers::default());
+        let waiters: Vec<_> = (0..2)
+            .map(|_| {
+                let watchers = watchers.clone();
+                thread::spawn(move || watchers.wait("key1", Duration::from_secs(60)))
+            })
+            .collect();
+        wait_for_waiters(&watchers, "key1", 2);
+        watchers.notify("key2");
+        watchers.notify("key1");
+        for waiter in waiters {
+            assert!(waiter.join().unwrap());
+        }
+        // the key is forgotten with its last waiter
+        assert!(watchers.keys.lock().unwrap().is_empty());
+        assert!(!watchers.wait("key1", Duration::from_millis(10)));
+    }
+}
@@ -0,0 +1,82 @@
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use crate::{KvServer, KvStore, KvsEngine, Result, ShutdownHandle, SledKvsEngine};
+use log::error;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+use tempfile::TempDir;
+
+/// A kvs server on an ephemeral port of localhost, storing its data in a
+/// temporary directory. It is shut down and its directory deleted on drop.
+///
+/// Example:
+/// ```rust
+/// # use kvs::{KvsClient, Result};
+/// # use kvs::test_support::TestServer;
+/// # fn try_main() -> Result<()> {
+/// let server = TestServer::kvs()?;
+/// let mut client = KvsClient::connect(server.addr())?;
+/// client.set("key".to_owned(), "value".to_owned())?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TestServer {
+    addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    handle: Option<JoinHandle<Result<()>>>,
+    temp_dir: TempDir,
+}
+
+impl TestServer {
+    /// Start a server of a `KvStore`.
+    pub fn kvs() -> Result<TestServer> {
+        TestServer::start(|path| Ok(KvServer::new(KvStore::open(path)?)))
+    }
+
+    /// Start a server of a `SledKvsEngine`.
+    pub fn sled() -> Result<TestServer> {
+        TestServer::start(|path| Ok(KvServer::new(SledKvsEngine::new(sled::open(path)?)?)))
+    }
+
+    /// Start the server built by `build` from the data directory.
+    pub fn start<E, F>(build: F) -> Result<TestServer>
+        where E: KvsEngine, F: FnOnce(&Path) -> Result<KvServer<E>>
+    {
+        let temp_dir = TempDir::new()?;
+        let server = build(temp_dir.path())?;
+        let shutdown = server.shutdown_handle();
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let pool = SharedQueueThreadPool::new(4)?;
+        let handle = thread::spawn(move || server.serve(listener, pool));
+        Ok(TestServer {
+            addr,
+            shutdown,
+            handle: Some(handle),
+            temp_dir,
+        })
+    }
+
+    /// The address the server listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The data directory of the server.
+    pub fn path(&self) -> &Path {
+        self.temp_dir.path()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.shutdown();
+        if let Some(handle) = self.handle.take() {
+            match handle.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Test server {} failed: {}", self.addr, e),
+                Err(_) => error!("Test server {} panicked", self.addr),
+            }
+        }
+    }
+}
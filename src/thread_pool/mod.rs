@@ -1,4 +1,4 @@
-use crate::Result;
+use crate::{KvsError, Result};
 
 mod naive;
 mod shared_queue;
@@ -17,4 +17,12 @@ pub trait ThreadPool {
     /// spawn a function
     fn spawn<F>(&self, f: F)
         where F: FnOnce() + Send + 'static;
-}
\ No newline at end of file
+}
+
+/// A pool without threads would never run its jobs.
+fn check_threads(threads: u32) -> Result<()> {
+    if threads == 0 {
+        return Err(KvsError::InvalidConfig("a thread pool needs at least one thread".to_owned()));
+    }
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use super::ThreadPool;
+use super::{check_threads, ThreadPool};
 use crate::Result;
 use std::thread;
 
@@ -6,9 +6,10 @@ use std::thread;
 pub struct NaiveThreadPool;
 
 impl ThreadPool for NaiveThreadPool {
-    fn new(_threads: u32) -> Result<Self>
+    fn new(threads: u32) -> Result<Self>
         where Self: Sized
     {
+        check_threads(threads)?;
         Ok(NaiveThreadPool)
     }
 
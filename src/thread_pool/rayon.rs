@@ -1,5 +1,5 @@
 use rayon;
-use super::ThreadPool;
+use super::{check_threads, ThreadPool};
 use crate::KvsError;
 use crate::Result;
 
@@ -12,6 +12,7 @@ impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self>
         where Self: Sized
     {
+        check_threads(threads)?;
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(threads as usize)
             .build()
@@ -1,4 +1,4 @@
-use crate::thread_pool::ThreadPool;
+use crate::thread_pool::{check_threads, ThreadPool};
 use crate::Result;
 use std::sync::mpsc::{Sender, Receiver, channel};
 use std::sync::{Arc, Mutex};
@@ -85,6 +85,7 @@ fn spawn_thread(id: u32, receiver: Arc<Mutex<Receiver<Message>>>) {
 
 impl ThreadPool for SharedQueueThreadPool {
     fn new(threads: u32) -> Result<Self> where Self: Sized {
+        check_threads(threads)?;
         let (sender, receiver) = channel::<Message>();
         let receiver = Arc::new(Mutex::new(receiver));
 
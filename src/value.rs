@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt::Write;
+use crate::{KvsError, Result};
+
+/// The type a value is tagged with when it is stored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ValueType {
+    /// text, the type of values set without a type
+    #[default]
+    String,
+    /// 64 bit signed integer
+    Int,
+    /// 64 bit floating point number
+    Float,
+    /// boolean
+    Bool,
+    /// arbitrary bytes
+    Bytes,
+}
+
+impl ValueType {
+    pub(crate) fn is_string(&self) -> bool {
+        *self == ValueType::String
+    }
+
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            ValueType::String => 0,
+            ValueType::Int => 1,
+            ValueType::Float => 2,
+            ValueType::Bool => 3,
+            ValueType::Bytes => 4,
+        }
+    }
+
+    pub(crate) fn from_byte(byte: u8) -> Result<ValueType> {
+        match byte {
+            0 => Ok(ValueType::String),
+            1 => Ok(ValueType::Int),
+            2 => Ok(ValueType::Float),
+            3 => Ok(ValueType::Bool),
+            4 => Ok(ValueType::Bytes),
+            _ => Err(KvsError::StringError(format!("Unknown value type {}", byte))),
+        }
+    }
+}
+
+/// A value with its type.
+///
+/// It is stored as text with a type tag, the text is what an untyped `get`
+/// returns: numbers and booleans as Rust formats them, bytes in lowercase hex.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(into = "(ValueType, String)", try_from = "(ValueType, String)")]
+pub enum TypedValue {
+    /// a string
+    String(String),
+    /// an integer
+    Int(i64),
+    /// a floating point number
+    Float(f64),
+    /// a boolean
+    Bool(bool),
+    /// bytes
+    Bytes(Vec<u8>),
+}
+
+impl TypedValue {
+    /// The type of the value.
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            TypedValue::String(_) => ValueType::String,
+            TypedValue::Int(_) => ValueType::Int,
+            TypedValue::Float(_) => ValueType::Float,
+            TypedValue::Bool(_) => ValueType::Bool,
+            TypedValue::Bytes(_) => ValueType::Bytes,
+        }
+    }
+
+    /// Convert the value to its stored text.
+    pub fn into_text(self) -> String {
+        match self {
+            TypedValue::String(value) => value,
+            TypedValue::Int(value) => value.to_string(),
+            TypedValue::Float(value) => value.to_string(),
+            TypedValue::Bool(value) => value.to_string(),
+            TypedValue::Bytes(value) => value.iter().fold(String::with_capacity(value.len() * 2), |mut hex, byte| {
+                let _ = write!(hex, "{:02x}", byte);
+                hex
+            }),
+        }
+    }
+
+    /// Parse the stored text of a value of the type.
+    pub fn from_text(value_type: ValueType, text: String) -> Result<TypedValue> {
+        let invalid = || KvsError::StringError(format!("Invalid {:?} value {:?}", value_type, text));
+        Ok(match value_type {
+            ValueType::String => return Ok(TypedValue::String(text)),
+            ValueType::Int => TypedValue::Int(text.parse().map_err(|_| invalid())?),
+            ValueType::Float => TypedValue::Float(text.parse().map_err(|_| invalid())?),
+            ValueType::Bool => TypedValue::Bool(text.parse().map_err(|_| invalid())?),
+            ValueType::Bytes => {
+                if !text.len().is_multiple_of(2) {
+                    return Err(invalid());
+                }
+                let bytes = (0..text.len())
+                    .step_by(2)
+                    .map(|i| text.get(i..i + 2).and_then(|hex| u8::from_str_radix(hex, 16).ok()))
+                    .collect::<Option<Vec<u8>>>()
+                    .ok_or_else(invalid)?;
+                TypedValue::Bytes(bytes)
+            }
+        })
+    }
+
+    /// The length of the stored text.
+    pub(crate) fn text_len(&self) -> usize {
+        match self {
+            TypedValue::String(value) => value.len(),
+            TypedValue::Bytes(value) => value.len() * 2,
+            _ => self.clone().into_text().len(),
+        }
+    }
+
+    /// Return the integer, or `KvsError::TypeMismatch` if the value is of another type.
+    pub(crate) fn into_int(self) -> Result<i64> {
+        match self {
+            TypedValue::Int(value) => Ok(value),
+            other => Err(KvsError::TypeMismatch {
+                expected: ValueType::Int,
+                found: other.value_type(),
+            }),
+        }
+    }
+
+    /// Return the bytes, or `KvsError::TypeMismatch` if the value is of another type.
+    pub(crate) fn into_bytes(self) -> Result<Vec<u8>> {
+        match self {
+            TypedValue::Bytes(value) => Ok(value),
+            other => Err(KvsError::TypeMismatch {
+                expected: ValueType::Bytes,
+                found: other.value_type(),
+            }),
+        }
+    }
+
+    /// The integer a counter holds: an `Int`, or a string of an integer as set
+    /// by an untyped set. `KvsError::NotAnInteger` for another string,
+    /// `KvsError::TypeMismatch` for a value of another type.
+    pub(crate) fn parse_int(self) -> Result<i64> {
+        match self {
+            TypedValue::String(text) => text.parse().map_err(|_| KvsError::NotAnInteger),
+            other => other.into_int(),
+        }
+    }
+}
+
+impl From<TypedValue> for (ValueType, String) {
+    fn from(value: TypedValue) -> (ValueType, String) {
+        (value.value_type(), value.into_text())
+    }
+}
+
+impl TryFrom<(ValueType, String)> for TypedValue {
+    type Error = KvsError;
+
+    fn try_from((value_type, text): (ValueType, String)) -> Result<TypedValue> {
+        TypedValue::from_text(value_type, text)
+    }
+}
@@ -1,6 +1,9 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use assert_cmd::prelude::*;
+use kvs::test_support::TestServer;
+use kvs::{KvStore, KvsClient, KvsEngine, KvsError, Result};
+use std::process::Command;
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -131,18 +134,21 @@ fn compaction() -> Result<()> {
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    let barrier = Arc::new(Barrier::new(1001));
+    // the threads are joined, not only waited for: their clones of the store
+    // must be dropped before it is opened again
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
-        let barrier = barrier.clone();
-        thread::spawn(move || {
+        let handle = thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
-            barrier.wait();
         });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
     }
-    barrier.wait();
 
     for i in 0..1000 {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
@@ -209,3 +215,140 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// `kvs-server --pool shared-queue --threads 4` should serve requests
+#[test]
+fn server_cli_shared_queue_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4103";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--pool", "shared-queue", "--threads", "4"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+
+    let result = (|| -> Result<()> {
+        let mut client = None;
+        for _ in 0..50 {
+            if let Ok(c) = KvsClient::connect(addr) {
+                client = Some(c);
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        let mut client = client.expect("kvs-server not started");
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        Ok(())
+    })();
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+    result
+}
+
+// A store served by a `kvs-server` process should be locked against other processes
+#[test]
+fn server_cli_locks_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4108";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+
+    let started = (0..50).any(|_| {
+        let connected = KvsClient::connect(addr).is_ok();
+        if !connected {
+            thread::sleep(Duration::from_millis(100));
+        }
+        connected
+    });
+    let locked = matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locked));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+    assert!(started, "kvs-server not started");
+    assert!(locked);
+    // the lock goes with the process
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+// `kvs-client shell` should run every command of stdin over one connection
+#[test]
+fn client_cli_shell() -> Result<()> {
+    let server = TestServer::kvs()?;
+    let input = "\
+set key1 value1
+set key2 value with spaces
+get key1
+get key2
+get key3
+keys
+stats
+rm key1
+rm key1
+get key1
+bogus
+ping
+quit
+get key2
+";
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["shell", "--addr", &server.addr().to_string()])
+        .with_stdin()
+        .buffer(input)
+        .assert()
+        .success()
+        .stdout(
+            "OK\nOK\nvalue1\nvalue with spaces\nKey not found\nkey1\nkey2\nkeys: 2\n\
+             OK\nerror: Key not found\nKey not found\n\
+             unknown command \"bogus\", type help for the commands\nPONG\n",
+        );
+    Ok(())
+}
+
+// `kvs-client getset` should print the value replaced
+#[test]
+fn client_cli_getset() -> Result<()> {
+    let server = TestServer::kvs()?;
+    let addr = server.addr().to_string();
+    let getset = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .arg("getset")
+            .args(args)
+            .args(["--addr", &addr])
+            .assert()
+    };
+    getset(&["key1", "value1"]).success().stdout("Key not found\n");
+    getset(&["key1", "value2"]).success().stdout("value1\n");
+    assert_eq!(KvsClient::connect(server.addr())?.get("key1")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// `kvs-client incr` should print the new value, a negative delta subtracting
+#[test]
+fn client_cli_incr() -> Result<()> {
+    let server = TestServer::kvs()?;
+    let addr = server.addr().to_string();
+    let incr = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .arg("incr")
+            .args(args)
+            .args(["--addr", &addr])
+            .assert()
+    };
+    incr(&["counter", "5"]).success().stdout("5\n");
+    incr(&["counter"]).success().stdout("6\n");
+    incr(&["counter", "-10"]).success().stdout("-4\n");
+    KvsClient::connect(server.addr())?.set("word", "forty")?;
+    incr(&["word", "1"]).failure().stderr("Value is not an integer\n");
+    Ok(())
+}
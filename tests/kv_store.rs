@@ -1,6 +1,13 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use kvs::{AutoCompaction, Change, CompactionLimiter, Durability, EvictionPolicy, FileLock, FileSystem, KvMap, KvStore, KvStoreOptions, KvsEngine, KvsError};
+use kvs::{MemoryFileSystem, OsFileSystem, ReadFile, RecoveryInfo, Result, TypedValue, WriteFile};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -81,6 +88,80 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Should fall back to an older readable record when the indexed one is corrupt
+#[test]
+fn recover_corrupt_read() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().recovery(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let log = temp_dir.path().join("1.log");
+    let valid_len = fs::metadata(&log)?.len();
+    store.set("key1".to_owned(), "value2".to_owned())?;
+
+    // break the newest record of key1 on disk
+    let mut file = OpenOptions::new().write(true).open(&log)?;
+    file.seek(SeekFrom::Start(valid_len))?;
+    file.write_all(b"#")?;
+    file.flush()?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    // the index entry is repaired
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should read values into a reused buffer
+#[test]
+fn get_into_reused_buffer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "a long value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value \"3\"".to_owned())?;
+
+    let mut buf = String::new();
+    assert!(store.get_into("key1", &mut buf)?);
+    assert_eq!(buf, "a long value1");
+    let capacity = buf.capacity();
+    assert!(store.get_into("key2", &mut buf)?);
+    assert_eq!(buf, "value2");
+    assert_eq!(buf.capacity(), capacity);
+    assert!(store.get_into("key3", &mut buf)?);
+    assert_eq!(buf, "value \"3\"");
+    assert!(!store.get_into("key4", &mut buf)?);
+
+    Ok(())
+}
+
+// Empty generation files left by a crash should be deleted on open
+#[test]
+fn remove_empty_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let empty_log = temp_dir.path().join("0.log");
+    fs::write(&empty_log, b"")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!empty_log.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // the unused active file of the last open is cleaned as well
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let log_count = fs::read_dir(temp_dir.path())?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+        .count();
+    assert_eq!(log_count, 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -131,18 +212,21 @@ fn compaction() -> Result<()> {
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    let barrier = Arc::new(Barrier::new(1001));
+    // the threads are joined, not only waited for: their clones of the store
+    // must be dropped before it is opened again
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
-        let barrier = barrier.clone();
-        thread::spawn(move || {
+        let handle = thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
-            barrier.wait();
         });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
     }
-    barrier.wait();
 
     for i in 0..1000 {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
@@ -209,3 +293,2188 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Reads racing with the merges of a writer should always see a valid value
+#[test]
+fn concurrent_get_during_merge() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}-0", i))?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        let done = done.clone();
+        handles.push(thread::spawn(move || {
+            let mut i = thread_id;
+            while !done.load(Ordering::SeqCst) {
+                let key_id = i % 100;
+                let value = store.get(format!("key{}", key_id)).unwrap();
+                assert!(value.unwrap().starts_with(&format!("value{}-", key_id)));
+                i += 1;
+            }
+        }));
+    }
+
+    for round in 1..30 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}-29", i)));
+    }
+    Ok(())
+}
+
+// Compacting to another directory should copy only the live keys
+#[test]
+fn compact_to_dest_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..5 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for key_id in 40..50 {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    store.compact_to(dest_dir.path())?;
+    let logs: Vec<_> = WalkDir::new(dest_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .collect();
+    assert_eq!(logs.len(), 1);
+    // the records are framed by binary headers, the keys are written as they are by both formats
+    let content = String::from_utf8_lossy(&fs::read(logs[0].path())?).into_owned();
+    for key_id in 0..50 {
+        assert_eq!(content.contains(&format!("key{}", key_id)), key_id < 40);
+    }
+    if !cfg!(feature = "bincode") {
+        assert_eq!(content.matches("\"Set\"").count(), 40);
+        assert!(!content.contains("\"Remove\""));
+    }
+
+    let dest = KvStore::open(dest_dir.path())?;
+    for key_id in 0..40 {
+        assert_eq!(dest.get(format!("key{}", key_id))?, Some("4".to_owned()));
+    }
+    for key_id in 40..50 {
+        assert_eq!(dest.get(format!("key{}", key_id))?, None);
+    }
+    // the source store is untouched
+    assert_eq!(store.get("key0".to_owned())?, Some("4".to_owned()));
+    assert!(store.compact_to(dest_dir.path()).is_err());
+    Ok(())
+}
+
+// Opening should cut a partially written record and report it
+#[test]
+fn recovery_info_reports_truncation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.last_recovery().is_clean());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut file = OpenOptions::new().append(true).open(&log_path)?;
+    file.write_all(br#"{"Set":{"key":"key3","val"#)?;
+    drop(file);
+    fs::write(temp_dir.path().join("7.log.tmp"), b"garbage")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.last_recovery(),
+        &RecoveryInfo {
+            truncated_records: 1,
+            removed_tmp_files: 1,
+            cleaned_empty_gens: 0,
+        }
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert!(!temp_dir.path().join("7.log.tmp").exists());
+    Ok(())
+}
+
+// Keys should be normalized on every operation and in the log
+#[test]
+fn key_normalizer_lowercase() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().key_normalizer(|key: &str| key.to_lowercase());
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("Foo".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("foo".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("FOO".to_owned())?, Some("value1".to_owned()));
+    store.set("fOO".to_owned(), "value2".to_owned())?;
+    store.set("Bar".to_owned(), "value3".to_owned())?;
+    store.remove("BAR".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("Foo".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("bar".to_owned())?, None);
+    drop(store);
+    // the log holds the normalized key
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("foo".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("Foo".to_owned())?, None);
+    Ok(())
+}
+
+// Should move the value to a new key
+#[test]
+fn rename_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("staged".to_owned(), "value1".to_owned())?;
+    store.rename("staged".to_owned(), "live".to_owned())?;
+    assert_eq!(store.get("staged".to_owned())?, None);
+    assert_eq!(store.get("live".to_owned())?, Some("value1".to_owned()));
+
+    // the rename survives a reopen
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("staged".to_owned())?, None);
+    assert_eq!(store.get("live".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should overwrite the value of an existing destination
+#[test]
+fn rename_over_existing_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("staged".to_owned(), "value2".to_owned())?;
+    store.set("live".to_owned(), "value1".to_owned())?;
+    store.rename("staged".to_owned(), "live".to_owned())?;
+    assert_eq!(store.get("staged".to_owned())?, None);
+    assert_eq!(store.get("live".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should error when renaming a non-existent key
+#[test]
+fn rename_missing_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("live".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        store.rename("staged".to_owned(), "live".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.get("live".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Renaming the least recently used key of a full store should not evict it
+#[test]
+fn rename_in_full_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().max_keys(2))?;
+    store.set("staged".to_owned(), "value1".to_owned())?;
+    store.set("other".to_owned(), "value2".to_owned())?;
+    store.rename("staged".to_owned(), "live".to_owned())?;
+    assert_eq!(store.get("staged".to_owned())?, None);
+    assert_eq!(store.get("live".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should load every key when opened with a key count hint, bounded or not
+#[test]
+fn open_with_expected_keys() -> Result<()> {
+    let options = KvStoreOptions::new().expected_keys(10_000);
+    for options in [options.clone(), options.max_keys(20_000)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        for i in 0..10_000 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for i in 0..10_000 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
+
+// A merge running out of disk should leave the store readable
+#[cfg(target_os = "linux")]
+#[test]
+fn compaction_disk_full() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(100);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    // every write to the merged file of the first merge fails with ENOSPC
+    let tmp_path = temp_dir.path().join("2.log.tmp");
+    std::os::unix::fs::symlink("/dev/full", &tmp_path)?;
+
+    let mut failed = false;
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            let value = format!("{}", iter);
+            match store.set(format!("key{}", key_id), value) {
+                Ok(()) => {}
+                Err(KvsError::CompactionFailed(_)) => failed = true,
+                Err(e) => return Err(e),
+            }
+            if failed {
+                break;
+            }
+        }
+        if failed {
+            break;
+        }
+    }
+    assert!(failed, "expect the merge to fail");
+    assert!(!tmp_path.exists());
+    assert_eq!(store.get("key0".to_owned())?, Some("1".to_owned()));
+
+    // the next merge succeeds
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+    Ok(())
+}
+
+// Only one store can open a directory at a time
+#[test]
+fn open_locked_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let clone = store.clone();
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locked)));
+
+    // the lock is released when the last clone drops
+    drop(store);
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locked)));
+    drop(clone);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+// Errors should work as boxed standard errors with their source
+#[test]
+fn error_is_std_error() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file_path = temp_dir.path().join("file");
+    fs::write(&file_path, "not a directory")?;
+
+    let err: Box<dyn std::error::Error> = match KvStore::open(&file_path) {
+        Err(e @ KvsError::Io(_)) => Box::new(e),
+        Err(e) => panic!("expect an io error, got {}", e),
+        Ok(_) => panic!("expect opening a file as a store to fail"),
+    };
+    let source = err.source().expect("io error should have a source");
+    assert!(source.downcast_ref::<std::io::Error>().is_some());
+    assert_eq!(err.to_string(), source.to_string());
+
+    // `?` converts it into a boxed error
+    let store = KvStore::open(temp_dir.path().join("store"))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    Ok(())
+}
+
+// A crash while writing the merged file leaves its temporary file, which is deleted on reopen
+#[test]
+fn crash_during_merge() -> Result<()> {
+    let fs = MemoryFileSystem::new();
+    let options = KvStoreOptions::new().file_system(Arc::new(fs.clone())).compaction_threshold(100);
+    let store = KvStore::open_with_options("/db", options.clone())?;
+
+    fs.crash_after(".log.tmp", 50);
+    let mut expected = HashMap::new();
+    for i in 0..1000 {
+        let key = format!("key{}", i % 10);
+        let value = format!("value{}", i);
+        let result = store.set(key.clone(), value.clone());
+        // the record is written before the merge it triggers
+        expected.insert(key, value);
+        if let Err(e) = result {
+            assert!(matches!(e, KvsError::CompactionFailed(_)));
+            break;
+        }
+    }
+    assert!(fs.is_crashed());
+    drop(store);
+
+    fs.restart();
+    let store = KvStore::open_with_options("/db", options)?;
+    assert_eq!(store.last_recovery().removed_tmp_files, 1);
+    for (key, value) in expected {
+        assert_eq!(store.get(key)?, Some(value));
+    }
+    Ok(())
+}
+
+// A crash while appending a record leaves it partially written, it is cut on reopen
+#[test]
+fn crash_during_write() -> Result<()> {
+    let fs = MemoryFileSystem::new();
+    let options = KvStoreOptions::new().file_system(Arc::new(fs.clone()));
+    let store = KvStore::open_with_options("/db", options.clone())?;
+
+    fs.crash_after("/db/1.log", 100);
+    let mut written = 0;
+    while store.set(format!("key{}", written), "value".to_owned()).is_ok() {
+        written += 1;
+    }
+    assert!(written > 0);
+    drop(store);
+
+    fs.restart();
+    let store = KvStore::open_with_options("/db", options)?;
+    assert_eq!(store.last_recovery().truncated_records, 1);
+    for i in 0..written {
+        assert_eq!(store.get(format!("key{}", i))?, Some("value".to_owned()));
+    }
+    assert_eq!(store.get(format!("key{}", written))?, None);
+    Ok(())
+}
+
+// With auto compaction off garbage piles up until `compact` is called
+#[test]
+fn auto_compaction_off() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().auto_compaction(AutoCompaction::Off);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    for i in 0..1000 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    let usage = store.disk_usage()?;
+    assert_eq!(usage.generations, 1);
+    assert!(usage.total_bytes > 100 * usage.live_bytes);
+    let live_bytes = usage.live_bytes;
+
+    store.compact()?;
+    let usage = store.disk_usage()?;
+    assert_eq!(usage.live_bytes, live_bytes);
+    let stats = store.store_stats()?;
+    assert_eq!(stats.generations, 1);
+    assert_eq!(stats.unmerged_bytes, 0);
+    // a compressed merged log is not the size of its records
+    if !cfg!(feature = "compression") {
+        assert_eq!(usage.total_bytes, usage.live_bytes);
+    }
+    assert_eq!(store.get("key".to_owned())?, Some("value999".to_owned()));
+    Ok(())
+}
+
+// Outside the window no compaction fires
+#[test]
+fn auto_compaction_window() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let day = 24 * 60 * 60;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() % day;
+    // a window starting an hour from now and ending two hours later
+    let window = AutoCompaction::Window {
+        start: Duration::from_secs((now + 60 * 60) % day),
+        end: Duration::from_secs((now + 3 * 60 * 60) % day),
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().auto_compaction(window))?;
+
+    for i in 0..1000 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    assert_eq!(store.disk_usage()?.generations, 1);
+    Ok(())
+}
+
+// Automatic compactions should follow the window on the clock of the store
+#[test]
+fn auto_compaction_window_fake_clock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hour = Duration::from_secs(60 * 60);
+    let now = Arc::new(Mutex::new(UNIX_EPOCH + 10 * hour));
+    let clock = now.clone();
+    let options = KvStoreOptions::new()
+        .auto_compaction(AutoCompaction::Window { start: 2 * hour, end: 4 * hour })
+        .compaction_threshold(100)
+        .clock(move || *clock.lock().unwrap());
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    for i in 0..1000 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    assert_eq!(store.stats()?.compactions, 0);
+
+    // the next day within the window
+    *now.lock().unwrap() += 17 * hour;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.stats()?.compactions, 1);
+    Ok(())
+}
+
+// The type of a value should be kept in the log, through renames and merges
+#[test]
+fn typed_values_persist() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_typed("int".to_owned(), TypedValue::Int(1))?;
+    store.set_typed("bool".to_owned(), TypedValue::Bool(false))?;
+    store.rename("bool".to_owned(), "flag".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.increment("int".to_owned(), 1)?, 2);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_typed("int".to_owned())?, Some(TypedValue::Int(2)));
+    assert_eq!(store.get_typed("flag".to_owned())?, Some(TypedValue::Bool(false)));
+    assert_eq!(store.get("flag".to_owned())?, Some("false".to_owned()));
+    Ok(())
+}
+
+// A multi-key read should never see a write to one key of a pair without the other
+#[test]
+fn multi_get_consistent_pairs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("balance".to_owned(), "0".to_owned())?;
+    store.set("version".to_owned(), "0".to_owned())?;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let store = store.clone();
+        let done = done.clone();
+        thread::spawn(move || -> Result<()> {
+            let mut i = 0;
+            while !done.load(Ordering::SeqCst) {
+                i += 1;
+                store.set("balance".to_owned(), i.to_string())?;
+                store.set("version".to_owned(), i.to_string())?;
+            }
+            Ok(())
+        })
+    };
+
+    for _ in 0..2000 {
+        let values = store.multi_get_consistent(vec!["balance".to_owned(), "version".to_owned()])?;
+        let balance: u64 = values[0].as_ref().unwrap().parse().unwrap();
+        let version: u64 = values[1].as_ref().unwrap().parse().unwrap();
+        // between the two sets of a round the balance is one ahead
+        assert!(balance == version || balance == version + 1, "{} {}", balance, version);
+    }
+    done.store(true, Ordering::SeqCst);
+    writer.join().unwrap()?;
+    Ok(())
+}
+
+// KvMap should behave like a HashMap and persist across reopen
+#[test]
+fn kv_map_like_hash_map() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let map = KvMap::open(temp_dir.path())?;
+
+    assert_eq!(map.insert("a".to_owned(), "1".to_owned())?, None);
+    assert_eq!(map.insert("a".to_owned(), "2".to_owned())?, Some("1".to_owned()));
+    assert!(map.contains_key("a"));
+    assert!(!map.contains_key("b"));
+    assert_eq!(map.remove("b")?, None);
+
+    // counting words with the entry API
+    for word in "the cat saw the other cat and the dog".split(' ') {
+        map.entry(word.to_owned())
+            .and_modify(|count| *count = (count.parse::<u32>().unwrap() + 1).to_string())
+            .or_insert("1".to_owned())?;
+    }
+    assert_eq!(map.entry("the".to_owned()).or_insert_with(|| unreachable!())?, "3");
+    assert_eq!(map.entry("bird".to_owned()).or_insert("0".to_owned())?, "0");
+    assert_eq!(map.remove("a")?, Some("2".to_owned()));
+    drop(map);
+
+    let map = KvMap::open(temp_dir.path())?;
+    assert_eq!(map.get("the")?, Some("3".to_owned()));
+    assert_eq!(map.get("cat")?, Some("2".to_owned()));
+    assert_eq!(map.get("dog")?, Some("1".to_owned()));
+    assert_eq!(map.get("bird")?, Some("0".to_owned()));
+    assert_eq!(map.get("a")?, None);
+    Ok(())
+}
+
+// Concurrent entry updates should not lose increments
+#[test]
+fn kv_map_entry_is_atomic() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let map = KvMap::open(temp_dir.path())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let map = map.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..100 {
+                    map.entry("counter".to_owned())
+                        .and_modify(|count| *count = (count.parse::<u32>().unwrap() + 1).to_string())
+                        .or_insert("1".to_owned())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(map.get("counter")?, Some("400".to_owned()));
+    Ok(())
+}
+
+// A replica should see the writes of the store writing its directory once reloaded
+#[test]
+fn replica_reload() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let replica = KvStore::open_replica(temp_dir.path())?;
+    assert_eq!(replica.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(replica.get("key3".to_owned())?, None);
+
+    replica.reload()?;
+    assert_eq!(replica.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(replica.get("key2".to_owned())?, None);
+    assert!(matches!(replica.set("key4".to_owned(), "value4".to_owned()), Err(KvsError::ReadOnly)));
+
+    // a compaction deletes the logs the replica loaded
+    for i in 0..1000 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    store.remove("key3".to_owned())?;
+    store.compact()?;
+    replica.reload()?;
+    assert_eq!(replica.get("key1".to_owned())?, Some("value999".to_owned()));
+    assert_eq!(replica.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// A bounded store should evict the oldest keys when a set goes over its bounds
+#[test]
+fn eviction_keeps_newest_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_keys(3).eviction_policy(EvictionPolicy::Fifo);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..7 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    for i in 7..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    drop(store);
+
+    // the evictions are persisted
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key6".to_owned())?, None);
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    assert_eq!(store.get("key7".to_owned())?, None);
+    assert_eq!(store.get("key8".to_owned())?, Some("value8".to_owned()));
+
+    // reads keep keys of an LRU store
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().max_keys(2))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // a byte budget evicts as many keys as needed
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().max_bytes(200))?;
+    for i in 0..4 {
+        store.set(format!("key{}", i), "v".repeat(30))?;
+    }
+    store.set("big".to_owned(), "v".repeat(150))?;
+    assert_eq!(store.get("big".to_owned())?, Some("v".repeat(150)));
+    for i in 0..4 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    Ok(())
+}
+
+// Concurrent sets of large values should each be written whole and indexed at their own record
+#[test]
+fn concurrent_large_sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handles: Vec<_> = (0..4u8)
+        .map(|id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..20 {
+                    let fill = char::from(b'a' + id).to_string();
+                    store.set(format!("key{}-{}", id, i % 5), format!("{}{}", i, fill.repeat(100_000)))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for id in 0..4u8 {
+            let fill = char::from(b'a' + id).to_string();
+            for i in 15..20 {
+                let expected = format!("{}{}", i, fill.repeat(100_000));
+                assert_eq!(store.get(format!("key{}-{}", id, i % 5))?, Some(expected));
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// The history of a key should list its records left in the logs in order
+#[test]
+fn key_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().auto_compaction(AutoCompaction::Off);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "other".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+
+    let history = store.history("key1")?;
+    let values: Vec<_> = history.iter().map(|entry| entry.value.as_deref()).collect();
+    assert_eq!(values, vec![Some("value1"), Some("value2"), Some("value3")]);
+    assert!(history.windows(2).all(|pair| pair[0].offset < pair[1].offset));
+    assert_eq!(store.history("missing")?, Vec::new());
+
+    // the records of an earlier generation come first
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.remove("key1".to_owned())?;
+    let history = store.history("key1")?;
+    assert_eq!(history.len(), 4);
+    assert_eq!(history[3].value, None);
+    assert!(history[3].generation > history[2].generation);
+
+    // a compaction leaves only the live record
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.compact()?;
+    let history = store.history("key1")?;
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].value, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Stores sharing a compaction limiter of 1 should never merge at the same time
+#[test]
+fn compaction_limiter_serializes_merges() -> Result<()> {
+    let limiter = CompactionLimiter::new(1);
+    let shards: Vec<_> = (0..4)
+        .map(|_| {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let options = KvStoreOptions::new().compaction_limiter(limiter.clone()).compaction_threshold(100);
+            let store = KvStore::open_with_options(temp_dir.path(), options)?;
+            Ok((store, temp_dir))
+        })
+        .collect::<Result<_>>()?;
+    let barrier = Arc::new(Barrier::new(shards.len()));
+    let handles: Vec<_> = shards
+        .iter()
+        .map(|(store, _)| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..2000 {
+                    store.set(format!("key{}", i % 100), format!("value{}", i))?;
+                }
+                barrier.wait();
+                store.compact()?;
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(limiter.peak(), 1);
+    for (store, _) in &shards {
+        // the automatic compactions are skipped while another store compacts
+        assert!(store.stats()?.compactions > 0);
+        assert_eq!(store.get("key99".to_owned())?, Some("value1999".to_owned()));
+    }
+    Ok(())
+}
+
+// Iterating a prefix should visit only the keys starting with it, in order
+#[test]
+fn for_each_prefix_in_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["user:2", "session", "user:1", "session:1", "user", "users"] {
+        store.set(key.to_string(), format!("value of {}", key))?;
+    }
+    store.remove("user:2".to_owned())?;
+
+    let mut visited = Vec::new();
+    store.for_each_prefix("user:", |key, value| {
+        assert_eq!(value, format!("value of {}", key));
+        visited.push(key);
+        Ok(())
+    })?;
+    assert_eq!(visited, vec!["user:1".to_owned()]);
+    let mut count = 0;
+    store.for_each_prefix("", |_, _| {
+        count += 1;
+        Ok(())
+    })?;
+    assert_eq!(count, 5);
+    Ok(())
+}
+
+// Removing a prefix should remove only the keys starting with it and count them
+#[test]
+fn remove_prefix_counts_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..50 {
+        store.set(format!("session:{}", i), "s".to_owned())?;
+        store.set(format!("user:{}", i), "u".to_owned())?;
+    }
+    store.set("session".to_owned(), "not under the prefix".to_owned())?;
+
+    assert_eq!(store.remove_prefix("session:".to_owned())?, 50);
+    assert_eq!(store.remove_prefix("session:".to_owned())?, 0);
+    for i in 0..50 {
+        assert_eq!(store.get(format!("session:{}", i))?, None);
+        assert_eq!(store.get(format!("user:{}", i))?, Some("u".to_owned()));
+    }
+    assert_eq!(store.get("session".to_owned())?, Some("not under the prefix".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("session:7".to_owned())?, None);
+    assert_eq!(store.get("user:7".to_owned())?, Some("u".to_owned()));
+    Ok(())
+}
+
+// Values above the separation threshold should round trip through the value logs
+// as those below it through the key log, across compactions and reopens
+#[test]
+fn value_separation_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().value_separation(64).auto_compaction(AutoCompaction::Off);
+    let value_logs = || {
+        fs::read_dir(temp_dir.path()).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("vlog".as_ref()))
+            .count()
+    };
+    let large = |i: usize| format!("{:0>100}", i);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..100 {
+        store.set(format!("small{}", i), format!("value{}", i))?;
+        store.set(format!("large{}", i), large(i))?;
+    }
+    assert_eq!(value_logs(), 1);
+    let mut buf = String::new();
+    assert!(store.get_into("large7", &mut buf)?);
+    assert_eq!(buf, large(7));
+    assert_eq!(store.get("small7".to_owned())?, Some("value7".to_owned()));
+    assert_eq!(store.history("large7")?[0].value, Some(large(7)));
+
+    // a value log mostly overwritten has its live values moved by the compaction
+    for round in 1..3 {
+        for i in 10..100 {
+            store.set(format!("large{}", i), large(i + round * 1000))?;
+        }
+    }
+    store.compact()?;
+    let stats = store.store_stats()?;
+    assert_eq!(stats.generations, 1);
+    assert_eq!(stats.unmerged_bytes, 0);
+    let usage = store.disk_usage()?;
+    if !cfg!(feature = "compression") {
+        assert_eq!(usage.total_bytes, usage.live_bytes);
+    }
+    assert_eq!(value_logs(), 1);
+    store.rename("large3".to_owned(), "moved".to_owned())?;
+    store.set("large4".to_owned(), "small now".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("small{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("large3".to_owned())?, None);
+    assert_eq!(store.get("moved".to_owned())?, Some(large(3)));
+    assert_eq!(store.get("large4".to_owned())?, Some("small now".to_owned()));
+    assert_eq!(store.get("large5".to_owned())?, Some(large(5)));
+    assert_eq!(store.get("large50".to_owned())?, Some(large(2050)));
+
+    // the compacted copy has the values in its records
+    let dest = temp_dir.path().join("dest");
+    store.compact_to(&dest)?;
+    drop(store);
+    let copy = KvStore::open(&dest)?;
+    assert_eq!(copy.get("large50".to_owned())?, Some(large(2050)));
+    assert_eq!(copy.get("small50".to_owned())?, Some("value50".to_owned()));
+    Ok(())
+}
+
+// A compaction bounded per pass should take several passes, with writes between
+// them, and keep every key correct once done
+#[test]
+fn compaction_in_passes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().max_entries_per_pass(10).auto_compaction(AutoCompaction::Off);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for round in 0..5 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+    }
+
+    // writes land between the passes of the compaction
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 0..50 {
+                store.set(format!("key{}", i * 2), format!("new{}", i))?;
+                store.remove(format!("key{}", i * 2 + 1))?;
+            }
+            Ok(())
+        })
+    };
+    store.compact()?;
+    writer.join().unwrap()?;
+    // the writes may all have landed before the last pass, leaving nothing stale
+    let stale = store.store_stats()?.unmerged_bytes > 0;
+    store.compact()?;
+    assert_eq!(store.stats()?.compactions, 1 + stale as u64);
+    // a log per pass of 10 keys at most
+    assert!(store.disk_usage()?.generations >= 6);
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..50 {
+            assert_eq!(store.get(format!("key{}", i * 2))?, Some(format!("new{}", i)));
+            assert_eq!(store.get(format!("key{}", i * 2 + 1))?, None);
+        }
+        Ok(())
+    };
+    check(&store)?;
+    assert_eq!(store.store_stats()?.unmerged_bytes, 0);
+    let usage = store.disk_usage()?;
+    if !cfg!(feature = "compression") {
+        assert_eq!(usage.total_bytes, usage.live_bytes);
+    }
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), options())?)
+}
+
+// The changes since a cursor should be exactly the keys set or removed after it,
+// and every live key once a compaction ran past it
+#[test]
+fn changes_since_cursor() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().auto_compaction(AutoCompaction::Off);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let cursor = store.cursor();
+
+    store.set("key1".to_owned(), "first".to_owned())?;
+    store.set("key1".to_owned(), "second".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("new".to_owned(), "value".to_owned())?;
+    store.set("gone".to_owned(), "value".to_owned())?;
+    store.remove("gone".to_owned())?;
+    let changes = store.changes_since(cursor)?;
+    assert!(!changes.full);
+    assert_eq!(changes.changes, vec![
+        Change::Remove { key: "gone".to_owned() },
+        Change::Set { key: "key1".to_owned(), value: "second".to_owned() },
+        Change::Remove { key: "key2".to_owned() },
+        Change::Set { key: "new".to_owned(), value: "value".to_owned() },
+    ]);
+    assert!(store.changes_since(changes.cursor)?.changes.is_empty());
+
+    // the cursor still holds across a reopen
+    let cursor = changes.cursor;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key3".to_owned(), "reopened".to_owned())?;
+    let changes = store.changes_since(cursor)?;
+    assert_eq!(changes.changes, vec![Change::Set { key: "key3".to_owned(), value: "reopened".to_owned() }]);
+
+    // a compaction leaves only the live keys to return
+    store.compact()?;
+    store.set("key4".to_owned(), "compacted".to_owned())?;
+    let changes = store.changes_since(changes.cursor)?;
+    assert!(changes.full);
+    assert_eq!(changes.changes.len(), 10);
+    assert!(changes.changes.contains(&Change::Set { key: "key4".to_owned(), value: "compacted".to_owned() }));
+    assert!(!changes.changes.iter().any(|change| matches!(change, Change::Set { key, .. } if key == "key2")));
+    Ok(())
+}
+
+// Reads should see the records a lazily flushed store left in its write buffer
+#[test]
+fn lazy_flush_reads_buffered_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_bytes = || -> u64 {
+        fs::read_dir(temp_dir.path()).unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+    let options = KvStoreOptions::new().lazy_flush(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key9".to_owned())?;
+    assert_eq!(log_bytes(), 0);
+
+    let reader = store.clone();
+    assert_eq!(thread::spawn(move || reader.get("key3".to_owned())).join().unwrap()?, Some("value3".to_owned()));
+    assert!(log_bytes() > 0);
+    store.set("key1".to_owned(), "buffered".to_owned())?;
+    let mut buf = String::new();
+    assert!(store.get_into("key1", &mut buf)?);
+    assert_eq!(buf, "buffered");
+    store.set("key2".to_owned(), "buffered".to_owned())?;
+    assert_eq!(store.try_get("key2".to_owned())?, Some(Some("buffered".to_owned())));
+    assert_eq!(store.get("key9".to_owned())?, None);
+
+    store.set("key4".to_owned(), "flushed".to_owned())?;
+    store.flush()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key4".to_owned())?, Some("flushed".to_owned()));
+    assert_eq!(store.get("key9".to_owned())?, None);
+    Ok(())
+}
+
+// The file system of the operating system, counting the bytes read and the fsyncs
+#[derive(Debug, Default)]
+struct CountingFileSystem {
+    bytes_read: Arc<AtomicU64>,
+    syncs: Arc<AtomicU64>,
+    dir_syncs: Arc<AtomicU64>,
+}
+
+struct CountingFile {
+    file: Box<dyn ReadFile>,
+    bytes_read: Arc<AtomicU64>,
+}
+
+impl Read for CountingFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = self.file.read(buf)?;
+        self.bytes_read.fetch_add(length as u64, Ordering::SeqCst);
+        Ok(length)
+    }
+}
+
+impl Seek for CountingFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+struct SyncCountingFile {
+    file: Box<dyn WriteFile>,
+    syncs: Arc<AtomicU64>,
+}
+
+impl Write for SyncCountingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for SyncCountingFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl WriteFile for SyncCountingFile {
+    fn sync_all(&self) -> io::Result<()> {
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        self.file.sync_all()
+    }
+}
+
+impl FileSystem for CountingFileSystem {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        OsFileSystem.create_dir_all(path)
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        let file = OsFileSystem.open_read(path)?;
+        Ok(Box::new(CountingFile { file, bytes_read: self.bytes_read.clone() }))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        let file = OsFileSystem.open_append(path)?;
+        Ok(Box::new(SyncCountingFile { file, syncs: self.syncs.clone() }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        OsFileSystem.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        OsFileSystem.remove_file(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        self.dir_syncs.fetch_add(1, Ordering::SeqCst);
+        OsFileSystem.sync_dir(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        OsFileSystem.read_dir(path)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        OsFileSystem.file_len(path)
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        OsFileSystem.truncate(path, len)
+    }
+
+    fn try_lock(&self, path: &Path) -> io::Result<Option<FileLock>> {
+        OsFileSystem.try_lock(path)
+    }
+}
+
+// The writes should be fsynced as often as the durability asks
+#[test]
+fn durability_fsyncs() -> Result<()> {
+    let cases = [
+        (Durability::None, 0),
+        (Durability::Flush, 0),
+        (Durability::Fsync, 10),
+        (Durability::EveryN(4), 2),
+    ];
+    for &(durability, syncs) in &cases {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let fs = Arc::new(CountingFileSystem::default());
+        let options = KvStoreOptions::new().file_system(fs.clone()).durability(durability);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for i in 0..8 {
+            store.set(format!("key{}", i), "value".to_owned())?;
+        }
+        store.remove("key0".to_owned())?;
+        store.remove("key1".to_owned())?;
+        assert_eq!(fs.syncs.load(Ordering::SeqCst), syncs, "{:?}", durability);
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key7".to_owned())?, Some("value".to_owned()));
+    }
+    Ok(())
+}
+
+// A merge should fsync the merged log and the directory before deleting the
+// logs it replaces, unless the durability asks for no fsync
+#[test]
+fn merge_fsyncs() -> Result<()> {
+    for &(durability, syncs) in &[(Durability::None, 0), (Durability::Flush, 1)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let fs = Arc::new(CountingFileSystem::default());
+        let options = KvStoreOptions::new()
+            .file_system(fs.clone())
+            .durability(durability)
+            .auto_compaction(AutoCompaction::Off);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for i in 0..100 {
+            store.set(format!("key{}", i % 10), format!("value{}", i))?;
+        }
+        assert_eq!(fs.syncs.load(Ordering::SeqCst), 0);
+        store.compact()?;
+        assert_eq!(fs.syncs.load(Ordering::SeqCst), syncs, "{:?}", durability);
+        assert_eq!(fs.dir_syncs.load(Ordering::SeqCst), syncs, "{:?}", durability);
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key9".to_owned())?, Some("value99".to_owned()));
+    }
+    Ok(())
+}
+
+// Warming up should read every log file and value log through, reads still work after it
+#[test]
+fn warmup_reads_every_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().value_separation(64);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store.set(format!("large{}", i), format!("{:0>100}", i))?;
+    }
+    drop(store);
+    let dir_bytes: u64 = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+
+    let file_system = Arc::new(CountingFileSystem::default());
+    let bytes_read = file_system.bytes_read.clone();
+    let store = KvStore::open_with_options(temp_dir.path(), options.file_system(file_system))?;
+    let opened = bytes_read.load(Ordering::SeqCst);
+    store.warmup()?;
+    assert_eq!(bytes_read.load(Ordering::SeqCst) - opened, dir_bytes);
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(store.get(format!("large{}", i))?, Some(format!("{:0>100}", i)));
+    }
+    Ok(())
+}
+
+// The debug dump should describe the generations, stats and options of the store
+#[test]
+fn debug_dump_fields() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().value_separation(64);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store.set(format!("large{}", i), format!("{:0>100}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.compact()?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+
+    let dump = store.debug_dump()?;
+    for field in ["write_generation", "merged_generation", "unmerged_bytes", "live_bytes", "stats", "generations", "options"] {
+        assert!(dump.get(field).is_some(), "missing {}", field);
+    }
+    assert_eq!(dump["stats"]["live_keys"], 200);
+    assert_eq!(dump["options"]["value_threshold"], 64);
+    let generations = dump["generations"].as_array().unwrap();
+    assert!(generations.iter().any(|generation| generation["merged"] == true));
+    assert!(generations.iter().any(|generation| generation["value_log_bytes"].as_u64() > Some(0)));
+    assert!(dump["write_generation"].as_u64() > dump["merged_generation"].as_u64());
+    Ok(())
+}
+
+// A write taking the garbage over a tiny threshold should compact the logs
+#[test]
+fn compaction_threshold_tiny() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.stats()?.compactions, 0);
+    store.set("key".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.stats()?.compactions, 1);
+    store.remove("key".to_owned())?;
+    assert_eq!(store.stats()?.compactions, 2);
+    Ok(())
+}
+
+// No write should compact the logs while the garbage stays under a huge threshold
+#[test]
+fn compaction_threshold_huge() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(u64::MAX);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..10_000 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    for i in 0..10 {
+        store.remove(format!("key{}", i))?;
+    }
+    assert_eq!(store.stats()?.compactions, 0);
+    assert!(store.disk_usage()?.total_bytes > 100_000);
+    Ok(())
+}
+
+// Concurrent writes should all read back while compactions run in the background
+#[test]
+fn background_compaction_concurrent_sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .background_compaction(true)
+        .compaction_threshold(4096)
+        .max_entries_per_pass(50);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..2000 {
+                    store.set(format!("key{}_{}", thread_id, i % 200), format!("value{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    // the compactor may still be running passes
+    for _ in 0..100 {
+        if store.stats()?.compactions > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(store.stats()?.compactions > 0);
+    for thread_id in 0..4 {
+        for i in 1800..2000 {
+            assert_eq!(store.get(format!("key{}_{}", thread_id, i % 200))?, Some(format!("value{}", i)));
+        }
+    }
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for thread_id in 0..4 {
+        assert_eq!(store.get(format!("key{}_199", thread_id))?, Some("value1999".to_owned()));
+    }
+    Ok(())
+}
+
+// A byte flipped in a record should fail its checksum instead of its deserialization
+#[test]
+fn corrupt_record_checksum() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let log = temp_dir.path().join("1.log");
+    let valid_len = fs::metadata(&log)?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let record_len = fs::metadata(&log)?.len() - valid_len;
+
+    // flip the last byte of the value of key2
+    let mut file = OpenOptions::new().read(true).write(true).open(&log)?;
+    file.seek(SeekFrom::Start(valid_len + record_len - 4))?;
+    let mut byte = [0; 1];
+    file.read_exact(&mut byte)?;
+    file.seek(SeekFrom::Current(-1))?;
+    file.write_all(&[byte[0] ^ 0x01])?;
+    drop(file);
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    match store.get("key2".to_owned()) {
+        Err(KvsError::Corruption { generation: 1, offset }) => assert_eq!(offset, valid_len),
+        other => panic!("expect corruption, got {:?}", other),
+    }
+    drop(store);
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { generation: 1, offset }) => assert_eq!(offset, valid_len),
+        other => panic!("expect corruption, got {:?}", other.map(|_| ())),
+    }
+    Ok(())
+}
+
+// Logs written before records were framed should still open, mixed with framed records
+#[test]
+fn open_unframed_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        br#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Remove":{"key":"key1"}}"#,
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    // the merged file copies the unframed record as it is
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Opening should cut half a framed record at the end, but fail on a corrupt record before it
+#[test]
+fn truncated_framed_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let content = fs::read(&log)?;
+    let record_len = content.len() / 2;
+    // half of a copy of the record of key2
+    let mut file = OpenOptions::new().append(true).open(&log)?;
+    file.write_all(&content[record_len..record_len + record_len / 2])?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_recovery().truncated_records, 1);
+    assert_eq!(fs::metadata(&log)?.len(), content.len() as u64);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // flip a byte of the value of key1, the record of key2 follows it
+    let mut content = content;
+    content[record_len - 3] ^= 0x01;
+    fs::write(&log, &content)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { generation: 1, offset: 0 }) => {}
+        other => panic!("expect corruption, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(fs::metadata(&log)?.len(), content.len() as u64);
+    Ok(())
+}
+
+// A corrupt length field reads as a partial record, opening should fail rather
+// than cut the whole records after it
+#[test]
+fn corrupt_record_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 1..=5 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log)?;
+    let record_len = content.len() / 5;
+    // bit 12 of the big-endian length of the record of key2
+    content[record_len + 2] ^= 0x10;
+    fs::write(&log, &content)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { generation: 1, offset }) => assert_eq!(offset, record_len as u64),
+        other => panic!("expect corruption, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(fs::metadata(&log)?.len(), content.len() as u64);
+    Ok(())
+}
+
+// Keys should read back from a merged log compressed by zstd, also after a reopen
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_merge_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().auto_compaction(AutoCompaction::Off);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let value = |i: usize| format!("the value of key {} is a line of text, repeated. ", i).repeat(4);
+    for i in 0..3000 {
+        store.set(format!("key{}", i), format!("stale{}", i))?;
+        store.set(format!("key{}", i), value(i))?;
+    }
+    let plain_bytes = store.disk_usage()?.total_bytes;
+    store.compact()?;
+
+    let extension_count = |extension: &str| {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some(extension.as_ref()))
+            .count()
+    };
+    assert_eq!(extension_count("zlog"), 1);
+    assert!(store.disk_usage()?.total_bytes * 4 < plain_bytes);
+    for i in 0..3000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+    }
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in (0..3000).rev() {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+    }
+    Ok(())
+}
+
+// An export should import into an empty store and over the keys of a populated one
+#[test]
+fn import_exported_keys() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = KvStore::open(source_dir.path())?;
+    for i in 0..1000 {
+        source.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..100 {
+        source.set(format!("key{}", i), format!("new{}", i))?;
+        source.remove(format!("key{}", i + 900))?;
+    }
+    source.set_typed("int".to_owned(), TypedValue::Int(42))?;
+    let mut dump = Vec::new();
+    source.export(&mut dump)?;
+    let mut expected = HashMap::new();
+    source.for_each(|key, value| {
+        expected.insert(key, value);
+        Ok(())
+    })?;
+
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = KvStore::open(dest_dir.path())?;
+    dest.set("key5".to_owned(), "overwritten".to_owned())?;
+    dest.set("other".to_owned(), "kept".to_owned())?;
+    assert_eq!(dest.import(&dump[..])?, 901);
+    let mut imported = HashMap::new();
+    dest.for_each(|key, value| {
+        imported.insert(key, value);
+        Ok(())
+    })?;
+    assert_eq!(imported.remove("other"), Some("kept".to_owned()));
+    assert_eq!(imported, expected);
+    assert_eq!(dest.get_typed("int".to_owned())?, Some(TypedValue::Int(42)));
+    Ok(())
+}
+
+// A truncated or corrupted export should fail the import with an error
+#[test]
+fn import_damaged_stream() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = KvStore::open(source_dir.path())?;
+    source.set("key1".to_owned(), "value1".to_owned())?;
+    source.set("key2".to_owned(), "value2".to_owned())?;
+    let mut dump = Vec::new();
+    source.export(&mut dump)?;
+
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = KvStore::open(dest_dir.path())?;
+    match dest.import(&dump[..dump.len() - 3]) {
+        Err(KvsError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+        other => panic!("expect an unexpected EOF, got {:?}", other),
+    }
+    // the key before the truncated record is imported
+    assert_eq!(dest.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let last = dump.len() - 1;
+    dump[last] ^= 0xff;
+    assert!(matches!(dest.import(&dump[..]), Err(KvsError::Corruption { generation: 0, .. })));
+    Ok(())
+}
+
+// The length should count the live keys, also after a reopen
+#[test]
+fn len_counts_live_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    for i in 0..3 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    store.set("key0".to_owned(), "overwritten".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.len(), 2);
+    assert!(!store.is_empty());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 2);
+    Ok(())
+}
+
+// contains_key should tell present, absent and removed keys apart without reading the logs
+#[test]
+fn contains_key_reads_no_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = Arc::new(CountingFileSystem::default());
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().file_system(fs.clone()))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let bytes_read = fs.bytes_read.load(Ordering::SeqCst);
+    assert!(store.contains_key("key1".to_owned())?);
+    assert!(!store.contains_key("key2".to_owned())?);
+    assert!(!store.contains_key("key3".to_owned())?);
+    assert_eq!(fs.bytes_read.load(Ordering::SeqCst), bytes_read);
+    Ok(())
+}
+
+// A batch should set every pair with a single fsync, separated values included
+#[test]
+fn set_batch_single_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = Arc::new(CountingFileSystem::default());
+    let options = KvStoreOptions::new()
+        .file_system(fs.clone())
+        .durability(Durability::Fsync)
+        .value_separation(64);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+    let syncs = fs.syncs.load(Ordering::SeqCst);
+    let value = |i: usize| if i >= 900 { format!("{:0>100}", i) } else { format!("value{}", i) };
+    store.set_batch((0..1000).map(|i| (format!("key{}", i), value(i))).collect())?;
+    // the value log is fsynced before the log
+    assert_eq!(fs.syncs.load(Ordering::SeqCst), syncs + 2);
+    assert_eq!(store.len(), 1000);
+    assert_eq!(store.stats()?.sets, 1001);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+    }
+    Ok(())
+}
+
+// A batch failing midway should set none of its keys, now or after reopening
+#[test]
+fn set_batch_failure_sets_nothing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = Arc::new(FailingFileSystem::default());
+    // a small buffer writes a part of the batch to the file before the failure
+    let options = KvStoreOptions::new().file_system(fs.clone()).buffer_size(256);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+    *fs.write_budget.lock().unwrap() = Some(1000);
+    let pairs = (0..100).map(|i| (format!("key{}", i), format!("value{}", i))).collect();
+    assert!(store.set_batch(pairs).is_err());
+    *fs.write_budget.lock().unwrap() = None;
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("old".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.len(), 2);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("old".to_owned()));
+    assert_eq!(store.get("key100".to_owned())?, Some("value100".to_owned()));
+    for i in 1..100 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    Ok(())
+}
+
+// The file system of the operating system, failing the writes past the bytes
+// of the budget once one is given, and the removes while asked to
+#[derive(Debug, Default)]
+struct FailingFileSystem {
+    write_budget: Arc<Mutex<Option<u64>>>,
+    fail_removes: AtomicBool,
+}
+
+struct FailingFile {
+    file: Box<dyn WriteFile>,
+    write_budget: Arc<Mutex<Option<u64>>>,
+}
+
+impl Write for FailingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(budget) = self.write_budget.lock().unwrap().as_mut() {
+            if (buf.len() as u64) > *budget {
+                return Err(io::Error::other("write failed"));
+            }
+            *budget -= buf.len() as u64;
+        }
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for FailingFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl WriteFile for FailingFile {
+    fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+impl FileSystem for FailingFileSystem {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        OsFileSystem.create_dir_all(path)
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        OsFileSystem.open_read(path)
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        let file = OsFileSystem.open_append(path)?;
+        Ok(Box::new(FailingFile { file, write_budget: self.write_budget.clone() }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        OsFileSystem.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        if self.fail_removes.load(Ordering::SeqCst) {
+            return Err(io::Error::other("remove failed"));
+        }
+        OsFileSystem.remove_file(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        OsFileSystem.sync_dir(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        OsFileSystem.read_dir(path)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        OsFileSystem.file_len(path)
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        OsFileSystem.truncate(path, len)
+    }
+
+    fn try_lock(&self, path: &Path) -> io::Result<Option<FileLock>> {
+        OsFileSystem.try_lock(path)
+    }
+}
+
+// A batch get should return the values in the order of the keys, across generations
+#[test]
+fn get_batch_positions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    // the keys of the second generation are read before those of the first
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i * 10), format!("new{}", i))?;
+    }
+    store.remove("key5".to_owned())?;
+    let keys: Vec<String> = ["key99", "key0", "missing", "key5", "key10", "key7", "key0"]
+        .iter()
+        .map(|&key| key.to_owned())
+        .collect();
+    let values = store.get_batch(keys)?;
+    let expected = [Some("value99"), Some("new0"), None, None, Some("new1"), Some("value7"), Some("new0")];
+    assert_eq!(values, expected.iter().map(|value| value.map(str::to_owned)).collect::<Vec<_>>());
+    assert_eq!(store.stats()?.gets, 7);
+    Ok(())
+}
+
+// Every read path should count a get per key it reads
+#[test]
+fn reads_count_gets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["a", "ab", "b"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    let mut buf = String::new();
+    assert!(store.get_into("a", &mut buf)?);
+    assert!(!store.get_into("missing", &mut buf)?);
+    assert_eq!(store.stats()?.gets, 2);
+    assert_eq!(store.scan_prefix("a".to_owned())?.len(), 2);
+    assert_eq!(store.range("a".to_owned(), "b".to_owned())?.len(), 2);
+    assert_eq!(store.stats()?.gets, 6);
+    store.for_each(|_, _| Ok(()))?;
+    store.for_each_prefix("b", |_, _| Ok(()))?;
+    assert_eq!(store.stats()?.gets, 10);
+    Ok(())
+}
+
+// A prefix scan should return exactly the keys starting with the prefix, in order
+#[test]
+fn scan_prefix_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["abc", "b", "a", "ab", "abd"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove("abd".to_owned())?;
+    let pairs = |pairs: &[&str]| -> Vec<(String, String)> {
+        pairs.iter().map(|key| (key.to_string(), format!("value-{}", key))).collect()
+    };
+    assert_eq!(store.scan_prefix("ab".to_owned())?, pairs(&["ab", "abc"]));
+    assert_eq!(store.scan_prefix("".to_owned())?, pairs(&["a", "ab", "abc", "b"]));
+    assert_eq!(store.scan_prefix("abc".to_owned())?, pairs(&["abc"]));
+    assert_eq!(store.scan_prefix("c".to_owned())?, pairs(&[]));
+    Ok(())
+}
+
+// A range should hold the live keys from its start to its end, excluded
+#[test]
+fn range_of_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key4".to_owned())?;
+    store.remove("key5".to_owned())?;
+    let pairs = |keys: &[u32]| -> Vec<(String, String)> {
+        keys.iter().map(|i| (format!("key{}", i), format!("value{}", i))).collect()
+    };
+    assert_eq!(store.range("key3".to_owned(), "key7".to_owned())?, pairs(&[3, 6]));
+    assert_eq!(store.range("key4".to_owned(), "key6".to_owned())?, pairs(&[]));
+    assert_eq!(store.range("key2".to_owned(), "key3".to_owned())?, pairs(&[2]));
+    assert_eq!(store.range("key2".to_owned(), "key2".to_owned())?, pairs(&[]));
+    assert_eq!(store.range("key7".to_owned(), "key3".to_owned())?, pairs(&[]));
+    assert_eq!(store.range("".to_owned(), "z".to_owned())?, pairs(&[0, 1, 2, 3, 6, 7, 8, 9]));
+    Ok(())
+}
+
+// A clear should remove every key, also after a reopen, while readers go on
+#[test]
+fn clear_removes_every_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().value_separation(64);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store.set(format!("large{}", i), "v".repeat(100))?;
+    }
+    store.compact()?;
+    let cursor = store.cursor();
+
+    let reading = Arc::new(AtomicBool::new(true));
+    let reader = {
+        let store = store.clone();
+        let reading = reading.clone();
+        thread::spawn(move || -> Result<()> {
+            while reading.load(Ordering::SeqCst) {
+                for i in 0..100 {
+                    store.get(format!("large{}", i))?;
+                }
+            }
+            Ok(())
+        })
+    };
+    store.clear()?;
+    reading.store(false, Ordering::SeqCst);
+    reader.join().unwrap()?;
+
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+        assert_eq!(store.get(format!("large{}", i))?, None);
+    }
+    assert!(store.is_empty());
+    let changes = store.changes_since(cursor)?;
+    assert!(changes.full);
+    assert!(changes.changes.is_empty());
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert!(store.is_empty());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A clear failing to delete the old logs should leave no key either, also after a reopen
+#[test]
+fn clear_despite_failed_deletes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = Arc::new(FailingFileSystem::default());
+    let options = KvStoreOptions::new().file_system(fs.clone()).value_separation(64);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store.set(format!("large{}", i), "v".repeat(100))?;
+    }
+    fs.fail_removes.store(true, Ordering::SeqCst);
+    store.clear()?;
+    fs.fail_removes.store(false, Ordering::SeqCst);
+    assert!(store.is_empty());
+    store.set("key0".to_owned(), "after".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+    assert_eq!(store.get("large0".to_owned())?, None);
+    store.set("key1".to_owned(), "value".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 2);
+    Ok(())
+}
+
+// A compare-and-swap should swap a matching value only, also across threads
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let some = |value: &str| Some(value.to_owned());
+    assert!(store.compare_and_swap("key1".to_owned(), None, some("value1"))?);
+    assert!(!store.compare_and_swap("key1".to_owned(), None, some("value2"))?);
+    assert!(!store.compare_and_swap("key1".to_owned(), some("value2"), some("value3"))?);
+    assert_eq!(store.get("key1".to_owned())?, some("value1"));
+    assert!(store.compare_and_swap("key1".to_owned(), some("value1"), some("value2"))?);
+    assert_eq!(store.get("key1".to_owned())?, some("value2"));
+    assert!(store.compare_and_swap("key1".to_owned(), some("value2"), None)?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.compare_and_swap("key1".to_owned(), None, None)?);
+
+    // every increment by swap counts once
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let current = store.get("counter".to_owned())?.unwrap();
+                        let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                        if store.compare_and_swap("counter".to_owned(), Some(current), Some(next))? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, some("200"));
+    Ok(())
+}
+
+// Increment should start a missing key from 0 and count a string of an integer
+#[test]
+fn increment_counts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.increment("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.increment("counter".to_owned(), 2)?, 7);
+    assert_eq!(store.increment("counter".to_owned(), -10)?, -3);
+    store.set("text".to_owned(), "41".to_owned())?;
+    assert_eq!(store.increment("text".to_owned(), 1)?, 42);
+    assert_eq!(store.get_typed("text".to_owned())?, Some(TypedValue::Int(42)));
+
+    store.set("word".to_owned(), "forty".to_owned())?;
+    assert!(matches!(store.increment("word".to_owned(), 1), Err(KvsError::NotAnInteger)));
+    assert_eq!(store.get("word".to_owned())?, Some("forty".to_owned()));
+    store.set_typed("flag".to_owned(), TypedValue::Bool(true))?;
+    assert!(matches!(store.increment("flag".to_owned(), 1), Err(KvsError::TypeMismatch { .. })));
+    Ok(())
+}
+
+// Append should start a missing key empty and return the new length, also past a reopen
+#[test]
+fn append_to_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().value_separation(16);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.append("log".to_owned(), "line1\n".to_owned())?, 6);
+    assert_eq!(store.append("log".to_owned(), "line2\n".to_owned())?, 12);
+    // the value grows past the separation threshold
+    assert_eq!(store.append("log".to_owned(), "line3\n".to_owned())?, 18);
+    store.set_typed("count".to_owned(), TypedValue::Int(4))?;
+    assert_eq!(store.append("count".to_owned(), "2".to_owned())?, 2);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("log".to_owned())?, Some("line1\nline2\nline3\n".to_owned()));
+    assert_eq!(store.get_typed("count".to_owned())?, Some(TypedValue::String("42".to_owned())));
+    Ok(())
+}
+
+// get_set should return None for a missing key, then the value it replaced as text
+#[test]
+fn get_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_set("key1".to_owned(), "first".to_owned())?, None);
+    assert_eq!(store.get_set("key1".to_owned(), "second".to_owned())?, Some("first".to_owned()));
+    store.set_typed("count".to_owned(), TypedValue::Int(4))?;
+    assert_eq!(store.get_set("count".to_owned(), "five".to_owned())?, Some("4".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("second".to_owned()));
+    assert_eq!(store.get_typed("count".to_owned())?, Some(TypedValue::String("five".to_owned())));
+    Ok(())
+}
+
+// Bytes which are not UTF-8 should be read back intact, also after a merge and a reopen
+#[test]
+fn binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blob = vec![b'a', 0, 0xff, 0xc3, 0x28, 0, b'z'];
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes("blob".to_owned(), blob.clone())?;
+    store.set_bytes("empty".to_owned(), Vec::new())?;
+    store.set("text".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get_bytes("blob".to_owned())?, Some(blob.clone()));
+    assert_eq!(store.get_bytes("missing".to_owned())?, None);
+    assert!(matches!(store.get_bytes("text".to_owned()), Err(KvsError::TypeMismatch { .. })));
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("blob".to_owned())?, Some(blob));
+    assert_eq!(store.get_bytes("empty".to_owned())?, Some(Vec::new()));
+    store.remove("blob".to_owned())?;
+    assert_eq!(store.get_bytes("blob".to_owned())?, None);
+    Ok(())
+}
+
+// get_or_insert should insert a missing key once, racing callers agreeing on its value
+#[test]
+fn get_or_insert() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_or_insert("key1".to_owned(), "first".to_owned())?, "first");
+    assert_eq!(store.get_or_insert("key1".to_owned(), "second".to_owned())?, "first");
+    assert_eq!(store.get("key1".to_owned())?, Some("first".to_owned()));
+
+    for round in 0..20 {
+        let key = format!("race{}", round);
+        let barrier = Arc::new(Barrier::new(4));
+        let threads: Vec<_> = (0..4)
+            .map(|thread| {
+                let store = store.clone();
+                let key = key.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    store.get_or_insert(key, format!("thread{}", thread))
+                })
+            })
+            .collect();
+        let values = threads.into_iter().map(|thread| thread.join().unwrap()).collect::<Result<Vec<_>>>()?;
+        let stored = store.get(key)?.unwrap();
+        assert!(values.iter().all(|value| *value == stored));
+    }
+    Ok(())
+}
+
+// The store stats should count the stale bytes of overwrites and removes until a compaction
+#[test]
+fn store_stats_unmerged() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().auto_compaction(AutoCompaction::Off);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let stats = store.store_stats()?;
+    assert_eq!(stats.unmerged_bytes, 0);
+    assert_eq!(stats.generations, 1);
+    assert_eq!(stats.live_keys, 2);
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let stats = store.store_stats()?;
+    assert!(stats.unmerged_bytes > 0);
+    assert!(stats.unmerged_bytes < stats.compaction_threshold);
+    assert_eq!(stats.live_keys, 1);
+
+    store.compact()?;
+    let compacted = store.store_stats()?;
+    assert_eq!(compacted.unmerged_bytes, 0);
+    assert!(compacted.write_generation > stats.write_generation);
+    Ok(())
+}
+
+// A manual compaction should return the bytes reclaimed, and nothing when no record is stale
+#[test]
+fn compact_reclaims_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().auto_compaction(AutoCompaction::Off);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..1000 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    let before = store.disk_usage()?.total_bytes;
+    let reclaimed = store.compact()?;
+    let after = store.disk_usage()?.total_bytes;
+    assert!(reclaimed > 0);
+    assert_eq!(before - after, reclaimed);
+    assert_eq!(store.get("key".to_owned())?, Some("value999".to_owned()));
+
+    assert_eq!(store.compact()?, 0);
+    assert_eq!(store.stats()?.compactions, 1);
+    Ok(())
+}
+
+// A store opened read only should serve reads, refuse writes and create no file
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let files = || -> Vec<PathBuf> {
+        let mut files: Vec<_> = fs::read_dir(temp_dir.path()).unwrap().map(|entry| entry.unwrap().path()).collect();
+        files.sort();
+        files
+    };
+    let before = files();
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(store.set("key2".to_owned(), "value2".to_owned()), Err(KvsError::ReadOnly)));
+    assert!(matches!(store.remove("key1".to_owned()), Err(KvsError::ReadOnly)));
+    assert!(matches!(store.append("key1".to_owned(), "!".to_owned()), Err(KvsError::ReadOnly)));
+    assert!(matches!(store.compact(), Err(KvsError::ReadOnly)));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert_eq!(files(), before);
+    Ok(())
+}
+
+// Values larger and smaller than the buffers should read back with any buffer size
+#[test]
+fn buffer_sizes() -> Result<()> {
+    for &buffer_size in &[1, 64, 1 << 20] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = || KvStoreOptions::new().buffer_size(buffer_size).value_separation(1000);
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        for i in 0..20 {
+            store.set(format!("key{}", i), "v".repeat(i * 100))?;
+        }
+        store.set("key0".to_owned(), "overwritten".to_owned())?;
+        store.compact()?;
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("overwritten".to_owned()));
+        for i in 1..20 {
+            assert_eq!(store.get(format!("key{}", i))?, Some("v".repeat(i * 100)));
+        }
+        assert_eq!(store.debug_dump()?["options"]["buffer_size"], buffer_size);
+    }
+    Ok(())
+}
+
+// Reads should see the records appended to the active log after its earlier
+// records were read, and the records moved by a merge
+#[test]
+fn reads_follow_growing_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().auto_compaction(AutoCompaction::Off);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for round in 0..3 {
+        for i in 0..200 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}-{}", i, round)));
+            // an earlier record of the same log
+            assert_eq!(store.get("key0".to_owned())?, Some(format!("value0-{}", round)));
+        }
+        store.compact()?;
+        for i in 0..200 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}-{}", i, round)));
+        }
+    }
+    Ok(())
+}
+
+// A cached value should be read without touching the logs, and read again
+// once a write or a compaction moved its record
+#[test]
+fn value_cache_skips_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = Arc::new(CountingFileSystem::default());
+    let options = KvStoreOptions::new().file_system(fs.clone()).value_cache(2);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let bytes_read = fs.bytes_read.load(Ordering::SeqCst);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(fs.bytes_read.load(Ordering::SeqCst), bytes_read);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // the least recently used key is dropped
+    for i in 3..6 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    let bytes_read = fs.bytes_read.load(Ordering::SeqCst);
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(fs.bytes_read.load(Ordering::SeqCst), bytes_read);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(fs.bytes_read.load(Ordering::SeqCst) > bytes_read);
+
+    store.compact()?;
+    for i in 3..6 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Concurrent writers should share the fsyncs of a group commit, every set
+// being in the log file once it returns
+#[test]
+fn group_commit_concurrent_sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = Arc::new(CountingFileSystem::default());
+    let options = KvStoreOptions::new()
+        .file_system(fs.clone())
+        .durability(Durability::Fsync)
+        .group_commit(Duration::from_millis(1));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|thread| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || -> Result<()> {
+                barrier.wait();
+                for i in 0..50 {
+                    store.set(format!("key{}-{}", thread, i), format!("value{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let syncs = fs.syncs.load(Ordering::SeqCst);
+    assert!(syncs > 0 && syncs < 400, "{} fsyncs", syncs);
+
+    // another process reading the files sees every set
+    let replica = KvStore::open_replica(temp_dir.path())?;
+    for thread in 0..8 {
+        for i in 0..50 {
+            assert_eq!(replica.get(format!("key{}-{}", thread, i))?, Some(format!("value{}", i)));
+        }
+    }
+    store.remove("key0-0".to_owned())?;
+    replica.reload()?;
+    assert_eq!(replica.get("key0-0".to_owned())?, None);
+    Ok(())
+}
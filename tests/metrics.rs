@@ -0,0 +1,55 @@
+use kvs::test_support::TestServer;
+use kvs::{KvServer, KvStore, KvsClient, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A logger keeping the messages of the metrics thread.
+struct MetricsLogger {
+    lines: Mutex<Vec<String>>,
+}
+
+impl Log for MetricsLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info
+    }
+
+    fn log(&self, record: &Record) {
+        let line = record.args().to_string();
+        if line.starts_with("Metrics:") {
+            self.lines.lock().unwrap().push(line);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: MetricsLogger = MetricsLogger {
+    lines: Mutex::new(Vec::new()),
+};
+
+// The server should log a metrics snapshot every interval while it serves
+#[test]
+fn metrics_logging() -> Result<()> {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Info);
+
+    let interval = Duration::from_millis(50);
+    let server = TestServer::start(|path| Ok(KvServer::new(KvStore::open(path)?).with_metrics_logging(interval)))?;
+    let mut client = KvsClient::connect(server.addr())?;
+    let start = Instant::now();
+    let mut i = 0;
+    while start.elapsed() < interval * 6 {
+        client.set(format!("key{}", i % 10), "value".to_owned())?;
+        client.get(format!("key{}", i % 10))?;
+        i += 1;
+    }
+    drop(client);
+    drop(server);
+
+    let lines = LOGGER.lines.lock().unwrap();
+    assert!(!lines.is_empty());
+    let last = lines.last().unwrap();
+    assert!(last.contains("live keys 10"), "{}", last);
+    Ok(())
+}
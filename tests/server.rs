@@ -0,0 +1,966 @@
+use kvs::test_support::TestServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{Codec, KvServer, KvStore, KvsClient, KvsClientPool, KvsEngine, KvsError, PipelineReply, Result, ShutdownHandle, TypedValue, ValueType, PROTOCOL_VERSION};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Send the handshake of a client of the protocol version, with the JSON codec
+fn send_hello(stream: &mut TcpStream, version: u16) -> Result<()> {
+    stream.write_all(b"KVS\0")?;
+    stream.write_all(&version.to_be_bytes())?;
+    stream.write_all(&[0])?;
+    Ok(())
+}
+
+// Start a kvs server in the background and wait until it accepts connections.
+fn start_server(addr: &'static str) -> TempDir {
+    start_configured_server(addr, |server| server)
+}
+
+// Start a kvs server configured by `configure`.
+fn start_configured_server<F>(addr: &'static str, configure: F) -> TempDir
+where
+    F: FnOnce(KvServer<KvStore>) -> KvServer<KvStore> + Send + 'static,
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || {
+        let pool = SharedQueueThreadPool::new(4).unwrap();
+        configure(KvServer::new(store)).start(addr, pool).unwrap();
+    });
+    for _ in 0..50 {
+        if KvsClient::connect(addr).is_ok() {
+            return temp_dir;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("server {} not started", addr);
+}
+
+// Sets without reply should all be applied once a ping returns
+#[test]
+fn set_noreply_then_ping() -> Result<()> {
+    let addr = "127.0.0.1:4101";
+    let _temp_dir = start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..1000 {
+        client.set_noreply(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.ping()?;
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..1000 {
+        assert_eq!(client.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// A waiting client should wake up with the value set by another client
+#[test]
+fn wait_for_key() -> Result<()> {
+    let addr = "127.0.0.1:4102";
+    let _temp_dir = start_server(addr);
+
+    let (woken, woken_receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let waited = KvsClient::connect(addr)
+            .and_then(|mut client| client.wait("key1".to_owned(), Duration::from_secs(10)));
+        woken.send(waited).unwrap();
+    });
+    // a set before the wait is registered is missed, set again until the waiter wakes
+    let mut client = KvsClient::connect(addr)?;
+    let waited = loop {
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        if let Ok(waited) = woken_receiver.recv_timeout(Duration::from_millis(10)) {
+            break waited;
+        }
+    };
+
+    assert_eq!(waited?, Some("value1".to_owned()));
+    match client.wait("key2".to_owned(), Duration::from_millis(100)) {
+        Err(KvsError::Timeout) => {}
+        other => panic!("expect timeout, got {:?}", other),
+    }
+    Ok(())
+}
+
+// Requests should round trip with every codec
+#[test]
+fn codec_round_trip() -> Result<()> {
+    let addr = "127.0.0.1:4104";
+    let _temp_dir = start_server(addr);
+
+    for &codec in &[Codec::Json, Codec::Bincode] {
+        let mut client = KvsClient::connect_with_codec(addr, codec)?;
+        let key = format!("{:?}", codec);
+        let value = "x".repeat(100_000);
+        assert_eq!(client.get(key.clone())?, None);
+        client.set(key.clone(), value.clone())?;
+        assert_eq!(client.get(key.clone())?, Some(value));
+        client.remove(key.clone())?;
+        assert!(client.remove(key.clone()).is_err());
+        client.ping()?;
+    }
+    Ok(())
+}
+
+// Every request type should round trip with every codec
+#[test]
+fn every_command_round_trips() -> Result<()> {
+    let addr = "127.0.0.1:4105";
+    let _temp_dir = start_server(addr);
+
+    for &codec in &[Codec::Json, Codec::Bincode] {
+        let mut client = KvsClient::connect_with_codec(addr, codec)?;
+        let key = format!("{:?}", codec);
+        assert_eq!(client.get(key.clone())?, None);
+        client.set(key.clone(), "value1".to_owned())?;
+        assert_eq!(client.get(key.clone())?, Some("value1".to_owned()));
+        client.set_noreply(key.clone(), "value2".to_owned())?;
+        client.ping()?;
+        assert_eq!(client.get(key.clone())?, Some("value2".to_owned()));
+        match client.wait(key.clone(), Duration::from_millis(50)) {
+            Err(KvsError::Timeout) => {}
+            other => panic!("expect timeout, got {:?}", other),
+        }
+        client.rename(key.clone(), "renamed".to_owned())?;
+        assert_eq!(client.get("renamed".to_owned())?, Some("value2".to_owned()));
+        client.rename("renamed".to_owned(), key.clone())?;
+        assert!(client.rename("renamed".to_owned(), key.clone()).is_err());
+        client.remove(key.clone())?;
+        assert_eq!(client.get(key.clone())?, None);
+        match client.remove(key.clone()) {
+            Err(KvsError::KeyNotFound) => {}
+            other => panic!("expect key not found, got {:?}", other),
+        }
+        client.ping()?;
+    }
+    Ok(())
+}
+
+// A dump should stream every live pair and leave the connection usable
+#[test]
+fn dump_all_pairs() -> Result<()> {
+    let addr = "127.0.0.1:4106";
+    let _temp_dir = start_server(addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..500 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..100 {
+        client.remove(format!("key{}", i))?;
+    }
+
+    let dumped = client.dump().collect::<Result<HashMap<_, _>>>()?;
+    assert_eq!(dumped.len(), 400);
+    for i in 100..500 {
+        assert_eq!(dumped.get(&format!("key{}", i)), Some(&format!("value{}", i)));
+    }
+
+    // stop reading early
+    assert!(client.dump().next().is_some());
+    client.ping()?;
+    assert_eq!(client.get("key100".to_owned())?, Some("value100".to_owned()));
+    Ok(())
+}
+
+// Only the oversized item of a batch should fail
+#[test]
+fn set_batch_partial_failure() -> Result<()> {
+    let addr = "127.0.0.1:4107";
+    let _temp_dir = start_configured_server(addr, |server| server.max_value_bytes(1024));
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut pairs: Vec<_> = (0..500).map(|i| (format!("key{}", i), format!("value{}", i))).collect();
+    pairs[250].1 = "x".repeat(2048);
+    let outcome = client.set_batch(pairs)?;
+    assert!(!outcome.all_ok());
+    assert_eq!(outcome.results().len(), 500);
+    let failures: Vec<_> = outcome.failures().map(|(i, _)| i).collect();
+    assert_eq!(failures, vec![250]);
+
+    assert_eq!(client.get("key250".to_owned())?, None);
+    for i in (0..500).filter(|&i| i != 250) {
+        assert_eq!(client.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert!(client.set_batch(vec![("key0".to_owned(), "value".to_owned())])?.all_ok());
+    Ok(())
+}
+
+// A ping should name the version and the engine of the server
+#[test]
+fn ping_version_and_engine() -> Result<()> {
+    for (server, engine) in [(TestServer::kvs()?, "kvs"), (TestServer::sled()?, "sled")] {
+        let mut client = KvsClient::connect(server.addr())?;
+        let info = client.ping()?;
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.engine, engine);
+    }
+    Ok(())
+}
+
+// A test server should serve a full round trip and shut down on drop
+#[test]
+fn test_server_round_trip() -> Result<()> {
+    for server in [TestServer::kvs()?, TestServer::sled()?] {
+        let mut client = KvsClient::connect(server.addr())?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        client.remove("key1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, None);
+        drop(client);
+
+        let addr = server.addr();
+        drop(server);
+        assert!(KvsClient::connect(addr).is_err());
+    }
+    Ok(())
+}
+
+// Exists should tell present, absent and removed keys apart on every engine
+#[test]
+fn exists_request() -> Result<()> {
+    for server in [TestServer::kvs()?, TestServer::sled()?] {
+        let mut client = KvsClient::connect(server.addr())?;
+        client.set("key1", "value1")?;
+        client.set("key2", "value2")?;
+        client.remove("key2")?;
+        assert!(client.contains_key("key1")?);
+        assert!(!client.contains_key("key2")?);
+        assert!(!client.contains_key("key3")?);
+    }
+    Ok(())
+}
+
+// A batch get should answer every key in its position, none for the missing ones
+#[test]
+fn get_batch_request() -> Result<()> {
+    for server in [TestServer::kvs()?, TestServer::sled()?] {
+        let mut client = KvsClient::connect(server.addr())?;
+        client.set("key1", "value1")?;
+        client.set("key2", "value2")?;
+        client.set("key3", "value3")?;
+        client.remove("key2")?;
+        let keys = vec!["key3", "missing", "key1", "key2", "key3"];
+        let values = client.get_batch(keys.into_iter().map(String::from).collect())?;
+        assert_eq!(values, vec![Some("value3".to_owned()), None, Some("value1".to_owned()), None, Some("value3".to_owned())]);
+        assert_eq!(client.get_batch(Vec::new())?, Vec::<Option<String>>::new());
+    }
+    Ok(())
+}
+
+// A compare-and-swap should swap a matching value only, create and remove keys
+#[test]
+fn cas_request() -> Result<()> {
+    for server in [TestServer::kvs()?, TestServer::sled()?] {
+        let mut client = KvsClient::connect(server.addr())?;
+        let some = |value: &str| Some(value.to_owned());
+        assert!(client.compare_and_swap("key1", None, some("value1"))?);
+        assert!(!client.compare_and_swap("key1", None, some("value2"))?);
+        assert!(!client.compare_and_swap("key1", some("value2"), some("value3"))?);
+        assert_eq!(client.get("key1")?, some("value1"));
+        assert!(client.compare_and_swap("key1", some("value1"), some("value2"))?);
+        assert_eq!(client.get("key1")?, some("value2"));
+        assert!(client.compare_and_swap("key1", some("value2"), None)?);
+        assert_eq!(client.get("key1")?, None);
+    }
+    Ok(())
+}
+
+// Append should answer the new length of the value on every engine
+#[test]
+fn append_request() -> Result<()> {
+    for server in [TestServer::kvs()?, TestServer::sled()?] {
+        let mut client = KvsClient::connect(server.addr())?;
+        assert_eq!(client.append("log", "a,")?, 2);
+        assert_eq!(client.append("log", "b,")?, 4);
+        assert_eq!(client.get("log")?, Some("a,b,".to_owned()));
+    }
+    Ok(())
+}
+
+// The errors of requests should come back typed, with the message for the rest
+#[test]
+fn typed_errors() -> Result<()> {
+    for server in [TestServer::kvs()?, TestServer::sled()?] {
+        let mut client = KvsClient::connect(server.addr())?;
+        assert!(matches!(client.remove("missing"), Err(KvsError::KeyNotFound)));
+        client.set("word", "forty")?;
+        assert!(matches!(client.increment("word", 1), Err(KvsError::NotAnInteger)));
+        client.set_typed("flag", TypedValue::Bool(true))?;
+        match client.increment("flag", 1) {
+            Err(KvsError::StringError(msg)) => assert_eq!(msg, "Value is of type Bool, not Int"),
+            other => panic!("expect type mismatch, got {:?}", other),
+        }
+    }
+    Ok(())
+}
+
+// A pipeline should answer every request in the order queued
+#[test]
+fn pipeline_requests() -> Result<()> {
+    let server = TestServer::kvs()?;
+    let mut client = KvsClient::connect(server.addr())?;
+    let mut pipeline = client.pipeline();
+    for i in 0..500 {
+        pipeline.set(format!("key{}", i), format!("value{}", i));
+    }
+    pipeline.get("key250").remove("missing");
+    let replies = pipeline.execute()?;
+    assert_eq!(replies.len(), 502);
+    assert!(replies[..500].iter().all(|reply| matches!(reply, Ok(PipelineReply::Set))));
+    assert_eq!(replies[500].as_ref().ok(), Some(&PipelineReply::Get(Some("value250".to_owned()))));
+    assert!(matches!(replies[501], Err(KvsError::KeyNotFound)));
+    assert!(pipeline.execute()?.is_empty());
+
+    assert_eq!(client.get("key499")?, Some("value499".to_owned()));
+    Ok(())
+}
+
+// GetSet should answer None on the first write, then the value it replaced
+#[test]
+fn get_set_request() -> Result<()> {
+    for server in [TestServer::kvs()?, TestServer::sled()?] {
+        let mut client = KvsClient::connect(server.addr())?;
+        assert_eq!(client.get_set("key1", "first")?, None);
+        assert_eq!(client.get_set("key1", "second")?, Some("first".to_owned()));
+        assert_eq!(client.get("key1")?, Some("second".to_owned()));
+    }
+    Ok(())
+}
+
+// get_or_insert should insert a missing key, then answer the stored value
+#[test]
+fn get_or_insert_request() -> Result<()> {
+    for server in [TestServer::kvs()?, TestServer::sled()?] {
+        let mut client = KvsClient::connect(server.addr())?;
+        assert_eq!(client.get_or_insert("key1", "first")?, "first");
+        assert_eq!(client.get_or_insert("key1", "second")?, "first");
+        assert_eq!(client.get("key1")?, Some("first".to_owned()));
+    }
+    Ok(())
+}
+
+// Client methods should accept borrowed and owned strings alike
+#[test]
+fn client_accepts_str_and_string() -> Result<()> {
+    let server = TestServer::kvs()?;
+    let mut client = KvsClient::connect(server.addr())?;
+    let key = String::from("key1");
+    client.set(&key, "value1")?;
+    client.set("key2", String::from("value2"))?;
+    assert_eq!(client.get(&key)?, Some("value1".to_owned()));
+    assert_eq!(client.get(key.clone())?, Some("value1".to_owned()));
+    client.rename("key2", "key3")?;
+    assert_eq!(client.get("key3")?, Some("value2".to_owned()));
+    assert_eq!(client.increment("counter", 2)?, 2);
+    client.remove(key)?;
+    assert_eq!(client.remove_prefix("key")?, 1);
+    assert_eq!(client.get("key3")?, None);
+    Ok(())
+}
+
+// A frame declaring a length over the limit should close the connection at once
+#[test]
+fn max_request_bytes() -> Result<()> {
+    let server = TestServer::start(|path| Ok(KvServer::new(KvStore::open(path)?).max_request_bytes(1024)))?;
+
+    let mut stream = TcpStream::connect(server.addr())?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    send_hello(&mut stream, PROTOCOL_VERSION)?;
+    let mut ack = [0; 1];
+    stream.read_exact(&mut ack)?;
+    assert_eq!(ack, [0]);
+    // declare a body of 1 GiB and send none of it
+    stream.write_all(&(1u32 << 30).to_be_bytes())?;
+    let mut buf = [0; 16];
+    match stream.read(&mut buf) {
+        Ok(0) => {}
+        Err(e) if e.kind() == ErrorKind::ConnectionReset => {}
+        other => panic!("expect the connection to be closed, got {:?}", other),
+    }
+
+    let mut client = KvsClient::connect(server.addr())?;
+    assert!(client.set("key1".to_owned(), "x".repeat(2048)).is_err());
+    let mut client = KvsClient::connect(server.addr())?;
+    client.set("key1".to_owned(), "x".repeat(512))?;
+    assert_eq!(client.get("key1".to_owned())?, Some("x".repeat(512)));
+    Ok(())
+}
+
+// Overwritten values should show up as garbage in the disk usage
+#[test]
+fn disk_usage_reports_garbage() -> Result<()> {
+    let server = TestServer::kvs()?;
+    let mut client = KvsClient::connect(server.addr())?;
+    let empty = client.disk_usage()?;
+    assert_eq!(empty.total_bytes, 0);
+    assert_eq!(empty.live_bytes, 0);
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.set("key1".to_owned(), "value3".to_owned())?;
+    let usage = client.disk_usage()?;
+    assert!(usage.live_bytes > 0);
+    assert!(usage.total_bytes > usage.live_bytes);
+    assert!(usage.generations >= 1 && usage.generations <= 2);
+    Ok(())
+}
+
+// Typed values should come back with their type on both engines
+#[test]
+fn typed_values_round_trip() -> Result<()> {
+    for server in [TestServer::kvs()?, TestServer::sled()?] {
+        let mut client = KvsClient::connect(server.addr())?;
+        client.set("string".to_owned(), "text".to_owned())?;
+        client.set_int("int".to_owned(), -42)?;
+        client.set_float("float".to_owned(), 0.1)?;
+        client.set_bool("bool".to_owned(), true)?;
+        client.set_bytes("bytes".to_owned(), vec![0, 1, 0xfe, 0xff])?;
+
+        assert_eq!(client.get_typed("string".to_owned())?, Some(TypedValue::String("text".to_owned())));
+        assert_eq!(client.get_int("int".to_owned())?, Some(-42));
+        assert_eq!(client.get_float("float".to_owned())?, Some(0.1));
+        assert_eq!(client.get_bool("bool".to_owned())?, Some(true));
+        assert_eq!(client.get_bytes("bytes".to_owned())?, Some(vec![0, 1, 0xfe, 0xff]));
+        assert_eq!(client.get_int("missing".to_owned())?, None);
+        // an untyped get returns the text of the value
+        assert_eq!(client.get("int".to_owned())?, Some("-42".to_owned()));
+
+        match client.get_int("string".to_owned()) {
+            Err(KvsError::TypeMismatch { expected: ValueType::Int, found: ValueType::String }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        // an untyped set drops the type
+        client.set("int".to_owned(), "7".to_owned())?;
+        assert_eq!(client.get_typed("int".to_owned())?, Some(TypedValue::String("7".to_owned())));
+    }
+    Ok(())
+}
+
+// Increment should only apply to integers, typed or set as text
+#[test]
+fn increment_requires_int() -> Result<()> {
+    for server in [TestServer::kvs()?, TestServer::sled()?] {
+        let mut client = KvsClient::connect(server.addr())?;
+        assert_eq!(client.increment("counter".to_owned(), 5)?, 5);
+        assert_eq!(client.increment("counter".to_owned(), -7)?, -2);
+        assert_eq!(client.get_int("counter".to_owned())?, Some(-2));
+
+        client.set("text".to_owned(), "1".to_owned())?;
+        assert_eq!(client.increment("text".to_owned(), 1)?, 2);
+        assert_eq!(client.get_int("text".to_owned())?, Some(2));
+
+        client.set("string".to_owned(), "one".to_owned())?;
+        assert!(client.increment("string".to_owned(), 1).is_err());
+        assert_eq!(client.get("string".to_owned())?, Some("one".to_owned()));
+        client.set_bool("bool".to_owned(), true)?;
+        assert!(client.increment("bool".to_owned(), 1).is_err());
+    }
+    Ok(())
+}
+
+// A request the server does not know should be answered, not close the connection
+#[test]
+fn unknown_command_response() -> Result<()> {
+    let server = TestServer::kvs()?;
+    let mut stream = TcpStream::connect(server.addr())?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    send_hello(&mut stream, PROTOCOL_VERSION)?;
+    let mut ack = [0; 1];
+    stream.read_exact(&mut ack)?;
+    assert_eq!(ack, [0]);
+
+    let mut exchange = |request: &str| -> Result<String> {
+        stream.write_all(&(request.len() as u32).to_be_bytes())?;
+        stream.write_all(request.as_bytes())?;
+        let mut header = [0; 4];
+        stream.read_exact(&mut header)?;
+        let mut body = vec![0; u32::from_be_bytes(header) as usize];
+        stream.read_exact(&mut body)?;
+        Ok(String::from_utf8(body)?)
+    };
+    assert_eq!(exchange(r#"{"Frobnicate":{"key":"key1"}}"#)?, r#"{"UnknownCommand":"Frobnicate"}"#);
+    assert_eq!(exchange(r#""Shutdown""#)?, r#"{"UnknownCommand":"Shutdown"}"#);
+    // the connection is still usable
+    assert!(exchange(r#""Ping""#)?.starts_with(r#"{"Ok":{"version":"#));
+    Ok(())
+}
+
+// A shutdown under load should finish every request it answered before the server returns
+#[test]
+fn shutdown_under_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvServer::new(KvStore::open(temp_dir.path())?);
+    let shutdown = server.shutdown_handle();
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || server.serve(listener, SharedQueueThreadPool::new(4)?));
+
+    // each client reports its first applied set, the pool serves 4 of them at once
+    let (started, started_receiver) = mpsc::channel();
+    let clients: Vec<_> = (0..8)
+        .map(|id| {
+            let started = started.clone();
+            thread::spawn(move || -> Result<Option<u64>> {
+                let mut client = match KvsClient::connect(addr) {
+                    Ok(client) => client,
+                    // still queued when the server shut down
+                    Err(_) => return Ok(None),
+                };
+                let mut last_applied = None;
+                for n in 0.. {
+                    if client.set(format!("client{}", id), n.to_string()).is_err() {
+                        break;
+                    }
+                    if last_applied.is_none() {
+                        let _ = started.send(());
+                    }
+                    last_applied = Some(n);
+                }
+                Ok(last_applied)
+            })
+        })
+        .collect();
+    for _ in 0..4 {
+        started_receiver.recv().unwrap();
+    }
+    shutdown.shutdown();
+    server.join().unwrap()?;
+
+    // the store is released once every connection is finished
+    let store = KvStore::open(temp_dir.path())?;
+    for (id, client) in clients.into_iter().enumerate() {
+        if let Some(n) = client.join().unwrap()? {
+            let value = store.get(format!("client{}", id))?.unwrap();
+            // the last set may be applied without its response being read
+            assert!(value == n.to_string() || value == (n + 1).to_string());
+        }
+    }
+    Ok(())
+}
+
+// A shutdown should answer the clients blocked in a wait instead of waiting for their timeout
+#[test]
+fn shutdown_wakes_waiters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvServer::new(KvStore::open(temp_dir.path())?);
+    let shutdown = server.shutdown_handle();
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || server.serve(listener, SharedQueueThreadPool::new(4)?));
+
+    let (connected, connected_receiver) = mpsc::channel();
+    let waiter = thread::spawn(move || -> Result<Option<String>> {
+        let mut client = KvsClient::connect(addr)?;
+        client.ping()?;
+        connected.send(()).unwrap();
+        client.wait("key1", Duration::from_secs(60))
+    });
+    connected_receiver.recv().unwrap();
+    let start = Instant::now();
+    shutdown.shutdown();
+    server.join().unwrap()?;
+    // answered a timeout, or closed if the wait was not read yet
+    assert!(waiter.join().unwrap().is_err());
+    assert!(start.elapsed() < Duration::from_secs(10));
+    Ok(())
+}
+
+// A filtered scan should stream only the pairs matching both the key prefix and the value substring
+#[test]
+fn scan_filter_streams_matches() -> Result<()> {
+    let server = TestServer::kvs()?;
+    let mut client = KvsClient::connect(server.addr())?;
+    for i in 0..100 {
+        let color = if i % 3 == 0 { "red" } else { "blue" };
+        client.set(format!("user:{}", i), format!("{} car", color))?;
+        client.set(format!("item:{}", i), format!("{} hat", color))?;
+    }
+
+    let matched = client
+        .scan_filter("user:".to_owned(), "red".to_owned())
+        .collect::<Result<HashMap<_, _>>>()?;
+    let expected: HashMap<_, _> = (0..100)
+        .filter(|i| i % 3 == 0)
+        .map(|i| (format!("user:{}", i), "red car".to_owned()))
+        .collect();
+    assert_eq!(matched, expected);
+    assert_eq!(client.scan_filter("user:".to_owned(), "green".to_owned()).count(), 0);
+    assert_eq!(client.scan_filter(String::new(), String::new()).count(), 200);
+
+    // stop reading early
+    assert!(client.scan_filter("item:".to_owned(), "hat".to_owned()).next().is_some());
+    client.ping()?;
+    Ok(())
+}
+
+// Removing a prefix through the client should report the keys removed on both engines
+#[test]
+fn client_remove_prefix() -> Result<()> {
+    for server in [TestServer::kvs()?, TestServer::sled()?] {
+        let mut client = KvsClient::connect(server.addr())?;
+        for i in 0..10 {
+            client.set(format!("a:{}", i), "value".to_owned())?;
+            client.set(format!("b:{}", i), "value".to_owned())?;
+        }
+        assert_eq!(client.remove_prefix("a:".to_owned())?, 10);
+        assert_eq!(client.get("a:3".to_owned())?, None);
+        assert_eq!(client.get("b:3".to_owned())?, Some("value".to_owned()));
+        assert_eq!(client.dump().count(), 10);
+    }
+    Ok(())
+}
+
+// Connections should have TCP_NODELAY set by default, the client can turn it off
+#[test]
+fn nodelay_by_default() -> Result<()> {
+    let server = TestServer::kvs()?;
+    let client = KvsClient::connect(server.addr())?;
+    assert!(client.nodelay()?);
+    client.set_nodelay(false)?;
+    assert!(!client.nodelay()?);
+    assert!(KvsClient::connect_timeout(server.addr(), Duration::from_secs(1))?.nodelay()?);
+    assert!(KvsClientPool::new(server.addr(), 1)?.get()?.nodelay()?);
+
+    // a server leaving Nagle's algorithm on still answers
+    let server = TestServer::start(|path| Ok(KvServer::new(KvStore::open(path)?).nodelay(false)))?;
+    let mut client = KvsClient::connect(server.addr())?;
+    client.set_nodelay(false)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// The server should list the requests it knows, so a client can check before using one
+#[test]
+fn capabilities_list_requests() -> Result<()> {
+    let server = TestServer::kvs()?;
+    let mut client = KvsClient::connect(server.addr())?;
+    let capabilities = client.capabilities()?;
+    assert_eq!(capabilities.version, env!("CARGO_PKG_VERSION"));
+    for feature in &["Get", "Set", "Remove", "Ping", "SetBatch", "Increment", "Capabilities"] {
+        assert!(capabilities.supports(feature), "{} is not supported", feature);
+    }
+    assert!(!capabilities.supports("Frobnicate"));
+
+    let count = if capabilities.supports("Increment") {
+        client.increment("count".to_owned(), 1)?
+    } else {
+        unreachable!()
+    };
+    assert_eq!(count, 1);
+    Ok(())
+}
+
+// A saturated pool should shed new connections until its backlog drains to the low watermark
+#[test]
+fn shed_connections_when_saturated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvServer::new(KvStore::open(temp_dir.path())?).shed_connections(1, 0);
+    let shutdown = server.shutdown_handle();
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || server.serve(listener, SharedQueueThreadPool::new(1)?));
+
+    // the only worker serves the first client, the next two wait for it
+    let busy = KvsClient::connect(addr)?;
+    let queued: Vec<_> = (0..2).map(|_| TcpStream::connect(addr)).collect::<std::io::Result<_>>()?;
+    assert!(KvsClient::connect(addr).is_err());
+    // a backlog back under the high watermark but above the low one still sheds
+    drop(busy);
+    assert!(KvsClient::connect(addr).is_err());
+
+    drop(queued);
+    let mut client = (0..50)
+        .find_map(|_| KvsClient::connect(addr).map_err(|_| thread::sleep(Duration::from_millis(100))).ok())
+        .expect("connections still shed after the backlog drained");
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    drop(client);
+
+    shutdown.shutdown();
+    server.join().unwrap()
+}
+
+// A request answered busy should be sent again after growing delays until it succeeds
+#[test]
+fn retry_busy_with_backoff() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    // answers busy to the first two requests, then the value
+    let server = thread::spawn(move || -> Result<Vec<Instant>> {
+        let (mut stream, _) = listener.accept()?;
+        let mut hello = [0; 7];
+        stream.read_exact(&mut hello)?;
+        stream.write_all(&[0])?;
+        let mut received = Vec::new();
+        for response in [&b"\"Busy\""[..], b"\"Busy\"", b"{\"Ok\":\"value\"}"] {
+            let mut header = [0; 4];
+            stream.read_exact(&mut header)?;
+            let mut body = vec![0; u32::from_be_bytes(header) as usize];
+            stream.read_exact(&mut body)?;
+            assert_eq!(body, b"{\"Get\":{\"key\":\"key\"}}");
+            received.push(Instant::now());
+            stream.write_all(&(response.len() as u32).to_be_bytes())?;
+            stream.write_all(response)?;
+        }
+        Ok(received)
+    });
+
+    let delay = Duration::from_millis(100);
+    let mut client = KvsClient::connect(addr)?.retry_busy(3, delay);
+    assert_eq!(client.get("key")?, Some("value".to_owned()));
+    let received = server.join().unwrap()?;
+    // each delay is between the half of its base and the base, doubling
+    assert!(received[1] - received[0] >= delay / 2);
+    assert!(received[2] - received[1] >= delay);
+    Ok(())
+}
+
+// Requests over the in-flight limit should fail busy until retried within the limit
+#[test]
+fn busy_when_over_in_flight_limit() -> Result<()> {
+    let server = TestServer::start(|path| Ok(KvServer::new(KvStore::open(path)?).max_in_flight_requests(0)))?;
+
+    let mut client = KvsClient::connect(server.addr())?.retry_busy(2, Duration::from_millis(1));
+    let e = client.set("key", "value").unwrap_err();
+    assert!(matches!(e, KvsError::Busy));
+    assert!(e.is_retriable());
+    // requests without reply are not rejected, a wait times out instead of failing busy
+    client.set_noreply("key", "value")?;
+    assert!(matches!(client.wait("key", Duration::from_millis(10)), Err(KvsError::Timeout)));
+    Ok(())
+}
+
+// A client of another protocol version should be told the version of the
+// server and disconnected before any request
+#[test]
+fn protocol_version_mismatch() -> Result<()> {
+    let server = TestServer::kvs()?;
+    let mut stream = TcpStream::connect(server.addr())?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    send_hello(&mut stream, PROTOCOL_VERSION + 1)?;
+    let mut reply = [0; 3];
+    stream.read_exact(&mut reply)?;
+    assert_eq!(reply[0], 2);
+    assert_eq!(u16::from_be_bytes([reply[1], reply[2]]), PROTOCOL_VERSION);
+    let mut buf = [0; 16];
+    match stream.read(&mut buf) {
+        Ok(0) => {}
+        Err(e) if e.kind() == ErrorKind::ConnectionReset => {}
+        other => panic!("expect the connection to be closed, got {:?}", other),
+    }
+
+    // a client of an older server learns both versions
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let old_server = thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        let mut hello = [0; 7];
+        stream.read_exact(&mut hello)?;
+        assert_eq!(&hello[..4], b"KVS\0");
+        assert_eq!(u16::from_be_bytes([hello[4], hello[5]]), PROTOCOL_VERSION);
+        stream.write_all(&[2])?;
+        stream.write_all(&(PROTOCOL_VERSION - 1).to_be_bytes())?;
+        Ok(())
+    });
+    match KvsClient::connect(addr) {
+        Err(KvsError::ProtocolMismatch { client, server }) => {
+            assert_eq!(client, PROTOCOL_VERSION);
+            assert_eq!(server, PROTOCOL_VERSION - 1);
+        }
+        other => panic!("expect a protocol mismatch, got {:?}", other.map(|_| ())),
+    }
+    old_server.join().unwrap()?;
+
+    let mut client = KvsClient::connect(server.addr())?;
+    client.set("key", "value")?;
+    assert_eq!(client.get("key")?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Connecting with a timeout should fail within about the timeout, whether the
+// port is closed or the address never answers
+#[test]
+fn connect_timeout() -> Result<()> {
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let start = Instant::now();
+    assert!(KvsClient::connect_timeout(addr, Duration::from_millis(200)).is_err());
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    // a non-routable address, unreachable at once or timing out
+    let start = Instant::now();
+    match KvsClient::connect_timeout("10.255.255.1:4000", Duration::from_millis(200)) {
+        Err(KvsError::Timeout) | Err(KvsError::Io(_)) => {}
+        other => panic!("expect timeout, got {:?}", other.map(|_| ())),
+    }
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    // a listener never accepting, the connection is made but the handshake never answered
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let start = Instant::now();
+    assert!(matches!(
+        KvsClient::connect_timeout(listener.local_addr()?, Duration::from_millis(200)),
+        Err(KvsError::Timeout)
+    ));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    Ok(())
+}
+
+// A client with a read timeout should give up on a server which never answers
+#[test]
+fn read_timeout_on_stalled_server() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        // accept the handshake, then read requests without ever answering
+        let mut hello = [0; 7];
+        stream.read_exact(&mut hello).unwrap();
+        stream.write_all(&[0]).unwrap();
+        let mut buf = [0; 1024];
+        while stream.read(&mut buf).is_ok_and(|n| n > 0) {}
+    });
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set_read_timeout(Some(Duration::from_millis(200)))?;
+    client.set_write_timeout(Some(Duration::from_millis(200)))?;
+    let start = Instant::now();
+    assert!(matches!(client.get("key1"), Err(KvsError::Timeout)));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    Ok(())
+}
+
+// A reconnecting client should recover from a restart of the server, sending
+// an idempotent request again and failing the others once
+#[test]
+fn reconnect_after_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let start = || -> Result<_> {
+        let server = KvServer::new(KvStore::open(temp_dir.path())?);
+        let shutdown = server.shutdown_handle();
+        let pool = SharedQueueThreadPool::new(4)?;
+        Ok((shutdown, thread::spawn(move || server.start(addr, pool))))
+    };
+    let restart = |(shutdown, handle): (ShutdownHandle, thread::JoinHandle<Result<()>>)| {
+        shutdown.shutdown();
+        handle.join().unwrap()?;
+        start()
+    };
+
+    let server = start()?;
+    let mut client = loop {
+        if let Ok(client) = KvsClient::connect(addr) {
+            break client.reconnect(20, Duration::from_millis(10), Duration::from_millis(200));
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    client.set("key1", "value1")?;
+
+    let server = restart(server)?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+
+    let _server = restart(server)?;
+    assert!(matches!(client.increment("count", 1), Err(KvsError::Io(_))));
+    assert_eq!(client.increment("count", 1)?, 1);
+    Ok(())
+}
+
+// Threads sharing a pool of fewer connections should each wait for one and all finish
+#[test]
+fn client_pool_shared_by_threads() -> Result<()> {
+    let server = TestServer::kvs()?;
+    let pool = KvsClientPool::new(server.addr(), 3)?;
+    let threads: Vec<_> = (0..8)
+        .map(|thread| {
+            let pool = pool.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    let mut client = pool.get()?;
+                    client.set(format!("key{}_{}", thread, i), format!("value{}", i))?;
+                    assert_eq!(client.get(format!("key{}_{}", thread, i))?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert!(pool.open_connections() <= 3);
+    assert_eq!(pool.get()?.get("key7_49")?, Some("value49".to_owned()));
+    Ok(())
+}
+
+// A pool should close the connections a restart of the server broke and open
+// new ones, and hand out connections as they were opened
+#[test]
+fn client_pool_after_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let start = || -> Result<_> {
+        let server = KvServer::new(KvStore::open(temp_dir.path())?);
+        let shutdown = server.shutdown_handle();
+        let pool = SharedQueueThreadPool::new(4)?;
+        Ok((shutdown, thread::spawn(move || server.start(addr, pool))))
+    };
+    let restart = |(shutdown, handle): (ShutdownHandle, thread::JoinHandle<Result<()>>)| {
+        shutdown.shutdown();
+        handle.join().unwrap()?;
+        start()
+    };
+
+    let server = start()?;
+    let pool = KvsClientPool::new(addr, 2)?;
+    let get = || loop {
+        if let Ok(client) = pool.get() {
+            break client;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    {
+        let (mut first, second) = (get(), get());
+        first.set("key1", "value1")?;
+        first.set_nodelay(false)?;
+        second.set_read_timeout(Some(Duration::from_millis(1)))?;
+    }
+    {
+        let (first, second) = (get(), get());
+        assert!(first.nodelay()? && second.nodelay()?);
+    }
+    assert_eq!(pool.open_connections(), 2);
+
+    let _server = restart(server)?;
+    // each idle connection fails once and is closed when handed back
+    for open in (0..2).rev() {
+        assert!(matches!(pool.get()?.ping(), Err(KvsError::Io(_))));
+        assert_eq!(pool.open_connections(), open);
+    }
+    assert_eq!(get().get("key1")?, Some("value1".to_owned()));
+    assert_eq!(pool.open_connections(), 1);
+    Ok(())
+}
+
+// The async client should round trip against the sync server
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn async_client_round_trip() -> Result<()> {
+    let server = TestServer::kvs()?;
+    let mut client = kvs::AsyncKvsClient::connect(server.addr()).await?;
+    assert_eq!(client.get("key1").await?, None);
+    client.set("key1", "value1").await?;
+    assert_eq!(client.get("key1").await?, Some("value1".to_owned()));
+    client.remove("key1").await?;
+    assert!(matches!(client.remove("key1").await, Err(KvsError::KeyNotFound)));
+    assert_eq!(KvsClient::connect(server.addr())?.get("key1")?, None);
+    Ok(())
+}
@@ -0,0 +1,260 @@
+use kvs::{FlushPolicy, KvsEngine, Result, SledKvsEngine, TypedValue};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+// Copy the files of a directory as they are on disk right now
+fn copy_dir(src: &Path, dest: &Path) {
+    for entry in WalkDir::new(src).into_iter().filter_map(|entry| entry.ok()) {
+        let target = dest.join(entry.path().strip_prefix(src).unwrap());
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target).unwrap();
+        } else {
+            fs::copy(entry.path(), &target).unwrap();
+        }
+    }
+}
+
+// Writes should be on disk when they return with the always flush policy
+#[test]
+fn always_flush_is_durable() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let copy_dir_path = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    let engine = SledKvsEngine::with_flush_policy(db, FlushPolicy::Always)?;
+    for i in 0..100 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.remove("key0".to_owned())?;
+
+    // the engine is still open, the copy only sees what was flushed
+    copy_dir(temp_dir.path(), copy_dir_path.path());
+    let engine = SledKvsEngine::new(sled::open(copy_dir_path.path())?)?;
+    assert_eq!(engine.get("key0".to_owned())?, None);
+    for i in 1..100 {
+        assert_eq!(engine.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Every policy should read back its own writes
+#[test]
+fn flush_policies_read_writes() -> Result<()> {
+    for &policy in &[FlushPolicy::Always, FlushPolicy::Never, FlushPolicy::EveryN(10)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = SledKvsEngine::with_flush_policy(sled::open(temp_dir.path())?, policy)?;
+        for i in 0..100 {
+            engine.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        assert_eq!(engine.get("key50".to_owned())?, Some("value50".to_owned()));
+        engine.remove("key50".to_owned())?;
+        assert_eq!(engine.get("key50".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// Writes should be visible at once without flushing, and on disk after an explicit flush
+#[test]
+fn never_flush_until_flushed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let copy_dir_path = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::with_flush_policy(sled::open(temp_dir.path())?, FlushPolicy::Never)?;
+    for i in 0..100 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+        assert_eq!(engine.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    engine.remove("key0".to_owned())?;
+    assert_eq!(engine.get("key0".to_owned())?, None);
+    let reader = engine.clone();
+    assert_eq!(std::thread::spawn(move || reader.get("key1".to_owned())).join().unwrap()?, Some("value1".to_owned()));
+
+    engine.flush()?;
+    copy_dir(temp_dir.path(), copy_dir_path.path());
+    let engine = SledKvsEngine::new(sled::open(copy_dir_path.path())?)?;
+    assert_eq!(engine.get("key0".to_owned())?, None);
+    for i in 1..100 {
+        assert_eq!(engine.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// contains_key should tell present, absent and removed keys apart
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    assert!(engine.contains_key("key1".to_owned())?);
+    assert!(!engine.contains_key("key2".to_owned())?);
+    assert!(!engine.contains_key("key3".to_owned())?);
+    Ok(())
+}
+
+// Iterating a prefix should visit only the keys starting with it
+#[test]
+fn for_each_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?)?;
+    for key in &["user:1", "user:2", "session:1", "users"] {
+        engine.set(key.to_string(), "value".to_owned())?;
+    }
+    let mut visited = Vec::new();
+    engine.for_each_prefix("user:", |key, _| {
+        visited.push(key);
+        Ok(())
+    })?;
+    assert_eq!(visited, vec!["user:1".to_owned(), "user:2".to_owned()]);
+    Ok(())
+}
+
+// A batch should set every pair, as strings whatever the type before
+#[test]
+fn set_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?)?;
+    engine.increment("key0".to_owned(), 1)?;
+    engine.set_batch((0..100).map(|i| (format!("key{}", i), format!("value{}", i))).collect())?;
+    for i in 0..100 {
+        assert_eq!(engine.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(engine.get_typed("key0".to_owned())?, Some(TypedValue::String("value0".to_owned())));
+    Ok(())
+}
+
+// A batch of 100 sets and a remove should be on disk after its single flush
+#[test]
+fn apply_batch_single_flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let copy_dir_path = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::with_flush_policy(sled::open(temp_dir.path())?, FlushPolicy::Always)?;
+    engine.set_typed("key0".to_owned(), TypedValue::Int(0))?;
+    engine.set("removed".to_owned(), "value".to_owned())?;
+    let mut ops: Vec<_> = (0..100).map(|i| (format!("key{}", i), Some(format!("value{}", i)))).collect();
+    ops.push(("removed".to_owned(), None));
+    ops.push(("missing".to_owned(), None));
+    engine.apply_batch(ops)?;
+    for i in 0..100 {
+        assert_eq!(engine.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(engine.get_typed("key0".to_owned())?, Some(TypedValue::String("value0".to_owned())));
+    assert_eq!(engine.get("removed".to_owned())?, None);
+
+    // the engine is still open, the copy only sees what was flushed
+    copy_dir(temp_dir.path(), copy_dir_path.path());
+    let engine = SledKvsEngine::new(sled::open(copy_dir_path.path())?)?;
+    for i in 0..100 {
+        assert_eq!(engine.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(engine.get("removed".to_owned())?, None);
+    Ok(())
+}
+
+// A range should hold the live keys from its start to its end, excluded
+#[test]
+fn range_of_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?)?;
+    for i in 0..10 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.remove("key4".to_owned())?;
+    engine.remove("key5".to_owned())?;
+    let pairs = |keys: &[u32]| -> Vec<(String, String)> {
+        keys.iter().map(|i| (format!("key{}", i), format!("value{}", i))).collect()
+    };
+    assert_eq!(engine.range("key3".to_owned(), "key7".to_owned())?, pairs(&[3, 6]));
+    assert_eq!(engine.range("key4".to_owned(), "key6".to_owned())?, pairs(&[]));
+    assert_eq!(engine.range("key2".to_owned(), "key3".to_owned())?, pairs(&[2]));
+    assert_eq!(engine.range("key7".to_owned(), "key3".to_owned())?, pairs(&[]));
+    Ok(())
+}
+
+// A clear should remove every key, also after a reopen
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::with_flush_policy(sled::open(temp_dir.path())?, FlushPolicy::Never)?;
+    for i in 0..10 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.set_typed("count".to_owned(), TypedValue::Int(3))?;
+    engine.clear()?;
+    for i in 0..10 {
+        assert_eq!(engine.get(format!("key{}", i))?, None);
+    }
+    assert_eq!(engine.get_typed("count".to_owned())?, None);
+    drop(engine);
+
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.stats()?.live_keys, 0);
+    Ok(())
+}
+
+// A compare-and-swap should swap a matching value only, and drop its type
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?)?;
+    let some = |value: &str| Some(value.to_owned());
+    assert!(engine.compare_and_swap("key1".to_owned(), None, some("value1"))?);
+    assert!(!engine.compare_and_swap("key1".to_owned(), None, some("value2"))?);
+    assert!(!engine.compare_and_swap("key1".to_owned(), some("value2"), some("value3"))?);
+    assert!(engine.compare_and_swap("key1".to_owned(), some("value1"), None)?);
+    assert_eq!(engine.get("key1".to_owned())?, None);
+
+    engine.set_typed("count".to_owned(), TypedValue::Int(3))?;
+    assert!(engine.compare_and_swap("count".to_owned(), some("3"), some("three"))?);
+    assert_eq!(engine.get_typed("count".to_owned())?, Some(TypedValue::String("three".to_owned())));
+    Ok(())
+}
+
+// Append should start a missing key empty, return the new length and drop the type
+#[test]
+fn append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?)?;
+    assert_eq!(engine.append("log".to_owned(), "line1\n".to_owned())?, 6);
+    assert_eq!(engine.append("log".to_owned(), "line2\n".to_owned())?, 12);
+    assert_eq!(engine.get("log".to_owned())?, Some("line1\nline2\n".to_owned()));
+    engine.set_typed("count".to_owned(), TypedValue::Int(4))?;
+    assert_eq!(engine.append("count".to_owned(), "2".to_owned())?, 2);
+    assert_eq!(engine.get_typed("count".to_owned())?, Some(TypedValue::String("42".to_owned())));
+    Ok(())
+}
+
+// Bytes which are not UTF-8 should be read back intact
+#[test]
+fn binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blob = vec![b'a', 0, 0xff, 0xc3, 0x28, 0, b'z'];
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?)?;
+    engine.set_bytes("blob".to_owned(), blob.clone())?;
+    assert_eq!(engine.get_bytes("blob".to_owned())?, Some(blob));
+    engine.remove("blob".to_owned())?;
+    assert_eq!(engine.get_bytes("blob".to_owned())?, None);
+    Ok(())
+}
+
+// get_or_insert should insert a missing key once, racing callers agreeing on its value
+#[test]
+fn get_or_insert() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?)?;
+    assert_eq!(engine.get_or_insert("key1".to_owned(), "first".to_owned())?, "first");
+    assert_eq!(engine.get_or_insert("key1".to_owned(), "second".to_owned())?, "first");
+
+    let threads: Vec<_> = (0..4)
+        .map(|thread| {
+            let engine = engine.clone();
+            std::thread::spawn(move || engine.get_or_insert("race".to_owned(), format!("thread{}", thread)))
+        })
+        .collect();
+    let values = threads.into_iter().map(|thread| thread.join().unwrap()).collect::<Result<Vec<_>>>()?;
+    let stored = engine.get("race".to_owned())?.unwrap();
+    assert!(values.iter().all(|value| *value == stored));
+    Ok(())
+}
@@ -2,7 +2,7 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvsError, Result};
 
 use crossbeam_utils::sync::WaitGroup;
 
@@ -29,6 +29,15 @@ fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
     Ok(())
 }
 
+fn zero_and_one_thread<P: ThreadPool>() -> Result<()> {
+    match P::new(0) {
+        Err(KvsError::InvalidConfig(_)) => {}
+        Err(e) => panic!("expect invalid config error, got {}", e),
+        Ok(_) => panic!("expect a pool of zero threads to be rejected"),
+    }
+    spawn_counter(P::new(1)?)
+}
+
 fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
     const TASK_NUM: usize = 1000;
 
@@ -67,4 +76,19 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
-}
\ No newline at end of file
+}
+
+#[test]
+fn naive_thread_pool_zero_threads() -> Result<()> {
+    zero_and_one_thread::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_zero_threads() -> Result<()> {
+    zero_and_one_thread::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_zero_threads() -> Result<()> {
+    zero_and_one_thread::<RayonThreadPool>()
+}
@@ -0,0 +1,64 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, KvsError, MemoryFileSystem, Result, TieredKvsEngine, WritePolicy};
+use std::sync::Arc;
+use tempfile::TempDir;
+
+// Open an empty store in memory as the hot engine
+fn open_hot() -> Result<KvStore> {
+    let options = KvStoreOptions::new().file_system(Arc::new(MemoryFileSystem::new()));
+    KvStore::open_with_options("/hot", options)
+}
+
+// A key only in the cold engine should be promoted to the hot one by its first read
+#[test]
+fn promote_on_miss() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cold = KvStore::open(temp_dir.path())?;
+    cold.set("key1".to_owned(), "value1".to_owned())?;
+    let hot = open_hot()?;
+    let engine = TieredKvsEngine::new(hot.clone(), cold);
+
+    assert_eq!(hot.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(hot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(hot.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Removes should clear both engines
+#[test]
+fn remove_clears_both_tiers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cold = KvStore::open(temp_dir.path())?;
+    let hot = open_hot()?;
+    let engine = TieredKvsEngine::new(hot.clone(), cold.clone());
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(hot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(cold.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.remove("key1".to_owned())?;
+    assert_eq!(hot.get("key1".to_owned())?, None);
+    assert_eq!(cold.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(matches!(engine.remove("key1".to_owned()), Err(KvsError::KeyNotFound)));
+    Ok(())
+}
+
+// Writes of the invalidate policy should leave the hot engine to the next read
+#[test]
+fn invalidate_on_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cold = KvStore::open(temp_dir.path())?;
+    let hot = open_hot()?;
+    let engine = TieredKvsEngine::new(hot.clone(), cold).write_policy(WritePolicy::Invalidate);
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(hot.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(hot.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.increment("count".to_owned(), 2)?, 2);
+    assert_eq!(engine.increment("count".to_owned(), 3)?, 5);
+    Ok(())
+}